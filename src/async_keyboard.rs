@@ -0,0 +1,483 @@
+// Async counterpart of the typing layer, for backends whose keystrokes are
+// subprocesses or network calls and shouldn't block the tokio runtime.
+// The transcript logic stays in VirtualKeyboard: it runs against a KeyQueue
+// that only records keystrokes, which are then replayed on the async hardware.
+
+use anyhow::{Context, Result};
+use std::future::Future;
+use std::time::Duration;
+
+use crate::input_event::*;
+use crate::virtual_keyboard::{KeyboardHardware, ModifierState, VirtualKeyboard};
+
+/// `KeyboardHardware` with non-blocking operations
+pub trait AsyncKeyboardHardware: Send {
+    fn type_text(&mut self, text: &str) -> impl Future<Output = Result<()>> + Send;
+    fn press_backspace(&mut self) -> impl Future<Output = Result<()>> + Send;
+    fn press_enter(&mut self) -> impl Future<Output = Result<()>> + Send;
+    fn press_key(&mut self, keycode: u16) -> impl Future<Output = Result<()>> + Send;
+    /// Press the keys in order, then release them in reverse (e.g. ctrl+shift+t)
+    fn press_combo(&mut self, keycodes: &[u16]) -> impl Future<Output = Result<()>> + Send;
+    /// Send a key-down event and leave the key held
+    fn press_down(&mut self, keycode: u16) -> impl Future<Output = Result<()>> + Send;
+    /// Send a key-up event, whether or not the key is down
+    fn release_key(&mut self, keycode: u16) -> impl Future<Output = Result<()>> + Send;
+    /// Press a key, keep it down for `duration`, then release it
+    fn hold_key(
+        &mut self,
+        keycode: u16,
+        duration: Duration,
+    ) -> impl Future<Output = Result<()>> + Send;
+    /// Enter a character no key produces via Ctrl+Shift+U, as in the sync trait
+    fn type_unicode(&mut self, c: char) -> impl Future<Output = Result<()>> + Send {
+        async move {
+            self.press_combo(&[KEY_LEFTCTRL, KEY_LEFTSHIFT, KEY_U])
+                .await?;
+            self.type_text(&format!("{:x}", c as u32)).await?;
+            self.press_key(KEY_SPACE).await
+        }
+    }
+    /// Modifiers this backend has pressed and not yet released, as in the sync trait
+    fn modifier_state(&self) -> ModifierState {
+        ModifierState::default()
+    }
+    /// write(2) calls made to the device so far, as in the sync trait
+    fn device_writes(&self) -> Option<u64> {
+        None
+    }
+}
+
+/// A keystroke recorded by `KeyQueue`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum KeyCommand {
+    Type(String),
+    Backspace,
+    Enter,
+    Key(u16),
+    Combo(Vec<u16>),
+    Down(u16),
+    Release(u16),
+    Hold(u16, Duration),
+    Unicode(char),
+    Pause(Duration),
+}
+
+/// Hardware that records keystrokes instead of sending them
+#[derive(Debug, Default)]
+pub struct KeyQueue {
+    commands: Vec<KeyCommand>,
+    /// What the hardware last reported, so `release_all` can warn about it
+    modifiers: ModifierState,
+}
+
+impl KeyQueue {
+    fn push(&mut self, command: KeyCommand) -> Result<()> {
+        self.commands.push(command);
+        Ok(())
+    }
+}
+
+impl KeyboardHardware for KeyQueue {
+    fn type_text(&mut self, text: &str) -> Result<()> {
+        // Consecutive text goes out in one call (one helper process, say)
+        if let Some(KeyCommand::Type(queued)) = self.commands.last_mut() {
+            queued.push_str(text);
+            return Ok(());
+        }
+        self.push(KeyCommand::Type(text.to_string()))
+    }
+
+    fn press_backspace(&mut self) -> Result<()> {
+        self.push(KeyCommand::Backspace)
+    }
+
+    fn press_enter(&mut self) -> Result<()> {
+        self.push(KeyCommand::Enter)
+    }
+
+    fn press_key(&mut self, keycode: u16) -> Result<()> {
+        self.push(KeyCommand::Key(keycode))
+    }
+
+    fn press_combo(&mut self, keycodes: &[u16]) -> Result<()> {
+        self.push(KeyCommand::Combo(keycodes.to_vec()))
+    }
+
+    fn press_down(&mut self, keycode: u16) -> Result<()> {
+        self.push(KeyCommand::Down(keycode))
+    }
+
+    fn release_key(&mut self, keycode: u16) -> Result<()> {
+        self.push(KeyCommand::Release(keycode))
+    }
+
+    fn hold_key(&mut self, keycode: u16, duration: Duration) -> Result<()> {
+        self.push(KeyCommand::Hold(keycode, duration))
+    }
+
+    fn type_unicode(&mut self, c: char) -> Result<()> {
+        self.push(KeyCommand::Unicode(c))
+    }
+
+    fn pause(&mut self, duration: Duration) {
+        self.commands.push(KeyCommand::Pause(duration));
+    }
+
+    fn modifier_state(&self) -> ModifierState {
+        self.modifiers
+    }
+}
+
+/// `VirtualKeyboard` driving async hardware
+pub struct AsyncVirtualKeyboard<A: AsyncKeyboardHardware> {
+    keyboard: VirtualKeyboard<KeyQueue>,
+    hardware: A,
+}
+
+impl<A: AsyncKeyboardHardware> AsyncVirtualKeyboard<A> {
+    pub fn new(hardware: A) -> Self {
+        Self {
+            keyboard: VirtualKeyboard::new(KeyQueue::default()),
+            hardware,
+        }
+    }
+
+    pub fn hardware(&self) -> &A {
+        &self.hardware
+    }
+
+    /// The transcript logic, for its settings
+    pub fn keyboard_mut(&mut self) -> &mut VirtualKeyboard<KeyQueue> {
+        &mut self.keyboard
+    }
+
+    /// Run `f` against the transcript logic, then send the keystrokes it made
+    pub async fn run<T>(
+        &mut self,
+        f: impl FnOnce(&mut VirtualKeyboard<KeyQueue>) -> Result<T>,
+    ) -> Result<T> {
+        let result = f(&mut self.keyboard);
+        // Keystrokes queued before a failure are already in the tracked text
+        self.flush().await?;
+        result
+    }
+
+    pub async fn release_all(&mut self) -> Result<()> {
+        self.keyboard.hardware_mut().modifiers = self.hardware.modifier_state();
+        self.run(|keyboard| keyboard.release_all()).await
+    }
+
+    async fn flush(&mut self) -> Result<()> {
+        let commands = std::mem::take(&mut self.keyboard.hardware_mut().commands);
+        for command in commands {
+            match command {
+                KeyCommand::Type(text) => self.hardware.type_text(&text).await?,
+                KeyCommand::Backspace => self.hardware.press_backspace().await?,
+                KeyCommand::Enter => self.hardware.press_enter().await?,
+                KeyCommand::Key(keycode) => self.hardware.press_key(keycode).await?,
+                KeyCommand::Combo(keycodes) => self.hardware.press_combo(&keycodes).await?,
+                KeyCommand::Down(keycode) => self.hardware.press_down(keycode).await?,
+                KeyCommand::Release(keycode) => self.hardware.release_key(keycode).await?,
+                KeyCommand::Hold(keycode, duration) => {
+                    self.hardware.hold_key(keycode, duration).await?
+                }
+                KeyCommand::Unicode(c) => self.hardware.type_unicode(c).await?,
+                KeyCommand::Pause(duration) => tokio::time::sleep(duration).await,
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Sync `KeyboardHardware` behind the async trait. Each call runs on the
+/// blocking pool, so a held key or the pacing between characters doesn't
+/// stall what runs alongside typing (audio, the STT connection).
+pub struct SyncHardware<H> {
+    /// Lent to the blocking task for the length of each call
+    hardware: Option<H>,
+}
+
+impl<H: KeyboardHardware + Send + 'static> SyncHardware<H> {
+    pub fn new(hardware: H) -> Self {
+        Self {
+            hardware: Some(hardware),
+        }
+    }
+
+    /// The hardware, unless a keystroke that failed took it with it
+    pub fn get_ref(&self) -> Option<&H> {
+        self.hardware.as_ref()
+    }
+
+    async fn call<T: Send + 'static>(
+        &mut self,
+        f: impl FnOnce(&mut H) -> Result<T> + Send + 'static,
+    ) -> Result<T> {
+        let mut hardware = self
+            .hardware
+            .take()
+            .context("Keyboard was lost by an earlier keystroke")?;
+        let (hardware, result) = tokio::task::spawn_blocking(move || {
+            let result = f(&mut hardware);
+            (hardware, result)
+        })
+        .await
+        .context("Keystroke task failed")?;
+        self.hardware = Some(hardware);
+        result
+    }
+}
+
+impl<H: KeyboardHardware + Send + 'static> AsyncKeyboardHardware for SyncHardware<H> {
+    async fn type_text(&mut self, text: &str) -> Result<()> {
+        let text = text.to_string();
+        self.call(move |hardware| hardware.type_text(&text)).await
+    }
+
+    async fn press_backspace(&mut self) -> Result<()> {
+        self.call(|hardware| hardware.press_backspace()).await
+    }
+
+    async fn press_enter(&mut self) -> Result<()> {
+        self.call(|hardware| hardware.press_enter()).await
+    }
+
+    async fn press_key(&mut self, keycode: u16) -> Result<()> {
+        self.call(move |hardware| hardware.press_key(keycode)).await
+    }
+
+    async fn press_combo(&mut self, keycodes: &[u16]) -> Result<()> {
+        let keycodes = keycodes.to_vec();
+        self.call(move |hardware| hardware.press_combo(&keycodes))
+            .await
+    }
+
+    async fn press_down(&mut self, keycode: u16) -> Result<()> {
+        self.call(move |hardware| hardware.press_down(keycode))
+            .await
+    }
+
+    async fn release_key(&mut self, keycode: u16) -> Result<()> {
+        self.call(move |hardware| hardware.release_key(keycode))
+            .await
+    }
+
+    async fn hold_key(&mut self, keycode: u16, duration: Duration) -> Result<()> {
+        self.call(move |hardware| hardware.hold_key(keycode, duration))
+            .await
+    }
+
+    async fn type_unicode(&mut self, c: char) -> Result<()> {
+        self.call(move |hardware| hardware.type_unicode(c)).await
+    }
+
+    fn modifier_state(&self) -> ModifierState {
+        self.get_ref().map(H::modifier_state).unwrap_or_default()
+    }
+
+    fn device_writes(&self) -> Option<u64> {
+        self.get_ref().and_then(H::device_writes)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::virtual_keyboard::{KeyAction, MockKeyboardHardware};
+
+    /// Records what reaches the hardware, yielding to the runtime on every call
+    #[derive(Default)]
+    struct AsyncMock {
+        text: String,
+        log: Vec<String>,
+    }
+
+    impl AsyncMock {
+        async fn record(&mut self, entry: String) -> Result<()> {
+            tokio::task::yield_now().await;
+            self.log.push(entry);
+            Ok(())
+        }
+    }
+
+    impl AsyncKeyboardHardware for AsyncMock {
+        async fn type_text(&mut self, text: &str) -> Result<()> {
+            self.text.push_str(text);
+            self.record(format!("type {text}")).await
+        }
+
+        async fn press_backspace(&mut self) -> Result<()> {
+            self.text.pop();
+            self.record("backspace".to_string()).await
+        }
+
+        async fn press_enter(&mut self) -> Result<()> {
+            self.text.push('\n');
+            self.record("enter".to_string()).await
+        }
+
+        async fn press_key(&mut self, keycode: u16) -> Result<()> {
+            self.record(format!("key {keycode}")).await
+        }
+
+        async fn press_combo(&mut self, keycodes: &[u16]) -> Result<()> {
+            self.record(format!("combo {keycodes:?}")).await
+        }
+
+        async fn press_down(&mut self, keycode: u16) -> Result<()> {
+            self.record(format!("down {keycode}")).await
+        }
+
+        async fn release_key(&mut self, keycode: u16) -> Result<()> {
+            self.record(format!("release {keycode}")).await
+        }
+
+        async fn hold_key(&mut self, keycode: u16, duration: Duration) -> Result<()> {
+            tokio::time::sleep(duration).await;
+            self.record(format!("hold {keycode}")).await
+        }
+    }
+
+    #[tokio::test]
+    async fn test_keystrokes_reach_async_hardware() {
+        let mut kb = AsyncVirtualKeyboard::new(AsyncMock::default());
+        kb.keyboard_mut().set_voice_enter_enabled(true);
+
+        for transcript in ["hello", "hello world", "hello word", "hello word enter"] {
+            kb.run(|kb| kb.update_transcript(transcript)).await.unwrap();
+        }
+        kb.run(|kb| kb.finalize_transcript()).await.unwrap();
+
+        assert_eq!(kb.hardware.text, "hello word\n");
+        assert_eq!(
+            kb.hardware.log,
+            vec![
+                "type hello",
+                "type  world",
+                "backspace",
+                "backspace",
+                "type d",
+                "type  enter",
+                "backspace",
+                "backspace",
+                "backspace",
+                "backspace",
+                "backspace",
+                "backspace",
+                "enter",
+            ]
+        );
+        assert_eq!(kb.keyboard_mut().get_current_text(), "");
+    }
+
+    #[tokio::test]
+    async fn test_sync_hardware_behind_the_async_keyboard() {
+        let mut mock = MockKeyboardHardware::new();
+        mock.modifiers.record(KEY_LEFTSHIFT, true);
+        let mut kb = AsyncVirtualKeyboard::new(SyncHardware::new(mock));
+        assert_eq!(kb.hardware.modifier_state().held(), vec![KEY_LEFTSHIFT]);
+
+        for transcript in ["hello", "hello world", "hello word"] {
+            kb.run(|kb| kb.update_transcript(transcript)).await.unwrap();
+        }
+        kb.run(|kb| kb.finalize_transcript()).await.unwrap();
+        kb.release_all().await.unwrap();
+
+        let mock = kb.hardware.hardware.as_ref().unwrap();
+        assert_eq!(mock.typed_chars.iter().collect::<String>(), "hello word");
+        assert_eq!(mock.backspace_count, 2);
+        assert!(mock.modifiers.is_empty());
+    }
+
+    /// Sync hardware whose holds really wait, like the uinput device's
+    struct SleepingHold(MockKeyboardHardware);
+
+    impl KeyboardHardware for SleepingHold {
+        fn type_text(&mut self, text: &str) -> Result<()> {
+            self.0.type_text(text)
+        }
+
+        fn press_backspace(&mut self) -> Result<()> {
+            self.0.press_backspace()
+        }
+
+        fn press_enter(&mut self) -> Result<()> {
+            self.0.press_enter()
+        }
+
+        fn press_key(&mut self, keycode: u16) -> Result<()> {
+            self.0.press_key(keycode)
+        }
+
+        fn press_combo(&mut self, keycodes: &[u16]) -> Result<()> {
+            self.0.press_combo(keycodes)
+        }
+
+        fn press_down(&mut self, keycode: u16) -> Result<()> {
+            self.0.press_down(keycode)
+        }
+
+        fn release_key(&mut self, keycode: u16) -> Result<()> {
+            self.0.release_key(keycode)
+        }
+
+        fn hold_key(&mut self, keycode: u16, duration: Duration) -> Result<()> {
+            std::thread::sleep(duration);
+            self.0.hold_key(keycode, duration)
+        }
+    }
+
+    #[tokio::test]
+    async fn test_spoken_hold_leaves_the_runtime_free() {
+        let hardware = SleepingHold(MockKeyboardHardware::new());
+        let mut kb = AsyncVirtualKeyboard::new(SyncHardware::new(hardware));
+        kb.keyboard_mut().set_voice_commands_enabled(true);
+        kb.run(|kb| kb.update_transcript("scroll down for 300 ms"))
+            .await
+            .unwrap();
+
+        // The test runtime has one thread, so the ticker only runs if the hold yields it
+        let ticks = std::cell::Cell::new(0);
+        let ticker = async {
+            loop {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+                ticks.set(ticks.get() + 1);
+            }
+        };
+        tokio::select! {
+            result = kb.run(|kb| kb.finalize_transcript()) => result.unwrap(),
+            _ = ticker => unreachable!(),
+        }
+        assert!(
+            ticks.get() >= 10,
+            "only {} ticks during the hold",
+            ticks.get()
+        );
+
+        let mock = &kb.hardware.hardware.as_ref().unwrap().0;
+        assert!(mock.actions.ends_with(&[
+            KeyAction::Press(KEY_DOWN),
+            KeyAction::Wait(Duration::from_millis(300)),
+            KeyAction::Release(KEY_DOWN),
+        ]));
+    }
+
+    #[tokio::test]
+    async fn test_unicode_default_and_hold() {
+        let mut hardware = AsyncMock::default();
+        hardware.type_unicode('é').await.unwrap();
+        hardware
+            .hold_key(KEY_DOWN, Duration::from_millis(1))
+            .await
+            .unwrap();
+        assert_eq!(
+            hardware.log,
+            vec![
+                format!("combo {:?}", [KEY_LEFTCTRL, KEY_LEFTSHIFT, KEY_U]),
+                "type e9".to_string(),
+                format!("key {KEY_SPACE}"),
+                format!("hold {KEY_DOWN}"),
+            ]
+        );
+    }
+}
@@ -1,12 +1,250 @@
-use anyhow::{Context, Result};
+use anyhow::{bail, Context, Result};
 use cpal::traits::{DeviceTrait, HostTrait, StreamTrait};
-use cpal::{Device, SampleFormat, Stream};
-use tracing::{debug, error};
+use cpal::{Device, SampleFormat, Stream, SupportedStreamConfig, SupportedStreamConfigRange};
+use serde::{Deserialize, Serialize};
+use std::path::Path;
+use tracing::{debug, error, warn};
+
+use crate::audio_processing::{ClipCounter, Reformat};
+use crate::error::VkError;
+
+/// An input device with its default and supported capture configs
+/// Fields are `None` when the device doesn't answer the config query.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct DeviceInfo {
+    pub index: usize,
+    pub name: String,
+    pub channels: Option<u16>,
+    pub default_sample_rate: Option<u32>,
+    /// Lowest and highest sample rate across all supported configs
+    pub supported_sample_rate_range: Option<(u32, u32)>,
+}
+
+impl DeviceInfo {
+    /// Build from the results of the default and supported config queries
+    pub fn from_configs(
+        index: usize,
+        name: String,
+        default_config: Option<&SupportedStreamConfig>,
+        supported: Option<&[SupportedStreamConfigRange]>,
+    ) -> Self {
+        let supported_sample_rate_range = supported.and_then(|ranges| {
+            let min = ranges.iter().map(|r| r.min_sample_rate().0).min()?;
+            let max = ranges.iter().map(|r| r.max_sample_rate().0).max()?;
+            Some((min, max))
+        });
+
+        Self {
+            index,
+            name,
+            channels: default_config.map(|c| c.channels()),
+            default_sample_rate: default_config.map(|c| c.sample_rate().0),
+            supported_sample_rate_range,
+        }
+    }
+
+    /// One-line description, e.g. "pipewire (2 ch, 48000 Hz, supports 8000-96000 Hz)"
+    pub fn summary(&self) -> String {
+        let default = match (self.channels, self.default_sample_rate) {
+            (Some(channels), Some(rate)) => format!("{channels} ch, {rate} Hz"),
+            _ => "default config unknown".to_string(),
+        };
+        match self.supported_sample_rate_range {
+            Some((min, max)) => format!("{} ({}, supports {}-{} Hz)", self.name, default, min, max),
+            None => format!("{} ({})", self.name, default),
+        }
+    }
+}
+
+/// The input devices of one audio host
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct DeviceListing {
+    pub host: String,
+    pub devices: Vec<DeviceInfo>,
+}
+
+impl DeviceListing {
+    /// Human-readable listing, one device per line
+    pub fn to_text(&self) -> String {
+        let mut out = format!("Host: {}\n", self.host);
+        if self.devices.is_empty() {
+            out.push_str("  (no input devices found)\n");
+        }
+        for device in &self.devices {
+            out.push_str(&format!("  {}: {}\n", device.index, device.summary()));
+        }
+        out
+    }
+}
+
+/// File in the config directory where `--remember-device` keeps its device
+pub const REMEMBERED_DEVICE_FILE: &str = "device.json";
+
+/// The input device and capture config of the last run that started
+/// recording, so a non-default mic doesn't need `--device` every time
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct RememberedDevice {
+    pub name: String,
+    pub channels: u16,
+    pub sample_rate: u32,
+}
+
+impl RememberedDevice {
+    /// `None` when nothing has been saved yet
+    pub fn load(path: &Path) -> Result<Option<Self>> {
+        let text = match std::fs::read_to_string(path) {
+            Ok(text) => text,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e).with_context(|| format!("Failed to read {}", path.display())),
+        };
+        serde_json::from_str(&text)
+            .map(Some)
+            .with_context(|| format!("Invalid remembered device in {}", path.display()))
+    }
+
+    pub fn save(&self, path: &Path) -> Result<()> {
+        if let Some(dir) = path.parent() {
+            std::fs::create_dir_all(dir)
+                .with_context(|| format!("Failed to create {}", dir.display()))?;
+        }
+        std::fs::write(path, serde_json::to_string_pretty(self)? + "\n")
+            .with_context(|| format!("Failed to write {}", path.display()))
+    }
+
+    /// The saved device if it's still among `available` device names;
+    /// otherwise (or when the file can't be read) warn and use the default
+    pub fn restore(path: &Path, available: &[String]) -> Option<Self> {
+        let remembered = match Self::load(path) {
+            Ok(remembered) => remembered?,
+            Err(e) => {
+                warn!("Ignoring the remembered audio device: {:#}", e);
+                return None;
+            }
+        };
+        if !available.contains(&remembered.name) {
+            warn!(
+                "Remembered audio device '{}' is gone; using the default device",
+                remembered.name
+            );
+            return None;
+        }
+        debug!("Using remembered audio device {:?}", remembered);
+        Some(remembered)
+    }
+}
+
+/// The first of the `preferred` device names (e.g. repeated `--device`s)
+/// that's among the `available` ones
+pub fn pick_device<'a>(preferred: &'a [String], available: &[String]) -> Option<&'a str> {
+    preferred
+        .iter()
+        .find(|name| available.contains(name))
+        .map(String::as_str)
+}
+
+/// The preference to switch to once `current` is no longer available;
+/// `None` while it still is, or when no other preference is
+pub fn failover_device<'a>(
+    preferred: &'a [String],
+    available: &[String],
+    current: &str,
+) -> Option<&'a str> {
+    if available.iter().any(|name| name == current) {
+        return None;
+    }
+    pick_device(preferred, available)
+}
+
+/// Parse a `--capture-format` value
+pub fn parse_capture_format(s: &str) -> Result<SampleFormat> {
+    match s.to_ascii_lowercase().as_str() {
+        "f32" => Ok(SampleFormat::F32),
+        "i16" => Ok(SampleFormat::I16),
+        "u16" => Ok(SampleFormat::U16),
+        other => bail!(
+            "Unknown capture format '{}' (expected f32, i16 or u16)",
+            other
+        ),
+    }
+}
+
+/// Check that one of the device's configs captures `format` with the
+/// channel count and sample rate already chosen from its default config
+fn check_format_supported(
+    supported: &[SupportedStreamConfigRange],
+    format: SampleFormat,
+    config: &cpal::StreamConfig,
+) -> Result<()> {
+    let matches = |range: &&SupportedStreamConfigRange| {
+        range.sample_format() == format
+            && range.channels() == config.channels
+            && (range.min_sample_rate()..=range.max_sample_rate()).contains(&config.sample_rate)
+    };
+    if supported.iter().any(|range| matches(&range)) {
+        return Ok(());
+    }
+
+    let mut available: Vec<String> = supported
+        .iter()
+        .filter(|range| range.channels() == config.channels)
+        .map(|range| range.sample_format().to_string())
+        .collect();
+    available.sort();
+    available.dedup();
+    Err(VkError::UnsupportedFormat(format!(
+        "Device can't capture {} at {} ch, {} Hz (formats it offers there: {})",
+        format,
+        config.channels,
+        config.sample_rate.0,
+        if available.is_empty() {
+            "none".to_string()
+        } else {
+            available.join(", ")
+        }
+    ))
+    .into())
+}
+
+/// Sample formats `start_recording` can convert, most preferred first
+const CAPTURE_FORMATS: [SampleFormat; 3] =
+    [SampleFormat::F32, SampleFormat::I16, SampleFormat::U16];
+
+/// Pick a supported config with exactly `channels` and `sample_rate`
+/// The default format is kept when the device offers it there; otherwise the
+/// first capturable one is used. `None` when no config matches.
+fn select_config(
+    supported: &[SupportedStreamConfigRange],
+    default_format: SampleFormat,
+    channels: u16,
+    sample_rate: u32,
+) -> Option<SupportedStreamConfig> {
+    let rate = cpal::SampleRate(sample_rate);
+    let candidates: Vec<&SupportedStreamConfigRange> = supported
+        .iter()
+        .filter(|range| {
+            range.channels() == channels
+                && (range.min_sample_rate()..=range.max_sample_rate()).contains(&rate)
+        })
+        .collect();
+    std::iter::once(default_format)
+        .chain(CAPTURE_FORMATS)
+        .filter(|format| CAPTURE_FORMATS.contains(format))
+        .find_map(|format| {
+            candidates
+                .iter()
+                .find(|range| range.sample_format() == format)
+        })
+        .map(|range| range.with_sample_rate(rate))
+}
 
 pub struct AudioInput {
     device: Device,
     config: cpal::StreamConfig,
+    sample_format: SampleFormat,
     stream: Option<Stream>,
+    clips: ClipCounter,
+    /// Channels and rate handed to the callback, when not the device's own
+    delivered: Option<(u16, u32)>,
 }
 
 impl AudioInput {
@@ -21,10 +259,11 @@ impl AudioInput {
         debug!("Using input device: {}", device.name()?);
 
         // Get the default config for the input device
-        let config = device
+        let default_config = device
             .default_input_config()
-            .context("Failed to get default input config")?
-            .config();
+            .context("Failed to get default input config")?;
+        let sample_format = default_config.sample_format();
+        let config = default_config.config();
 
         debug!(
             "Input config: {} channels, {} Hz sample rate",
@@ -34,12 +273,61 @@ impl AudioInput {
         Ok(Self {
             device,
             config,
+            sample_format,
             stream: None,
+            clips: ClipCounter::default(),
+            delivered: None,
         })
     }
 
-    #[cfg(false)]
-    #[allow(dead_code)]
+    /// Open the named input device, or the default one
+    pub fn open(device_name: Option<&str>) -> Result<Self> {
+        match device_name {
+            Some(name) => Self::new_with_device_name(name),
+            None => Self::new(),
+        }
+    }
+
+    /// Open the named input device, or the default one, capturing with the
+    /// requested channel count and sample rate when the device supports them
+    /// Unset values keep the default config's; an unsupported combination
+    /// falls back to the default config with a warning.
+    pub fn with_config(
+        device_name: Option<&str>,
+        channels: Option<u16>,
+        sample_rate: Option<u32>,
+    ) -> Result<Self> {
+        let mut input = Self::open(device_name)?;
+        if channels.is_none() && sample_rate.is_none() {
+            return Ok(input);
+        }
+
+        let channels = channels.unwrap_or(input.config.channels);
+        let sample_rate = sample_rate.unwrap_or(input.config.sample_rate.0);
+        let supported: Vec<_> = input
+            .device
+            .supported_input_configs()
+            .context("Failed to query supported input configs")?
+            .collect();
+        match select_config(&supported, input.sample_format, channels, sample_rate) {
+            Some(selected) => {
+                debug!(
+                    "Forcing input config: {} channels, {} Hz, {}",
+                    channels,
+                    sample_rate,
+                    selected.sample_format()
+                );
+                input.sample_format = selected.sample_format();
+                input.config = selected.config();
+            }
+            None => warn!(
+                "Device doesn't support {} ch at {} Hz; using its default {} ch at {} Hz",
+                channels, sample_rate, input.config.channels, input.config.sample_rate.0
+            ),
+        }
+        Ok(input)
+    }
+
     pub fn new_with_device_name(device_name: &str) -> Result<Self> {
         let host = cpal::default_host();
 
@@ -58,15 +346,19 @@ impl AudioInput {
                 }
             })
             .next()
-            .context(format!("Device '{device_name}' not found"))?;
+            .ok_or_else(|| VkError::DeviceUnavailable {
+                message: format!("Device '{device_name}' not found"),
+                errno: None,
+            })?;
 
         debug!("Using input device: {}", device.name()?);
 
         // Get the default config for the input device
-        let config = device
+        let default_config = device
             .default_input_config()
-            .context("Failed to get default input config")?
-            .config();
+            .context("Failed to get default input config")?;
+        let sample_format = default_config.sample_format();
+        let config = default_config.config();
 
         debug!(
             "Input config: {} channels, {} Hz sample rate",
@@ -76,31 +368,104 @@ impl AudioInput {
         Ok(Self {
             device,
             config,
+            sample_format,
             stream: None,
+            clips: ClipCounter::default(),
+            delivered: None,
         })
     }
 
-    pub fn list_available_devices() -> Result<Vec<String>> {
+    pub fn list_available_devices() -> Result<Vec<DeviceInfo>> {
         let host = cpal::default_host();
+        Self::list_host_devices(&host)
+    }
+
+    /// Names of the input devices present right now
+    pub fn available_device_names() -> Result<Vec<String>> {
+        Ok(Self::list_available_devices()?
+            .into_iter()
+            .map(|device| device.name)
+            .collect())
+    }
+
+    fn list_host_devices(host: &cpal::Host) -> Result<Vec<DeviceInfo>> {
         let devices = host.input_devices()?;
 
-        let mut device_names = Vec::new();
-        for device in devices {
-            if let Ok(name) = device.name() {
-                device_names.push(name);
-            }
+        let mut infos = Vec::new();
+        for (index, device) in devices.enumerate() {
+            let Ok(name) = device.name() else {
+                continue;
+            };
+            // Keep devices whose config queries fail; they're listed with unknown fields
+            let default_config = device
+                .default_input_config()
+                .map_err(|e| debug!("No default config for '{}': {}", name, e))
+                .ok();
+            let supported: Option<Vec<_>> = device
+                .supported_input_configs()
+                .map_err(|e| debug!("No supported configs for '{}': {}", name, e))
+                .ok()
+                .map(|configs| configs.collect());
+            infos.push(DeviceInfo::from_configs(
+                index,
+                name,
+                default_config.as_ref(),
+                supported.as_deref(),
+            ));
         }
 
-        Ok(device_names)
+        Ok(infos)
+    }
+
+    /// Describe the default host's input devices without opening any streams
+    pub fn describe_devices() -> Result<DeviceListing> {
+        let host = cpal::default_host();
+        Ok(DeviceListing {
+            host: host.id().name().to_string(),
+            devices: Self::list_host_devices(&host)?,
+        })
+    }
+
+    /// Capture in `format` instead of the device's default format, e.g. to
+    /// work around drivers whose default produces distorted audio
+    pub fn set_sample_format(&mut self, format: SampleFormat) -> Result<()> {
+        let supported: Vec<_> = self
+            .device
+            .supported_input_configs()
+            .context("Failed to query supported input configs")?
+            .collect();
+        check_format_supported(&supported, format, &self.config)?;
+        debug!("Capturing as {} instead of {}", format, self.sample_format);
+        self.sample_format = format;
+        Ok(())
+    }
+
+    /// Hand the callback audio converted to `channels` at `sample_rate`,
+    /// whatever the device captures, e.g. when failing over to a device
+    /// that can't match the processing set up for the previous one
+    pub fn deliver_as(&mut self, channels: u16, sample_rate: u32) {
+        let captured = (self.config.channels, self.config.sample_rate.0);
+        self.delivered = Some((channels, sample_rate)).filter(|&wanted| wanted != captured);
     }
 
     pub fn start_recording<F>(&mut self, mut callback: F) -> Result<()>
     where
         F: FnMut(&[f32]) + Send + 'static,
     {
+        let clips = self.clips.clone();
+        let mut reformat = self.delivered.map(|delivered| {
+            Reformat::new((self.config.channels, self.config.sample_rate.0), delivered)
+        });
+        let mut callback = move |data: &[f32]| {
+            clips.record(data);
+            match &mut reformat {
+                Some(reformat) => callback(&reformat.process(data)),
+                None => callback(data),
+            }
+        };
         let err_fn = |err| error!("An error occurred on the audio stream: {}", err);
 
-        let stream = match self.device.default_input_config()?.sample_format() {
+        let stream = match self.sample_format {
             SampleFormat::F32 => self.device.build_input_stream(
                 &self.config,
                 move |data: &[f32], _: &_| callback(data),
@@ -131,7 +496,7 @@ impl AudioInput {
                 err_fn,
                 None,
             )?,
-            _ => return Err(anyhow::anyhow!("Unsupported sample format")),
+            other => bail!("Unsupported sample format {}", other),
         };
 
         stream.play()?;
@@ -140,16 +505,247 @@ impl AudioInput {
         Ok(())
     }
 
-    #[allow(dead_code)]
     pub fn stop_recording(&mut self) {
         self.stream = None;
     }
 
     pub fn get_sample_rate(&self) -> u32 {
-        self.config.sample_rate.0
+        self.delivered
+            .map_or(self.config.sample_rate.0, |(_, rate)| rate)
     }
 
     pub fn get_channels(&self) -> u16 {
-        self.config.channels
+        self.delivered
+            .map_or(self.config.channels, |(channels, _)| channels)
+    }
+
+    pub fn device_name(&self) -> Result<String> {
+        Ok(self.device.name()?)
+    }
+
+    /// The device and config being captured, to remember for the next run
+    pub fn remembered(&self) -> Result<RememberedDevice> {
+        Ok(RememberedDevice {
+            name: self.device.name()?,
+            channels: self.config.channels,
+            sample_rate: self.config.sample_rate.0,
+        })
+    }
+
+    /// Samples captured at full scale since the device was opened, before
+    /// any gain; a rising count means the input level is set too high
+    pub fn clip_count(&self) -> u64 {
+        self.clips.count()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn range(min: u32, max: u32) -> SupportedStreamConfigRange {
+        SupportedStreamConfigRange::new(
+            2,
+            cpal::SampleRate(min),
+            cpal::SampleRate(max),
+            cpal::SupportedBufferSize::Unknown,
+            SampleFormat::F32,
+        )
+    }
+
+    fn listing() -> DeviceListing {
+        let default_config = SupportedStreamConfig::new(
+            2,
+            cpal::SampleRate(48000),
+            cpal::SupportedBufferSize::Unknown,
+            SampleFormat::F32,
+        );
+        DeviceListing {
+            host: "ALSA".to_string(),
+            devices: vec![
+                DeviceInfo::from_configs(
+                    0,
+                    "pipewire".to_string(),
+                    Some(&default_config),
+                    Some(&[range(44100, 48000), range(8000, 16000)]),
+                ),
+                // Both config queries failed
+                DeviceInfo::from_configs(1, "hw:CARD=USB,DEV=0".to_string(), None, None),
+            ],
+        }
+    }
+
+    #[test]
+    fn test_capture_format_must_be_supported() {
+        let config = cpal::StreamConfig {
+            channels: 2,
+            sample_rate: cpal::SampleRate(48000),
+            buffer_size: cpal::BufferSize::Default,
+        };
+        let i16_range = SupportedStreamConfigRange::new(
+            2,
+            cpal::SampleRate(8000),
+            cpal::SampleRate(48000),
+            cpal::SupportedBufferSize::Unknown,
+            SampleFormat::I16,
+        );
+        let supported = [range(44100, 48000), i16_range, range(8000, 16000)];
+
+        assert!(check_format_supported(&supported, SampleFormat::F32, &config).is_ok());
+        assert!(check_format_supported(&supported, SampleFormat::I16, &config).is_ok());
+        let err = check_format_supported(&supported, SampleFormat::U16, &config).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Device can't capture u16 at 2 ch, 48000 Hz (formats it offers there: f32, i16)"
+        );
+
+        assert_eq!(parse_capture_format("I16").unwrap(), SampleFormat::I16);
+        assert!(parse_capture_format("f64").is_err());
+    }
+
+    #[test]
+    fn test_select_config_prefers_default_format() {
+        let i16_mono = SupportedStreamConfigRange::new(
+            1,
+            cpal::SampleRate(8000),
+            cpal::SampleRate(48000),
+            cpal::SupportedBufferSize::Unknown,
+            SampleFormat::I16,
+        );
+        let f32_mono = SupportedStreamConfigRange::new(
+            1,
+            cpal::SampleRate(16000),
+            cpal::SampleRate(16000),
+            cpal::SupportedBufferSize::Unknown,
+            SampleFormat::F32,
+        );
+        let supported = [range(44100, 48000), i16_mono, f32_mono];
+
+        // Mono 16 kHz is offered in both formats; the default one wins
+        let selected = select_config(&supported, SampleFormat::F32, 1, 16000).unwrap();
+        assert_eq!(selected.channels(), 1);
+        assert_eq!(selected.sample_rate().0, 16000);
+        assert_eq!(selected.sample_format(), SampleFormat::F32);
+        let selected = select_config(&supported, SampleFormat::I16, 1, 16000).unwrap();
+        assert_eq!(selected.sample_format(), SampleFormat::I16);
+
+        // Only the i16 range reaches 8 kHz, so the format changes
+        let selected = select_config(&supported, SampleFormat::F32, 1, 8000).unwrap();
+        assert_eq!(selected.sample_format(), SampleFormat::I16);
+        assert_eq!(selected.config().sample_rate.0, 8000);
+
+        // Stereo is only offered at 44.1-48 kHz
+        let selected = select_config(&supported, SampleFormat::F32, 2, 44100).unwrap();
+        assert_eq!(selected.config().channels, 2);
+    }
+
+    #[test]
+    fn test_select_config_unsupported() {
+        let i8_mono = SupportedStreamConfigRange::new(
+            1,
+            cpal::SampleRate(16000),
+            cpal::SampleRate(16000),
+            cpal::SupportedBufferSize::Unknown,
+            SampleFormat::I8,
+        );
+        let supported = [range(44100, 48000), i8_mono];
+
+        assert!(select_config(&supported, SampleFormat::F32, 2, 16000).is_none());
+        assert!(select_config(&supported, SampleFormat::F32, 6, 48000).is_none());
+        // A matching range in a format that can't be converted doesn't count
+        assert!(select_config(&supported, SampleFormat::I8, 1, 16000).is_none());
+        assert!(select_config(&[], SampleFormat::F32, 1, 16000).is_none());
+    }
+
+    fn names(names: &[&str]) -> Vec<String> {
+        names.iter().map(|name| name.to_string()).collect()
+    }
+
+    #[test]
+    fn test_pick_device_follows_preference_order() {
+        let preferred = names(&["Dock USB Mic", "Webcam", "Built-in"]);
+        let all = names(&["Built-in", "Webcam", "Dock USB Mic"]);
+        assert_eq!(pick_device(&preferred, &all), Some("Dock USB Mic"));
+        // Undocked: the first preference that's left
+        let undocked = names(&["Built-in", "Webcam"]);
+        assert_eq!(pick_device(&preferred, &undocked), Some("Webcam"));
+        assert_eq!(pick_device(&preferred, &names(&["Other"])), None);
+        assert_eq!(pick_device(&[], &all), None);
+    }
+
+    #[test]
+    fn test_failover_only_when_the_current_device_is_gone() {
+        let preferred = names(&["Dock USB Mic", "Webcam", "Built-in"]);
+        let all = names(&["Built-in", "Webcam", "Dock USB Mic"]);
+        assert_eq!(failover_device(&preferred, &all, "Webcam"), None);
+
+        let undocked = names(&["Built-in", "Webcam"]);
+        assert_eq!(
+            failover_device(&preferred, &undocked, "Dock USB Mic"),
+            Some("Webcam")
+        );
+        let only_builtin = names(&["Built-in"]);
+        assert_eq!(
+            failover_device(&preferred, &only_builtin, "Webcam"),
+            Some("Built-in")
+        );
+        assert_eq!(failover_device(&preferred, &[], "Built-in"), None);
+    }
+
+    #[test]
+    fn test_device_info_from_configs() {
+        let devices = listing().devices;
+        assert_eq!(devices[0].channels, Some(2));
+        assert_eq!(devices[0].default_sample_rate, Some(48000));
+        assert_eq!(devices[0].supported_sample_rate_range, Some((8000, 48000)));
+
+        assert_eq!(devices[1].name, "hw:CARD=USB,DEV=0");
+        assert_eq!(devices[1].default_sample_rate, None);
+    }
+
+    #[test]
+    fn test_device_listing_text() {
+        assert_eq!(
+            listing().to_text(),
+            "Host: ALSA\n  0: pipewire (2 ch, 48000 Hz, supports 8000-48000 Hz)\n  1: hw:CARD=USB,DEV=0 (default config unknown)\n"
+        );
+    }
+
+    #[test]
+    fn test_device_listing_json() {
+        let json = serde_json::to_value(listing()).unwrap();
+        assert_eq!(json["host"], "ALSA");
+        assert_eq!(json["devices"][0]["name"], "pipewire");
+        assert_eq!(json["devices"][0]["default_sample_rate"], 48000);
+        assert_eq!(json["devices"][0]["supported_sample_rate_range"][0], 8000);
+        assert!(json["devices"][1]["channels"].is_null());
+    }
+
+    #[test]
+    fn test_remembered_device_roundtrip_and_fallback() {
+        let dir = std::env::temp_dir().join(format!("vk-device-{}", std::process::id()));
+        let path = dir.join(REMEMBERED_DEVICE_FILE);
+        assert_eq!(RememberedDevice::load(&path).unwrap(), None);
+
+        let device = RememberedDevice {
+            name: "hw:CARD=USB,DEV=0".to_string(),
+            channels: 1,
+            sample_rate: 16000,
+        };
+        device.save(&path).unwrap();
+        assert_eq!(RememberedDevice::load(&path).unwrap(), Some(device.clone()));
+
+        let names = |names: &[&str]| names.iter().map(|n| n.to_string()).collect::<Vec<_>>();
+        assert_eq!(
+            RememberedDevice::restore(&path, &names(&["pipewire", "hw:CARD=USB,DEV=0"])),
+            Some(device)
+        );
+        // Unplugged since the last run: back to the default device
+        assert!(RememberedDevice::restore(&path, &names(&["pipewire"])).is_none());
+
+        std::fs::write(&path, "not json").unwrap();
+        assert!(RememberedDevice::load(&path).is_err());
+        assert!(RememberedDevice::restore(&path, &names(&["pipewire"])).is_none());
+        std::fs::remove_dir_all(&dir).unwrap();
     }
 }
@@ -0,0 +1,942 @@
+use anyhow::{bail, Result};
+use std::collections::VecDeque;
+use std::fmt;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::Notify;
+
+/// Root mean square level of a block of samples
+pub fn rms(samples: &[f32]) -> f32 {
+    if samples.is_empty() {
+        return 0.0;
+    }
+    (samples.iter().map(|&s| s * s).sum::<f32>() / samples.len() as f32).sqrt()
+}
+
+/// Mean absolute level of each channel of interleaved samples
+pub fn channel_levels(samples: &[f32], channels: usize) -> Vec<f32> {
+    let channels = channels.max(1);
+    let mut sums = vec![0.0; channels];
+    for frame in samples.chunks_exact(channels) {
+        for (sum, sample) in sums.iter_mut().zip(frame) {
+            *sum += sample.abs();
+        }
+    }
+    let frames = (samples.len() / channels).max(1) as f32;
+    sums.into_iter().map(|sum| sum / frames).collect()
+}
+
+/// Magnitude from which a captured sample counts as clipped; integer formats
+/// never quite reach 1.0 after conversion
+pub const CLIP_LEVEL: f32 = 0.999;
+
+/// Counts captured samples at full scale, shared between the audio callback
+/// and whoever reports it. A clipping microphone degrades recognition.
+#[derive(Debug, Clone, Default)]
+pub struct ClipCounter(Arc<AtomicU64>);
+
+impl ClipCounter {
+    pub fn record(&self, samples: &[f32]) {
+        let clipped = samples.iter().filter(|s| s.abs() >= CLIP_LEVEL).count();
+        if clipped > 0 {
+            self.0.fetch_add(clipped as u64, Ordering::Relaxed);
+        }
+    }
+
+    pub fn count(&self) -> u64 {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+/// How long without an audio chunk before the watchdog warns
+pub const DEFAULT_WATCHDOG_TIMEOUT: Duration = Duration::from_secs(3);
+
+/// Notices when the microphone stops delivering audio (e.g. a hung driver),
+/// which otherwise just looks like a long silence
+/// The audio callback stamps each chunk it sends through a `ChunkStamp`, and
+/// `check` compares the latest stamp with the clock.
+#[derive(Debug)]
+pub struct AudioWatchdog {
+    stamp: ChunkStamp,
+    timeout: Duration,
+    stalled: bool,
+}
+
+impl AudioWatchdog {
+    /// Start watching at `now`, as if a chunk had just arrived
+    pub fn new(timeout: Duration, now: Instant) -> Self {
+        Self {
+            stamp: ChunkStamp {
+                started: now,
+                last_chunk_ms: Arc::new(AtomicU64::new(0)),
+            },
+            timeout,
+            stalled: false,
+        }
+    }
+
+    /// Handle for the audio callback to record chunks with
+    pub fn stamp(&self) -> ChunkStamp {
+        self.stamp.clone()
+    }
+
+    /// How long audio has been missing, reported once when it passes the
+    /// timeout; the next report needs a chunk in between
+    pub fn check(&mut self, now: Instant) -> Option<Duration> {
+        let silent_for = now.saturating_duration_since(self.stamp.last_chunk());
+        if silent_for < self.timeout {
+            self.stalled = false;
+            return None;
+        }
+        if self.stalled {
+            return None;
+        }
+        self.stalled = true;
+        Some(silent_for)
+    }
+}
+
+/// Records when the last audio chunk was sent, shared with the watchdog
+#[derive(Debug, Clone)]
+pub struct ChunkStamp {
+    started: Instant,
+    /// Milliseconds after `started`, so it fits an atomic
+    last_chunk_ms: Arc<AtomicU64>,
+}
+
+impl ChunkStamp {
+    pub fn record(&self, now: Instant) {
+        let ms = now.saturating_duration_since(self.started).as_millis() as u64;
+        self.last_chunk_ms.fetch_max(ms, Ordering::Relaxed);
+    }
+
+    fn last_chunk(&self) -> Instant {
+        self.started + Duration::from_millis(self.last_chunk_ms.load(Ordering::Relaxed))
+    }
+}
+
+/// Audio flow counters for `--verbose-audio`, updated by the audio callback
+/// and rolled up periodically by `take`
+#[derive(Debug, Default)]
+pub struct AudioFlow {
+    chunks: AtomicU64,
+    bytes: AtomicU64,
+    /// Encoded bytes waiting for a full chunk after the last callback
+    buffered: AtomicU64,
+}
+
+impl AudioFlow {
+    /// Count one chunk handed to the STT connection
+    pub fn record_chunk(&self, bytes: usize) {
+        self.chunks.fetch_add(1, Ordering::Relaxed);
+        self.bytes.fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    pub fn set_buffered(&self, bytes: usize) {
+        self.buffered.store(bytes as u64, Ordering::Relaxed);
+    }
+
+    /// Summarize the chunks counted over the last `elapsed` and start over
+    pub fn take(&self, elapsed: Duration) -> AudioFlowSummary {
+        let chunks = self.chunks.swap(0, Ordering::Relaxed);
+        let bytes = self.bytes.swap(0, Ordering::Relaxed);
+        let secs = elapsed.as_secs_f64();
+        let per_sec = |count: u64| if secs > 0.0 { count as f64 / secs } else { 0.0 };
+        AudioFlowSummary {
+            chunks_per_sec: per_sec(chunks),
+            bytes_per_sec: per_sec(bytes),
+            avg_chunk_bytes: bytes.checked_div(chunks).unwrap_or(0),
+            buffered_bytes: self.buffered.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// One `--verbose-audio` rollup
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct AudioFlowSummary {
+    pub chunks_per_sec: f64,
+    pub bytes_per_sec: f64,
+    pub avg_chunk_bytes: u64,
+    pub buffered_bytes: u64,
+}
+
+impl fmt::Display for AudioFlowSummary {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "chunks/s={:.1} bytes/s={:.0} avg_chunk={}B buffered={}B",
+            self.chunks_per_sec, self.bytes_per_sec, self.avg_chunk_bytes, self.buffered_bytes
+        )
+    }
+}
+
+/// Average interleaved samples of any channel count down to mono
+pub fn downmix(samples: &[f32], channels: usize) -> Vec<f32> {
+    if channels <= 1 {
+        return samples.to_vec();
+    }
+    samples
+        .chunks_exact(channels)
+        .map(|frame| frame.iter().sum::<f32>() / channels as f32)
+        .collect()
+}
+
+/// Converts interleaved audio to another channel count and sample rate, so a
+/// device switched to mid-session can feed processing set up for the first.
+/// Channels are averaged and copied out again; the rate is converted by
+/// linear interpolation, carrying the position across blocks.
+#[derive(Debug, Clone)]
+pub struct Reformat {
+    from_channels: usize,
+    to_channels: usize,
+    /// Input frames per output frame
+    step: f64,
+    /// Where the next output frame falls, in frames after `last`
+    pos: f64,
+    /// The previous block's final frame, interpolated from at the seam
+    last: Option<Vec<f32>>,
+}
+
+impl Reformat {
+    /// From `(channels, sample_rate)` to another
+    pub fn new(from: (u16, u32), to: (u16, u32)) -> Self {
+        Self {
+            from_channels: from.0.max(1) as usize,
+            to_channels: to.0.max(1) as usize,
+            step: from.1 as f64 / to.1.max(1) as f64,
+            pos: 0.0,
+            last: None,
+        }
+    }
+
+    pub fn process(&mut self, samples: &[f32]) -> Vec<f32> {
+        let channels = self.to_channels;
+        let samples = if self.from_channels == channels {
+            samples.to_vec()
+        } else {
+            downmix(samples, self.from_channels)
+                .into_iter()
+                .flat_map(|s| std::iter::repeat_n(s, channels))
+                .collect()
+        };
+        if self.step == 1.0 {
+            return samples;
+        }
+
+        let mut frames: Vec<&[f32]> = self.last.iter().map(Vec::as_slice).collect();
+        frames.extend(samples.chunks_exact(channels));
+        let mut out = Vec::new();
+        while (self.pos as usize) + 1 < frames.len() {
+            let (a, b) = (frames[self.pos as usize], frames[self.pos as usize + 1]);
+            let frac = self.pos.fract() as f32;
+            out.extend(a.iter().zip(b).map(|(a, b)| a + (b - a) * frac));
+            self.pos += self.step;
+        }
+        if let Some(&last) = frames.last() {
+            self.pos -= (frames.len() - 1) as f64;
+            self.last = Some(last.to_vec());
+        }
+        out
+    }
+}
+
+/// Per-channel weights for the downmix, scaled to sum to 1
+/// Channels without a weight are left out, so `[1, 0]` keeps just the left channel.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ChannelWeights(Vec<f32>);
+
+impl ChannelWeights {
+    pub fn new(weights: Vec<f32>) -> Result<Self> {
+        if weights.iter().any(|w| !w.is_finite() || *w < 0.0) {
+            bail!(
+                "Channel weights must be non-negative numbers, got {:?}",
+                weights
+            );
+        }
+        let total: f32 = weights.iter().sum();
+        if total <= 0.0 {
+            bail!("At least one channel weight must be above zero");
+        }
+        Ok(Self(weights.into_iter().map(|w| w / total).collect()))
+    }
+
+    /// Use only `channel` (counting from 0)
+    pub fn single(channel: usize) -> Self {
+        let mut weights = vec![0.0; channel + 1];
+        weights[channel] = 1.0;
+        Self(weights)
+    }
+
+    /// Fail if a weighted channel doesn't exist on a device with `channels`
+    pub fn check(&self, channels: u16) -> Result<()> {
+        if let Some(missing) = self.0.iter().skip(channels as usize).position(|&w| w > 0.0) {
+            bail!(
+                "Channel {} is weighted, but the device only has {} channel(s)",
+                channels as usize + missing,
+                channels
+            );
+        }
+        Ok(())
+    }
+
+    /// Weighted sum of each frame of interleaved samples
+    pub fn downmix(&self, samples: &[f32], channels: usize) -> Vec<f32> {
+        samples
+            .chunks_exact(channels.max(1))
+            .map(|frame| frame.iter().zip(&self.0).map(|(s, w)| s * w).sum())
+            .collect()
+    }
+}
+
+impl FromStr for ChannelWeights {
+    type Err = anyhow::Error;
+
+    /// Comma-separated weights, one per channel, e.g. "1,0"
+    fn from_str(s: &str) -> Result<Self> {
+        let weights = s
+            .split(',')
+            .map(|w| {
+                w.trim()
+                    .parse::<f32>()
+                    .map_err(|_| anyhow::anyhow!("Invalid channel weight '{}'", w.trim()))
+            })
+            .collect::<Result<Vec<_>>>()?;
+        Self::new(weights)
+    }
+}
+
+/// Fixed gain applied to every sample, clamped to [-1.0, 1.0]
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Gain {
+    factor: f32,
+}
+
+impl Gain {
+    pub fn new(factor: f32) -> Result<Self> {
+        if !factor.is_finite() || factor <= 0.0 {
+            bail!("Gain must be a positive number, got {}", factor);
+        }
+        Ok(Self { factor })
+    }
+
+    pub fn apply(&self, samples: &mut [f32]) {
+        for sample in samples.iter_mut() {
+            *sample = (*sample * self.factor).clamp(-1.0, 1.0);
+        }
+    }
+}
+
+/// Automatic gain that steers the block RMS toward a target level
+/// The gain moves gradually (by `smoothing` per block) so it doesn't pump,
+/// and blocks quieter than `silence_floor` leave it alone so background
+/// noise isn't boosted between utterances.
+#[derive(Debug, Clone, PartialEq)]
+pub struct AutoGain {
+    target_rms: f32,
+    max_gain: f32,
+    smoothing: f32,
+    silence_floor: f32,
+    gain: f32,
+}
+
+impl AutoGain {
+    pub fn new(target_rms: f32) -> Self {
+        Self {
+            target_rms,
+            max_gain: 20.0,
+            smoothing: 0.2,
+            silence_floor: 0.0005,
+            gain: 1.0,
+        }
+    }
+
+    pub fn apply(&mut self, samples: &mut [f32]) {
+        let level = rms(samples);
+        if level > self.silence_floor {
+            let desired = (self.target_rms / level).clamp(1.0 / self.max_gain, self.max_gain);
+            self.gain += (desired - self.gain) * self.smoothing;
+        }
+
+        for sample in samples.iter_mut() {
+            *sample = (*sample * self.gain).clamp(-1.0, 1.0);
+        }
+    }
+}
+
+/// Gain stage selected on the command line
+#[derive(Debug, Clone, PartialEq, Default)]
+pub enum GainMode {
+    #[default]
+    Off,
+    Fixed(Gain),
+    Auto(AutoGain),
+}
+
+impl GainMode {
+    pub fn apply(&mut self, samples: &mut [f32]) {
+        match self {
+            GainMode::Off => {}
+            GainMode::Fixed(gain) => gain.apply(samples),
+            GainMode::Auto(auto) => auto.apply(samples),
+        }
+    }
+}
+
+/// Default audio kept from before the gate opens
+pub const DEFAULT_PREBUFFER: Duration = Duration::from_millis(300);
+
+/// How long the gate stays open after the level drops, so pauses between
+/// words don't chop the audio
+const GATE_HOLD: Duration = Duration::from_millis(500);
+
+/// Noise gate that sends silence while the level is below a threshold
+/// While closed it holds back the most recent `prebuffer` of audio; when
+/// speech opens it, that audio goes out first so the onset isn't clipped.
+/// Every input sample yields one output sample, so the stream keeps pace.
+#[derive(Debug, Clone)]
+pub struct NoiseGate {
+    threshold: f32,
+    /// Samples the gate stays open after the level drops
+    hold: usize,
+    /// Samples left until the gate closes; zero while closed
+    open_for: usize,
+    prebuffer: VecDeque<f32>,
+    prebuffer_len: usize,
+}
+
+impl NoiseGate {
+    /// `threshold` is a block RMS level; `sample_rate` is of the mono stream
+    pub fn new(threshold: f32, sample_rate: u32, prebuffer: Duration) -> Self {
+        let samples = |d: Duration| (d.as_secs_f64() * f64::from(sample_rate)) as usize;
+        Self {
+            threshold,
+            hold: samples(GATE_HOLD),
+            open_for: 0,
+            prebuffer: VecDeque::new(),
+            prebuffer_len: samples(prebuffer),
+        }
+    }
+
+    pub fn is_open(&self) -> bool {
+        self.open_for > 0
+    }
+
+    /// Gate one block of mono samples, returning what to send
+    pub fn process(&mut self, block: &[f32]) -> Vec<f32> {
+        let was_open = self.is_open();
+        if rms(block) >= self.threshold {
+            self.open_for = self.hold;
+        } else {
+            self.open_for = self.open_for.saturating_sub(block.len());
+        }
+
+        if was_open {
+            return block.to_vec();
+        }
+        if self.is_open() {
+            // Just opened: the held-back audio leads into the speech
+            let mut out: Vec<f32> = self.prebuffer.drain(..).collect();
+            out.extend_from_slice(block);
+            return out;
+        }
+
+        // Closed: hold on to the newest audio and let silence out in its place
+        self.prebuffer.extend(block);
+        let overflow = self.prebuffer.len().saturating_sub(self.prebuffer_len);
+        self.prebuffer.drain(..overflow);
+        vec![0.0; overflow]
+    }
+}
+
+/// Silence after speech that ends a turn with `--local-endpointing`
+pub const DEFAULT_ENDPOINT_SILENCE: Duration = Duration::from_millis(800);
+
+/// Block RMS level that counts as speech for local endpointing when no
+/// `--noise-gate` level is given
+pub const DEFAULT_SPEECH_LEVEL: f32 = 0.01;
+
+/// Energy-based end-of-speech detection for servers that never end a turn
+/// Reports once when the level has stayed below `threshold` for `silence`
+/// after some speech; the next report needs speech again.
+#[derive(Debug, Clone)]
+pub struct Endpointer {
+    threshold: f32,
+    /// Samples of silence that end the speech
+    silence: usize,
+    heard_speech: bool,
+    quiet_for: usize,
+}
+
+impl Endpointer {
+    /// `threshold` is a block RMS level; `sample_rate` is of the mono stream
+    pub fn new(threshold: f32, sample_rate: u32, silence: Duration) -> Self {
+        Self {
+            threshold,
+            silence: (silence.as_secs_f64() * f64::from(sample_rate)) as usize,
+            heard_speech: false,
+            quiet_for: 0,
+        }
+    }
+
+    /// Take one block of mono samples; true when it completes the silence
+    /// that ends the speech
+    pub fn process(&mut self, block: &[f32]) -> bool {
+        if rms(block) >= self.threshold {
+            self.heard_speech = true;
+            self.quiet_for = 0;
+            return false;
+        }
+        if !self.heard_speech {
+            return false;
+        }
+        self.quiet_for += block.len();
+        if self.quiet_for < self.silence {
+            return false;
+        }
+        self.heard_speech = false;
+        self.quiet_for = 0;
+        true
+    }
+}
+
+/// Lets the audio callback tell the typing loop that speech ended
+/// Clones share the same signal.
+#[derive(Debug, Clone, Default)]
+pub struct EndOfSpeech(Arc<Notify>);
+
+impl EndOfSpeech {
+    pub fn fire(&self) {
+        self.0.notify_one();
+    }
+
+    pub async fn fired(&self) {
+        self.0.notified().await;
+    }
+}
+
+/// Wire encoding of the audio sent to the STT service
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Encoding {
+    /// 16-bit little-endian PCM
+    #[default]
+    Linear16,
+    /// G.711 mu-law, 8 bits per sample
+    Mulaw,
+    /// G.711 A-law, 8 bits per sample
+    Alaw,
+}
+
+impl FromStr for Encoding {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_ascii_lowercase().as_str() {
+            "linear16" => Ok(Self::Linear16),
+            "mulaw" => Ok(Self::Mulaw),
+            "alaw" => Ok(Self::Alaw),
+            other => bail!(
+                "Unknown encoding '{}' (expected linear16, mulaw or alaw)",
+                other
+            ),
+        }
+    }
+}
+
+impl Encoding {
+    /// Value of the STT `encoding` query parameter
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Linear16 => "linear16",
+            Self::Mulaw => "mulaw",
+            Self::Alaw => "alaw",
+        }
+    }
+
+    pub fn bytes_per_sample(self) -> usize {
+        match self {
+            Self::Linear16 => 2,
+            Self::Mulaw | Self::Alaw => 1,
+        }
+    }
+
+    /// Append the encoded bytes of one 16-bit sample to `out`
+    pub fn encode(self, sample: i16, out: &mut Vec<u8>) {
+        match self {
+            Self::Linear16 => out.extend_from_slice(&sample.to_le_bytes()),
+            Self::Mulaw => out.push(linear_to_mulaw(sample)),
+            Self::Alaw => out.push(linear_to_alaw(sample)),
+        }
+    }
+}
+
+/// Index of the first segment end that `value` fits under (8 if none)
+fn segment(value: i16, ends: &[i16; 8]) -> i16 {
+    ends.iter().position(|&end| value <= end).unwrap_or(8) as i16
+}
+
+/// G.711 mu-law, as in the CCITT reference (14-bit magnitude, bias 33)
+pub fn linear_to_mulaw(sample: i16) -> u8 {
+    const ENDS: [i16; 8] = [0x3F, 0x7F, 0xFF, 0x1FF, 0x3FF, 0x7FF, 0xFFF, 0x1FFF];
+    const CLIP: i16 = 8159;
+    const BIAS: i16 = 0x21;
+
+    let value = sample >> 2;
+    let (magnitude, mask) = if value < 0 {
+        (-value, 0x7F)
+    } else {
+        (value, 0xFF)
+    };
+    let magnitude = magnitude.min(CLIP) + BIAS;
+    let seg = segment(magnitude, &ENDS);
+    if seg >= 8 {
+        return 0x7F ^ mask;
+    }
+    let code = (seg << 4) as u8 | ((magnitude >> (seg + 1)) & 0xF) as u8;
+    code ^ mask
+}
+
+/// G.711 A-law, as in the CCITT reference (13-bit magnitude)
+pub fn linear_to_alaw(sample: i16) -> u8 {
+    const ENDS: [i16; 8] = [0x1F, 0x3F, 0x7F, 0xFF, 0x1FF, 0x3FF, 0x7FF, 0xFFF];
+
+    let value = sample >> 3;
+    let (magnitude, mask) = if value >= 0 {
+        (value, 0xD5)
+    } else {
+        (-value - 1, 0x55)
+    };
+    let seg = segment(magnitude, &ENDS);
+    if seg >= 8 {
+        return 0x7F ^ mask;
+    }
+    let shift = if seg < 2 { 1 } else { seg };
+    let code = (seg << 4) as u8 | ((magnitude >> shift) & 0xF) as u8;
+    code ^ mask
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_channel_levels_of_interleaved_samples() {
+        // Three channels: loud, silent, and a quiet one with mixed signs
+        let samples = [0.8, 0.0, 0.1, -0.6, 0.0, -0.3, 0.8, 0.0, 0.2];
+        let levels = channel_levels(&samples, 3);
+        let expected = [0.733_333, 0.0, 0.2];
+        for (level, expected) in levels.iter().zip(expected) {
+            assert!((level - expected).abs() < 1e-5, "{levels:?}");
+        }
+        assert_eq!(channel_levels(&[0.5, -0.5], 1), vec![0.5]);
+        assert_eq!(channel_levels(&[], 2), vec![0.0, 0.0]);
+    }
+
+    #[test]
+    fn test_clip_counter_counts_full_scale_samples() {
+        let clips = ClipCounter::default();
+        let callback_clips = clips.clone();
+        callback_clips.record(&[0.5, 1.0, -1.0, 0.998, -0.9995]);
+        callback_clips.record(&[0.0; 16]);
+        callback_clips.record(&[i16::MIN as f32 / i16::MAX as f32, 1.0]);
+        assert_eq!(clips.count(), 5);
+        assert_eq!(ClipCounter::default().count(), 0);
+    }
+
+    #[test]
+    fn test_downmix_any_channel_count() {
+        let quad = [0.4, 0.0, 0.0, 0.0, 1.0, 1.0, -1.0, -1.0];
+        assert_eq!(downmix(&quad, 4), vec![0.1, 0.0]);
+        assert_eq!(downmix(&[0.2, 0.4, 0.6, 0.8], 2), vec![0.3, 0.70000005]);
+        assert_eq!(downmix(&[0.5, 0.25], 1), vec![0.5, 0.25]);
+    }
+
+    #[test]
+    fn test_reformat_channels_and_rate() {
+        // Same format: passed through untouched
+        let mut same = Reformat::new((2, 16_000), (2, 16_000));
+        assert_eq!(
+            same.process(&[0.1, 0.2, 0.3, 0.4]),
+            vec![0.1, 0.2, 0.3, 0.4]
+        );
+
+        // Stereo to mono and back
+        let mut to_mono = Reformat::new((2, 16_000), (1, 16_000));
+        assert_eq!(
+            to_mono.process(&[0.2, 0.4, 0.6, 0.8]),
+            vec![0.3, 0.70000005]
+        );
+        let mut to_stereo = Reformat::new((1, 16_000), (2, 16_000));
+        assert_eq!(to_stereo.process(&[0.5, -0.5]), vec![0.5, 0.5, -0.5, -0.5]);
+
+        // A ramp at 48 kHz comes out as the same ramp at 16 kHz, across blocks
+        let ramp: Vec<f32> = (0..480).map(|i| i as f32).collect();
+        let mut down = Reformat::new((1, 48_000), (1, 16_000));
+        let mut out = down.process(&ramp[..100]);
+        out.extend(down.process(&ramp[100..]));
+        assert_eq!(out.len(), 160);
+        assert!(out.iter().enumerate().all(|(i, &s)| s == (i * 3) as f32));
+
+        // And up from 8 kHz, interpolating; a frame waits for the one after it
+        let mut up = Reformat::new((1, 8_000), (1, 16_000));
+        let mut out = up.process(&[0.0, 1.0]);
+        out.extend(up.process(&[2.0]));
+        assert_eq!(out, vec![0.0, 0.5, 1.0, 1.5]);
+    }
+
+    #[test]
+    fn test_audio_flow_rolls_up_chunks() {
+        let flow = AudioFlow::default();
+        // One second of 160 ms chunks at 16 kHz linear16 (5120 bytes each)
+        for _ in 0..6 {
+            flow.record_chunk(5120);
+        }
+        flow.set_buffered(1280);
+        let summary = flow.take(Duration::from_secs(1));
+        assert_eq!(summary.chunks_per_sec, 6.0);
+        assert_eq!(summary.bytes_per_sec, 30_720.0);
+        assert_eq!(summary.avg_chunk_bytes, 5120);
+        assert_eq!(summary.buffered_bytes, 1280);
+        assert_eq!(
+            summary.to_string(),
+            "chunks/s=6.0 bytes/s=30720 avg_chunk=5120B buffered=1280B"
+        );
+
+        // Counts start over; a short final chunk lowers the average
+        flow.record_chunk(5120);
+        flow.record_chunk(5120);
+        flow.record_chunk(2560);
+        let summary = flow.take(Duration::from_millis(500));
+        assert_eq!(summary.chunks_per_sec, 6.0);
+        assert_eq!(summary.bytes_per_sec, 25_600.0);
+        assert_eq!(summary.avg_chunk_bytes, 4266);
+
+        // Nothing sent (or no time passed) reports zeros rather than NaN
+        let summary = flow.take(Duration::from_secs(1));
+        assert_eq!((summary.chunks_per_sec, summary.avg_chunk_bytes), (0.0, 0));
+        assert_eq!(flow.take(Duration::ZERO).bytes_per_sec, 0.0);
+    }
+
+    #[test]
+    fn test_endpointer_fires_once_after_speech_then_silence() {
+        // 100 ms blocks at 16 kHz, ending the speech after 800 ms of silence
+        let mut endpointer = Endpointer::new(0.01, 16_000, Duration::from_millis(800));
+        let speech = vec![0.2; 1600];
+        let silence = vec![0.001; 1600];
+        let profile = [
+            (&silence, 5),
+            (&speech, 10),
+            // A pause shorter than the silence doesn't end it
+            (&silence, 4),
+            (&speech, 5),
+            (&silence, 20),
+        ];
+        let mut fired_at = Vec::new();
+        let mut block = 0;
+        for (samples, count) in profile {
+            for _ in 0..count {
+                if endpointer.process(samples) {
+                    fired_at.push(block);
+                }
+                block += 1;
+            }
+        }
+        // Eight blocks into the final silence, which starts at block 24
+        assert_eq!(fired_at, vec![31]);
+
+        // It takes more speech to fire again
+        for _ in 0..3 {
+            assert!(!endpointer.process(&speech));
+        }
+        let fired = (0..8).filter(|_| endpointer.process(&silence)).count();
+        assert_eq!(fired, 1);
+    }
+
+    #[test]
+    fn test_watchdog_reports_a_stall_once() {
+        let start = Instant::now();
+        let at = |ms| start + Duration::from_millis(ms);
+        let mut watchdog = AudioWatchdog::new(Duration::from_secs(3), start);
+        let stamp = watchdog.stamp();
+
+        // Chunks keep it quiet however long the session runs
+        for ms in (0..10_000).step_by(100) {
+            stamp.record(at(ms));
+            assert_eq!(watchdog.check(at(ms + 50)), None);
+        }
+
+        // The callback goes quiet after the chunk at 9.9 s
+        assert_eq!(watchdog.check(at(12_000)), None);
+        assert_eq!(watchdog.check(at(12_900)), Some(Duration::from_secs(3)));
+        assert_eq!(watchdog.check(at(14_000)), None);
+
+        // Audio coming back re-arms it
+        stamp.record(at(14_500));
+        assert_eq!(watchdog.check(at(15_000)), None);
+        assert_eq!(
+            watchdog.check(at(18_000)),
+            Some(Duration::from_millis(3500))
+        );
+
+        // No audio at all from the start counts too
+        let mut silent = AudioWatchdog::new(Duration::from_secs(3), start);
+        assert_eq!(silent.check(at(2_999)), None);
+        assert!(silent.check(at(3_000)).is_some());
+    }
+
+    #[test]
+    fn test_channel_weights_downmix() {
+        let stereo = [0.2, 0.4, 0.6, 0.8];
+        let left: ChannelWeights = "1,0".parse().unwrap();
+        assert_eq!(left.downmix(&stereo, 2), vec![0.2, 0.6]);
+        assert_eq!(
+            ChannelWeights::single(1).downmix(&stereo, 2),
+            vec![0.4, 0.8]
+        );
+
+        // Equal weights of any size are the plain average
+        let equal: ChannelWeights = "2, 2".parse().unwrap();
+        assert_eq!(equal.downmix(&stereo, 2), downmix(&stereo, 2));
+
+        let quad = [0.4, 0.0, 0.0, 0.0, 1.0, 1.0, -1.0, -1.0];
+        let weighted: ChannelWeights = "3,1".parse().unwrap();
+        assert_eq!(weighted.downmix(&quad, 4), vec![0.3, 1.0]);
+
+        assert!("1,x".parse::<ChannelWeights>().is_err());
+        assert!("0,0".parse::<ChannelWeights>().is_err());
+        assert!("1,-1".parse::<ChannelWeights>().is_err());
+
+        assert!(left.check(2).is_ok());
+        assert!(left.check(1).is_ok());
+        assert!(ChannelWeights::single(2).check(2).is_err());
+    }
+
+    #[test]
+    fn test_noise_gate_prepends_prebuffer_when_opening() {
+        // 1 kHz keeps the sample counts readable: 3 ms of prebuffer, 500 ms hold
+        let mut gate = NoiseGate::new(0.1, 1000, Duration::from_millis(3));
+        let quiet = |n: usize, start: usize| -> Vec<f32> {
+            (start..start + n).map(|i| i as f32 * 1e-4).collect()
+        };
+
+        // Closed: silence goes out, delayed by what the prebuffer holds
+        assert_eq!(gate.process(&quiet(2, 1)), Vec::<f32>::new());
+        assert_eq!(gate.process(&quiet(2, 3)), vec![0.0]);
+        assert!(!gate.is_open());
+
+        // Speech: the three pre-speech samples come out ahead of it
+        let speech = vec![0.5; 4];
+        let out = gate.process(&speech);
+        assert_eq!(&out[..3], quiet(3, 2).as_slice());
+        assert_eq!(&out[3..], speech.as_slice());
+        assert!(gate.is_open());
+
+        // Every sample in yields one sample out
+        let quiet_again = quiet(4, 10);
+        assert_eq!(gate.process(&quiet_again), quiet_again);
+    }
+
+    #[test]
+    fn test_noise_gate_closes_after_hold() {
+        let mut gate = NoiseGate::new(0.1, 1000, Duration::ZERO);
+        gate.process(&[0.5; 10]);
+        // Still open through a pause shorter than the hold
+        assert_eq!(gate.process(&[0.01; 400]), vec![0.01; 400]);
+        assert!(gate.is_open());
+        gate.process(&[0.01; 100]);
+        assert!(!gate.is_open());
+        assert_eq!(gate.process(&[0.01; 10]), vec![0.0; 10]);
+    }
+
+    fn sine(amplitude: f32, len: usize) -> Vec<f32> {
+        (0..len)
+            .map(|i| amplitude * (i as f32 * 2.0 * std::f32::consts::PI * 440.0 / 16_000.0).sin())
+            .collect()
+    }
+
+    #[test]
+    fn test_fixed_gain_amplifies_and_clamps() {
+        let gain = Gain::new(4.0).unwrap();
+        let mut samples = vec![0.1, -0.1, 0.5, -0.9];
+        gain.apply(&mut samples);
+        assert_eq!(samples, vec![0.4, -0.4, 1.0, -1.0]);
+    }
+
+    #[test]
+    fn test_fixed_gain_rejects_invalid_factor() {
+        assert!(Gain::new(0.0).is_err());
+        assert!(Gain::new(-2.0).is_err());
+        assert!(Gain::new(f32::NAN).is_err());
+    }
+
+    #[test]
+    fn test_auto_gain_amplifies_quiet_sine_toward_target() {
+        let mut auto = AutoGain::new(0.1);
+        let quiet = sine(0.01, 1600);
+        let input_rms = rms(&quiet);
+
+        let mut output_rms = 0.0;
+        for _ in 0..50 {
+            let mut block = quiet.clone();
+            auto.apply(&mut block);
+            assert!(block.iter().all(|s| (-1.0..=1.0).contains(s)));
+            output_rms = rms(&block);
+        }
+
+        assert!(output_rms > input_rms * 5.0);
+        assert!((output_rms - 0.1).abs() < 0.01, "rms was {output_rms}");
+    }
+
+    #[test]
+    fn test_auto_gain_never_exceeds_clamp() {
+        let mut auto = AutoGain::new(0.9);
+        for _ in 0..50 {
+            let mut block = sine(0.5, 1600);
+            auto.apply(&mut block);
+            assert!(block.iter().all(|s| (-1.0..=1.0).contains(s)));
+        }
+    }
+
+    #[test]
+    fn test_auto_gain_ignores_silence() {
+        let mut auto = AutoGain::new(0.1);
+        let mut silence = vec![0.0; 1600];
+        auto.apply(&mut silence);
+        assert_eq!(auto.gain, 1.0);
+    }
+
+    #[test]
+    fn test_mulaw_and_alaw_reference_values() {
+        // (linear, mu-law, A-law) from the G.711 reference implementation
+        let cases: [(i16, u8, u8); 10] = [
+            (0, 0xFF, 0xD5),
+            (1, 0xFF, 0xD5),
+            (-1, 0x7E, 0x55),
+            (100, 0xF2, 0xD3),
+            (-100, 0x72, 0x53),
+            (1000, 0xCE, 0xFA),
+            (-1000, 0x4E, 0x7A),
+            (8000, 0xA0, 0x8A),
+            (i16::MAX, 0x80, 0xAA),
+            (i16::MIN, 0x00, 0x2A),
+        ];
+        for (linear, mulaw, alaw) in cases {
+            assert_eq!(linear_to_mulaw(linear), mulaw, "mu-law of {linear}");
+            assert_eq!(linear_to_alaw(linear), alaw, "A-law of {linear}");
+        }
+    }
+
+    #[test]
+    fn test_encoding_parse_and_encode() {
+        assert_eq!("MULAW".parse::<Encoding>().unwrap(), Encoding::Mulaw);
+        assert_eq!("alaw".parse::<Encoding>().unwrap(), Encoding::Alaw);
+        assert!("opus".parse::<Encoding>().is_err());
+
+        let mut out = Vec::new();
+        Encoding::Linear16.encode(-2, &mut out);
+        Encoding::Mulaw.encode(0, &mut out);
+        Encoding::Alaw.encode(0, &mut out);
+        assert_eq!(out, vec![0xFE, 0xFF, 0xFF, 0xD5]);
+    }
+}
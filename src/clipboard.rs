@@ -0,0 +1,196 @@
+// Optional copy of each finalized line to the clipboard, as a backup for
+// apps that drop keystrokes. The text goes through a helper program
+// (wl-copy, xclip, ...) that reads it on stdin.
+
+use anyhow::{anyhow, bail, Context, Result};
+use std::io::Write;
+use std::path::Path;
+use std::process::{Command, Stdio};
+use tracing::warn;
+
+use crate::virtual_keyboard::{KeyboardHardware, VirtualKeyboard};
+
+/// Somewhere finalized lines are copied to
+pub trait ClipboardWriter: Send {
+    fn copy(&self, text: &str) -> Result<()>;
+}
+
+/// Copies by running a command with the text on its stdin
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CommandClipboard {
+    program: String,
+    args: Vec<String>,
+}
+
+impl CommandClipboard {
+    /// Parse a command line such as `xclip -selection clipboard`
+    pub fn parse(command: &str) -> Result<Self> {
+        let mut words = command.split_whitespace().map(str::to_string);
+        let program = words
+            .next()
+            .ok_or_else(|| anyhow!("Clipboard command is empty"))?;
+        Ok(Self {
+            program,
+            args: words.collect(),
+        })
+    }
+
+    /// wl-copy in a Wayland session, xclip otherwise
+    pub fn detect() -> Self {
+        let command = if std::env::var_os("WAYLAND_DISPLAY").is_some() {
+            "wl-copy"
+        } else {
+            "xclip -selection clipboard"
+        };
+        Self::parse(command).expect("built-in command is not empty")
+    }
+
+    pub fn program(&self) -> &str {
+        &self.program
+    }
+
+    /// Whether the program exists, by path or on $PATH
+    pub fn is_available(&self) -> bool {
+        if self.program.contains('/') {
+            return Path::new(&self.program).is_file();
+        }
+        std::env::var_os("PATH").is_some_and(|paths| {
+            std::env::split_paths(&paths).any(|dir| dir.join(&self.program).is_file())
+        })
+    }
+}
+
+impl ClipboardWriter for CommandClipboard {
+    fn copy(&self, text: &str) -> Result<()> {
+        let mut child = Command::new(&self.program)
+            .args(&self.args)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .spawn()
+            .with_context(|| format!("Failed to run {}", self.program))?;
+        // Dropping stdin closes it, so the program sees the end of the text
+        child
+            .stdin
+            .take()
+            .context("No stdin for the clipboard command")?
+            .write_all(text.as_bytes())?;
+        // wl-copy and xclip fork to serve the selection, so this returns quickly
+        let status = child.wait()?;
+        if !status.success() {
+            bail!("{} exited with {}", self.program, status);
+        }
+        Ok(())
+    }
+}
+
+/// Copy every finalized line to `writer`; a failed copy is logged, typing goes on
+pub fn mirror_to<H, W>(keyboard: &mut VirtualKeyboard<H>, writer: W)
+where
+    H: KeyboardHardware,
+    W: ClipboardWriter + 'static,
+{
+    keyboard.set_on_finalize(move |line| {
+        if let Err(e) = writer.copy(line) {
+            warn!("Failed to copy to the clipboard: {:#}", e);
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::virtual_keyboard::MockKeyboardHardware;
+    use std::sync::{Arc, Mutex};
+
+    /// Keeps everything copied, for inspection
+    #[derive(Clone, Default)]
+    struct RecordingClipboard {
+        copies: Arc<Mutex<Vec<String>>>,
+    }
+
+    impl ClipboardWriter for RecordingClipboard {
+        fn copy(&self, text: &str) -> Result<()> {
+            self.copies.lock().unwrap().push(text.to_string());
+            Ok(())
+        }
+    }
+
+    struct BrokenClipboard;
+
+    impl ClipboardWriter for BrokenClipboard {
+        fn copy(&self, _text: &str) -> Result<()> {
+            bail!("no clipboard here")
+        }
+    }
+
+    #[test]
+    fn test_finalized_lines_are_copied_without_commands() {
+        let clipboard = RecordingClipboard::default();
+        let mut kb = VirtualKeyboard::new(MockKeyboardHardware::new());
+        kb.set_voice_enter_enabled(true);
+        kb.set_voice_commands_enabled(true);
+        mirror_to(&mut kb, clipboard.clone());
+
+        kb.update_transcript("hello").unwrap();
+        kb.update_transcript("hello world enter").unwrap();
+        kb.finalize_transcript().unwrap();
+        kb.update_transcript("next field tab").unwrap();
+        kb.finalize_transcript().unwrap();
+        // A turn that leaves nothing on the line copies nothing
+        kb.update_transcript("enter").unwrap();
+        kb.finalize_transcript().unwrap();
+
+        assert_eq!(
+            *clipboard.copies.lock().unwrap(),
+            vec!["hello world", "next field"]
+        );
+    }
+
+    #[test]
+    fn test_continued_line_copies_only_the_new_turn() {
+        let clipboard = RecordingClipboard::default();
+        let mut kb = VirtualKeyboard::new(MockKeyboardHardware::new());
+        kb.set_voice_enter_enabled(true);
+        kb.set_continuous_line_enabled(true);
+        mirror_to(&mut kb, clipboard.clone());
+
+        for turn in ["first part", "second part"] {
+            kb.update_transcript(turn).unwrap();
+            kb.finalize_transcript().unwrap();
+        }
+        assert_eq!(
+            *clipboard.copies.lock().unwrap(),
+            vec!["first part", "second part"]
+        );
+    }
+
+    #[test]
+    fn test_failed_copy_does_not_stop_typing() {
+        let mut kb = VirtualKeyboard::new(MockKeyboardHardware::new());
+        mirror_to(&mut kb, BrokenClipboard);
+        kb.update_transcript("still typed").unwrap();
+        kb.finalize_transcript().unwrap();
+        let text: String = kb.hardware().typed_chars.iter().collect();
+        assert_eq!(text, "still typed");
+    }
+
+    #[test]
+    fn test_command_clipboard_pipes_text() {
+        let path = std::env::temp_dir().join(format!("vk-clipboard-{}", std::process::id()));
+        let clipboard = CommandClipboard {
+            program: "sh".to_string(),
+            args: vec!["-c".to_string(), format!("cat > {}", path.display())],
+        };
+        assert!(clipboard.is_available());
+        clipboard.copy("copied line").unwrap();
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "copied line");
+        let _ = std::fs::remove_file(&path);
+
+        let missing = CommandClipboard::parse("vk-no-such-clipboard --flag").unwrap();
+        assert_eq!(missing.args, vec!["--flag"]);
+        assert!(!missing.is_available());
+        assert!(missing.copy("lost").is_err());
+        assert!(CommandClipboard::parse("  ").is_err());
+    }
+}
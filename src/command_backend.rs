@@ -0,0 +1,391 @@
+// Keyboard output through an external helper (ydotool or wtype) instead of
+// uinput, for Wayland sessions where those tools are already set up.
+// Text is passed as a single argv entry (never through a shell), after `--`
+// so that text starting with '-' isn't taken for an option.
+// Helpers run as async child processes so waiting on them doesn't block the runtime.
+// Each keystroke is its own wtype process, and wtype lets go of the modifiers
+// it pressed when it exits, so a modifier can't be left held on that backend.
+
+use anyhow::{anyhow, bail, Context, Result};
+use std::future::Future;
+use std::str::FromStr;
+use std::time::Duration;
+use tokio::process::Command;
+use tracing::debug;
+
+use crate::async_keyboard::AsyncKeyboardHardware;
+use crate::input_event::*;
+use crate::metrics::METRICS;
+
+/// Where keystrokes are sent
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Backend {
+    /// Our own /dev/uinput device (needs root)
+    #[default]
+    Uinput,
+    Ydotool,
+    Wtype,
+}
+
+impl FromStr for Backend {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_ascii_lowercase().as_str() {
+            "uinput" => Ok(Self::Uinput),
+            "ydotool" => Ok(Self::Ydotool),
+            "wtype" => Ok(Self::Wtype),
+            other => bail!(
+                "Unknown backend '{}' (expected uinput, ydotool or wtype)",
+                other
+            ),
+        }
+    }
+}
+
+/// Runs a helper program; injectable so tests can see the exact invocations
+pub trait CommandRunner: Send {
+    fn run(&mut self, program: &str, args: &[String]) -> impl Future<Output = Result<()>> + Send;
+}
+
+/// Runs helpers as real child processes and waits for them
+pub struct ProcessRunner;
+
+impl CommandRunner for ProcessRunner {
+    async fn run(&mut self, program: &str, args: &[String]) -> Result<()> {
+        debug!("Running {} {:?}", program, args);
+        let status = Command::new(program)
+            .args(args)
+            .status()
+            .await
+            .with_context(|| {
+                format!("Failed to run '{}'; is it installed and on PATH?", program)
+            })?;
+        if !status.success() {
+            bail!("'{}' failed ({})", program, status);
+        }
+        Ok(())
+    }
+}
+
+/// Keyboard output via `ydotool` or `wtype`
+pub struct CommandKeyboardHardware<R: CommandRunner = ProcessRunner> {
+    backend: Backend,
+    runner: R,
+}
+
+impl CommandKeyboardHardware {
+    pub fn new(backend: Backend) -> Result<Self> {
+        Self::with_runner(backend, ProcessRunner)
+    }
+}
+
+impl<R: CommandRunner> CommandKeyboardHardware<R> {
+    pub fn with_runner(backend: Backend, runner: R) -> Result<Self> {
+        if backend == Backend::Uinput {
+            bail!("The uinput backend doesn't use an external command");
+        }
+        Ok(Self { backend, runner })
+    }
+
+    fn program(&self) -> &'static str {
+        match self.backend {
+            Backend::Ydotool => "ydotool",
+            _ => "wtype",
+        }
+    }
+
+    async fn run(&mut self, args: Vec<String>) -> Result<()> {
+        let program = self.program();
+        self.runner.run(program, &args).await
+    }
+
+    /// One invocation that taps the last key with the others held
+    fn combo_args(&self, keycodes: &[u16]) -> Result<Vec<String>> {
+        let Some((&last, held)) = keycodes.split_last() else {
+            return Ok(Vec::new());
+        };
+        let mut args = Vec::new();
+        if self.backend == Backend::Ydotool {
+            args.push("key".to_string());
+        }
+        for &keycode in held {
+            args.extend(self.key_args(keycode, Some(true))?);
+        }
+        args.extend(self.key_args(last, None)?);
+        for &keycode in held.iter().rev() {
+            args.extend(self.key_args(keycode, Some(false))?);
+        }
+        Ok(args)
+    }
+
+    /// One invocation that only presses or only releases a key
+    fn edge_args(&self, keycode: u16, pressed: bool) -> Result<Vec<String>> {
+        let mut args = Vec::new();
+        if self.backend == Backend::Ydotool {
+            args.push("key".to_string());
+        }
+        args.extend(self.key_args(keycode, Some(pressed))?);
+        Ok(args)
+    }
+
+    /// Arguments that press (`Some(true)`), release (`Some(false)`) or tap (`None`) a key
+    fn key_args(&self, keycode: u16, pressed: Option<bool>) -> Result<Vec<String>> {
+        if self.backend == Backend::Ydotool {
+            // ydotool takes raw evdev codes as CODE:1 (down) / CODE:0 (up)
+            return Ok(match pressed {
+                Some(down) => vec![format!("{}:{}", keycode, u8::from(down))],
+                None => vec![format!("{keycode}:1"), format!("{keycode}:0")],
+            });
+        }
+
+        // wtype works with modifier names and XKB keysym names
+        if let Some(modifier) = wtype_modifier(keycode) {
+            return Ok(match pressed {
+                Some(true) => vec!["-M".to_string(), modifier.to_string()],
+                Some(false) => vec!["-m".to_string(), modifier.to_string()],
+                None => vec![
+                    "-M".to_string(),
+                    modifier.to_string(),
+                    "-m".to_string(),
+                    modifier.to_string(),
+                ],
+            });
+        }
+        let keysym = keysym_name(keycode)
+            .ok_or_else(|| anyhow!("No wtype key name for keycode {}", keycode))?;
+        let flag = match pressed {
+            Some(true) => "-P",
+            Some(false) => "-p",
+            None => "-k",
+        };
+        Ok(vec![flag.to_string(), keysym])
+    }
+}
+
+impl<R: CommandRunner> AsyncKeyboardHardware for CommandKeyboardHardware<R> {
+    async fn type_text(&mut self, text: &str) -> Result<()> {
+        if text.is_empty() {
+            return Ok(());
+        }
+        let args = match self.backend {
+            Backend::Ydotool => vec!["type".to_string(), "--".to_string(), text.to_string()],
+            _ => vec!["--".to_string(), text.to_string()],
+        };
+        self.run(args).await?;
+        METRICS.add_chars_typed(text.chars().count());
+        Ok(())
+    }
+
+    async fn press_backspace(&mut self) -> Result<()> {
+        self.press_key(KEY_BACKSPACE).await?;
+        METRICS.record_backspace();
+        Ok(())
+    }
+
+    async fn press_enter(&mut self) -> Result<()> {
+        self.press_key(KEY_ENTER).await
+    }
+
+    async fn press_key(&mut self, keycode: u16) -> Result<()> {
+        self.press_combo(&[keycode]).await
+    }
+
+    async fn press_combo(&mut self, keycodes: &[u16]) -> Result<()> {
+        if keycodes.is_empty() {
+            return Ok(());
+        }
+        let args = self.combo_args(keycodes)?;
+        self.run(args).await
+    }
+
+    async fn press_down(&mut self, keycode: u16) -> Result<()> {
+        if self.backend == Backend::Wtype && wtype_modifier(keycode).is_some() {
+            bail!(
+                "wtype can't leave modifier key {} held between calls",
+                keycode
+            );
+        }
+        let args = self.edge_args(keycode, true)?;
+        self.run(args).await
+    }
+
+    async fn release_key(&mut self, keycode: u16) -> Result<()> {
+        let args = self.edge_args(keycode, false)?;
+        self.run(args).await
+    }
+
+    async fn type_unicode(&mut self, c: char) -> Result<()> {
+        // wtype types any character itself; ydotool only knows keys
+        if self.backend == Backend::Wtype {
+            return self.type_text(&c.to_string()).await;
+        }
+        self.press_combo(&[KEY_LEFTCTRL, KEY_LEFTSHIFT, KEY_U])
+            .await?;
+        self.type_text(&format!("{:x}", c as u32)).await?;
+        self.press_key(KEY_SPACE).await
+    }
+
+    async fn hold_key(&mut self, keycode: u16, duration: Duration) -> Result<()> {
+        if self.backend == Backend::Ydotool {
+            let args = self.edge_args(keycode, true)?;
+            self.run(args).await?;
+            tokio::time::sleep(duration).await;
+            let args = self.edge_args(keycode, false)?;
+            return self.run(args).await;
+        }
+
+        // wtype can sleep between its own actions
+        let mut args = self.key_args(keycode, Some(true))?;
+        args.extend(["-s".to_string(), duration.as_millis().to_string()]);
+        args.extend(self.key_args(keycode, Some(false))?);
+        self.run(args).await
+    }
+}
+
+fn wtype_modifier(keycode: u16) -> Option<&'static str> {
+    match keycode {
+        KEY_LEFTSHIFT | KEY_RIGHTSHIFT => Some("shift"),
+        KEY_LEFTCTRL | KEY_RIGHTCTRL => Some("ctrl"),
+        KEY_LEFTALT => Some("alt"),
+        KEY_RIGHTALT => Some("altgr"),
+        _ => None,
+    }
+}
+
+/// XKB keysym name for the keys voice commands can press
+fn keysym_name(keycode: u16) -> Option<String> {
+    let name = match keycode {
+        KEY_ESC => "Escape",
+        KEY_BACKSPACE => "BackSpace",
+        KEY_TAB => "Tab",
+        KEY_ENTER => "Return",
+        KEY_SPACE => "space",
+        KEY_HOME => "Home",
+        KEY_END => "End",
+        KEY_UP => "Up",
+        KEY_DOWN => "Down",
+        KEY_LEFT => "Left",
+        KEY_RIGHT => "Right",
+        KEY_PAGEUP => "Prior",
+        KEY_PAGEDOWN => "Next",
+        KEY_MUTE => "XF86AudioMute",
+        KEY_VOLUMEDOWN => "XF86AudioLowerVolume",
+        KEY_VOLUMEUP => "XF86AudioRaiseVolume",
+        KEY_PLAYPAUSE => "XF86AudioPlay",
+        KEY_F1..=KEY_F10 => return Some(format!("F{}", keycode - KEY_F1 + 1)),
+        KEY_F11 => "F11",
+        KEY_F12 => "F12",
+        KEY_F13..=KEY_F24 => return Some(format!("F{}", keycode - KEY_F13 + 13)),
+        _ => {
+            // Letters and digits are named after themselves
+            return ('a'..='z')
+                .chain('0'..='9')
+                .find(|&c| char_to_keycode(c) == Some((keycode, false)))
+                .map(String::from);
+        }
+    };
+    Some(name.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Default)]
+    struct RecordingRunner {
+        calls: Vec<(String, Vec<String>)>,
+    }
+
+    impl CommandRunner for RecordingRunner {
+        async fn run(&mut self, program: &str, args: &[String]) -> Result<()> {
+            self.calls.push((program.to_string(), args.to_vec()));
+            Ok(())
+        }
+    }
+
+    fn hardware(backend: Backend) -> CommandKeyboardHardware<RecordingRunner> {
+        CommandKeyboardHardware::with_runner(backend, RecordingRunner::default()).unwrap()
+    }
+
+    fn calls(hardware: &CommandKeyboardHardware<RecordingRunner>) -> Vec<String> {
+        hardware
+            .runner
+            .calls
+            .iter()
+            .map(|(program, args)| format!("{} {}", program, args.join(" ")))
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn test_ydotool_commands() {
+        let mut kb = hardware(Backend::Ydotool);
+        kb.type_text("-rf; echo $HOME").await.unwrap();
+        kb.press_backspace().await.unwrap();
+        kb.press_combo(&[KEY_LEFTCTRL, KEY_LEFTSHIFT, KEY_T])
+            .await
+            .unwrap();
+        kb.release_key(KEY_LEFTSHIFT).await.unwrap();
+        kb.hold_key(KEY_DOWN, Duration::from_millis(1))
+            .await
+            .unwrap();
+        assert_eq!(
+            calls(&kb),
+            vec![
+                "ydotool type -- -rf; echo $HOME",
+                "ydotool key 14:1 14:0",
+                "ydotool key 29:1 42:1 20:1 20:0 42:0 29:0",
+                "ydotool key 42:0",
+                "ydotool key 108:1",
+                "ydotool key 108:0",
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_wtype_commands() {
+        let mut kb = hardware(Backend::Wtype);
+        kb.type_text("--help").await.unwrap();
+        kb.press_enter().await.unwrap();
+        kb.press_key(KEY_F13).await.unwrap();
+        kb.press_combo(&[KEY_LEFTCTRL, KEY_C]).await.unwrap();
+        kb.hold_key(KEY_DOWN, Duration::from_millis(1500))
+            .await
+            .unwrap();
+        kb.press_key(KEY_VOLUMEUP).await.unwrap();
+        // Released again as soon as that wtype exits
+        assert!(kb.press_down(KEY_LEFTSHIFT).await.is_err());
+        assert_eq!(
+            calls(&kb),
+            vec![
+                "wtype -- --help",
+                "wtype -k Return",
+                "wtype -k F13",
+                "wtype -M ctrl -k c -m ctrl",
+                "wtype -P Down -s 1500 -p Down",
+                "wtype -k XF86AudioRaiseVolume",
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_text_is_one_argument() {
+        let mut hardware = hardware(Backend::Wtype);
+        hardware.type_text("it's \"quoted\" `x`").await.unwrap();
+        assert_eq!(
+            hardware.runner.calls[0].1,
+            vec!["--".to_string(), "it's \"quoted\" `x`".to_string()]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_missing_helper_is_a_clear_error() {
+        let err = ProcessRunner
+            .run("voice-keyboard-no-such-helper", &[])
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains("is it installed"));
+        assert!(CommandKeyboardHardware::new(Backend::Uinput).is_err());
+        assert_eq!("WTYPE".parse::<Backend>().unwrap(), Backend::Wtype);
+    }
+}
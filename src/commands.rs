@@ -0,0 +1,793 @@
+use anyhow::{Context, Result};
+use regex::{Captures, Regex};
+use std::str::FromStr;
+use std::sync::LazyLock;
+use std::time::Duration;
+
+use crate::emoji::EmojiTable;
+use crate::input_event::*;
+use crate::transforms;
+
+/// Longest hold a voice command may request
+pub const MAX_HOLD: Duration = Duration::from_secs(30);
+
+/// Command words shorter than this always need an exact match, since a
+/// single edit turns them into unrelated words ("mute" and "mutt")
+const MIN_FUZZY_WORD_LEN: usize = 5;
+
+/// "<amount> <unit>", e.g. "three seconds" or "500 ms"
+static SPOKEN_DURATION: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(r"(?i)^(?P<amount>.+?)\s+(?P<unit>seconds?|secs?|milliseconds?|ms)$").unwrap()
+});
+
+/// Name of the built-in entry that turns a spoken "enter" into the ENTER key
+pub const ENTER_COMMAND: &str = "enter";
+
+/// What the STT writes for a spoken "enter" that is too far off to catch by
+/// letters alone, yet shouldn't be typed out under fuzzy matching
+const ENTER_MISHEARS: &[&str] = &["inner"];
+
+/// Endings that make another form of a command word ("entered", "enters")
+const INFLECTIONS: &[&str] = &["s", "es", "d", "ed", "ing"];
+
+/// Spoken names of F13..F24, in order
+const HIGH_FUNCTION_KEY_WORDS: [&str; 12] = [
+    "thirteen",
+    "fourteen",
+    "fifteen",
+    "sixteen",
+    "seventeen",
+    "eighteen",
+    "nineteen",
+    "twenty",
+    "twenty one",
+    "twenty two",
+    "twenty three",
+    "twenty four",
+];
+
+/// Which voice commands are acted on
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum InputMode {
+    /// Commands and dictation mixed, as before modes existed
+    #[default]
+    Blended,
+    /// Command words are typed as text; only "command mode" is recognized
+    Dictation,
+    /// Every enabled command is acted on
+    Command,
+}
+
+impl InputMode {
+    /// Whether a command with `action` fires in this mode
+    pub fn allows(self, action: &CommandAction) -> bool {
+        self != Self::Dictation || matches!(action, CommandAction::SetMode(_))
+    }
+}
+
+impl FromStr for InputMode {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_ascii_lowercase().as_str() {
+            "blended" => Ok(Self::Blended),
+            "dictation" => Ok(Self::Dictation),
+            "command" => Ok(Self::Command),
+            other => anyhow::bail!(
+                "Unknown input mode '{}' (expected blended, dictation or command)",
+                other
+            ),
+        }
+    }
+}
+
+/// What to do when a command phrase is recognized at the end of a turn
+#[derive(Debug, Clone, PartialEq)]
+pub enum CommandAction {
+    /// Tap a single key
+    PressKey(u16),
+    /// Hold every key but the last, tap the last, then release in reverse order
+    /// (no built-in command uses this yet; tables added via `commands_mut` may)
+    #[cfg_attr(not(test), allow(dead_code))]
+    PressCombo(Vec<u16>),
+    /// Remove the last word typed before the phrase
+    DeleteLastWord,
+    /// Remove everything typed during this turn
+    ClearLine,
+    /// Remove the whole line the cursor is on with Shift+Home, Backspace,
+    /// including text this tool didn't type
+    DeleteLine,
+    /// Remove this turn and the previously finalized one, to re-dictate it
+    CorrectLast,
+    /// Type the previously finalized line again (and Enter if it ended with one)
+    RepeatLast,
+    /// End the text with exactly one period (a '?' or '!' already there stays)
+    EndSentence,
+    /// Type the current local date in the keyboard's date format
+    InsertDate,
+    /// Type the current local time in the keyboard's time format
+    InsertTime,
+    /// Replace the phrase with literal text (for commands added via `commands_mut`)
+    #[cfg_attr(not(test), allow(dead_code))]
+    Literal(String),
+    /// Insert an emoji by code point (or type its name where that isn't supported)
+    Emoji { emoji: char, name: String },
+    /// Hold a key down for a while (e.g. to scroll); the duration comes from the
+    /// pattern's `duration` capture when it has one
+    HoldKey(u16, Duration),
+    /// Switch between dictation and command mode
+    SetMode(InputMode),
+    /// Revert the last command where possible by re-typing what it deleted
+    UndoLast,
+}
+
+impl CommandAction {
+    /// Fill in parts of the action spoken as part of the phrase
+    /// Returns `None` when a capture can't be understood, so the phrase is left as text.
+    fn resolve(&self, caps: &Captures) -> Option<Self> {
+        match (self, caps.name("duration")) {
+            (Self::HoldKey(keycode, _), Some(spoken)) => Some(Self::HoldKey(
+                *keycode,
+                parse_spoken_duration(spoken.as_str())?,
+            )),
+            _ => Some(self.clone()),
+        }
+    }
+}
+
+/// Parse a spoken duration like "three seconds", "2.5 secs", "half a second"
+/// or "500 milliseconds", capped at `MAX_HOLD`
+pub fn parse_spoken_duration(spoken: &str) -> Option<Duration> {
+    let caps = SPOKEN_DURATION.captures(spoken.trim())?;
+    let amount = caps["amount"].trim().to_lowercase();
+    let amount: f64 = match amount.as_str() {
+        "a" | "an" | "one" => 1.0,
+        "half a" | "half" => 0.5,
+        other => transforms::numbers_to_digits(other).parse().ok()?,
+    };
+
+    let unit = caps["unit"].to_lowercase();
+    let secs = if unit.starts_with("ms") || unit.starts_with("milli") {
+        amount / 1000.0
+    } else {
+        amount
+    };
+
+    if !secs.is_finite() || secs <= 0.0 {
+        return None;
+    }
+    Some(Duration::from_secs_f64(secs).min(MAX_HOLD))
+}
+
+/// A trailing phrase and the action it triggers
+#[derive(Debug, Clone)]
+pub struct Command {
+    pub name: String,
+    pub pattern: Regex,
+    pub action: CommandAction,
+    pub enabled: bool,
+    /// The literal phrase's lowercased words, for fuzzy matching; empty for
+    /// commands built from a pattern
+    pub words: Vec<String>,
+    /// Words the STT is known to hear instead of the phrase's, taken as near
+    /// misses under fuzzy matching
+    pub mishears: Vec<String>,
+}
+
+impl Command {
+    /// Build a command from a regex that must match at the end of the text
+    pub fn from_pattern(name: &str, pattern: &str, action: CommandAction) -> Result<Self> {
+        let pattern =
+            Regex::new(pattern).with_context(|| format!("Invalid pattern for command '{name}'"))?;
+        Ok(Self {
+            name: name.to_string(),
+            pattern,
+            action,
+            enabled: true,
+            words: Vec::new(),
+            mishears: Vec::new(),
+        })
+    }
+
+    /// Build a command from a literal phrase
+    /// Matching is case-insensitive on whole words, tolerates any whitespace
+    /// between words, and absorbs leading whitespace plus trailing punctuation.
+    pub fn from_phrase(name: &str, phrase: &str, action: CommandAction) -> Result<Self> {
+        let mut command = Self::from_pattern(name, &phrase_pattern(phrase), action)?;
+        command.words = phrase.split_whitespace().map(str::to_lowercase).collect();
+        Ok(command)
+    }
+
+    /// Where a near miss of the phrase starts at the end of `text`: each word
+    /// heard is the phrase's, a known mishear of it, another form of it
+    /// ("entered"), or within `max_edits` of it with the same first letter
+    fn fuzzy_start(&self, text: &str, max_edits: usize) -> Option<usize> {
+        let (start, heard) = trailing_words(text, self.words.len())?;
+        let near = self.words.iter().zip(&heard).all(|(word, heard)| {
+            let heard = heard
+                .trim_matches(|c: char| c.is_ascii_punctuation())
+                .to_lowercase();
+            if heard == *word || self.mishears.contains(&heard) {
+                return true;
+            }
+            if word.chars().count() < MIN_FUZZY_WORD_LEN {
+                return false;
+            }
+            let inflected = heard
+                .strip_prefix(word.as_str())
+                .is_some_and(|ending| INFLECTIONS.contains(&ending));
+            inflected
+                || (word.chars().next() == heard.chars().next()
+                    && edit_distance(word, &heard) <= max_edits)
+        });
+        near.then_some(start)
+    }
+}
+
+/// The last `count` words of `text`, ignoring trailing punctuation, and the
+/// byte offset where they start including the whitespace before them
+fn trailing_words(text: &str, count: usize) -> Option<(usize, Vec<&str>)> {
+    let trimmed = text.trim_end_matches(|c: char| c.is_whitespace() || c.is_ascii_punctuation());
+    let words: Vec<(usize, &str)> = trimmed
+        .split_whitespace()
+        .map(|word| (word.as_ptr() as usize - trimmed.as_ptr() as usize, word))
+        .collect();
+    if count == 0 || words.len() < count {
+        return None;
+    }
+    let tail = &words[words.len() - count..];
+    let start = trimmed[..tail[0].0].trim_end().len();
+    Some((start, tail.iter().map(|&(_, word)| word).collect()))
+}
+
+/// Levenshtein distance between two words, counted in characters
+pub fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut previous: Vec<usize> = (0..=b.len()).collect();
+    for (i, ca) in a.chars().enumerate() {
+        let mut current = vec![i + 1; b.len() + 1];
+        for (j, &cb) in b.iter().enumerate() {
+            let substitution = previous[j] + usize::from(ca != cb);
+            current[j + 1] = substitution.min(previous[j + 1] + 1).min(current[j] + 1);
+        }
+        previous = current;
+    }
+    previous[b.len()]
+}
+
+/// Regex for a literal trailing phrase: (?i)\s*\b<words>\b[[:punct:]\s]*$
+pub fn phrase_pattern(phrase: &str) -> String {
+    let words: Vec<String> = phrase.split_whitespace().map(regex::escape).collect();
+    format!(r"(?i)\s*\b{}\b[[:punct:]\s]*$", words.join(r"\s+"))
+}
+
+/// A command found at the end of the text
+#[derive(Debug, Clone, PartialEq)]
+pub struct CommandMatch {
+    pub name: String,
+    /// Byte offset where the matched phrase (including leading whitespace) starts
+    pub start: usize,
+    /// Number of characters the matched phrase occupies
+    pub char_len: usize,
+    pub action: CommandAction,
+}
+
+/// Ordered list of trailing voice commands evaluated at finalize time
+/// The first enabled command whose pattern matches the end of the text wins.
+#[derive(Debug, Clone, Default)]
+pub struct CommandTable {
+    commands: Vec<Command>,
+    /// Edits a spoken word may be off by and still trigger; 0 for exact only
+    fuzzy_edits: usize,
+}
+
+impl CommandTable {
+    /// An empty table
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The default table: just the spoken "enter"
+    pub fn with_enter(absorb_verb: bool) -> Self {
+        let mut table = Self::new();
+        table.upsert(Self::enter_command(absorb_verb));
+        table
+    }
+
+    /// The "enter" entry, optionally absorbing an adjacent "press"/"hit"/"send"
+    pub fn enter_command(absorb_verb: bool) -> Command {
+        // Regex to match "enter" (case-insensitive) at the end, optionally followed by
+        // punctuation and/or whitespace: (?i)\s*\benter\b[[:punct:]\s]*$
+        // When verb absorption is enabled, an adjacent "press"/"hit"/"send"
+        // directly before "enter" is matched as part of the command too
+        let pattern = if absorb_verb {
+            r"(?i)\s*(?:\b(?:press|hit|send)\s+)?\benter\b[[:punct:]\s]*$"
+        } else {
+            r"(?i)\s*\benter\b[[:punct:]\s]*$"
+        };
+        let mut command =
+            Command::from_pattern(ENTER_COMMAND, pattern, CommandAction::PressKey(KEY_ENTER))
+                .expect("built-in enter pattern is valid");
+        command.words = vec![ENTER_COMMAND.to_string()];
+        command.mishears = ENTER_MISHEARS.iter().map(|word| word.to_string()).collect();
+        command
+    }
+
+    /// Add the optional editing and media commands ("tab", "scratch that", "clear line",
+    /// "insert date", "volume up", ...) and "function thirteen" to "function twenty four"
+    pub fn add_builtin_commands(&mut self) {
+        let builtins = [
+            ("tab", "tab", CommandAction::PressKey(KEY_TAB)),
+            ("escape", "escape", CommandAction::PressKey(KEY_ESC)),
+            (
+                "scratch that",
+                "scratch that",
+                CommandAction::DeleteLastWord,
+            ),
+            ("undo that", "undo that", CommandAction::UndoLast),
+            ("clear line", "clear line", CommandAction::ClearLine),
+            ("delete line", "delete line", CommandAction::DeleteLine),
+            ("correct that", "correct that", CommandAction::CorrectLast),
+            ("repeat that", "repeat that", CommandAction::RepeatLast),
+            ("insert date", "insert date", CommandAction::InsertDate),
+            ("insert time", "insert time", CommandAction::InsertTime),
+            (
+                "volume up",
+                "volume up",
+                CommandAction::PressKey(KEY_VOLUMEUP),
+            ),
+            (
+                "volume down",
+                "volume down",
+                CommandAction::PressKey(KEY_VOLUMEDOWN),
+            ),
+            ("mute", "mute", CommandAction::PressKey(KEY_MUTE)),
+            (
+                "play pause",
+                "play pause",
+                CommandAction::PressKey(KEY_PLAYPAUSE),
+            ),
+        ];
+        for (name, phrase, action) in builtins {
+            self.upsert(Command::from_phrase(name, phrase, action).expect("built-in phrase"));
+        }
+        self.add_mode_commands();
+
+        // An explicit space, e.g. after a command left the cursor against text
+        self.upsert(
+            Command::from_pattern(
+                "space",
+                r"(?i)\s*\bspace(?:\s*bar)?\b[[:punct:]\s]*$",
+                CommandAction::PressKey(KEY_SPACE),
+            )
+            .expect("built-in space pattern"),
+        );
+
+        // "period" or "full stop" ends the sentence without pressing anything
+        self.upsert(
+            Command::from_pattern(
+                "period",
+                r"(?i)\s*\b(?:period|full\s+stop)\b[[:punct:]\s]*$",
+                CommandAction::EndSentence,
+            )
+            .expect("built-in period pattern"),
+        );
+
+        // "scroll down for three seconds" holds the arrow key
+        for (direction, keycode) in [("down", KEY_DOWN), ("up", KEY_UP)] {
+            let pattern = format!(
+                r"(?i)\s*\bscroll\s+{direction}\s+for\s+(?P<duration>[\w\s.]+?)[[:punct:]\s]*$"
+            );
+            self.upsert(
+                Command::from_pattern(
+                    &format!("scroll {direction}"),
+                    &pattern,
+                    CommandAction::HoldKey(keycode, Duration::ZERO),
+                )
+                .expect("built-in scroll pattern"),
+            );
+        }
+
+        for (offset, word) in HIGH_FUNCTION_KEY_WORDS.iter().enumerate() {
+            let number = 13 + offset;
+            // STT may write it as "F13", "function 13" or "function thirteen"
+            let pattern = format!(
+                r"(?i)\s*\b(?:f\s*{number}|function\s+(?:{number}|{}))\b[[:punct:]\s]*$",
+                word.replace(' ', r"[\s-]+")
+            );
+            self.upsert(
+                Command::from_pattern(
+                    &format!("f{number}"),
+                    &pattern,
+                    CommandAction::PressKey(KEY_F13 + offset as u16),
+                )
+                .expect("built-in function key pattern"),
+            );
+        }
+    }
+
+    /// Add a "<name> emoji" command for every entry in `table`
+    pub fn add_emoji_commands(&mut self, table: &EmojiTable) {
+        for (name, emoji) in table.entries() {
+            let phrase = format!("{name} emoji");
+            let action = CommandAction::Emoji {
+                emoji: *emoji,
+                name: name.clone(),
+            };
+            self.upsert(Command::from_phrase(&phrase, &phrase, action).expect("emoji phrase"));
+        }
+    }
+
+    /// Add "command mode" and "dictation mode", which switch the input mode
+    pub fn add_mode_commands(&mut self) {
+        for (phrase, mode) in [
+            ("command mode", InputMode::Command),
+            ("dictation mode", InputMode::Dictation),
+        ] {
+            self.upsert(
+                Command::from_phrase(phrase, phrase, CommandAction::SetMode(mode))
+                    .expect("built-in mode phrase"),
+            );
+        }
+    }
+
+    /// Append a command, or replace the one with the same name in place
+    /// (keeping its position and enabled state)
+    pub fn upsert(&mut self, command: Command) {
+        if let Some(existing) = self.commands.iter_mut().find(|c| c.name == command.name) {
+            let enabled = existing.enabled;
+            *existing = command;
+            existing.enabled = enabled;
+        } else {
+            self.commands.push(command);
+        }
+    }
+
+    /// Enable or disable a command by name; returns false if it doesn't exist
+    pub fn set_enabled(&mut self, name: &str, enabled: bool) -> bool {
+        match self.commands.iter_mut().find(|c| c.name == name) {
+            Some(command) => {
+                command.enabled = enabled;
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Let literal phrases trigger when each word is within `max_edits`
+    /// edits of the phrase's (0 turns this off)
+    /// Near misses like "entr" or "enters" then count, at the cost of sometimes
+    /// triggering on a real word that's close to a command word.
+    pub fn set_fuzzy_edits(&mut self, max_edits: usize) {
+        self.fuzzy_edits = max_edits;
+    }
+
+    /// Keep only the commands for which `keep` returns true
+    pub fn retain<F: FnMut(&Command) -> bool>(&mut self, keep: F) {
+        self.commands.retain(keep);
+    }
+
+    #[cfg_attr(not(test), allow(dead_code))]
+    pub fn commands(&self) -> &[Command] {
+        &self.commands
+    }
+
+    /// Find the first enabled command matching the end of `text`
+    #[cfg_attr(not(test), allow(dead_code))]
+    pub fn find(&self, text: &str) -> Option<CommandMatch> {
+        self.find_in_mode(text, InputMode::Blended)
+    }
+
+    /// Like `find`, skipping commands that `mode` doesn't act on
+    /// Exact matches win over fuzzy ones anywhere in the table.
+    pub fn find_in_mode(&self, text: &str, mode: InputMode) -> Option<CommandMatch> {
+        let mut active = self
+            .commands
+            .iter()
+            .filter(|c| c.enabled && mode.allows(&c.action));
+        let exact = active.clone().find_map(|command| {
+            let caps = command.pattern.captures(text)?;
+            let m = caps.get(0)?;
+            Some(CommandMatch {
+                name: command.name.clone(),
+                start: m.start(),
+                char_len: m.as_str().chars().count(),
+                action: command.action.resolve(&caps)?,
+            })
+        });
+        if exact.is_some() || self.fuzzy_edits == 0 {
+            return exact;
+        }
+        active.find_map(|command| {
+            let start = command.fuzzy_start(text, self.fuzzy_edits)?;
+            Some(CommandMatch {
+                name: command.name.clone(),
+                start,
+                char_len: text[start..].chars().count(),
+                action: command.action.clone(),
+            })
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn builtin_table() -> CommandTable {
+        let mut table = CommandTable::with_enter(false);
+        table.add_builtin_commands();
+        table
+    }
+
+    #[test]
+    fn test_table_dispatches_trailing_phrases() {
+        let table = builtin_table();
+        let cases = vec![
+            ("hello enter", Some(CommandAction::PressKey(KEY_ENTER)), 6),
+            ("hello tab", Some(CommandAction::PressKey(KEY_TAB)), 4),
+            ("hello Escape.", Some(CommandAction::PressKey(KEY_ESC)), 8),
+            (
+                "hello scratch that",
+                Some(CommandAction::DeleteLastWord),
+                13,
+            ),
+            ("hello clear  line!", Some(CommandAction::ClearLine), 13),
+            ("hello Delete line.", Some(CommandAction::DeleteLine), 13),
+            ("hello world", None, 0),
+            ("tabs are nice", None, 0),
+            ("hello table", None, 0),
+            ("foo space", Some(CommandAction::PressKey(KEY_SPACE)), 6),
+            (
+                "foo Spacebar.",
+                Some(CommandAction::PressKey(KEY_SPACE)),
+                10,
+            ),
+            (
+                "foo space bar",
+                Some(CommandAction::PressKey(KEY_SPACE)),
+                10,
+            ),
+            ("two spaces", None, 0),
+            ("hello period", Some(CommandAction::EndSentence), 7),
+            (
+                "meeting notes insert date.",
+                Some(CommandAction::InsertDate),
+                13,
+            ),
+            ("Insert time", Some(CommandAction::InsertTime), 11),
+            ("hello. Full stop.", Some(CommandAction::EndSentence), 11),
+            ("periodic", None, 0),
+            ("myspace", None, 0),
+            ("space station", None, 0),
+        ];
+
+        for (input, expected, char_len) in cases {
+            let found = table.find(input);
+            assert_eq!(
+                found.as_ref().map(|m| m.action.clone()),
+                expected,
+                "unexpected dispatch for '{input}'"
+            );
+            if let Some(m) = found {
+                assert_eq!(m.char_len, char_len, "wrong match length for '{input}'");
+                assert_eq!(m.start + m.char_len, input.len());
+            }
+        }
+    }
+
+    #[test]
+    fn test_media_and_function_key_commands() {
+        let table = builtin_table();
+        let cases = vec![
+            ("volume up", KEY_VOLUMEUP),
+            ("Volume down.", KEY_VOLUMEDOWN),
+            ("mute", KEY_MUTE),
+            ("play pause", KEY_PLAYPAUSE),
+            ("F13", KEY_F13),
+            ("function 20", KEY_F20),
+            ("function twenty-one", KEY_F21),
+            ("function twenty four", KEY_F24),
+        ];
+
+        for (input, keycode) in cases {
+            assert_eq!(
+                table.find(input).map(|m| m.action),
+                Some(CommandAction::PressKey(keycode)),
+                "unexpected dispatch for '{input}'"
+            );
+        }
+        // "function twenty" must not swallow the start of "twenty one"
+        assert_eq!(
+            table.find("function twenty").map(|m| m.action),
+            Some(CommandAction::PressKey(KEY_F20))
+        );
+        assert!(table.find("unmute").is_none());
+    }
+
+    #[test]
+    fn test_parse_spoken_duration() {
+        let cases = vec![
+            ("three seconds", Some(Duration::from_secs(3))),
+            ("3 seconds", Some(Duration::from_secs(3))),
+            ("a second", Some(Duration::from_secs(1))),
+            ("half a second", Some(Duration::from_millis(500))),
+            ("2.5 secs", Some(Duration::from_millis(2500))),
+            ("500 milliseconds", Some(Duration::from_millis(500))),
+            ("twenty five seconds", Some(Duration::from_secs(25))),
+            ("ten minutes", None),
+            ("ninety seconds", Some(MAX_HOLD)),
+            ("zero seconds", None),
+            ("a while", None),
+        ];
+        for (spoken, expected) in cases {
+            assert_eq!(parse_spoken_duration(spoken), expected, "'{spoken}'");
+        }
+    }
+
+    #[test]
+    fn test_scroll_command_captures_duration() {
+        let table = builtin_table();
+        let found = table
+            .find("read on scroll down for three seconds.")
+            .unwrap();
+        assert_eq!(
+            found.action,
+            CommandAction::HoldKey(KEY_DOWN, Duration::from_secs(3))
+        );
+        assert_eq!(found.char_len, " scroll down for three seconds.".len());
+
+        // An unparseable duration leaves the text alone
+        assert!(table.find("scroll down for a while").is_none());
+    }
+
+    #[test]
+    fn test_table_first_match_wins() {
+        let mut table = CommandTable::new();
+        table.upsert(
+            Command::from_phrase("first", "that", CommandAction::Literal("1".into())).unwrap(),
+        );
+        table.upsert(
+            Command::from_phrase("second", "scratch that", CommandAction::DeleteLastWord).unwrap(),
+        );
+        assert_eq!(table.find("scratch that").unwrap().name, "first");
+    }
+
+    #[test]
+    fn test_disabled_commands_are_skipped() {
+        let mut table = builtin_table();
+        assert!(table.set_enabled(ENTER_COMMAND, false));
+        assert!(table.find("hello enter").is_none());
+        assert!(!table.set_enabled("missing", false));
+    }
+
+    #[test]
+    fn test_upsert_replaces_in_place() {
+        let mut table = builtin_table();
+        table.set_enabled(ENTER_COMMAND, false);
+        table.upsert(CommandTable::enter_command(true));
+
+        assert_eq!(table.commands()[0].name, ENTER_COMMAND);
+        assert!(!table.commands()[0].enabled);
+        assert_eq!(table.commands().len(), 33);
+    }
+
+    #[test]
+    fn test_fuzzy_matching_near_misses() {
+        let mut table = builtin_table();
+        let find = |table: &CommandTable, text: &str| table.find(text).map(|m| m.action);
+        let enter = Some(CommandAction::PressKey(KEY_ENTER));
+        assert_eq!(find(&table, "send it entr"), None);
+
+        table.set_fuzzy_edits(1);
+        let cases = [
+            // One edit away, same first letter
+            ("send it entr", enter.clone()),
+            ("send it enters.", enter.clone()),
+            ("send it Entor!", enter.clone()),
+            ("okay scratch thta", None),
+            ("okay scrach that", Some(CommandAction::DeleteLastWord)),
+            // Other forms of the word, and known mishears
+            ("send it entered", enter.clone()),
+            ("send it entering.", enter.clone()),
+            ("send it Inner", enter.clone()),
+            ("okay scratched that", Some(CommandAction::DeleteLastWord)),
+            // Different first letter, or too far away
+            ("meet me in the center", None),
+            ("after", None),
+            ("put it under", None),
+            ("send it winter", None),
+            ("an intern", None),
+            ("send it entrd", None),
+            ("send it entertain", None),
+            // Short command words stay exact
+            ("open a cab", None),
+            ("tap", None),
+            ("mutt", None),
+            ("clear lane", None),
+            // Still a whole trailing word
+            ("reentr", None),
+        ];
+        for (text, expected) in cases {
+            assert_eq!(find(&table, text), expected, "{text}");
+        }
+
+        // The matched span covers the heard words and the space before them
+        let found = table.find("send it entr.").unwrap();
+        assert_eq!(found.start, "send it".len());
+        assert_eq!(found.char_len, " entr.".len());
+
+        // A wider threshold takes the farther mishears too
+        table.set_fuzzy_edits(2);
+        assert_eq!(find(&table, "send it entrd"), enter);
+        assert_eq!(find(&table, "put it under"), None);
+        assert_eq!(find(&table, "meet me in the center"), None);
+    }
+
+    #[test]
+    fn test_edit_distance() {
+        assert_eq!(edit_distance("enter", "enter"), 0);
+        assert_eq!(edit_distance("enter", "entr"), 1);
+        assert_eq!(edit_distance("enter", "inner"), 2);
+        assert_eq!(edit_distance("enter", "entered"), 2);
+        assert_eq!(edit_distance("", "tab"), 3);
+        assert_eq!(edit_distance("café", "cafe"), 1);
+    }
+
+    #[test]
+    fn test_dictation_mode_only_recognizes_the_mode_switch() {
+        let table = builtin_table();
+        assert!(table
+            .find_in_mode("press enter", InputMode::Dictation)
+            .is_none());
+        assert!(table.find_in_mode("tab", InputMode::Dictation).is_none());
+        assert_eq!(
+            table
+                .find_in_mode("okay, Command mode.", InputMode::Dictation)
+                .map(|m| m.action),
+            Some(CommandAction::SetMode(InputMode::Command))
+        );
+        assert_eq!(
+            table
+                .find_in_mode("tab", InputMode::Command)
+                .map(|m| m.action),
+            Some(CommandAction::PressKey(KEY_TAB))
+        );
+        assert_eq!(
+            table.find("dictation mode").map(|m| m.action),
+            Some(CommandAction::SetMode(InputMode::Dictation))
+        );
+
+        assert_eq!(
+            "Dictation".parse::<InputMode>().unwrap(),
+            InputMode::Dictation
+        );
+        assert!("typing".parse::<InputMode>().is_err());
+    }
+
+    #[test]
+    fn test_emoji_commands_strip_the_phrase() {
+        let mut table = CommandTable::with_enter(false);
+        table.add_emoji_commands(&EmojiTable::builtin());
+
+        let found = table.find("see you soon Thumbs Up emoji.").unwrap();
+        assert_eq!(
+            found.action,
+            CommandAction::Emoji {
+                emoji: '👍',
+                name: "thumbs up".to_string()
+            }
+        );
+        assert_eq!(found.start, "see you soon".len());
+        assert!(table.find("banana emoji").is_none());
+        assert!(table.find("smiley").is_none());
+    }
+
+    #[test]
+    fn test_phrase_pattern_escapes_and_joins_words() {
+        let command =
+            Command::from_phrase("c", "c++ mode", CommandAction::Literal(String::new())).unwrap();
+        assert!(command.pattern.is_match("use c++   mode"));
+        assert!(!command.pattern.is_match("use cpp mode"));
+    }
+}
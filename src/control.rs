@@ -0,0 +1,291 @@
+// Optional HTTP endpoint on localhost for panel applets and scripts:
+//   GET /status    -> {"connected":..,"muted":..,"last_transcript_at":..}
+//   POST /mute     -> stop listening (silence is streamed instead)
+//   POST /unmute   -> resume
+// Just enough HTTP/1.1 for curl and friends; one request per connection.
+// Requests from web pages are refused: a browser sends Origin on cross-site
+// requests, and under DNS rebinding a Host other than localhost.
+
+use anyhow::{Context, Result};
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tracing::{debug, info};
+
+/// Process-wide state the control endpoint reports and changes
+pub static CONTROL: ControlState = ControlState::new();
+
+/// Largest request head we read before giving up on a client
+const MAX_REQUEST_BYTES: usize = 8192;
+
+/// How long a client may take to send its request head
+const READ_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Debug, Default)]
+pub struct ControlState {
+    muted: AtomicBool,
+    connected: AtomicBool,
+    /// Milliseconds since the epoch of the last non-empty transcript; 0 if none yet
+    last_transcript_ms: AtomicU64,
+}
+
+impl ControlState {
+    pub const fn new() -> Self {
+        Self {
+            muted: AtomicBool::new(false),
+            connected: AtomicBool::new(false),
+            last_transcript_ms: AtomicU64::new(0),
+        }
+    }
+
+    pub fn is_muted(&self) -> bool {
+        self.muted.load(Ordering::Relaxed)
+    }
+
+    pub fn set_muted(&self, muted: bool) {
+        if self.muted.swap(muted, Ordering::Relaxed) != muted {
+            info!("{}", if muted { "Muted" } else { "Unmuted" });
+        }
+    }
+
+    pub fn set_connected(&self, connected: bool) {
+        self.connected.store(connected, Ordering::Relaxed);
+    }
+
+    pub fn record_transcript(&self) {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        self.last_transcript_ms
+            .store(now.as_millis() as u64, Ordering::Relaxed);
+    }
+
+    fn status_json(&self) -> serde_json::Value {
+        let last_ms = self.last_transcript_ms.load(Ordering::Relaxed);
+        serde_json::json!({
+            "connected": self.connected.load(Ordering::Relaxed),
+            "muted": self.is_muted(),
+            // Unix time in seconds, or null before the first transcript
+            "last_transcript_at": (last_ms > 0)
+                .then(|| Duration::from_millis(last_ms).as_secs_f64()),
+        })
+    }
+}
+
+/// Status code and JSON body for a request
+fn respond(state: &ControlState, method: &str, path: &str) -> (u16, serde_json::Value) {
+    match (method, path) {
+        ("GET", "/status") => (200, state.status_json()),
+        ("POST", "/mute") => {
+            state.set_muted(true);
+            (200, serde_json::json!({ "muted": true }))
+        }
+        ("POST", "/unmute") => {
+            state.set_muted(false);
+            (200, serde_json::json!({ "muted": false }))
+        }
+        (_, "/status" | "/mute" | "/unmute") => {
+            (405, serde_json::json!({ "error": "method not allowed" }))
+        }
+        _ => (404, serde_json::json!({ "error": "not found" })),
+    }
+}
+
+/// Whether a Host header value names this machine
+fn is_local_host(host: &str) -> bool {
+    let name = match host.strip_prefix('[') {
+        Some(bracketed) => bracketed.split(']').next().unwrap_or_default(),
+        None => host.split(':').next().unwrap_or_default(),
+    };
+    name.eq_ignore_ascii_case("localhost") || name == "127.0.0.1" || name == "::1"
+}
+
+/// Whether the request head could have come from a web page
+fn is_from_browser(head: &str) -> bool {
+    head.lines()
+        .skip(1)
+        .filter_map(|line| line.split_once(':'))
+        .any(|(name, value)| {
+            name.eq_ignore_ascii_case("origin")
+                || (name.eq_ignore_ascii_case("host") && !is_local_host(value.trim()))
+        })
+}
+
+fn reason(status: u16) -> &'static str {
+    match status {
+        200 => "OK",
+        400 => "Bad Request",
+        403 => "Forbidden",
+        405 => "Method Not Allowed",
+        _ => "Not Found",
+    }
+}
+
+async fn read_head(stream: &mut TcpStream) -> Result<Vec<u8>> {
+    let mut request = Vec::new();
+    let mut buf = [0u8; 1024];
+    while !request.windows(4).any(|w| w == b"\r\n\r\n") {
+        let n = stream.read(&mut buf).await?;
+        if n == 0 || request.len() + n > MAX_REQUEST_BYTES {
+            break;
+        }
+        request.extend_from_slice(&buf[..n]);
+    }
+    Ok(request)
+}
+
+async fn handle_connection(
+    mut stream: TcpStream,
+    state: &ControlState,
+    read_timeout: Duration,
+) -> Result<()> {
+    let request = tokio::time::timeout(read_timeout, read_head(&mut stream))
+        .await
+        .context("Timed out waiting for the request")??;
+
+    let head = String::from_utf8_lossy(&request);
+    let mut parts = head.lines().next().unwrap_or_default().split_whitespace();
+    let (status, body) = match (parts.next(), parts.next()) {
+        _ if is_from_browser(&head) => {
+            debug!("Refused a control request from a web page");
+            (403, serde_json::json!({ "error": "forbidden" }))
+        }
+        (Some(method), Some(path)) => {
+            debug!("Control request: {} {}", method, path);
+            respond(state, method, path)
+        }
+        _ => (400, serde_json::json!({ "error": "bad request" })),
+    };
+
+    let body = body.to_string();
+    let response = format!(
+        "HTTP/1.1 {} {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        reason(status),
+        body.len(),
+        body
+    );
+    stream.write_all(response.as_bytes()).await?;
+    stream.shutdown().await?;
+    Ok(())
+}
+
+/// Answer requests on `listener` until the process exits
+pub async fn serve(listener: TcpListener, state: &'static ControlState) {
+    loop {
+        let Ok((stream, peer)) = listener.accept().await else {
+            continue;
+        };
+        tokio::spawn(async move {
+            if let Err(e) = handle_connection(stream, state, READ_TIMEOUT).await {
+                debug!("Control connection from {} failed: {}", peer, e);
+            }
+        });
+    }
+}
+
+/// Start the endpoint on localhost; returns the bound address
+pub async fn spawn(port: u16) -> Result<SocketAddr> {
+    let listener = TcpListener::bind(("127.0.0.1", port))
+        .await
+        .with_context(|| format!("Failed to bind control port {port}"))?;
+    let addr = listener.local_addr()?;
+    tokio::spawn(serve(listener, &CONTROL));
+    Ok(addr)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn request(addr: SocketAddr, method: &str, path: &str) -> (u16, serde_json::Value) {
+        request_with(addr, method, path, "Host: localhost\r\n").await
+    }
+
+    async fn request_with(
+        addr: SocketAddr,
+        method: &str,
+        path: &str,
+        headers: &str,
+    ) -> (u16, serde_json::Value) {
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        let request = format!("{method} {path} HTTP/1.1\r\n{headers}\r\n");
+        stream.write_all(request.as_bytes()).await.unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+
+        let status = response[9..12].parse().unwrap();
+        let (_, body) = response.split_once("\r\n\r\n").unwrap();
+        (status, serde_json::from_str(body).unwrap())
+    }
+
+    #[tokio::test]
+    async fn test_endpoints_flip_the_mute_flag() {
+        static STATE: ControlState = ControlState::new();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(serve(listener, &STATE));
+
+        let (status, body) = request(addr, "GET", "/status").await;
+        assert_eq!(status, 200);
+        assert_eq!(body["muted"], false);
+        assert_eq!(body["connected"], false);
+        assert!(body["last_transcript_at"].is_null());
+
+        let (status, body) = request(addr, "POST", "/mute").await;
+        assert_eq!((status, body["muted"].clone()), (200, true.into()));
+        assert!(STATE.is_muted());
+
+        STATE.set_connected(true);
+        STATE.record_transcript();
+        let (_, body) = request(addr, "GET", "/status").await;
+        assert_eq!(body["muted"], true);
+        assert_eq!(body["connected"], true);
+        assert!(body["last_transcript_at"].as_f64().unwrap() > 1.6e9);
+
+        request(addr, "POST", "/unmute").await;
+        assert!(!STATE.is_muted());
+
+        assert_eq!(request(addr, "GET", "/mute").await.0, 405);
+        assert_eq!(request(addr, "GET", "/nope").await.0, 404);
+    }
+
+    #[tokio::test]
+    async fn test_requests_from_web_pages_are_refused() {
+        static STATE: ControlState = ControlState::new();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(serve(listener, &STATE));
+
+        // A rebound name, or a cross-site form posting to localhost
+        let rebound = request_with(addr, "POST", "/mute", "Host: evil.example:8080\r\n").await;
+        assert_eq!(rebound.0, 403);
+        let cross_site = "Host: 127.0.0.1\r\nOrigin: https://evil.example\r\n";
+        assert_eq!(request_with(addr, "POST", "/mute", cross_site).await.0, 403);
+        assert!(!STATE.is_muted());
+
+        for host in ["localhost:7777", "127.0.0.1", "[::1]:7777", "LocalHost"] {
+            let headers = format!("Host: {host}\r\n");
+            assert_eq!(request_with(addr, "GET", "/status", &headers).await.0, 200);
+        }
+        assert_eq!(request_with(addr, "GET", "/status", "").await.0, 200);
+    }
+
+    #[tokio::test]
+    async fn test_silent_client_is_dropped() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        let (stream, _) = listener.accept().await.unwrap();
+
+        let timeout = Duration::from_millis(50);
+        let result = handle_connection(stream, &CONTROL, timeout).await;
+        assert!(result.unwrap_err().to_string().contains("Timed out"));
+        let mut response = String::new();
+        client.read_to_string(&mut response).await.unwrap();
+        assert_eq!(response, "");
+    }
+}
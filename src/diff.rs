@@ -0,0 +1,297 @@
+// Minimal edits between the typed text and a revised transcript.
+// The default retype strategy backspaces everything after the common prefix;
+// when a correction lands early in a long line it is often cheaper to walk
+// the cursor back with Left, edit in place, and jump back with End.
+
+/// One step of an edit script turning `old` into `new`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Edit {
+    /// Characters both texts share
+    Equal(usize),
+    /// Characters only in `old`
+    Delete(usize),
+    /// Characters only in `new`
+    Insert(String),
+}
+
+/// Most single-character edits `edit_script` looks for. The search keeps a
+/// row per edit, so its memory grows with the square of this rather than
+/// with the length of the line. Texts further apart are retyped.
+pub const MAX_EDITS: usize = 256;
+
+/// Shortest edit script between `old` and `new` (Myers' O(ND) algorithm),
+/// with adjacent steps of the same kind merged; `None` if it would take
+/// more than `MAX_EDITS` edits
+pub fn edit_script(old: &[char], new: &[char]) -> Option<Vec<Edit>> {
+    let n = old.len() as isize;
+    let m = new.len() as isize;
+    let max = (n + m).min(MAX_EDITS as isize);
+    let offset = max as usize + 1;
+    let mut v = vec![0isize; 2 * offset + 1];
+    // Per round d, the diagonals -(d + 1)..=d + 1 as they were before it
+    let mut trace = Vec::new();
+    let mut found = false;
+
+    // Forward pass: furthest-reaching path on each diagonal k = x - y, per edit count d
+    'search: for d in 0..=max {
+        let row = offset - d as usize - 1..=offset + d as usize + 1;
+        trace.push(v[row].to_vec());
+        for k in (-d..=d).step_by(2) {
+            let idx = (k + offset as isize) as usize;
+            let mut x = if k == -d || (k != d && v[idx - 1] < v[idx + 1]) {
+                v[idx + 1]
+            } else {
+                v[idx - 1] + 1
+            };
+            let mut y = x - k;
+            while x < n && y < m && old[x as usize] == new[y as usize] {
+                x += 1;
+                y += 1;
+            }
+            v[idx] = x;
+            if x >= n && y >= m {
+                found = true;
+                break 'search;
+            }
+        }
+    }
+    if !found {
+        return None;
+    }
+
+    // Backtrack from the end, one edit per recorded round
+    let mut steps = Vec::new();
+    let (mut x, mut y) = (n, m);
+    for (d, row) in trace.iter().enumerate().rev() {
+        let d = d as isize;
+        let v = |k: isize| row[(k + d + 1) as usize];
+        let k = x - y;
+        let prev_k = if k == -d || (k != d && v(k - 1) < v(k + 1)) {
+            k + 1
+        } else {
+            k - 1
+        };
+        let prev_x = v(prev_k);
+        let prev_y = prev_x - prev_k;
+        while x > prev_x && y > prev_y {
+            steps.push(Edit::Equal(1));
+            x -= 1;
+            y -= 1;
+        }
+        if d > 0 {
+            if x == prev_x {
+                steps.push(Edit::Insert(new[(y - 1) as usize].to_string()));
+            } else {
+                steps.push(Edit::Delete(1));
+            }
+        }
+        x = prev_x;
+        y = prev_y;
+    }
+
+    let mut script: Vec<Edit> = Vec::new();
+    for step in steps.into_iter().rev() {
+        match (script.last_mut(), step) {
+            (Some(Edit::Equal(a)), Edit::Equal(b)) => *a += b,
+            (Some(Edit::Delete(a)), Edit::Delete(b)) => *a += b,
+            (Some(Edit::Insert(a)), Edit::Insert(b)) => a.push_str(&b),
+            (_, step) => script.push(step),
+        }
+    }
+    Some(script)
+}
+
+/// A keyboard operation used to apply a diff
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum KeyOp {
+    Left(usize),
+    Right(usize),
+    /// Jump to the end of the line
+    End,
+    Backspace(usize),
+    Type(String),
+}
+
+/// Keystrokes a plan costs
+pub fn keystrokes(plan: &[KeyOp]) -> usize {
+    plan.iter()
+        .map(|op| match op {
+            KeyOp::Left(n) | KeyOp::Right(n) | KeyOp::Backspace(n) => *n,
+            KeyOp::End => 1,
+            KeyOp::Type(text) => text.chars().count(),
+        })
+        .sum()
+}
+
+/// The default plan: backspace after the common prefix, type the rest
+pub fn retype_plan(old: &[char], new: &[char]) -> Vec<KeyOp> {
+    let prefix = old.iter().zip(new).take_while(|(a, b)| a == b).count();
+    let mut plan = Vec::new();
+    if old.len() > prefix {
+        plan.push(KeyOp::Backspace(old.len() - prefix));
+    }
+    if new.len() > prefix {
+        plan.push(KeyOp::Type(new[prefix..].iter().collect()));
+    }
+    plan
+}
+
+/// Edit in place: walk left to the first change, apply each change moving
+/// right over the unchanged text between them, then return to the end.
+/// Retypes instead when the texts are more than `MAX_EDITS` edits apart.
+pub fn cursor_plan(old: &[char], new: &[char]) -> Vec<KeyOp> {
+    let Some(script) = edit_script(old, new) else {
+        return retype_plan(old, new);
+    };
+    let mut plan = Vec::new();
+    // Position in `old` of the next script step, and the cursor's position
+    // (in `old`, once it has left the end)
+    let mut pos = 0;
+    let mut cursor = None;
+    for edit in script {
+        match edit {
+            Edit::Equal(count) => pos += count,
+            Edit::Delete(count) => {
+                pos += count;
+                move_cursor(&mut plan, &mut cursor, pos, old.len());
+                plan.push(KeyOp::Backspace(count));
+            }
+            Edit::Insert(text) => {
+                move_cursor(&mut plan, &mut cursor, pos, old.len());
+                plan.push(KeyOp::Type(text));
+            }
+        }
+    }
+    match cursor.map(|at| old.len() - at) {
+        None | Some(0) => {}
+        Some(1) => plan.push(KeyOp::Right(1)),
+        Some(_) => plan.push(KeyOp::End),
+    }
+    plan
+}
+
+/// Bring the cursor to `target` in the old text before an edit there
+fn move_cursor(plan: &mut Vec<KeyOp>, cursor: &mut Option<usize>, target: usize, len: usize) {
+    match *cursor {
+        // The cursor starts at the end of the text
+        None if len > target => plan.push(KeyOp::Left(len - target)),
+        Some(at) if target > at => plan.push(KeyOp::Right(target - at)),
+        _ => {}
+    }
+    *cursor = Some(target);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn chars(s: &str) -> Vec<char> {
+        s.chars().collect()
+    }
+
+    /// Replay a plan on `old`, with the cursor starting at the end
+    fn apply(old: &str, plan: &[KeyOp]) -> String {
+        let mut text = chars(old);
+        let mut cursor = text.len();
+        for op in plan {
+            match op {
+                KeyOp::Left(n) => cursor -= n,
+                KeyOp::Right(n) => cursor += n,
+                KeyOp::End => cursor = text.len(),
+                KeyOp::Backspace(n) => {
+                    text.drain(cursor - n..cursor);
+                    cursor -= n;
+                }
+                KeyOp::Type(s) => {
+                    for c in s.chars() {
+                        text.insert(cursor, c);
+                        cursor += 1;
+                    }
+                }
+            }
+        }
+        assert_eq!(
+            cursor,
+            text.len(),
+            "plan must end with the cursor at the end"
+        );
+        text.into_iter().collect()
+    }
+
+    #[test]
+    fn test_edit_script_is_minimal() {
+        let script = edit_script(&chars("abcabba"), &chars("cbabac")).unwrap();
+        let edits: usize = script
+            .iter()
+            .map(|e| match e {
+                Edit::Equal(_) => 0,
+                Edit::Delete(n) => *n,
+                Edit::Insert(s) => s.chars().count(),
+            })
+            .sum();
+        // The classic example from Myers' paper has an edit distance of 5
+        assert_eq!(edits, 5);
+
+        assert_eq!(edit_script(&[], &[]), Some(vec![]));
+        assert_eq!(
+            edit_script(&chars("same"), &chars("same")),
+            Some(vec![Edit::Equal(4)])
+        );
+    }
+
+    #[test]
+    fn test_edit_script_gives_up_past_max_edits() {
+        let line = chars(&"a".repeat(MAX_EDITS));
+        assert_eq!(edit_script(&line, &[]), Some(vec![Edit::Delete(MAX_EDITS)]));
+
+        // Rewritten throughout: too far apart to search, so the plan retypes
+        let old = chars(&"a".repeat(MAX_EDITS));
+        let new = chars(&"b".repeat(MAX_EDITS));
+        assert_eq!(edit_script(&old, &new), None);
+        assert_eq!(cursor_plan(&old, &new), retype_plan(&old, &new));
+    }
+
+    #[test]
+    fn test_plans_produce_the_new_text() {
+        let cases = [
+            ("hello", "hello world"),
+            ("hello world", "hello"),
+            ("hello", "hi there"),
+            ("the cat sat on the mat", "a cat sat on a mat"),
+            ("", "abc"),
+            ("abc", ""),
+            ("naïve café", "naive café"),
+        ];
+        for (old, new) in cases {
+            let (o, n) = (chars(old), chars(new));
+            assert_eq!(apply(old, &retype_plan(&o, &n)), new, "retype {old:?}");
+            assert_eq!(apply(old, &cursor_plan(&o, &n)), new, "cursor {old:?}");
+        }
+    }
+
+    #[test]
+    fn test_cursor_plan_is_cheaper_on_reflow() {
+        // A correction near the start of a long line
+        let old = chars("the quick brown fox jumps over the lazy dog");
+        let new = chars("a quick brown fox jumps over the lazy dog");
+        let retype = retype_plan(&old, &new);
+        let cursor = cursor_plan(&old, &new);
+        assert_eq!(keystrokes(&retype), 43 + 41);
+        assert_eq!(
+            cursor,
+            vec![
+                KeyOp::Left(40),
+                KeyOp::Backspace(3),
+                KeyOp::Type("a".into()),
+                KeyOp::End
+            ]
+        );
+        assert_eq!(keystrokes(&cursor), 45);
+
+        // A change at the very end costs the same either way
+        let old = chars("see you tomorrow");
+        let new = chars("see you tomorrow.");
+        assert_eq!(keystrokes(&retype_plan(&old, &new)), 1);
+        assert_eq!(keystrokes(&cursor_plan(&old, &new)), 1);
+    }
+}
@@ -0,0 +1,167 @@
+// Spoken emoji names ("heart emoji") and the characters they insert.
+// Emoji are entered by code point (Ctrl+Shift+U), since no key produces them.
+
+use anyhow::{anyhow, Context, Result};
+use std::path::Path;
+
+/// The curated default set
+const BUILTIN_EMOJI: &[(&str, char)] = &[
+    ("smiley", '😃'),
+    ("smile", '😄'),
+    ("grin", '😁'),
+    ("laughing", '😆'),
+    ("joy", '😂'),
+    ("wink", '😉'),
+    ("sad", '🙁'),
+    ("crying", '😢'),
+    ("thinking", '🤔'),
+    ("heart", '❤'),
+    ("thumbs up", '👍'),
+    ("thumbs down", '👎'),
+    ("clap", '👏'),
+    ("wave", '👋'),
+    ("pray", '🙏'),
+    ("ok hand", '👌'),
+    ("eyes", '👀'),
+    ("fire", '🔥'),
+    ("party", '🎉'),
+    ("rocket", '🚀'),
+    ("star", '⭐'),
+    ("check mark", '✅'),
+    ("cross mark", '❌'),
+    ("hundred", '💯'),
+];
+
+/// Emoji by spoken name, in insertion order
+#[derive(Debug, Clone, PartialEq)]
+pub struct EmojiTable {
+    entries: Vec<(String, char)>,
+}
+
+impl EmojiTable {
+    pub fn builtin() -> Self {
+        Self {
+            entries: BUILTIN_EMOJI
+                .iter()
+                .map(|&(name, emoji)| (name.to_string(), emoji))
+                .collect(),
+        }
+    }
+
+    /// Add an emoji, or change what an existing name inserts
+    pub fn insert(&mut self, name: &str, emoji: char) {
+        let name = normalize_name(name);
+        match self.entries.iter_mut().find(|(n, _)| *n == name) {
+            Some(entry) => entry.1 = emoji,
+            None => self.entries.push((name, emoji)),
+        }
+    }
+
+    pub fn entries(&self) -> &[(String, char)] {
+        &self.entries
+    }
+}
+
+fn normalize_name(name: &str) -> String {
+    name.split_whitespace()
+        .collect::<Vec<_>>()
+        .join(" ")
+        .to_lowercase()
+}
+
+/// Parse `name=emoji` lines, where the emoji is the character itself or its
+/// hex code point (`party=1F389` or `party=U+1F389`); `# ` lines are comments
+pub fn parse_emoji_file(text: &str) -> Result<Vec<(String, char)>> {
+    let mut entries = Vec::new();
+    for (number, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line == "#" || line.starts_with("# ") {
+            continue;
+        }
+        let entry = parse_emoji_line(line)
+            .with_context(|| format!("Invalid emoji line {}: '{}'", number + 1, line))?;
+        entries.push(entry);
+    }
+    Ok(entries)
+}
+
+fn parse_emoji_line(line: &str) -> Result<(String, char)> {
+    let (name, value) = line
+        .split_once('=')
+        .ok_or_else(|| anyhow!("expected 'name=emoji'"))?;
+    let name = normalize_name(name);
+    if name.is_empty() {
+        return Err(anyhow!("missing name"));
+    }
+    let value = value.trim();
+
+    let mut chars = value.chars();
+    if let (Some(emoji), None) = (chars.next(), chars.next()) {
+        return Ok((name, emoji));
+    }
+    let hex = value
+        .strip_prefix("U+")
+        .or_else(|| value.strip_prefix("u+"))
+        .unwrap_or(value);
+    let emoji = u32::from_str_radix(hex, 16)
+        .ok()
+        .and_then(char::from_u32)
+        .ok_or_else(|| {
+            anyhow!(
+                "bad emoji '{}' (use the character or a hex code point)",
+                value
+            )
+        })?;
+    Ok((name, emoji))
+}
+
+pub fn load_emoji_file(path: &Path) -> Result<Vec<(String, char)>> {
+    let text = std::fs::read_to_string(path)
+        .with_context(|| format!("Failed to read emoji file {}", path.display()))?;
+    parse_emoji_file(&text)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Which emoji a spoken name inserts, matched like the command phrase
+    fn lookup(table: &EmojiTable, name: &str) -> Option<char> {
+        let name = normalize_name(name);
+        table
+            .entries()
+            .iter()
+            .find(|(n, _)| *n == name)
+            .map(|&(_, emoji)| emoji)
+    }
+
+    #[test]
+    fn test_lookup_by_name() {
+        let table = EmojiTable::builtin();
+        assert_eq!(lookup(&table, "smiley"), Some('😃'));
+        assert_eq!(lookup(&table, "Heart"), Some('❤'));
+        assert_eq!(lookup(&table, "thumbs   up"), Some('👍'));
+        assert_eq!(lookup(&table, "banana"), None);
+    }
+
+    #[test]
+    fn test_emoji_file_extends_and_overrides() {
+        let entries =
+            parse_emoji_file("# extras\n\nbanana=U+1F34C\nParty Popper=1f389\nheart=💙\n").unwrap();
+        let mut table = EmojiTable::builtin();
+        let builtin_len = table.entries().len();
+        for (name, emoji) in entries {
+            table.insert(&name, emoji);
+        }
+
+        assert_eq!(lookup(&table, "banana"), Some('🍌'));
+        assert_eq!(lookup(&table, "party popper"), Some('🎉'));
+        assert_eq!(lookup(&table, "heart"), Some('💙'));
+        assert_eq!(table.entries().len(), builtin_len + 2);
+
+        let err = parse_emoji_file("ok\nbad=zz").unwrap_err();
+        assert!(format!("{err:#}").contains("line 1"));
+        assert!(parse_emoji_file("bad=110000").is_err());
+        assert!(parse_emoji_file("=1F389").is_err());
+    }
+}
@@ -0,0 +1,150 @@
+// Error kinds worth handling differently (don't retry bad credentials, pick
+// another device, ...). Functions keep returning anyhow::Result with context;
+// a VkError sits at the root of the chain where one of these failures starts,
+// and `VkError::find` gets it back. main just reports the whole chain.
+
+use nix::errno::Errno;
+use thiserror::Error;
+use tokio_tungstenite::tungstenite::error::Error as WsError;
+
+#[derive(Debug, Error)]
+pub enum VkError {
+    /// The STT service couldn't be reached, or the connection dropped
+    #[error("{0}")]
+    Connection(String),
+    /// The STT handshake was refused with 401 or 403
+    #[error("The speech-to-text service rejected the credentials ({status}); check DEEPGRAM_API_KEY\nBody: {body}")]
+    AuthRejected {
+        status: http::StatusCode,
+        body: String,
+    },
+    /// The STT service sent something we can't make sense of
+    #[error("{0}")]
+    Protocol(String),
+    /// The STT service reported an error message
+    #[error("server error: {code} - {description}")]
+    Server { code: String, description: String },
+    /// Not allowed to open a device
+    #[cfg_attr(not(target_os = "linux"), allow(dead_code))]
+    #[error("{message}")]
+    DevicePermission {
+        message: String,
+        #[source]
+        errno: Errno,
+    },
+    /// A device is missing or can't be used right now
+    #[error("{message}")]
+    DeviceUnavailable {
+        message: String,
+        #[source]
+        errno: Option<Errno>,
+    },
+    /// The audio device can't capture in the requested format
+    #[error("{0}")]
+    UnsupportedFormat(String),
+}
+
+impl VkError {
+    /// The first `VkError` in an error's chain
+    pub fn find(err: &anyhow::Error) -> Option<&VkError> {
+        err.chain().find_map(|cause| cause.downcast_ref())
+    }
+}
+
+impl From<WsError> for VkError {
+    fn from(err: WsError) -> Self {
+        match err {
+            WsError::Http(resp) => {
+                let (parts, body_opt) = resp.into_parts();
+                let status = parts.status;
+                let body_text = body_opt
+                    .as_ref()
+                    .map(|b| String::from_utf8_lossy(b).to_string())
+                    .unwrap_or_else(|| "<no body>".to_string());
+                if matches!(
+                    status,
+                    http::StatusCode::UNAUTHORIZED | http::StatusCode::FORBIDDEN
+                ) {
+                    return Self::AuthRejected {
+                        status,
+                        body: body_text,
+                    };
+                }
+                let mut header_lines = String::new();
+                for (k, v) in parts.headers.iter() {
+                    // Limit very long values
+                    let val = v.to_str().unwrap_or("<binary>");
+                    let shortened = if val.len() > 256 { &val[..256] } else { val };
+                    header_lines.push_str(&format!("\n  {}: {}", k, shortened));
+                }
+                Self::Connection(format!(
+                    "WebSocket HTTP handshake failed: {}\nHeaders:{}\nBody: {}",
+                    status, header_lines, body_text
+                ))
+            }
+            WsError::Io(e) => Self::Connection(format!("WebSocket I/O error: {}", e)),
+            WsError::Tls(e) => Self::Connection(format!("WebSocket TLS error: {}", e)),
+            WsError::AlreadyClosed => Self::Connection("WebSocket already closed".to_string()),
+            WsError::ConnectionClosed => {
+                Self::Connection("WebSocket connection closed".to_string())
+            }
+            WsError::Url(e) => Self::Connection(format!("WebSocket URL error: {}", e)),
+            WsError::Protocol(e) => Self::Protocol(format!("WebSocket protocol error: {}", e)),
+            WsError::Capacity(e) => Self::Protocol(format!("WebSocket capacity error: {}", e)),
+            WsError::HttpFormat(e) => Self::Protocol(format!("WebSocket HTTP format error: {}", e)),
+            WsError::Utf8 => Self::Protocol("WebSocket UTF-8 error".to_string()),
+            other => Self::Protocol(other.to_string()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::Context;
+
+    #[test]
+    fn test_websocket_errors_map_to_kinds() {
+        let rejected = http::Response::builder()
+            .status(401)
+            .body(Some(b"bad key".to_vec()))
+            .unwrap();
+        let err = VkError::from(WsError::Http(rejected));
+        assert!(matches!(err, VkError::AuthRejected { status, .. } if status == 401));
+        assert!(err.to_string().contains("DEEPGRAM_API_KEY"));
+
+        let unavailable = http::Response::builder().status(503).body(None).unwrap();
+        assert!(matches!(
+            VkError::from(WsError::Http(unavailable)),
+            VkError::Connection(_)
+        ));
+        let io = std::io::Error::from(std::io::ErrorKind::ConnectionRefused);
+        assert!(matches!(
+            VkError::from(WsError::Io(io)),
+            VkError::Connection(_)
+        ));
+        assert!(matches!(VkError::from(WsError::Utf8), VkError::Protocol(_)));
+    }
+
+    #[test]
+    fn test_find_looks_through_context() {
+        let err = anyhow::Error::from(VkError::UnsupportedFormat("no u16".into()))
+            .context("Failed to start recording");
+        assert!(matches!(
+            VkError::find(&err),
+            Some(VkError::UnsupportedFormat(_))
+        ));
+        assert!(VkError::find(&anyhow::anyhow!("plain")).is_none());
+
+        let err = Err::<(), _>(VkError::DevicePermission {
+            message: "no access".into(),
+            errno: Errno::EACCES,
+        })
+        .context("Failed to create the virtual keyboard")
+        .unwrap_err();
+        // The errno is still reachable for callers that look at causes
+        assert!(err
+            .chain()
+            .any(|cause| cause.downcast_ref::<Errno>() == Some(&Errno::EACCES)));
+    }
+}
@@ -0,0 +1,173 @@
+// Test-only stand-in for the STT service: a local WebSocket server that
+// records what the client sends and plays back scripted responses
+
+use futures_util::{SinkExt, StreamExt};
+use std::sync::{Arc, Mutex};
+use tokio::net::TcpListener;
+use tokio_tungstenite::tungstenite::handshake::server::{Request, Response};
+use tokio_tungstenite::tungstenite::Message;
+
+use crate::stt_client::UNSUPPORTED_MESSAGE_CODE;
+
+/// Everything the client sent over one connection
+#[derive(Debug, Clone, Default)]
+pub struct Received {
+    /// Path and query of the handshake request
+    pub uri: String,
+    /// Binary (audio) frames in arrival order
+    pub audio: Vec<Vec<u8>>,
+    /// Text (control) messages in arrival order
+    pub control: Vec<String>,
+}
+
+impl Received {
+    pub fn audio_bytes(&self) -> Vec<u8> {
+        self.audio.concat()
+    }
+}
+
+/// How the server behaves beyond playing back its responses
+#[derive(Debug, Clone, Copy, Default)]
+struct Behavior {
+    /// Close the socket right after the scripted responses
+    close_after_responses: bool,
+    /// Answer Finalize with an Error of this code
+    finalize_error: Option<&'static str>,
+}
+
+pub struct FakeSttServer {
+    pub url: String,
+    received: Arc<Mutex<Received>>,
+}
+
+impl FakeSttServer {
+    /// Accept a single connection. The scripted `responses` are sent after the
+    /// first audio frame, and the socket is closed once the client sends CloseStream.
+    pub async fn start(responses: Vec<String>) -> Self {
+        Self::spawn(responses, Behavior::default()).await
+    }
+
+    /// Like `start`, but close the socket right after the scripted responses,
+    /// as a server going away mid-turn would
+    pub async fn start_closing(responses: Vec<String>) -> Self {
+        let behavior = Behavior {
+            close_after_responses: true,
+            ..Behavior::default()
+        };
+        Self::spawn(responses, behavior).await
+    }
+
+    /// Like `start`, but answer a Finalize message with the error servers
+    /// that don't support it send
+    pub async fn start_rejecting_finalize(responses: Vec<String>) -> Self {
+        let behavior = Behavior {
+            finalize_error: Some(UNSUPPORTED_MESSAGE_CODE),
+            ..Behavior::default()
+        };
+        Self::spawn(responses, behavior).await
+    }
+
+    /// Like `start`, but fail with an unrelated error right after a Finalize
+    pub async fn start_failing_after_finalize(responses: Vec<String>) -> Self {
+        let behavior = Behavior {
+            finalize_error: Some("INTERNAL_SERVER_ERROR"),
+            ..Behavior::default()
+        };
+        Self::spawn(responses, behavior).await
+    }
+
+    async fn spawn(responses: Vec<String>, behavior: Behavior) -> Self {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let received = Arc::new(Mutex::new(Received::default()));
+
+        let state = received.clone();
+        tokio::spawn(async move {
+            let Ok((stream, _)) = listener.accept().await else {
+                return;
+            };
+            let uri_state = state.clone();
+            // The handshake callback's signature is fixed by tungstenite
+            #[allow(clippy::result_large_err)]
+            let callback = move |request: &Request, response: Response| {
+                uri_state.lock().unwrap().uri = request.uri().to_string();
+                Ok(response)
+            };
+            let Ok(mut ws) = tokio_tungstenite::accept_hdr_async(stream, callback).await else {
+                return;
+            };
+
+            let mut responses = Some(responses);
+            while let Some(Ok(message)) = ws.next().await {
+                match message {
+                    Message::Binary(data) => {
+                        state.lock().unwrap().audio.push(data);
+                        if let Some(responses) = responses.take() {
+                            for response in responses {
+                                let _ = ws.send(Message::Text(response)).await;
+                            }
+                            if behavior.close_after_responses {
+                                let _ = ws.close(None).await;
+                            }
+                        }
+                    }
+                    Message::Text(text) => {
+                        let close = text.contains("CloseStream");
+                        let error = behavior
+                            .finalize_error
+                            .filter(|_| text.contains("Finalize"));
+                        state.lock().unwrap().control.push(text);
+                        if let Some(code) = error {
+                            let _ = ws.send(Message::Text(error_message(code))).await;
+                        }
+                        if close {
+                            let _ = ws.close(None).await;
+                        }
+                    }
+                    _ => {}
+                }
+            }
+        });
+
+        Self {
+            url: format!("ws://{addr}/v2/listen"),
+            received,
+        }
+    }
+
+    pub fn received(&self) -> Received {
+        self.received.lock().unwrap().clone()
+    }
+}
+
+/// An Error message with `code`, as the service would send it
+fn error_message(code: &str) -> String {
+    let description = if code == UNSUPPORTED_MESSAGE_CODE {
+        "Unknown message type 'Finalize'"
+    } else {
+        "Something went wrong"
+    };
+    serde_json::json!({
+        "type": "Error",
+        "code": code,
+        "description": description,
+    })
+    .to_string()
+}
+
+/// A TurnInfo message as the service would send it
+pub fn turn_info(event: &str, turn_index: u32, transcript: &str) -> String {
+    serde_json::json!({
+        "type": "TurnInfo",
+        "request_id": "fake",
+        "sequence_id": 0,
+        "event": event,
+        "turn_index": turn_index,
+        "audio_window_start": 0.0,
+        "audio_window_end": 1.0,
+        "transcript": transcript,
+        "words": [],
+        "end_of_turn_confidence": 0.9,
+    })
+    .to_string()
+}
@@ -0,0 +1,332 @@
+// Which application has the keyboard focus, so typing options can follow it
+// (--app-profiles). Reading focus depends on the display server: X11 is
+// supported when built with the `x11` feature; everywhere else no window is
+// reported and the startup profile stays in effect.
+//
+// The app-profiles file maps window classes to profiles:
+//   # WM_CLASS (either part, any case) = profile
+//   slack = chat
+//   code = editor
+// The first line naming the focused window's class wins.
+
+use anyhow::{anyhow, bail, Context, Result};
+use std::fmt;
+use std::path::Path;
+use tracing::warn;
+
+/// File in the config directory that `--app-profiles` reads
+pub const APP_PROFILES_FILE: &str = "app-profiles";
+
+/// The two WM_CLASS strings of a window, e.g. "Navigator" and "firefox"
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct WindowClass {
+    pub instance: String,
+    pub class: String,
+}
+
+impl WindowClass {
+    /// Parse a WM_CLASS property value: two NUL-terminated strings
+    #[cfg_attr(not(feature = "x11"), allow(dead_code))]
+    pub fn from_wm_class(value: &[u8]) -> Option<Self> {
+        let mut parts = value
+            .split(|&b| b == 0)
+            .map(|part| String::from_utf8_lossy(part).into_owned());
+        let instance = parts.next().unwrap_or_default();
+        let class = parts.next().unwrap_or_default();
+        if instance.is_empty() && class.is_empty() {
+            return None;
+        }
+        Some(Self { instance, class })
+    }
+
+    fn names(&self) -> [&str; 2] {
+        [&self.instance, &self.class]
+    }
+}
+
+impl fmt::Display for WindowClass {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.class.is_empty() {
+            f.write_str(&self.instance)
+        } else {
+            f.write_str(&self.class)
+        }
+    }
+}
+
+/// A source for the focused window
+pub trait FocusProvider: Send {
+    /// The focused window's class; `None` when no window has focus or it has
+    /// no class
+    fn focused(&mut self) -> Result<Option<WindowClass>>;
+}
+
+/// Never reports a window, for sessions focus can't be read from
+#[derive(Debug, Default)]
+pub struct NoFocus;
+
+impl FocusProvider for NoFocus {
+    fn focused(&mut self) -> Result<Option<WindowClass>> {
+        Ok(None)
+    }
+}
+
+/// Reads the focused window from the X server via `_NET_ACTIVE_WINDOW`
+#[cfg(feature = "x11")]
+pub struct X11Focus {
+    conn: x11rb::rust_connection::RustConnection,
+    root: u32,
+    active_window: u32,
+}
+
+#[cfg(feature = "x11")]
+impl X11Focus {
+    /// Connect to the display in `$DISPLAY`
+    pub fn connect() -> Result<Self> {
+        use x11rb::connection::Connection;
+        use x11rb::protocol::xproto::ConnectionExt;
+
+        let (conn, screen) = x11rb::connect(None).context("Failed to connect to the X server")?;
+        let root = conn.setup().roots[screen].root;
+        let active_window = conn
+            .intern_atom(false, b"_NET_ACTIVE_WINDOW")?
+            .reply()?
+            .atom;
+        Ok(Self {
+            conn,
+            root,
+            active_window,
+        })
+    }
+}
+
+#[cfg(feature = "x11")]
+impl FocusProvider for X11Focus {
+    fn focused(&mut self) -> Result<Option<WindowClass>> {
+        use x11rb::protocol::xproto::{AtomEnum, ConnectionExt};
+
+        let active = self
+            .conn
+            .get_property(false, self.root, self.active_window, AtomEnum::WINDOW, 0, 1)?
+            .reply()?;
+        let window = active.value32().and_then(|mut ids| ids.next());
+        let Some(window) = window.filter(|&id| id != x11rb::NONE) else {
+            return Ok(None);
+        };
+        let class = self
+            .conn
+            .get_property(false, window, AtomEnum::WM_CLASS, AtomEnum::STRING, 0, 256)?
+            .reply()?;
+        Ok(WindowClass::from_wm_class(&class.value))
+    }
+}
+
+/// The provider for this session: X11 where it's built in and available,
+/// otherwise one that never reports a window
+pub fn detect() -> Box<dyn FocusProvider> {
+    #[cfg(feature = "x11")]
+    if std::env::var_os("DISPLAY").is_some() {
+        match X11Focus::connect() {
+            Ok(provider) => return Box::new(provider),
+            Err(e) => warn!("Can't read the focused window: {:#}", e),
+        }
+    }
+    warn!("Following the focused window needs X11 and a build with the x11 feature; keeping the startup profile");
+    Box::new(NoFocus)
+}
+
+/// Window classes mapped to the profile to use while they have focus
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct AppProfiles {
+    /// (lowercased class, profile) in file order
+    rules: Vec<(String, String)>,
+}
+
+impl AppProfiles {
+    /// Parse `class = profile` lines; `# ` lines are comments
+    pub fn parse(text: &str) -> Result<Self> {
+        let mut rules = Vec::new();
+        for (number, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line == "#" || line.starts_with("# ") {
+                continue;
+            }
+            let (class, profile) = line
+                .split_once('=')
+                .ok_or_else(|| anyhow!("Expected 'class = profile' on line {}", number + 1))?;
+            let (class, profile) = (class.trim(), profile.trim());
+            if class.is_empty() || profile.is_empty() {
+                bail!("Missing class or profile on line {}", number + 1);
+            }
+            rules.push((class.to_lowercase(), profile.to_string()));
+        }
+        Ok(Self { rules })
+    }
+
+    pub fn load(path: &Path) -> Result<Self> {
+        let text = std::fs::read_to_string(path)
+            .with_context(|| format!("Failed to read {}", path.display()))?;
+        Self::parse(&text).with_context(|| format!("Invalid app profiles in {}", path.display()))
+    }
+
+    /// The profile for `window`, from the first rule naming either part of
+    /// its class
+    pub fn profile_for(&self, window: &WindowClass) -> Option<&str> {
+        self.rules
+            .iter()
+            .find(|(class, _)| {
+                window
+                    .names()
+                    .iter()
+                    .any(|name| name.to_lowercase() == *class)
+            })
+            .map(|(_, profile)| profile.as_str())
+    }
+
+    /// Every profile a rule names, each once
+    pub fn profiles(&self) -> Vec<&str> {
+        let mut profiles: Vec<&str> = Vec::new();
+        for (_, profile) in &self.rules {
+            if !profiles.contains(&profile.as_str()) {
+                profiles.push(profile);
+            }
+        }
+        profiles
+    }
+}
+
+/// Follows the focused window and reports when the profile for it changes
+pub struct FocusProfiles {
+    provider: Box<dyn FocusProvider>,
+    apps: AppProfiles,
+    /// For windows no rule names (the `--profile` one, if any)
+    fallback: Option<String>,
+    active: Option<String>,
+}
+
+impl FocusProfiles {
+    /// Start out in the fallback profile
+    pub fn new(
+        provider: Box<dyn FocusProvider>,
+        apps: AppProfiles,
+        fallback: Option<String>,
+    ) -> Self {
+        Self {
+            provider,
+            apps,
+            active: fallback.clone(),
+            fallback,
+        }
+    }
+
+    /// Check the focused window; returns it and the profile to switch to when
+    /// that differs from the active one
+    pub fn poll(&mut self) -> Result<Option<(Option<WindowClass>, Option<String>)>> {
+        let window = self.provider.focused()?;
+        let profile = window
+            .as_ref()
+            .and_then(|window| self.apps.profile_for(window))
+            .map(str::to_string)
+            .or_else(|| self.fallback.clone());
+        if profile == self.active {
+            return Ok(None);
+        }
+        self.active = profile.clone();
+        Ok(Some((window, profile)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::VecDeque;
+
+    fn window(instance: &str, class: &str) -> WindowClass {
+        WindowClass {
+            instance: instance.to_string(),
+            class: class.to_string(),
+        }
+    }
+
+    #[test]
+    fn test_class_strings_map_to_profiles() {
+        let apps = AppProfiles::parse(
+            "# chat apps\nslack = chat\nNavigator = browser\n\ncode = editor\nfirefox = ignored\n",
+        )
+        .unwrap();
+
+        assert_eq!(apps.profile_for(&window("slack", "Slack")), Some("chat"));
+        // Either part of WM_CLASS, in any case; the first matching rule wins
+        assert_eq!(
+            apps.profile_for(&window("Navigator", "firefox")),
+            Some("browser")
+        );
+        assert_eq!(apps.profile_for(&window("code", "Code")), Some("editor"));
+        assert_eq!(apps.profile_for(&window("xterm", "XTerm")), None);
+        assert_eq!(
+            apps.profiles(),
+            vec!["chat", "browser", "editor", "ignored"]
+        );
+
+        for bad in ["slack", "= chat", "slack ="] {
+            assert!(AppProfiles::parse(bad).is_err(), "for '{bad}'");
+        }
+    }
+
+    #[test]
+    fn test_wm_class_property() {
+        assert_eq!(
+            WindowClass::from_wm_class(b"Navigator\0firefox\0"),
+            Some(window("Navigator", "firefox"))
+        );
+        assert_eq!(
+            WindowClass::from_wm_class(b"xterm\0"),
+            Some(window("xterm", ""))
+        );
+        assert_eq!(WindowClass::from_wm_class(b""), None);
+        assert_eq!(window("Navigator", "firefox").to_string(), "firefox");
+    }
+
+    /// Reports a scripted sequence of focused windows
+    struct Scripted(VecDeque<Option<WindowClass>>);
+
+    impl FocusProvider for Scripted {
+        fn focused(&mut self) -> Result<Option<WindowClass>> {
+            Ok(self.0.pop_front().flatten())
+        }
+    }
+
+    #[test]
+    fn test_switches_only_when_the_profile_changes() {
+        let apps = AppProfiles::parse("slack = chat\ndiscord = chat\ncode = editor").unwrap();
+        let script = [
+            Some(window("slack", "Slack")),
+            Some(window("discord", "discord")),
+            Some(window("code", "Code")),
+            Some(window("xterm", "XTerm")),
+            None,
+        ];
+        let provider = Scripted(script.into_iter().collect());
+        let mut focus = FocusProfiles::new(Box::new(provider), apps, Some("terminal".into()));
+
+        let mut switches = Vec::new();
+        for _ in 0..5 {
+            if let Some((_, profile)) = focus.poll().unwrap() {
+                switches.push(profile);
+            }
+        }
+        assert_eq!(
+            switches,
+            vec![
+                Some("chat".to_string()),
+                Some("editor".to_string()),
+                Some("terminal".to_string()),
+            ]
+        );
+
+        // Without focus information nothing ever changes
+        let apps = AppProfiles::parse("slack = chat").unwrap();
+        let mut focus = FocusProfiles::new(Box::new(NoFocus), apps, None);
+        assert_eq!(focus.poll().unwrap(), None);
+    }
+}
@@ -41,6 +41,11 @@ impl InputEvent {
 // Event types
 pub const EV_SYN: u16 = 0x00;
 pub const EV_KEY: u16 = 0x01;
+pub const EV_REP: u16 = 0x14;
+
+// Auto-repeat parameters (EV_REP codes), in milliseconds
+pub const REP_DELAY: u16 = 0x00;
+pub const REP_PERIOD: u16 = 0x01;
 
 // Synchronization events
 pub const SYN_REPORT: u16 = 0;
@@ -102,8 +107,14 @@ pub const KEY_SLASH: u16 = 53;
 pub const KEY_RIGHTSHIFT: u16 = 54;
 pub const KEY_LEFTALT: u16 = 56;
 pub const KEY_SPACE: u16 = 57;
+// Extra key left of Z on ISO keyboards (< > | on German layouts)
+pub const KEY_102ND: u16 = 86;
 pub const KEY_CAPSLOCK: u16 = 58;
 
+// Right-hand modifiers; right Alt acts as AltGr on most non-US layouts
+pub const KEY_RIGHTCTRL: u16 = 97;
+pub const KEY_RIGHTALT: u16 = 100;
+
 // Function keys
 pub const KEY_F1: u16 = 59;
 pub const KEY_F2: u16 = 60;
@@ -117,6 +128,45 @@ pub const KEY_F9: u16 = 67;
 pub const KEY_F10: u16 = 68;
 pub const KEY_F11: u16 = 87;
 pub const KEY_F12: u16 = 88;
+pub const KEY_F13: u16 = 183;
+pub const KEY_F14: u16 = 184;
+pub const KEY_F15: u16 = 185;
+pub const KEY_F16: u16 = 186;
+pub const KEY_F17: u16 = 187;
+pub const KEY_F18: u16 = 188;
+pub const KEY_F19: u16 = 189;
+pub const KEY_F20: u16 = 190;
+pub const KEY_F21: u16 = 191;
+pub const KEY_F22: u16 = 192;
+pub const KEY_F23: u16 = 193;
+pub const KEY_F24: u16 = 194;
+
+// Navigation keys
+pub const KEY_HOME: u16 = 102;
+pub const KEY_UP: u16 = 103;
+pub const KEY_PAGEUP: u16 = 104;
+pub const KEY_LEFT: u16 = 105;
+pub const KEY_RIGHT: u16 = 106;
+pub const KEY_END: u16 = 107;
+pub const KEY_DOWN: u16 = 108;
+pub const KEY_PAGEDOWN: u16 = 109;
+
+// Media keys
+pub const KEY_MUTE: u16 = 113;
+pub const KEY_VOLUMEDOWN: u16 = 114;
+pub const KEY_VOLUMEUP: u16 = 115;
+pub const KEY_PLAYPAUSE: u16 = 164;
+
+// Modifiers that must never be left pressed when we stop, right Alt included
+// because layout characters hold it as AltGr
+pub const MODIFIER_KEYS: [u16; 6] = [
+    KEY_LEFTSHIFT,
+    KEY_RIGHTSHIFT,
+    KEY_LEFTCTRL,
+    KEY_RIGHTCTRL,
+    KEY_LEFTALT,
+    KEY_RIGHTALT,
+];
 
 // uinput constants
 pub const UINPUT_MAX_NAME_SIZE: usize = 80;
@@ -278,3 +328,43 @@ impl Default for UInputUserDev {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_high_function_and_media_keys_registered() {
+        let keycodes = get_all_keycodes();
+        let extra = [
+            KEY_F13,
+            KEY_F14,
+            KEY_F15,
+            KEY_F16,
+            KEY_F17,
+            KEY_F18,
+            KEY_F19,
+            KEY_F20,
+            KEY_F21,
+            KEY_F22,
+            KEY_F23,
+            KEY_F24,
+            KEY_MUTE,
+            KEY_VOLUMEDOWN,
+            KEY_VOLUMEUP,
+            KEY_PLAYPAUSE,
+        ];
+        for keycode in extra {
+            assert!(keycodes.contains(&keycode), "key {keycode} not enabled");
+        }
+        assert_eq!(KEY_F24 - KEY_F13, 11);
+    }
+
+    #[test]
+    fn test_right_modifiers_registered() {
+        let keycodes = get_all_keycodes();
+        for keycode in [KEY_RIGHTCTRL, KEY_RIGHTALT, KEY_102ND] {
+            assert!(keycodes.contains(&keycode), "key {keycode} not enabled");
+        }
+    }
+}
@@ -0,0 +1,820 @@
+use anyhow::{anyhow, Result};
+use std::str::FromStr;
+use std::time::{Duration, Instant};
+use tracing::debug;
+
+use crate::stt_client::{DISCONNECTED_EVENT, RECONNECTED_EVENT};
+use crate::virtual_keyboard::{KeyboardHardware, VirtualKeyboard};
+
+/// Default minimum spacing between typed interim updates in debounced mode
+pub const DEFAULT_DEBOUNCE: Duration = Duration::from_millis(300);
+
+/// When interim (non-final) transcripts are typed
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum InterimMode {
+    /// Type every interim update as it arrives
+    #[default]
+    Live,
+    /// Type at most one interim update per interval
+    Debounced(Duration),
+    /// Only type the transcript once the turn ends
+    FinalOnly,
+    /// Ignore interim results entirely; type each turn's final transcript in
+    /// one shot, without any trailing command phrase
+    Commit,
+}
+
+impl FromStr for InterimMode {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_ascii_lowercase().as_str() {
+            "live" => Ok(Self::Live),
+            "debounced" => Ok(Self::Debounced(DEFAULT_DEBOUNCE)),
+            "final-only" => Ok(Self::FinalOnly),
+            "commit" => Ok(Self::Commit),
+            other => Err(anyhow!(
+                "Unknown interim mode '{}' (expected live, debounced, final-only or commit)",
+                other
+            )),
+        }
+    }
+}
+
+/// Decides which STT events reach the keyboard according to an `InterimMode`
+#[derive(Debug, Clone)]
+pub struct InterimFilter {
+    mode: InterimMode,
+    last_update: Option<Instant>,
+    /// Whether interim results arrived since the last end of turn, which
+    /// commit mode is holding back
+    turn_pending: bool,
+    /// What commit mode typed for the last end of turn, until the next turn's
+    /// interim results, so a repeated EndOfTurn isn't committed twice
+    last_committed: Option<String>,
+    /// Finalize locally once no transcript has arrived for this long
+    finalize_after: Option<Duration>,
+    /// The latest interim transcript and when it arrived, until the turn ends
+    latest: Option<(Instant, String)>,
+    /// What a local finalize already committed of the server's current turn
+    finalized_locally: Option<String>,
+    /// A turn starting this soon after the last one ended continues its line
+    merge_window: Option<Duration>,
+    /// A turn end held back for the merge window: when it came, and the text
+    held_turn: Option<(Instant, String)>,
+    /// Text of earlier turns that the current one continues
+    merged: Option<String>,
+    /// Updates in a row the last word must survive before it's typed
+    stabilize_tail: Option<usize>,
+    /// The last word of the latest update: where it starts, the word, and in
+    /// how many updates in a row it has been the same
+    tail: Option<(usize, String, usize)>,
+}
+
+impl InterimFilter {
+    pub fn new(mode: InterimMode) -> Self {
+        Self {
+            mode,
+            last_update: None,
+            turn_pending: false,
+            last_committed: None,
+            finalize_after: None,
+            latest: None,
+            finalized_locally: None,
+            merge_window: None,
+            held_turn: None,
+            merged: None,
+            stabilize_tail: None,
+            tail: None,
+        }
+    }
+
+    /// Hold back the last word of interim updates until it has come out the
+    /// same in `updates` updates in a row (or the turn ends), since that's the
+    /// word the model keeps revising; the words before it are typed as usual.
+    /// `None` types every update whole.
+    pub fn set_stabilize_tail(&mut self, updates: Option<usize>) {
+        self.stabilize_tail = updates;
+    }
+
+    /// The part of an interim transcript to type: all of it, or without its
+    /// last word while that word is still changing
+    fn stable_part<'a>(&mut self, transcript: &'a str) -> &'a str {
+        let Some(needed) = self.stabilize_tail else {
+            return transcript;
+        };
+        let trimmed = transcript.trim_end();
+        let before_tail = trimmed.trim_end_matches(|c: char| !c.is_whitespace());
+        let start = before_tail.len();
+        let word = &trimmed[start..];
+        let seen = match &self.tail {
+            Some((at, last, seen)) if *at == start && last == word => seen + 1,
+            _ => 1,
+        };
+        self.tail = Some((start, word.to_string(), seen));
+        if seen >= needed {
+            transcript
+        } else {
+            debug!("Holding back unstable last word '{}'", word);
+            before_tail.trim_end()
+        }
+    }
+
+    /// Whether an interim update may have been typed short of its last word
+    fn holds_tail(&self) -> bool {
+        self.stabilize_tail.is_some_and(|needed| needed > 1)
+    }
+
+    /// Hold each turn end back for `window`, and continue the line instead of
+    /// finalizing it if another turn starts by then, for servers that split one
+    /// sentence into quick turns; `None` finalizes right away. Not used in
+    /// commit mode.
+    pub fn set_turn_merge(&mut self, window: Option<Duration>) {
+        self.merge_window = window;
+    }
+
+    /// Finalize a turn end still held for the merge window, e.g. when the
+    /// session ends; returns false if there was none
+    pub fn finish_held_turn<H: KeyboardHardware>(
+        &mut self,
+        keyboard: &mut VirtualKeyboard<H>,
+    ) -> Result<bool> {
+        if self.held_turn.take().is_none() {
+            return Ok(false);
+        }
+        self.merged = None;
+        keyboard.finalize_transcript()?;
+        Ok(true)
+    }
+
+    /// Finalize the turn ourselves when the stream stalls for `after`, for
+    /// servers that are slow to send EndOfTurn; `None` waits for the server
+    pub fn set_finalize_after(&mut self, after: Option<Duration>) {
+        self.finalize_after = after;
+    }
+
+    /// Whether a turn is under way: it has typed or held back text, or its
+    /// end is held for the merge window
+    pub fn in_turn(&self) -> bool {
+        self.latest.is_some()
+            || self.turn_pending
+            || self.held_turn.is_some()
+            || self.finalized_locally.is_some()
+    }
+
+    /// When `finalize_stalled` should run if no transcript arrives before then
+    /// Final-only and commit modes have nothing typed to finalize early, but
+    /// a held turn end is due when its merge window closes.
+    pub fn finalize_deadline(&self) -> Option<Instant> {
+        if let (Some((at, _)), Some(window)) = (&self.held_turn, self.merge_window) {
+            return Some(*at + window);
+        }
+        if matches!(self.mode, InterimMode::FinalOnly | InterimMode::Commit) {
+            return None;
+        }
+        let (at, _) = self.latest.as_ref()?;
+        Some(*at + self.finalize_after?)
+    }
+
+    /// Finalize the current text because the stream stalled, or finish a held
+    /// turn end whose merge window closed without a new turn
+    /// Returns true only for a stall that finalized something.
+    pub fn finalize_stalled<H: KeyboardHardware>(
+        &mut self,
+        keyboard: &mut VirtualKeyboard<H>,
+    ) -> Result<bool> {
+        if self.finish_held_turn(keyboard)? {
+            debug!("No new turn within the merge window; finalized the line");
+            return Ok(false);
+        }
+        self.finalize_locally(keyboard)
+    }
+
+    /// End the turn in progress ourselves, typing what was held back first;
+    /// the server's later transcripts for the turn only add the words after
+    /// it. Returns false if there was nothing to finalize.
+    pub fn finalize_locally<H: KeyboardHardware>(
+        &mut self,
+        keyboard: &mut VirtualKeyboard<H>,
+    ) -> Result<bool> {
+        let Some((_, transcript)) = self.latest.take() else {
+            return Ok(false);
+        };
+        self.tail = None;
+        if self.mode != InterimMode::Live || self.holds_tail() {
+            keyboard.update_transcript(&transcript)?;
+        }
+        // Live mode types every transcript, so this one came out as nothing
+        if self.mode == InterimMode::Live && keyboard.get_current_text().is_empty() {
+            return Ok(false);
+        }
+        debug!("Finalizing locally: {}", transcript);
+        self.last_update = None;
+        keyboard.finalize_locally()?;
+        // The server's transcripts for this turn still contain these words
+        let committed = match self.finalized_locally.take() {
+            Some(earlier) => format!("{earlier} {transcript}"),
+            None => transcript,
+        };
+        self.finalized_locally = Some(committed);
+        Ok(true)
+    }
+
+    /// The part of a transcript a local finalize hasn't committed yet
+    fn uncommitted<'a>(&self, transcript: &'a str) -> &'a str {
+        let Some(committed) = &self.finalized_locally else {
+            return transcript;
+        };
+        // Compare by word count: the server may still revise committed words
+        let mut rest = transcript;
+        for _ in committed.split_whitespace() {
+            rest = rest.trim_start();
+            rest = &rest[rest.find(char::is_whitespace).unwrap_or(rest.len())..];
+        }
+        rest.trim_start()
+    }
+
+    /// The session dropped and its open turn won't be finished: leave what's
+    /// typed on screen and start the next turn fresh. A turn end held for the
+    /// merge window was complete, so it's still finalized.
+    fn session_lost<H: KeyboardHardware>(
+        &mut self,
+        keyboard: &mut VirtualKeyboard<H>,
+    ) -> Result<()> {
+        self.finish_held_turn(keyboard)?;
+        debug!("Session lost; forgetting the turn in progress");
+        self.last_update = None;
+        self.turn_pending = false;
+        self.latest = None;
+        self.finalized_locally = None;
+        self.merged = None;
+        self.tail = None;
+        keyboard.reset_tracking();
+        Ok(())
+    }
+
+    /// Apply one STT event to the keyboard
+    /// "EndOfTurn" finalizes; outside live mode the final transcript is synced first,
+    /// since interim updates may have been skipped. Other events are interim updates.
+    /// In commit mode nothing is typed until "EndOfTurn", which commits the final text.
+    pub fn apply<H: KeyboardHardware>(
+        &mut self,
+        keyboard: &mut VirtualKeyboard<H>,
+        event: &str,
+        transcript: &str,
+        now: Instant,
+    ) -> Result<()> {
+        if event == DISCONNECTED_EVENT {
+            return self.session_lost(keyboard);
+        }
+        if event == RECONNECTED_EVENT {
+            return Ok(());
+        }
+
+        if self.mode == InterimMode::Commit {
+            if event != "EndOfTurn" {
+                self.turn_pending = true;
+                self.last_committed = None;
+                debug!("Holding back interim [{}]: {}", event, transcript);
+                return Ok(());
+            }
+            // A short utterance may end without any interim results first
+            self.turn_pending = false;
+            if transcript.is_empty() || self.last_committed.as_deref() == Some(transcript) {
+                debug!("Nothing to commit for this end of turn");
+                return Ok(());
+            }
+            self.last_committed = Some(transcript.to_string());
+            return keyboard.commit_transcript(transcript);
+        }
+
+        if let Some((at, text)) = self.held_turn.take() {
+            if event == "EndOfTurn" {
+                debug!("Turn end already held; ignoring repeated end-of-turn");
+                self.held_turn = Some((at, text));
+                return Ok(());
+            }
+            if self
+                .merge_window
+                .is_some_and(|window| now.duration_since(at) <= window)
+            {
+                debug!("New turn within the merge window; continuing the line");
+                self.merged = Some(text);
+            } else {
+                self.merged = None;
+                keyboard.finalize_transcript()?;
+            }
+        }
+
+        if event == "StartOfTurn" {
+            self.finalized_locally = None;
+        }
+        let transcript = self.uncommitted(transcript);
+        // A merged turn's transcripts only hold its own words
+        let transcript = match &self.merged {
+            Some(earlier) if transcript.is_empty() => earlier.clone(),
+            Some(earlier) => format!("{earlier} {transcript}"),
+            None => transcript.to_string(),
+        };
+        let transcript = transcript.as_str();
+
+        if event == "EndOfTurn" {
+            self.latest = None;
+            self.tail = None;
+            // Nothing left if a local finalize already committed the whole
+            // turn, but the turn's end may still call for Enter
+            if self.finalized_locally.take().is_some() && transcript.is_empty() {
+                debug!("Turn was already finalized locally");
+                return keyboard.finish_local_turn();
+            }
+            // A held-back last word is typed now the turn is over
+            let synced = self.mode == InterimMode::Live && !self.holds_tail();
+            if !synced && !transcript.is_empty() {
+                keyboard.update_transcript(transcript)?;
+            }
+            self.last_update = None;
+            if self.merge_window.is_some() {
+                debug!("Holding the turn end in case the next turn continues it");
+                self.held_turn = Some((now, transcript.to_string()));
+                self.merged = None;
+                return Ok(());
+            }
+            return keyboard.finalize_transcript();
+        }
+
+        if !transcript.is_empty() {
+            self.latest = Some((now, transcript.to_string()));
+        }
+        let transcript = self.stable_part(transcript);
+
+        let should_type = match self.mode {
+            InterimMode::Live => true,
+            InterimMode::FinalOnly | InterimMode::Commit => false,
+            InterimMode::Debounced(interval) => self
+                .last_update
+                .is_none_or(|last| now.duration_since(last) >= interval),
+        };
+
+        if should_type {
+            self.last_update = Some(now);
+            keyboard.update_transcript(transcript)
+        } else {
+            debug!("Holding back interim [{}]: {}", event, transcript);
+            Ok(())
+        }
+    }
+}
+
+/// Ends a session after a number of turns (`--once` is a limit of one)
+#[derive(Debug, Clone, Default)]
+pub struct TurnLimit {
+    remaining: Option<usize>,
+}
+
+impl TurnLimit {
+    /// `None` means unlimited
+    pub fn new(max_turns: Option<usize>) -> Self {
+        Self {
+            remaining: max_turns,
+        }
+    }
+
+    /// Whether the limit has been used up; later events should be ignored
+    pub fn is_reached(&self) -> bool {
+        self.remaining == Some(0)
+    }
+
+    /// Count one STT event; returns true if it was the turn that hit the limit
+    pub fn record(&mut self, event: &str) -> bool {
+        match &mut self.remaining {
+            Some(remaining) if *remaining > 0 && event == "EndOfTurn" => {
+                *remaining -= 1;
+                *remaining == 0
+            }
+            _ => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::virtual_keyboard::MockKeyboardHardware;
+
+    /// Run synthetic (offset ms, event, transcript) results through a filter,
+    /// returning how many updates reached the keyboard and the final on-screen text
+    fn run(mode: InterimMode, results: &[(u64, &str, &str)]) -> (usize, String) {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+        use std::sync::Arc;
+
+        let updates = Arc::new(AtomicUsize::new(0));
+        let updates_clone = updates.clone();
+        let mut kb = VirtualKeyboard::new(MockKeyboardHardware::new());
+        kb.set_on_text_change(move |text| {
+            if !text.is_empty() {
+                updates_clone.fetch_add(1, Ordering::SeqCst);
+            }
+        });
+
+        let mut filter = InterimFilter::new(mode);
+        let start = Instant::now();
+        for &(offset_ms, event, transcript) in results {
+            let now = start + Duration::from_millis(offset_ms);
+            filter.apply(&mut kb, event, transcript, now).unwrap();
+        }
+
+        let text: String = kb.hardware().typed_chars.iter().collect();
+        (updates.load(Ordering::SeqCst), text)
+    }
+
+    fn stream() -> Vec<(u64, &'static str, &'static str)> {
+        vec![
+            (0, "StartOfTurn", "the"),
+            (100, "Update", "the quick"),
+            (200, "Update", "the quack"),
+            (300, "Update", "the quick brown"),
+            (400, "Update", "the quick brown fox"),
+            (500, "Update", "the quick brown fox jumps"),
+            (600, "EndOfTurn", "the quick brown fox jumped"),
+        ]
+    }
+
+    #[test]
+    fn test_live_types_every_update() {
+        let (updates, text) = run(InterimMode::Live, &stream());
+        assert_eq!(updates, 6);
+        // Live mode finalizes whatever the last update typed
+        assert_eq!(text, "the quick brown fox jumps");
+    }
+
+    #[test]
+    fn test_debounced_limits_update_rate() {
+        let mode = InterimMode::Debounced(Duration::from_millis(250));
+        let (updates, text) = run(mode, &stream());
+        // Updates at 0, 300 and 600 ms (the last one being the final sync)
+        assert_eq!(updates, 3);
+        assert_eq!(text, "the quick brown fox jumped");
+    }
+
+    #[test]
+    fn test_final_only_types_once_per_turn() {
+        let (updates, text) = run(InterimMode::FinalOnly, &stream());
+        assert_eq!(updates, 1);
+        assert_eq!(text, "the quick brown fox jumped");
+    }
+
+    #[test]
+    fn test_commit_types_nothing_until_end_of_turn() {
+        let mut kb = VirtualKeyboard::new(MockKeyboardHardware::new());
+        let mut filter = InterimFilter::new(InterimMode::Commit);
+        let start = Instant::now();
+        let results = stream();
+        let (last, interim) = results.split_last().unwrap();
+        for &(offset_ms, event, transcript) in interim {
+            let now = start + Duration::from_millis(offset_ms);
+            filter.apply(&mut kb, event, transcript, now).unwrap();
+            assert!(kb.hardware().typed_chars.is_empty(), "typed at {event}");
+        }
+
+        let (offset_ms, event, transcript) = *last;
+        let now = start + Duration::from_millis(offset_ms);
+        filter.apply(&mut kb, event, transcript, now).unwrap();
+        // A repeated end of turn commits nothing more
+        filter.apply(&mut kb, event, transcript, now).unwrap();
+
+        let text: String = kb.hardware().typed_chars.iter().collect();
+        assert_eq!(text, "the quick brown fox jumped");
+        assert_eq!(kb.hardware().backspace_count, 0);
+        assert_eq!(kb.hardware().actions.len(), 2 * text.len());
+    }
+
+    #[test]
+    fn test_commit_types_a_turn_with_no_interim_results() {
+        let mut kb = VirtualKeyboard::new(MockKeyboardHardware::new());
+        let mut filter = InterimFilter::new(InterimMode::Commit);
+        let now = Instant::now();
+        filter.apply(&mut kb, "EndOfTurn", "yes", now).unwrap();
+        filter.apply(&mut kb, "EndOfTurn", "yes", now).unwrap();
+        assert_eq!(kb.hardware().typed_chars.iter().collect::<String>(), "yes");
+
+        // The same words again in a new turn are typed again
+        filter.apply(&mut kb, "StartOfTurn", "yes", now).unwrap();
+        filter.apply(&mut kb, "EndOfTurn", "yes", now).unwrap();
+        filter.apply(&mut kb, "EndOfTurn", "no", now).unwrap();
+        assert_eq!(
+            kb.hardware().typed_chars.iter().collect::<String>(),
+            "yesyesno"
+        );
+    }
+
+    #[test]
+    fn test_stalled_stream_finalizes_locally() {
+        // As the CLI types by default: the server's turn ends press Enter
+        let mut kb = VirtualKeyboard::new(MockKeyboardHardware::new());
+        kb.set_voice_enter_enabled(false);
+        let mut filter = InterimFilter::new(InterimMode::Live);
+        filter.set_finalize_after(Some(Duration::from_millis(500)));
+        let start = Instant::now();
+        let at = |ms| start + Duration::from_millis(ms);
+
+        assert_eq!(filter.finalize_deadline(), None);
+        filter
+            .apply(&mut kb, "StartOfTurn", "hello", at(0))
+            .unwrap();
+        assert_eq!(filter.finalize_deadline(), Some(at(500)));
+        // Each new transcript pushes the deadline back
+        filter
+            .apply(&mut kb, "Update", "hello world", at(300))
+            .unwrap();
+        assert_eq!(filter.finalize_deadline(), Some(at(800)));
+
+        // The stream stalls past the deadline; the speaker may not be done,
+        // so the line isn't sent yet
+        assert!(filter.finalize_stalled(&mut kb).unwrap());
+        assert_eq!(kb.get_current_text(), "");
+        assert_eq!(kb.hardware().enter_count, 0);
+        assert_eq!(filter.finalize_deadline(), None);
+        assert!(!filter.finalize_stalled(&mut kb).unwrap());
+
+        // Late words of the same turn continue the utterance after a space;
+        // the server's EndOfTurn doesn't type or finalize the committed words
+        // again, and sends the line
+        filter
+            .apply(&mut kb, "Update", "hello world again", at(1000))
+            .unwrap();
+        assert_eq!(kb.get_current_text(), "again");
+        filter
+            .apply(&mut kb, "EndOfTurn", "hello world again", at(1100))
+            .unwrap();
+        assert_eq!(kb.get_current_text(), "");
+        let text: String = kb.hardware().typed_chars.iter().collect();
+        assert_eq!(text, "hello world again");
+        assert_eq!(kb.hardware().enter_count, 1);
+
+        // A turn committed entirely by the local finalize types nothing more
+        // when the server ends it, but gets its Enter then
+        filter
+            .apply(&mut kb, "StartOfTurn", "next", at(2000))
+            .unwrap();
+        assert!(filter.finalize_stalled(&mut kb).unwrap());
+        assert_eq!(kb.hardware().enter_count, 1);
+        let typed = kb.hardware().typed_chars.len();
+        filter
+            .apply(&mut kb, "EndOfTurn", "next", at(2600))
+            .unwrap();
+        assert_eq!(kb.hardware().typed_chars.len(), typed);
+        assert_eq!(kb.hardware().enter_count, 2);
+
+        // The two turns are on separate lines, with an Enter between them
+        let text: String = kb.hardware().typed_chars.iter().collect();
+        assert_eq!(text, "hello world againnext");
+    }
+
+    #[test]
+    fn test_stalled_debounced_stream_syncs_before_finalizing() {
+        let mut kb = VirtualKeyboard::new(MockKeyboardHardware::new());
+        let mut filter = InterimFilter::new(InterimMode::Debounced(Duration::from_millis(250)));
+        filter.set_finalize_after(Some(Duration::from_millis(500)));
+        let start = Instant::now();
+        filter.apply(&mut kb, "StartOfTurn", "the", start).unwrap();
+        filter
+            .apply(
+                &mut kb,
+                "Update",
+                "the quick",
+                start + Duration::from_millis(100),
+            )
+            .unwrap();
+
+        assert!(filter.finalize_stalled(&mut kb).unwrap());
+        let text: String = kb.hardware().typed_chars.iter().collect();
+        assert_eq!(text, "the quick");
+
+        let mut final_only = InterimFilter::new(InterimMode::FinalOnly);
+        final_only.set_finalize_after(Some(Duration::from_millis(500)));
+        final_only.apply(&mut kb, "Update", "words", start).unwrap();
+        assert_eq!(final_only.finalize_deadline(), None);
+    }
+
+    #[test]
+    fn test_final_only_turn_finalized_locally() {
+        let mut kb = VirtualKeyboard::new(MockKeyboardHardware::new());
+        kb.set_auto_enter_enabled(true);
+        let mut filter = InterimFilter::new(InterimMode::FinalOnly);
+        let start = Instant::now();
+        filter
+            .apply(&mut kb, "Update", "hello there", start)
+            .unwrap();
+        assert!(kb.hardware().typed_chars.is_empty());
+
+        // Local endpointing ends the turn before the server does
+        assert!(filter.finalize_locally(&mut kb).unwrap());
+        assert!(!filter.finalize_locally(&mut kb).unwrap());
+        filter
+            .apply(&mut kb, "EndOfTurn", "hello there", start)
+            .unwrap();
+
+        let text: String = kb.hardware().typed_chars.iter().collect();
+        assert_eq!(text, "hello there");
+        assert_eq!(kb.hardware().enter_count, 1);
+    }
+
+    fn two_turns(gap_ms: u64) -> VirtualKeyboard<MockKeyboardHardware> {
+        let mut kb = VirtualKeyboard::new(MockKeyboardHardware::new());
+        kb.set_auto_enter_enabled(true);
+        let mut filter = InterimFilter::new(InterimMode::Live);
+        filter.set_turn_merge(Some(Duration::from_millis(400)));
+        let start = Instant::now();
+        let at = |ms| start + Duration::from_millis(ms);
+
+        filter
+            .apply(&mut kb, "StartOfTurn", "hello", at(0))
+            .unwrap();
+        filter
+            .apply(&mut kb, "EndOfTurn", "hello", at(200))
+            .unwrap();
+        // The turn end waits for the merge window
+        assert_eq!(kb.hardware().enter_count, 0);
+        assert_eq!(filter.finalize_deadline(), Some(at(600)));
+        filter
+            .apply(&mut kb, "EndOfTurn", "hello", at(250))
+            .unwrap();
+
+        let next = 200 + gap_ms;
+        if filter
+            .finalize_deadline()
+            .is_some_and(|due| due <= at(next))
+        {
+            assert!(!filter.finalize_stalled(&mut kb).unwrap());
+        }
+        filter
+            .apply(&mut kb, "StartOfTurn", "world", at(next))
+            .unwrap();
+        filter
+            .apply(&mut kb, "EndOfTurn", "world", at(next + 200))
+            .unwrap();
+        assert!(filter.finish_held_turn(&mut kb).unwrap());
+        assert!(!filter.finish_held_turn(&mut kb).unwrap());
+        kb
+    }
+
+    #[test]
+    fn test_in_turn_until_the_turn_ends() {
+        let mut kb = VirtualKeyboard::new(MockKeyboardHardware::new());
+        let mut filter = InterimFilter::new(InterimMode::Live);
+        filter.set_turn_merge(Some(Duration::from_millis(400)));
+        let start = Instant::now();
+        let at = |ms| start + Duration::from_millis(ms);
+
+        assert!(!filter.in_turn());
+        filter
+            .apply(&mut kb, "StartOfTurn", "hello", at(0))
+            .unwrap();
+        assert!(filter.in_turn());
+        // A turn end held for the merge window may still be continued
+        filter
+            .apply(&mut kb, "EndOfTurn", "hello", at(100))
+            .unwrap();
+        assert!(filter.in_turn());
+        filter.finalize_stalled(&mut kb).unwrap();
+        assert!(!filter.in_turn());
+
+        // In commit mode nothing is typed, but the turn is under way
+        let mut filter = InterimFilter::new(InterimMode::Commit);
+        filter.apply(&mut kb, "StartOfTurn", "hi", at(0)).unwrap();
+        assert!(filter.in_turn());
+        filter.apply(&mut kb, "EndOfTurn", "hi", at(100)).unwrap();
+        assert!(!filter.in_turn());
+    }
+
+    #[test]
+    fn test_turn_within_merge_window_continues_the_line() {
+        let kb = two_turns(300);
+        let text: String = kb.hardware().typed_chars.iter().collect();
+        assert_eq!(text, "hello world");
+        assert_eq!(kb.hardware().enter_count, 1);
+    }
+
+    #[test]
+    fn test_turn_after_merge_window_starts_a_new_line() {
+        let kb = two_turns(500);
+        let text: String = kb.hardware().typed_chars.iter().collect();
+        assert_eq!(text, "helloworld");
+        assert_eq!(kb.hardware().enter_count, 2);
+    }
+
+    /// An update stream whose last word is revised a few times before it settles
+    fn jittery_stream() -> Vec<(u64, &'static str, &'static str)> {
+        vec![
+            (0, "StartOfTurn", "meet"),
+            (100, "Update", "meet me at"),
+            (200, "Update", "meet me at the"),
+            (300, "Update", "meet me at the see"),
+            (400, "Update", "meet me at the sea"),
+            (500, "Update", "meet me at the cafe"),
+            (600, "Update", "meet me at the caffe"),
+            (700, "Update", "meet me at the cafe"),
+            (800, "Update", "meet me at the cafe"),
+            (900, "Update", "meet me at the cafe"),
+            (1000, "EndOfTurn", "meet me at the cafe"),
+        ]
+    }
+
+    fn run_stabilized(updates: Option<usize>) -> MockKeyboardHardware {
+        let mut kb = VirtualKeyboard::new(MockKeyboardHardware::new());
+        let mut filter = InterimFilter::new(InterimMode::Live);
+        filter.set_stabilize_tail(updates);
+        let start = Instant::now();
+        for (offset_ms, event, transcript) in jittery_stream() {
+            let now = start + Duration::from_millis(offset_ms);
+            filter.apply(&mut kb, event, transcript, now).unwrap();
+            // Only what's on screen is tracked
+            let typed: String = kb.hardware().typed_chars.iter().collect();
+            if event != "EndOfTurn" {
+                assert_eq!(kb.get_current_text(), typed, "after '{transcript}'");
+            }
+        }
+        std::mem::replace(kb.hardware_mut(), MockKeyboardHardware::new())
+    }
+
+    #[test]
+    fn test_stabilized_tail_backspaces_less() {
+        let live = run_stabilized(None);
+        let stabilized = run_stabilized(Some(3));
+        let text = |hw: &MockKeyboardHardware| hw.typed_chars.iter().collect::<String>();
+        assert_eq!(text(&live), "meet me at the cafe");
+        assert_eq!(text(&stabilized), "meet me at the cafe");
+        // see -> sea -> cafe -> caffe -> cafe
+        assert_eq!(live.backspace_count, 7);
+        // "cafe" is typed once it has held for three updates, and nothing is retyped
+        assert_eq!(stabilized.backspace_count, 0);
+    }
+
+    #[test]
+    fn test_held_tail_is_typed_when_the_turn_ends() {
+        let mut kb = VirtualKeyboard::new(MockKeyboardHardware::new());
+        let mut filter = InterimFilter::new(InterimMode::Live);
+        filter.set_stabilize_tail(Some(3));
+        let now = Instant::now();
+        filter
+            .apply(&mut kb, "StartOfTurn", "hello wor", now)
+            .unwrap();
+        filter.apply(&mut kb, "Update", "hello world", now).unwrap();
+        assert_eq!(kb.get_current_text(), "hello");
+        filter
+            .apply(&mut kb, "EndOfTurn", "hello world", now)
+            .unwrap();
+        let text: String = kb.hardware().typed_chars.iter().collect();
+        assert_eq!(text, "hello world");
+
+        // A stalled turn types its held word before finalizing too
+        let mut kb = VirtualKeyboard::new(MockKeyboardHardware::new());
+        filter.apply(&mut kb, "StartOfTurn", "bye", now).unwrap();
+        assert_eq!(kb.get_current_text(), "");
+        assert!(filter.finalize_locally(&mut kb).unwrap());
+        let text: String = kb.hardware().typed_chars.iter().collect();
+        assert_eq!(text, "bye");
+    }
+
+    #[test]
+    fn test_interim_mode_from_str() {
+        assert_eq!("live".parse::<InterimMode>().unwrap(), InterimMode::Live);
+        assert_eq!(
+            "Final-Only".parse::<InterimMode>().unwrap(),
+            InterimMode::FinalOnly
+        );
+        assert_eq!(
+            "debounced".parse::<InterimMode>().unwrap(),
+            InterimMode::Debounced(DEFAULT_DEBOUNCE)
+        );
+        assert_eq!(
+            "commit".parse::<InterimMode>().unwrap(),
+            InterimMode::Commit
+        );
+        assert!("sometimes".parse::<InterimMode>().is_err());
+    }
+
+    #[test]
+    fn test_turn_limit_stops_after_first_end_of_turn() {
+        let mut events: Vec<&str> = stream().iter().map(|&(_, event, _)| event).collect();
+        events.extend(["StartOfTurn", "Update", "EndOfTurn"]);
+
+        let mut once = TurnLimit::new(Some(1));
+        let mut stopped_at = None;
+        for (i, event) in events.iter().enumerate() {
+            assert!(!once.is_reached(), "event {i} arrived after the limit");
+            if once.record(event) {
+                stopped_at = Some(i);
+                break;
+            }
+        }
+        // Only the first EndOfTurn (index 6) ends the session, not the interims
+        assert_eq!(stopped_at, Some(6));
+        assert!(once.is_reached());
+        assert!(!once.record("EndOfTurn"));
+
+        let mut unlimited = TurnLimit::new(None);
+        assert!(events.iter().all(|event| !unlimited.record(event)));
+        assert!(!unlimited.is_reached());
+    }
+}
@@ -0,0 +1,343 @@
+// Keyboard layouts: which physical key (plus modifiers) produces a character.
+// The layout must match the one configured in the desktop session, since
+// uinput sends keycodes and the compositor maps them back to characters.
+
+use anyhow::{anyhow, bail, Context, Result};
+use std::collections::HashMap;
+use std::path::Path;
+use std::str::FromStr;
+
+use crate::input_event::*;
+
+/// A key plus the modifiers that must be held while it is tapped
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct KeyStroke {
+    pub keycode: u16,
+    pub shift: bool,
+    /// Held with KEY_RIGHTALT, which acts as AltGr on most non-US layouts
+    pub altgr: bool,
+}
+
+impl KeyStroke {
+    pub const fn plain(keycode: u16) -> Self {
+        Self {
+            keycode,
+            shift: false,
+            altgr: false,
+        }
+    }
+
+    pub const fn shifted(keycode: u16) -> Self {
+        Self {
+            keycode,
+            shift: true,
+            altgr: false,
+        }
+    }
+
+    pub const fn altgr(keycode: u16) -> Self {
+        Self {
+            keycode,
+            shift: false,
+            altgr: true,
+        }
+    }
+
+    /// Key events (keycode, pressed), with modifiers wrapped around the key
+    pub fn events(&self) -> Vec<(u16, bool)> {
+        let mut modifiers = Vec::new();
+        if self.shift {
+            modifiers.push(KEY_LEFTSHIFT);
+        }
+        if self.altgr {
+            modifiers.push(KEY_RIGHTALT);
+        }
+
+        let mut events: Vec<(u16, bool)> = modifiers.iter().map(|&m| (m, true)).collect();
+        events.push((self.keycode, true));
+        events.push((self.keycode, false));
+        events.extend(modifiers.iter().rev().map(|&m| (m, false)));
+        events
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum KeyboardLayout {
+    /// US QWERTY
+    #[default]
+    Us,
+    /// German QWERTZ, which needs AltGr for @, €, braces and more
+    De,
+}
+
+impl FromStr for KeyboardLayout {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_ascii_lowercase().as_str() {
+            "us" => Ok(Self::Us),
+            "de" => Ok(Self::De),
+            other => bail!("Unknown keyboard layout '{}' (expected us or de)", other),
+        }
+    }
+}
+
+impl KeyboardLayout {
+    /// How to type `c` on this layout, or None if it has no key for it
+    pub fn keystroke(self, c: char) -> Option<KeyStroke> {
+        match self {
+            Self::Us => char_to_keycode(c).map(|(keycode, shift)| KeyStroke {
+                keycode,
+                shift,
+                altgr: false,
+            }),
+            Self::De => de_keystroke(c),
+        }
+    }
+}
+
+/// A layout plus per-character overrides, e.g. for custom XKB remaps
+#[derive(Debug, Clone, Default)]
+pub struct Keymap {
+    pub layout: KeyboardLayout,
+    overrides: HashMap<char, (u16, bool)>,
+    /// Use only the overrides, ignoring the layout entirely
+    replace_layout: bool,
+    /// Keep shift down across a run of shifted characters instead of
+    /// pressing it for each one
+    pub hold_shift: bool,
+}
+
+impl Keymap {
+    /// Consult `overrides` before the layout, or instead of it if `replace_layout`
+    #[cfg_attr(not(target_os = "linux"), allow(dead_code))]
+    pub fn set_overrides(&mut self, overrides: HashMap<char, (u16, bool)>, replace_layout: bool) {
+        self.overrides = overrides;
+        self.replace_layout = replace_layout;
+    }
+
+    pub fn keystroke(&self, c: char) -> Option<KeyStroke> {
+        if let Some(&(keycode, shift)) = self.overrides.get(&c) {
+            return Some(KeyStroke {
+                keycode,
+                shift,
+                altgr: false,
+            });
+        }
+        if self.replace_layout {
+            return None;
+        }
+        self.layout.keystroke(c)
+    }
+}
+
+/// Parse a keymap file: one `char=keycode[,shift]` per line, e.g. `é=3,false`.
+/// Use `space=` for a space. Blank lines and lines starting with `# ` are ignored.
+pub fn parse_keymap(text: &str) -> Result<HashMap<char, (u16, bool)>> {
+    let mut overrides = HashMap::new();
+    for (number, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line == "#" || line.starts_with("# ") {
+            continue;
+        }
+        let (c, mapping) = parse_keymap_line(line)
+            .with_context(|| format!("Invalid keymap line {}: '{}'", number + 1, line))?;
+        overrides.insert(c, mapping);
+    }
+    Ok(overrides)
+}
+
+fn parse_keymap_line(line: &str) -> Result<(char, (u16, bool))> {
+    let (c, value) = match line.strip_prefix("space=") {
+        Some(value) => (' ', value),
+        None => {
+            let mut chars = line.chars();
+            let c = chars.next().ok_or_else(|| anyhow!("missing character"))?;
+            let value = chars
+                .as_str()
+                .strip_prefix('=')
+                .ok_or_else(|| anyhow!("expected 'char=keycode[,shift]'"))?;
+            (c, value)
+        }
+    };
+
+    let (keycode, shift) = match value.split_once(',') {
+        Some((keycode, shift)) => (keycode, shift.trim()),
+        None => (value, "false"),
+    };
+    let keycode: u16 = keycode
+        .trim()
+        .parse()
+        .with_context(|| format!("bad keycode '{}'", keycode.trim()))?;
+    let shift = match shift {
+        "true" | "shift" => true,
+        "false" => false,
+        other => bail!("bad shift flag '{}' (expected true or false)", other),
+    };
+    Ok((c, (keycode, shift)))
+}
+
+pub fn load_keymap_file(path: &Path) -> Result<HashMap<char, (u16, bool)>> {
+    let text = std::fs::read_to_string(path)
+        .with_context(|| format!("Failed to read keymap file {}", path.display()))?;
+    parse_keymap(&text)
+}
+
+fn de_keystroke(c: char) -> Option<KeyStroke> {
+    let stroke = match c {
+        // Y and Z trade places on QWERTZ
+        'y' => KeyStroke::plain(KEY_Z),
+        'Y' => KeyStroke::shifted(KEY_Z),
+        'z' => KeyStroke::plain(KEY_Y),
+        'Z' => KeyStroke::shifted(KEY_Y),
+        'ü' => KeyStroke::plain(KEY_LEFTBRACE),
+        'Ü' => KeyStroke::shifted(KEY_LEFTBRACE),
+        'ö' => KeyStroke::plain(KEY_SEMICOLON),
+        'Ö' => KeyStroke::shifted(KEY_SEMICOLON),
+        'ä' => KeyStroke::plain(KEY_APOSTROPHE),
+        'Ä' => KeyStroke::shifted(KEY_APOSTROPHE),
+        'ß' => KeyStroke::plain(KEY_MINUS),
+        // Shifted number row
+        '!' => KeyStroke::shifted(KEY_1),
+        '"' => KeyStroke::shifted(KEY_2),
+        '§' => KeyStroke::shifted(KEY_3),
+        '$' => KeyStroke::shifted(KEY_4),
+        '%' => KeyStroke::shifted(KEY_5),
+        '&' => KeyStroke::shifted(KEY_6),
+        '/' => KeyStroke::shifted(KEY_7),
+        '(' => KeyStroke::shifted(KEY_8),
+        ')' => KeyStroke::shifted(KEY_9),
+        '=' => KeyStroke::shifted(KEY_0),
+        '?' => KeyStroke::shifted(KEY_MINUS),
+        // Punctuation keys
+        '+' => KeyStroke::plain(KEY_RIGHTBRACE),
+        '*' => KeyStroke::shifted(KEY_RIGHTBRACE),
+        '#' => KeyStroke::plain(KEY_BACKSLASH),
+        '\'' => KeyStroke::shifted(KEY_BACKSLASH),
+        '°' => KeyStroke::shifted(KEY_GRAVE),
+        '<' => KeyStroke::plain(KEY_102ND),
+        '>' => KeyStroke::shifted(KEY_102ND),
+        ',' => KeyStroke::plain(KEY_COMMA),
+        ';' => KeyStroke::shifted(KEY_COMMA),
+        '.' => KeyStroke::plain(KEY_DOT),
+        ':' => KeyStroke::shifted(KEY_DOT),
+        '-' => KeyStroke::plain(KEY_SLASH),
+        '_' => KeyStroke::shifted(KEY_SLASH),
+        // AltGr level
+        '@' => KeyStroke::altgr(KEY_Q),
+        '€' => KeyStroke::altgr(KEY_E),
+        'µ' => KeyStroke::altgr(KEY_M),
+        '²' => KeyStroke::altgr(KEY_2),
+        '³' => KeyStroke::altgr(KEY_3),
+        '{' => KeyStroke::altgr(KEY_7),
+        '[' => KeyStroke::altgr(KEY_8),
+        ']' => KeyStroke::altgr(KEY_9),
+        '}' => KeyStroke::altgr(KEY_0),
+        '\\' => KeyStroke::altgr(KEY_MINUS),
+        '~' => KeyStroke::altgr(KEY_RIGHTBRACE),
+        '|' => KeyStroke::altgr(KEY_102ND),
+        // ^ and ` are dead keys on this layout, so they are left unmapped
+        '^' | '`' => return None,
+        // Letters, digits and whitespace sit where they do on US QWERTY
+        _ if c.is_ascii_alphanumeric() || c.is_whitespace() => {
+            let (keycode, shift) = char_to_keycode(c)?;
+            KeyStroke {
+                keycode,
+                shift,
+                altgr: false,
+            }
+        }
+        _ => return None,
+    };
+    Some(stroke)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_us_layout_matches_char_to_keycode() {
+        for c in "aZ9 !@{}|~\n".chars() {
+            let (keycode, shift) = char_to_keycode(c).unwrap();
+            assert_eq!(
+                KeyboardLayout::Us.keystroke(c),
+                Some(KeyStroke {
+                    keycode,
+                    shift,
+                    altgr: false
+                })
+            );
+        }
+        assert_eq!(KeyboardLayout::Us.keystroke('€'), None);
+    }
+
+    #[test]
+    fn test_de_layout_uses_altgr_for_international_characters() {
+        let de = KeyboardLayout::De;
+        assert_eq!(de.keystroke('@'), Some(KeyStroke::altgr(KEY_Q)));
+        assert_eq!(de.keystroke('€'), Some(KeyStroke::altgr(KEY_E)));
+        assert_eq!(de.keystroke('{'), Some(KeyStroke::altgr(KEY_7)));
+        assert_eq!(de.keystroke('}'), Some(KeyStroke::altgr(KEY_0)));
+        assert_eq!(de.keystroke('z'), Some(KeyStroke::plain(KEY_Y)));
+        assert_eq!(de.keystroke('Ö'), Some(KeyStroke::shifted(KEY_SEMICOLON)));
+        assert_eq!(de.keystroke('a'), Some(KeyStroke::plain(KEY_A)));
+        assert_eq!(de.keystroke('^'), None);
+    }
+
+    #[test]
+    fn test_altgr_is_held_around_the_key() {
+        assert_eq!(
+            KeyStroke::altgr(KEY_Q).events(),
+            vec![
+                (KEY_RIGHTALT, true),
+                (KEY_Q, true),
+                (KEY_Q, false),
+                (KEY_RIGHTALT, false)
+            ]
+        );
+        assert_eq!(
+            KeyStroke::shifted(KEY_A).events(),
+            vec![
+                (KEY_LEFTSHIFT, true),
+                (KEY_A, true),
+                (KEY_A, false),
+                (KEY_LEFTSHIFT, false)
+            ]
+        );
+    }
+
+    #[test]
+    fn test_keymap_overrides_take_precedence() {
+        let overrides = parse_keymap("# remaps\n\né=3,false\na=30,true\n==13\nspace=57\n").unwrap();
+        let mut keymap = Keymap::default();
+        keymap.set_overrides(overrides.clone(), false);
+
+        assert_eq!(keymap.keystroke('é'), Some(KeyStroke::plain(KEY_2)));
+        assert_eq!(keymap.keystroke('a'), Some(KeyStroke::shifted(KEY_A)));
+        assert_eq!(keymap.keystroke('='), Some(KeyStroke::plain(KEY_EQUAL)));
+        // Characters without an override fall back to the layout
+        assert_eq!(keymap.keystroke('b'), Some(KeyStroke::plain(KEY_B)));
+
+        keymap.set_overrides(overrides, true);
+        assert_eq!(keymap.keystroke('a'), Some(KeyStroke::shifted(KEY_A)));
+        assert_eq!(keymap.keystroke('b'), None);
+    }
+
+    #[test]
+    fn test_parse_keymap_errors() {
+        assert!(parse_keymap("a").is_err());
+        assert!(parse_keymap("a=key").is_err());
+        assert!(parse_keymap("a=30,maybe").is_err());
+        assert!(parse_keymap("ab=30").is_err());
+        let overrides = parse_keymap("#=4,true").unwrap();
+        assert_eq!(overrides[&'#'], (KEY_3, true));
+    }
+
+    #[test]
+    fn test_parse_layout() {
+        assert_eq!("DE".parse::<KeyboardLayout>().unwrap(), KeyboardLayout::De);
+        assert_eq!("us".parse::<KeyboardLayout>().unwrap(), KeyboardLayout::Us);
+        assert!("dvorak".parse::<KeyboardLayout>().is_err());
+    }
+}
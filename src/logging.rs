@@ -0,0 +1,190 @@
+use anyhow::{anyhow, Context, Result};
+use std::path::Path;
+use tracing::Subscriber;
+use tracing_appender::non_blocking::{NonBlocking, WorkerGuard};
+use tracing_appender::rolling::{RollingFileAppender, Rotation};
+use tracing_subscriber::filter::LevelFilter;
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::util::SubscriberInitExt;
+use tracing_subscriber::{EnvFilter, Layer};
+
+/// Output format for the log file
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LogFormat {
+    Text,
+    Json,
+}
+
+impl std::str::FromStr for LogFormat {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "text" => Ok(LogFormat::Text),
+            "json" => Ok(LogFormat::Json),
+            other => Err(anyhow!("Unknown log format '{}'", other)),
+        }
+    }
+}
+
+/// Install the global tracing subscriber
+/// Logs always go to stderr; when `log_file` is set they are also written to a
+/// daily-rolling file next to the given path, with the date before the
+/// extension (`voice-keyboard.log` is written as `voice-keyboard.YYYY-MM-DD.log`).
+/// Level filtering follows RUST_LOG.
+/// When running as root for another user, `owner` is that user's (uid, gid):
+/// the files are handed to them, since later days' files are created after
+/// root privileges are dropped.
+/// The returned guard must be kept alive so buffered file logs get flushed.
+pub fn init(
+    log_file: Option<&Path>,
+    format: LogFormat,
+    owner: Option<(u32, u32)>,
+) -> Result<Option<WorkerGuard>> {
+    let (writer, guard) = match log_file {
+        Some(path) => {
+            let (writer, guard) = file_writer(path, owner)?;
+            (Some(writer), Some(guard))
+        }
+        None => (None, None),
+    };
+
+    build_subscriber(writer, format)
+        .try_init()
+        .map_err(|e| anyhow!("Failed to install log subscriber: {}", e))?;
+
+    Ok(guard)
+}
+
+fn file_writer(path: &Path, owner: Option<(u32, u32)>) -> Result<(NonBlocking, WorkerGuard)> {
+    let file_name = Path::new(
+        path.file_name()
+            .context("Log file path must include a file name")?,
+    );
+    let directory = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    let created = !directory.exists();
+    std::fs::create_dir_all(directory)
+        .with_context(|| format!("Failed to create log directory {}", directory.display()))?;
+
+    let utf8 = |part: &std::ffi::OsStr| {
+        part.to_str()
+            .map(str::to_string)
+            .context("Log file name must be UTF-8")
+    };
+    let prefix = utf8(file_name.file_stem().unwrap_or(file_name.as_os_str()))?;
+    let mut builder = RollingFileAppender::builder()
+        .rotation(Rotation::DAILY)
+        .filename_prefix(&prefix);
+    if let Some(extension) = file_name.extension() {
+        builder = builder.filename_suffix(utf8(extension)?);
+    }
+    let appender = builder
+        .build(directory)
+        .with_context(|| format!("Failed to open log file {}", path.display()))?;
+    if let Some(owner) = owner {
+        hand_over(directory, created, &prefix, owner)
+            .with_context(|| format!("Failed to hand over log files in {}", directory.display()))?;
+    }
+    Ok(tracing_appender::non_blocking(appender))
+}
+
+/// Give `owner` the log directory, if we just created it, and the log files
+/// root owns in it
+fn hand_over(directory: &Path, created: bool, prefix: &str, owner: (u32, u32)) -> Result<()> {
+    use std::os::unix::fs::{chown, MetadataExt};
+
+    let (uid, gid) = owner;
+    if created {
+        chown(directory, Some(uid), Some(gid))?;
+    }
+    for entry in std::fs::read_dir(directory)? {
+        let entry = entry?;
+        let ours = entry
+            .file_name()
+            .to_str()
+            .is_some_and(|name| name.starts_with(prefix));
+        if ours && entry.metadata()?.uid() == 0 {
+            chown(entry.path(), Some(uid), Some(gid))?;
+        }
+    }
+    Ok(())
+}
+
+fn build_subscriber(
+    file_writer: Option<NonBlocking>,
+    format: LogFormat,
+) -> impl Subscriber + Send + Sync {
+    let filter = EnvFilter::builder()
+        .with_default_directive(LevelFilter::INFO.into())
+        .from_env_lossy();
+
+    let file_layer = file_writer.map(|writer| match format {
+        LogFormat::Text => tracing_subscriber::fmt::layer()
+            .with_ansi(false)
+            .with_writer(writer)
+            .boxed(),
+        LogFormat::Json => tracing_subscriber::fmt::layer()
+            .json()
+            .with_writer(writer)
+            .boxed(),
+    });
+
+    tracing_subscriber::registry()
+        .with(filter)
+        .with(tracing_subscriber::fmt::layer().with_writer(std::io::stderr))
+        .with(file_layer)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_json_log_file_receives_events() {
+        let dir = std::env::temp_dir().join(format!("vk-log-test-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let path = dir.join("voice-keyboard.log");
+
+        // Handing the files to ourselves goes through the motions harmlessly
+        let owner = (
+            nix::unistd::getuid().as_raw(),
+            nix::unistd::getgid().as_raw(),
+        );
+        let (writer, guard) = file_writer(&path, Some(owner)).unwrap();
+        let subscriber = build_subscriber(Some(writer), LogFormat::Json);
+        tracing::subscriber::with_default(subscriber, || {
+            tracing::info!("hello from the log test");
+        });
+        // Dropping the guard flushes the background writer
+        drop(guard);
+
+        let files: Vec<_> = std::fs::read_dir(&dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .collect();
+        // Dated before the extension, e.g. voice-keyboard.2024-05-01.log
+        let name = files[0].file_name().unwrap().to_str().unwrap();
+        assert_eq!(files.len(), 1);
+        assert!(name.starts_with("voice-keyboard.") && name.ends_with(".log"));
+        assert_eq!(name.len(), "voice-keyboard.YYYY-MM-DD.log".len());
+        let contents = std::fs::read_to_string(&files[0]).unwrap();
+        let line = contents
+            .lines()
+            .next()
+            .expect("log file should not be empty");
+        let json: serde_json::Value = serde_json::from_str(line).unwrap();
+        assert_eq!(json["fields"]["message"], "hello from the log test");
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_log_format_parse() {
+        assert_eq!("text".parse::<LogFormat>().unwrap(), LogFormat::Text);
+        assert_eq!("json".parse::<LogFormat>().unwrap(), LogFormat::Json);
+        assert!("xml".parse::<LogFormat>().is_err());
+    }
+}
@@ -1,22 +1,154 @@
 use anyhow::{Context, Result};
-use clap::{Arg, Command};
-use nix::unistd::{getgid, getuid, setgid, setuid, Gid, Uid};
+use clap::{Arg, ArgMatches, Command};
+use http::{HeaderName, HeaderValue};
+use nix::unistd::{getegid, geteuid, getgid, getuid, setgid, setuid, Gid, Uid, User};
+use std::collections::{HashMap, HashSet};
 use std::env;
-use std::sync::mpsc;
+use std::ffi::{CStr, CString};
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::sync::{mpsc, Arc, Mutex, OnceLock};
 use std::thread;
 use std::time::Duration;
-use tracing::{debug, error, info};
+use tracing::{debug, error, info, warn};
 
+mod async_keyboard;
 mod audio_input;
+mod audio_processing;
+mod clipboard;
+mod command_backend;
+mod commands;
+mod control;
+mod diff;
+mod emoji;
+mod error;
+#[cfg(test)]
+mod fake_stt;
+mod focus;
 mod input_event;
+mod interim;
+mod layout;
+mod logging;
+mod metrics;
+mod profile;
+mod protocol_dump;
+mod retry;
 mod stt_client;
+mod transcript_socket;
+mod transforms;
 mod virtual_keyboard;
 
-use audio_input::AudioInput;
-use stt_client::{AudioBuffer, SttClient};
-use virtual_keyboard::{RealKeyboardHardware, VirtualKeyboard};
+use async_keyboard::{AsyncKeyboardHardware, AsyncVirtualKeyboard, SyncHardware};
+use audio_input::{AudioInput, RememberedDevice, REMEMBERED_DEVICE_FILE};
+use audio_processing::{
+    AudioFlow, AudioWatchdog, AutoGain, ChannelWeights, EndOfSpeech, Endpointer, Gain, GainMode,
+    NoiseGate, DEFAULT_ENDPOINT_SILENCE, DEFAULT_PREBUFFER, DEFAULT_SPEECH_LEVEL,
+    DEFAULT_WATCHDOG_TIMEOUT,
+};
+use clipboard::CommandClipboard;
+use command_backend::{Backend, CommandKeyboardHardware};
+use error::VkError;
+use focus::{AppProfiles, FocusProfiles, WindowClass, APP_PROFILES_FILE};
+use interim::{InterimFilter, InterimMode, TurnLimit};
+use layout::KeyboardLayout;
+use protocol_dump::ProtocolDump;
+use retry::RetryPolicy;
+use stt_client::{AudioBuffer, SttClient, SttConfig, DEFAULT_CHUNK_MS};
+use transcript_socket::TranscriptSocket;
+use virtual_keyboard::{
+    BackspacePacing, KeyRepeat, KeyboardHardware, MockKeyboardHardware, RealKeyboardHardware,
+    SimulatedKeyboardHardware, VirtualKeyboard, DEFAULT_DATE_FORMAT, DEFAULT_TIME_FORMAT,
+};
 use std::time::Instant;
 
+/// Capture-side settings shared by the STT modes
+#[derive(Debug, Clone, Default)]
+struct AudioOptions {
+    gain: GainMode,
+    /// Input device names in order of preference; the default device when empty
+    devices: Vec<String>,
+    /// Where `--remember-device` keeps the last device used; off when `None`
+    remembered_device: Option<PathBuf>,
+    /// Audio per STT message; `DEFAULT_CHUNK_MS` when `None`
+    chunk_ms: Option<u32>,
+    /// Capture sample format; the device's default when `None`
+    capture_format: Option<cpal::SampleFormat>,
+    /// Requested capture channel count; the device's default when `None`
+    channels: Option<u16>,
+    /// Requested capture sample rate; the device's default when `None`
+    sample_rate: Option<u32>,
+    /// How channels are mixed to mono; a plain average when `None`
+    channel_weights: Option<ChannelWeights>,
+    /// RMS level below which silence is sent instead of the microphone
+    noise_gate: Option<f32>,
+    /// Audio kept from before the gate opens; `DEFAULT_PREBUFFER` when `None`
+    prebuffer: Option<Duration>,
+    /// Warn when no audio has arrived for this long; off when `None`
+    watchdog_timeout: Option<Duration>,
+    /// Rebuild the capture stream when the watchdog fires
+    restart_stalled_audio: bool,
+    /// Log a rollup of chunks and bytes sent every `AUDIO_FLOW_INTERVAL`
+    verbose_audio: bool,
+    /// End the turn locally after this much silence following speech
+    local_endpointing: Option<Duration>,
+    /// Fired by the audio callback when local endpointing hears speech end
+    end_of_speech: EndOfSpeech,
+}
+
+/// Settings for a typing session on top of the STT stream
+#[derive(Debug, Clone, Default)]
+struct SessionOptions {
+    interim_mode: InterimMode,
+    /// Wait before streaming so the user can focus the target window
+    start_delay: Duration,
+    /// Stop after the first finalized turn
+    once: bool,
+    /// Finalize locally after this long without a transcript
+    finalize_after: Option<Duration>,
+    /// Continue the line if a new turn starts this soon after the last one
+    turn_merge: Option<Duration>,
+    /// Updates in a row an interim transcript's last word must hold before it's typed
+    stabilize_tail: Option<usize>,
+    /// Where every result is also published for other programs
+    socket: Option<TranscriptSocket>,
+    /// Typing options to switch to as the focused window changes
+    app_profiles: Option<AppProfileSet>,
+}
+
+/// The profiles `--app-profiles` switches between, resolved up front so a
+/// bad one fails at startup
+#[derive(Debug, Clone)]
+struct AppProfileSet {
+    apps: AppProfiles,
+    /// The `--profile` given at startup, for windows no rule names
+    fallback: Option<String>,
+    /// The command line with each profile's options in front of it
+    matches: HashMap<Option<String>, ArgMatches>,
+    /// The options each profile sets
+    options: HashMap<Option<String>, HashSet<String>>,
+}
+
+impl AppProfileSet {
+    /// The command line to configure from when switching profiles, and the
+    /// options to apply from it: those either profile sets, so the old
+    /// profile's are undone and anything else (e.g. an input mode chosen by
+    /// voice) is left alone
+    fn switch(
+        &self,
+        from: &Option<String>,
+        to: &Option<String>,
+    ) -> Result<(&ArgMatches, HashSet<String>)> {
+        let resolved = |name| {
+            let matches = self.matches.get(name)?;
+            Some((matches, self.options.get(name)?))
+        };
+        let ((_, from_options), (matches, to_options)) = resolved(from)
+            .zip(resolved(to))
+            .context("Profile wasn't resolved at startup")?;
+        Ok((matches, from_options.union(to_options).cloned().collect()))
+    }
+}
+
 #[derive(Debug)]
 struct OriginalUser {
     uid: Uid,
@@ -51,6 +183,19 @@ impl OriginalUser {
         }
     }
 
+    /// `voice-keyboard` under `$XDG_CONFIG_HOME` or `~/.config`, for the
+    /// invoking user (under sudo, HOME is usually root's)
+    fn config_dir(&self) -> Option<PathBuf> {
+        if let Some(dir) = env::var_os("XDG_CONFIG_HOME").filter(|d| !d.is_empty()) {
+            return Some(PathBuf::from(dir).join("voice-keyboard"));
+        }
+        let sudo_home = env::var_os("SUDO_UID")
+            .and_then(|_| User::from_uid(self.uid).ok().flatten())
+            .map(|user| user.dir);
+        let home = sudo_home.or_else(|| self.home.as_ref().map(PathBuf::from))?;
+        Some(home.join(".config").join("voice-keyboard"))
+    }
+
     fn drop_privileges(&self) -> Result<()> {
         if getuid().is_root() {
             debug!(
@@ -64,9 +209,30 @@ impl OriginalUser {
             let display = env::var("DISPLAY").ok();
             let wayland_display = env::var("WAYLAND_DISPLAY").ok();
 
-            // Drop group first, then user (required order)
+            // Swap root's supplementary groups for the user's (e.g. input and
+            // audio), then group, then user (required order)
+            let name = User::from_uid(self.uid)
+                .ok()
+                .flatten()
+                .map(|user| user.name);
+            match name.map(CString::new) {
+                Some(Ok(name)) => init_groups(&name, self.gid)
+                    .context("Failed to set the user's supplementary groups")?,
+                _ => {
+                    warn!(
+                        "Can't look up user {}; dropping all supplementary groups",
+                        self.uid
+                    );
+                    set_only_group(self.gid).context("Failed to drop supplementary groups")?;
+                }
+            }
             setgid(self.gid).context("Failed to drop group privileges")?;
             setuid(self.uid).context("Failed to drop user privileges")?;
+            verify_dropped(
+                (self.uid, self.gid),
+                (getuid(), geteuid()),
+                (getgid(), getegid()),
+            )?;
 
             // Restore environment variables for the original user
             if let Some(ref home) = self.home {
@@ -102,260 +268,2463 @@ impl OriginalUser {
     }
 }
 
-#[tokio::main]
-async fn main() -> Result<()> {
-    tracing_subscriber::fmt::init();
-
-    info!("Starting Voice Keyboard v{}", env!("CARGO_PKG_VERSION"));
-
-    // Capture original user info before we do anything
-    let original_user = OriginalUser::capture();
-
-    let matches = Command::new("voice-keyboard")
+/// The command-line interface
+fn cli() -> Command {
+    let cmd = Command::new("voice-keyboard")
         .version(env!("CARGO_PKG_VERSION"))
         .about("Voice-controlled keyboard input")
+        // Lets explicit flags override the ones a profile put in front of them
+        .args_override_self(true)
+        .arg(
+            Arg::new("profile")
+                .long("profile")
+                .help("Apply a named bundle of options: chat, editor, terminal, or one from the profiles file in the config directory; explicit flags still win")
+                .value_name("NAME"),
+        )
+        .arg(
+            Arg::new("app-profiles")
+                .long("app-profiles")
+                .help("Switch typing options to the profile the app-profiles file in the config directory gives the focused window's class (X11, built with --features x11)")
+                .action(clap::ArgAction::SetTrue),
+        )
         .arg(
             Arg::new("test-audio")
                 .long("test-audio")
                 .help("Test audio input")
                 .action(clap::ArgAction::SetTrue),
         )
+        .arg(
+            Arg::new("self-test")
+                .long("self-test")
+                .help("Type a test string and press Enter, to check the virtual keyboard without audio or STT")
+                .action(clap::ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("benchmark")
+                .long("benchmark")
+                .help("Type a long sample string, report characters/s and device writes, and exit, e.g. to pick a --char-delay-ms (with --simulate, into a silent mock)")
+                .action(clap::ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("list-devices")
+                .long("list-devices")
+                .help("Print the available input devices and exit (text or json)")
+                .value_name("FORMAT")
+                .num_args(0..=1)
+                .value_parser(["text", "json"])
+                .default_missing_value("text"),
+        )
+        .arg(
+            Arg::new("device")
+                .long("device")
+                .help("Input device name to record from (see --list-devices); repeat to list fallbacks in order of preference")
+                .value_name("NAME")
+                .action(clap::ArgAction::Append),
+        )
+        .arg(
+            Arg::new("remember-device")
+                .long("remember-device")
+                .help("Reuse the input device and capture config of the last run unless --device is given")
+                .action(clap::ArgAction::SetTrue),
+        )
         .arg(
             Arg::new("test-stt")
                 .long("test-stt")
                 .help("Test speech-to-text functionality")
                 .action(clap::ArgAction::SetTrue),
         )
+        .arg(
+            Arg::new("simulate")
+                .long("simulate")
+                .help("Print what would be typed instead of typing it (no virtual keyboard)")
+                .action(clap::ArgAction::SetTrue),
+        )
         .arg(
             Arg::new("debug-stt")
                 .long("debug-stt")
                 .help("Debug speech-to-text (print transcripts without typing)")
                 .action(clap::ArgAction::SetTrue),
         )
+        .arg(
+            Arg::new("socket")
+                .long("socket")
+                .help("Stream every transcription result as a JSON line to clients of a Unix socket at this path")
+                .value_name("PATH"),
+        )
+        .arg(
+            Arg::new("no-type")
+                .long("no-type")
+                .help("Only stream results to the --socket clients; don't type anything (no virtual keyboard)")
+                .action(clap::ArgAction::SetTrue)
+                .requires("socket"),
+        )
         .arg(
             Arg::new("stt-url")
                 .long("stt-url")
                 .help("Custom STT service URL")
                 .value_name("URL"),
         )
+        .arg(
+            Arg::new("auth-scheme")
+                .long("auth-scheme")
+                .help("Authorization scheme for DEEPGRAM_API_KEY: token, bearer, basic, or a custom scheme name")
+                .value_name("SCHEME"),
+        )
+        .arg(
+            Arg::new("header")
+                .long("header")
+                .help("Extra header for the STT handshake, e.g. 'X-Api-Version: 2' (repeatable)")
+                .value_name("NAME:VALUE")
+                .value_parser(stt_client::parse_header)
+                .action(clap::ArgAction::Append),
+        )
+        .arg(
+            Arg::new("compare-url")
+                .long("compare-url")
+                .help("Also stream the audio to this STT URL and log its transcripts, for comparing providers (repeatable)")
+                .value_name("URL")
+                .action(clap::ArgAction::Append),
+        )
+        .arg(
+            Arg::new("dump-protocol")
+                .long("dump-protocol")
+                .help("Append every WebSocket frame exchanged with the STT service to this file, whatever the log level")
+                .value_name("PATH"),
+        )
+        .arg(
+            Arg::new("subprotocol")
+                .long("subprotocol")
+                .help("WebSocket subprotocol to request from the STT service")
+                .value_name("PROTOCOL"),
+        )
+        .arg(
+            Arg::new("quality")
+                .long("quality")
+                .help("STT preset: fast (ends turns soonest), balanced (service defaults) or accurate (waits for surer endpoints)")
+                .value_name("PRESET")
+                .value_parser(["fast", "balanced", "accurate"]),
+        )
+        .arg(
+            Arg::new("stt-model")
+                .long("stt-model")
+                .help("STT model to request (default: flux-general-en; overrides --quality)")
+                .value_name("MODEL"),
+        )
+        .arg(
+            Arg::new("eot-threshold")
+                .long("eot-threshold")
+                .help("End-of-turn confidence the service needs to end a turn, 0-1 (overrides --quality)")
+                .value_name("CONFIDENCE")
+                .value_parser(stt_client::parse_confidence),
+        )
+        .arg(
+            Arg::new("eot-timeout-ms")
+                .long("eot-timeout-ms")
+                .help("Silence after which the service ends a turn regardless (overrides --quality)")
+                .value_name("MS")
+                .value_parser(clap::value_parser!(u32).range(500..=10000)),
+        )
+        .arg(
+            Arg::new("min-eot-confidence")
+                .long("min-eot-confidence")
+                .help("Only finalize a line on end-of-turn events at least this confident (0-1); others count as updates")
+                .value_name("CONFIDENCE")
+                .value_parser(stt_client::parse_confidence),
+        )
+        .arg(
+            Arg::new("connect-retries")
+                .long("connect-retries")
+                .help("How many times to retry the initial STT connection")
+                .value_name("N")
+                .value_parser(clap::value_parser!(u32))
+                .default_value("3"),
+        )
+        .arg(
+            Arg::new("connect-backoff-ms")
+                .long("connect-backoff-ms")
+                .help("Delay before the first connection retry; doubles after each attempt")
+                .value_name("MS")
+                .value_parser(clap::value_parser!(u64))
+                .default_value("1000"),
+        )
+        .arg(
+            Arg::new("connect-timeout-secs")
+                .long("connect-timeout-secs")
+                .help("Give up on a connection attempt (including the WebSocket handshake) after this long (default: 10)")
+                .value_name("SECS")
+                .value_parser(clap::value_parser!(u64).range(1..=300)),
+        )
+        .arg(
+            Arg::new("max-reconnects")
+                .long("max-reconnects")
+                .help("Reconnect when the STT service drops the session; exit with an error once N reconnects in a row have failed")
+                .value_name("N")
+                .value_parser(clap::value_parser!(u32)),
+        )
+        .arg(
+            Arg::new("backend")
+                .long("backend")
+                .help("How keystrokes are sent: uinput (default), or ydotool/wtype for Wayland sessions")
+                .value_name("BACKEND")
+                .value_parser(["uinput", "ydotool", "wtype"])
+                .default_value("uinput"),
+        )
+        .arg(
+            Arg::new("uinput-retries")
+                .long("uinput-retries")
+                .help("Retries when creating the virtual keyboard fails transiently (e.g. right after boot)")
+                .value_name("N")
+                .value_parser(clap::value_parser!(u32))
+                .default_value("3"),
+        )
+        .arg(
+            Arg::new("char-delay-ms")
+                .long("char-delay-ms")
+                .help("Pause after each character typed through uinput, for apps that drop fast input")
+                .value_name("MS")
+                .value_parser(clap::value_parser!(u64).range(0..=1_000))
+                .default_value("10"),
+        )
+        .arg(
+            Arg::new("repeat-delay-ms")
+                .long("repeat-delay-ms")
+                .help("How long a held key waits before auto-repeating (default: 250)")
+                .value_name("MS")
+                .value_parser(clap::value_parser!(u64).range(1..=10_000)),
+        )
+        .arg(
+            Arg::new("repeat-period-ms")
+                .long("repeat-period-ms")
+                .help("Time between auto-repeats of a held key (default: 33)")
+                .value_name("MS")
+                .value_parser(clap::value_parser!(u64).range(1..=10_000)),
+        )
+        .arg(
+            Arg::new("layout")
+                .long("layout")
+                .help("Keyboard layout of the desktop session, used to map characters to keys")
+                .value_parser(["us", "de"])
+                .default_value("us"),
+        )
+        .arg(
+            Arg::new("keymap-file")
+                .long("keymap-file")
+                .help("Per-character key overrides, one 'char=keycode[,shift]' per line")
+                .value_name("PATH"),
+        )
+        .arg(
+            Arg::new("keymap-replace")
+                .long("keymap-replace")
+                .help("Use only the keymap file, not the --layout defaults")
+                .requires("keymap-file")
+                .action(clap::ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("hold-shift")
+                .long("hold-shift")
+                .help("Keep shift down across consecutive shifted characters instead of per key")
+                .action(clap::ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("gain")
+                .long("gain")
+                .help("Amplify microphone samples by this factor (clamped to avoid clipping)")
+                .value_name("FACTOR")
+                .value_parser(clap::value_parser!(f32))
+                .conflicts_with("auto-gain"),
+        )
+        .arg(
+            Arg::new("auto-gain")
+                .long("auto-gain")
+                .help("Automatically adjust gain toward this target RMS level (e.g. 0.1)")
+                .value_name("TARGET_RMS")
+                .value_parser(clap::value_parser!(f32)),
+        )
+        .arg(
+            Arg::new("noise-gate")
+                .long("noise-gate")
+                .help("Send silence while the microphone level is below this RMS (e.g. 0.01)")
+                .value_name("LEVEL")
+                .value_parser(clap::value_parser!(f32)),
+        )
+        .arg(
+            Arg::new("prebuffer-ms")
+                .long("prebuffer-ms")
+                .help("Audio from just before the noise gate opens to send with the speech, so the first word isn't clipped (default: 300)")
+                .value_name("MS")
+                .value_parser(clap::value_parser!(u64).range(..=5000))
+                .requires("noise-gate"),
+        )
+        .arg(
+            Arg::new("audio-watchdog-secs")
+                .long("audio-watchdog-secs")
+                .help("Warn when the microphone delivers no audio for this long; 0 turns the check off (default: 3)")
+                .value_name("SECS")
+                .value_parser(clap::value_parser!(u64).range(..=600)),
+        )
+        .arg(
+            Arg::new("restart-stalled-audio")
+                .long("restart-stalled-audio")
+                .help("Restart the audio stream when the watchdog sees it has stalled")
+                .action(clap::ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("local-endpointing")
+                .long("local-endpointing")
+                .help("End a turn ourselves when the microphone goes quiet after speech, for servers that never send end-of-turn (the --noise-gate level counts as speech if given)")
+                .action(clap::ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("endpoint-silence-ms")
+                .long("endpoint-silence-ms")
+                .help("Silence after speech that ends the turn with --local-endpointing (default: 800)")
+                .value_name("MS")
+                .value_parser(clap::value_parser!(u64).range(100..=10_000))
+                .requires("local-endpointing"),
+        )
+        .arg(
+            Arg::new("verbose-audio")
+                .long("verbose-audio")
+                .help("Log chunks and bytes sent per second, average chunk size and buffer fill once a second")
+                .action(clap::ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("capture-format")
+                .long("capture-format")
+                .help("Capture in this sample format instead of the device default (works around driver quirks)")
+                .value_name("FORMAT")
+                .value_parser(audio_input::parse_capture_format),
+        )
+        .arg(
+            Arg::new("channels")
+                .long("channels")
+                .help("Capture this many channels when the device supports it (e.g. 1 to skip the downmix)")
+                .value_name("N")
+                .value_parser(clap::value_parser!(u16).range(1..=32)),
+        )
+        .arg(
+            Arg::new("channel-weights")
+                .long("channel-weights")
+                .help("Mix channels to mono with these weights instead of averaging (e.g. 1,0 for just the left channel)")
+                .value_name("W1,W2,...")
+                .conflicts_with("channel"),
+        )
+        .arg(
+            Arg::new("channel")
+                .long("channel")
+                .help("Use only this input channel (counting from 0), e.g. the real mic on a multichannel interface")
+                .value_name("N")
+                .value_parser(clap::value_parser!(u16).range(0..32)),
+        )
+        .arg(
+            Arg::new("sample-rate")
+                .long("sample-rate")
+                .help("Capture at this rate in Hz when the device supports it (e.g. 16000)")
+                .value_name("HZ")
+                .value_parser(clap::value_parser!(u32).range(8000..=192000)),
+        )
+        .arg(
+            Arg::new("chunk-ms")
+                .long("chunk-ms")
+                .help("Milliseconds of audio per STT message; smaller lowers latency (default: 160)")
+                .value_name("MS")
+                .value_parser(clap::value_parser!(u32).range(10..=1000)),
+        )
+        .arg(
+            Arg::new("encoding")
+                .long("encoding")
+                .help("Audio encoding sent to the STT service (mulaw/alaw for telephony endpoints)")
+                .value_parser(["linear16", "mulaw", "alaw"])
+                .default_value("linear16"),
+        )
+        .arg(
+            Arg::new("start-delay-secs")
+                .long("start-delay-secs")
+                .help("Wait this long before listening, to focus the target window")
+                .value_name("SECS")
+                .value_parser(clap::value_parser!(u64)),
+        )
+        .arg(
+            Arg::new("stats-interval")
+                .long("stats-interval")
+                .help("Log runtime stats every SECS seconds (they are always logged on SIGUSR2)")
+                .value_name("SECS")
+                .value_parser(clap::value_parser!(u64).range(1..)),
+        )
+        .arg(
+            Arg::new("control-port")
+                .long("control-port")
+                .help("Serve GET /status and POST /mute, /unmute on this localhost port")
+                .value_name("PORT")
+                .value_parser(clap::value_parser!(u16).range(1..)),
+        )
+        .arg(
+            Arg::new("once")
+                .long("once")
+                .help("Exit after the first finalized turn (for scripts and hotkeys); Ctrl+C ends the turn now, a second Ctrl+C quits")
+                .action(clap::ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("interim-mode")
+                .long("interim-mode")
+                .help("When interim transcripts are typed: live, debounced, final-only, or commit (only each turn's final text, typed once without command words)")
+                .value_parser(["live", "debounced", "final-only", "commit"])
+                .default_value("live"),
+        )
+        .arg(
+            Arg::new("interim-debounce-ms")
+                .long("interim-debounce-ms")
+                .help("Minimum spacing between typed updates in debounced mode")
+                .value_name("MS")
+                .value_parser(clap::value_parser!(u64))
+                .default_value("300"),
+        )
+        .arg(
+            Arg::new("finalize-after-ms")
+                .long("finalize-after-ms")
+                .help("Finalize the line locally after this long without a new transcript, if the server is slow to end the turn")
+                .value_name("MS")
+                .value_parser(clap::value_parser!(u64).range(1..)),
+        )
+        .arg(
+            Arg::new("turn-merge-ms")
+                .long("turn-merge-ms")
+                .help("Continue the same line if a new turn starts within this long after the last one ended, instead of finalizing it")
+                .value_name("MS")
+                .value_parser(clap::value_parser!(u64).range(1..)),
+        )
+        .arg(
+            Arg::new("stabilize-tail")
+                .long("stabilize-tail")
+                .help("Type the last word of an interim transcript only once it has come out the same in K updates in a row (or the turn ends), so a word the model keeps revising isn't retyped")
+                .value_name("K")
+                .value_parser(clap::value_parser!(u64).range(1..=20)),
+        )
         .arg(
             Arg::new("voice-enter")
                 .long("voice-enter")
                 .help("Interpret the word 'enter' at end-of-turn as an Enter key press")
                 .action(clap::ArgAction::SetTrue),
         )
+        .arg(
+            Arg::new("voice-enter-verbs")
+                .long("voice-enter-verbs")
+                .help("Also strip 'press', 'hit' or 'send' directly before a spoken 'enter'")
+                .action(clap::ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("voice-commands")
+                .long("voice-commands")
+                .help("Recognize editing and media commands at end-of-turn ('tab', 'volume up', ...)")
+                .action(clap::ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("input-mode")
+                .long("input-mode")
+                .help("Start in blended, dictation (command words are typed) or command mode; say 'command mode' / 'dictation mode' to switch")
+                .value_name("MODE")
+                .value_parser(["blended", "dictation", "command"])
+                .default_value("blended"),
+        )
+        .arg(
+            Arg::new("tab-as-spaces")
+                .long("tab-as-spaces")
+                .help("Have the 'tab' command type this many spaces instead of pressing Tab (0 = the Tab key)")
+                .value_name("N")
+                .value_parser(clap::value_parser!(u8).range(..=16)),
+        )
+        .arg(
+            Arg::new("fuzzy-commands")
+                .long("fuzzy-commands")
+                .help("Let command words through with up to this many wrong letters (1 or 2), e.g. 'entr' for 'enter', along with other forms of them ('entered') and known mishears ('inner'); words under 5 letters still need an exact match")
+                .value_name("EDITS")
+                .value_parser(clap::value_parser!(u8).range(1..=2)),
+        )
+        .arg(
+            Arg::new("auto-enter")
+                .long("auto-enter")
+                .help("Press Enter after every finalized turn but command turns (a spoken 'enter' is still stripped)")
+                .action(clap::ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("continuous-line")
+                .long("continuous-line")
+                .help("Keep finalized text on the line so the next turn continues it (until Enter)")
+                .action(clap::ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("smart-punctuation")
+                .long("smart-punctuation")
+                .help("End finalized lines with a period when they lack terminal punctuation")
+                .action(clap::ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("number-mode")
+                .long("number-mode")
+                .help("Type spoken number words as digits ('five five five' -> '555')")
+                .action(clap::ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("spoken-punctuation")
+                .long("spoken-punctuation")
+                .help("Type spoken 'comma', 'period', 'question mark', ... as marks, spaced like prose")
+                .action(clap::ArgAction::SetTrue),
+        )
         .arg(
             Arg::new("uppercase")
                 .long("uppercase")
                 .help("Convert all typed text to uppercase")
                 .action(clap::ArgAction::SetTrue),
         )
-        .get_matches();
+        .arg(
+            Arg::new("force-lowercase")
+                .long("force-lowercase")
+                .help("Type everything in lowercase, whatever the model's casing (e.g. for code or search boxes)")
+                .action(clap::ArgAction::SetTrue)
+                .conflicts_with("uppercase"),
+        )
+        .arg(
+            Arg::new("substitutions")
+                .long("substitutions")
+                .help("Fix misheard vocabulary: replace whole phrases from this file (one 'phrase -> replacement' per line, any case)")
+                .value_name("PATH"),
+        )
+        .arg(
+            Arg::new("blocklist")
+                .long("blocklist")
+                .help("Censor words listed in this file (one word or phrase per line) before typing")
+                .value_name("PATH"),
+        )
+        .arg(
+            Arg::new("blocklist-mode")
+                .long("blocklist-mode")
+                .help("Replace blocklisted words with *** (mask) or remove them (drop)")
+                .value_parser(["mask", "drop"])
+                .default_value("mask")
+                .requires("blocklist"),
+        )
+        .arg(
+            Arg::new("max-backspace")
+                .long("max-backspace")
+                .help("Maximum characters a single transcript correction may backspace")
+                .value_name("N")
+                .value_parser(clap::value_parser!(usize)),
+        )
+        .arg(
+            Arg::new("emoji")
+                .long("emoji")
+                .help("Insert emoji for phrases like 'heart emoji' (entered with Ctrl+Shift+U)")
+                .action(clap::ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("emoji-file")
+                .long("emoji-file")
+                .help("Extra or replacement emoji names, one 'name=emoji' or 'name=1F389' per line")
+                .value_name("PATH")
+                .requires("emoji"),
+        )
+        .arg(
+            Arg::new("emoji-as-names")
+                .long("emoji-as-names")
+                .help("Type the emoji's name instead, for desktops without Ctrl+Shift+U input")
+                .action(clap::ArgAction::SetTrue)
+                .requires("emoji"),
+        )
+        .arg(
+            Arg::new("diff-strategy")
+                .long("diff-strategy")
+                .help("Retype corrections from the first changed character (char), from the start of that word (word), or edit in place with arrow keys when shorter (minimal)")
+                .value_name("STRATEGY")
+                .value_parser(["char", "word", "minimal"])
+                .default_value("char"),
+        )
+        .arg(
+            Arg::new("backspace-batch")
+                .long("backspace-batch")
+                .help("Backspaces sent back to back before each pause (default: 1)")
+                .value_name("N")
+                .value_parser(clap::value_parser!(u16).range(1..)),
+        )
+        .arg(
+            Arg::new("backspace-pause-ms")
+                .long("backspace-pause-ms")
+                .help("Pause after each batch of backspaces (default: 0)")
+                .value_name("MS")
+                .value_parser(clap::value_parser!(u64).range(..=1000)),
+        )
+        .arg(
+            Arg::new("max-backspace-rate")
+                .long("max-backspace-rate")
+                .help("Send at most this many backspaces per second, for remote desktops and web editors that drop fast keys")
+                .value_name("N")
+                .value_parser(clap::value_parser!(u32).range(1..)),
+        )
+        .arg(
+            Arg::new("enter-key")
+                .long("enter-key")
+                .help("Key combination sent for 'enter': enter, shift-enter (newline in chat apps) or ctrl-enter (submit in editors)")
+                .value_name("KEY")
+                .value_parser(["enter", "shift-enter", "ctrl-enter"])
+                .default_value("enter"),
+        )
+        .arg(
+            Arg::new("turn-separator")
+                .long("turn-separator")
+                .help("What to type between turns that don't end with Enter: none, space or newline")
+                .value_name("SEP")
+                .value_parser(["none", "space", "newline"])
+                .default_value("none"),
+        )
+        .arg(
+            Arg::new("trim-leading-space")
+                .long("trim-leading-space")
+                .help("Drop a leading space from transcripts typed at the start of a line")
+                .action(clap::ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("line-prefix")
+                .long("line-prefix")
+                .help("Text typed before each finalized line, e.g. \"- \" for a bullet list")
+                .value_name("TEXT"),
+        )
+        .arg(
+            Arg::new("line-suffix")
+                .long("line-suffix")
+                .help("Text typed after each finalized line, before any Enter")
+                .value_name("TEXT"),
+        )
+        .arg(
+            Arg::new("date-format")
+                .long("date-format")
+                .help("strftime format typed by the 'insert date' voice command (default: %Y-%m-%d)")
+                .value_name("FORMAT")
+                .value_parser(virtual_keyboard::parse_time_format),
+        )
+        .arg(
+            Arg::new("time-format")
+                .long("time-format")
+                .help("strftime format typed by the 'insert time' voice command (default: %H:%M)")
+                .value_name("FORMAT")
+                .value_parser(virtual_keyboard::parse_time_format),
+        )
+        .arg(
+            Arg::new("clipboard-mirror")
+                .long("clipboard-mirror")
+                .help("Also copy each finalized line to the clipboard (wl-copy on Wayland, xclip otherwise)")
+                .action(clap::ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("clipboard-command")
+                .long("clipboard-command")
+                .help("Command that reads the text to copy on stdin, e.g. 'xsel -ib' (implies --clipboard-mirror)")
+                .value_name("CMD"),
+        )
+        .arg(
+            Arg::new("log-file")
+                .long("log-file")
+                .help("Also write logs to a daily-rolling file at this path")
+                .value_name("PATH"),
+        )
+        .arg(
+            Arg::new("log-format")
+                .long("log-format")
+                .help("Format of the log file")
+                .value_parser(["text", "json"])
+                .default_value("text"),
+        );
+    with_negations(cmd)
+}
 
-    let device_name = "Voice Keyboard";
+/// Add a hidden `--no-<switch>` for each switch, so one a profile turns on
+/// can be turned off again; of a switch and its negation, the last given wins
+fn with_negations(cmd: Command) -> Command {
+    // Arg ids must be 'static, so the names are made once per run
+    static NEGATIONS: OnceLock<Vec<(&'static str, &'static str)>> = OnceLock::new();
+    let negations = NEGATIONS.get_or_init(|| {
+        let leak = |name: String| -> &'static str { Box::leak(name.into_boxed_str()) };
+        cmd.get_arguments()
+            .filter(|arg| matches!(arg.get_action(), clap::ArgAction::SetTrue))
+            .map(|arg| arg.get_id().as_str())
+            .filter(|id| !id.starts_with("no-"))
+            .map(|id| (leak(id.to_string()), leak(format!("no-{id}"))))
+            .collect()
+    });
+    negations.iter().fold(cmd, |cmd, &(switch, negation)| {
+        cmd.mut_arg(switch, |arg| arg.overrides_with(negation)).arg(
+            Arg::new(negation)
+                .long(negation)
+                .hide(true)
+                .action(clap::ArgAction::SetTrue)
+                .overrides_with(switch),
+        )
+    })
+}
 
-    // Step 1: Create virtual keyboard while we have root privileges
-    debug!("Creating virtual keyboard device (requires root privileges)...");
-    let hardware =
-        RealKeyboardHardware::new(device_name).context("Failed to create keyboard hardware")?;
-    let mut keyboard = VirtualKeyboard::new(hardware);
-    let voice_enter_enabled = matches.get_flag("voice-enter");
-    let uppercase_enabled = matches.get_flag("uppercase");
-    keyboard.set_voice_enter_enabled(voice_enter_enabled);
-    keyboard.set_uppercase_enabled(uppercase_enabled);
-    debug!("Virtual keyboard created successfully");
+#[tokio::main]
+async fn main() -> Result<()> {
+    // Capture original user info before we do anything
+    let original_user = OriginalUser::capture();
 
-    // Step 2: Drop root privileges before initializing audio
-    original_user
-        .drop_privileges()
-        .context("Failed to drop root privileges")?;
+    // Pick up DEEPGRAM_API_KEY and friends from the config directory's .env;
+    // variables already in the environment win
+    let env_file = original_user.config_dir().map(|dir| dir.join(".env"));
+    let env_file_loaded = match &env_file {
+        Some(path) => load_env_file(path)?,
+        None => false,
+    };
 
-    if matches.get_flag("test-audio") {
-        test_audio().await?;
-    } else if matches.get_flag("test-stt") {
-        let stt_url = matches
-            .get_one::<String>("stt-url")
-            .map(|s| s.as_str())
-            .unwrap_or(stt_client::STT_URL);
-        test_stt(keyboard, stt_url).await?;
+    let mut matches = cli().get_matches();
+    // A profile's options go in front of the user's own, which then win
+    let profile_name = matches.get_one::<String>("profile").cloned();
+    if let Some(name) = &profile_name {
+        let profiles_file = original_user.config_dir().map(|dir| dir.join("profiles"));
+        let profile = profile::resolve(name, profiles_file.as_deref())?;
+        profile
+            .validate(&cli())
+            .with_context(|| format!("Invalid profile '{name}'"))?;
+        matches = cli().get_matches_from(profile::expand_args(env::args_os(), &profile));
+    }
+
+    // Set up logging before anything else logs
+    let log_format: logging::LogFormat = matches
+        .get_one::<String>("log-format")
+        .map(|s| s.as_str())
+        .unwrap_or("text")
+        .parse()?;
+    // Under sudo the log files belong to the invoking user, who writes
+    // them once root privileges are dropped
+    let log_owner = (getuid().is_root() && !original_user.uid.is_root())
+        .then(|| (original_user.uid.as_raw(), original_user.gid.as_raw()));
+    let _log_guard = logging::init(
+        matches.get_one::<String>("log-file").map(Path::new),
+        log_format,
+        log_owner,
+    )?;
+
+    info!("Starting Voice Keyboard v{}", env!("CARGO_PKG_VERSION"));
+    if let (Some(path), true) = (&env_file, env_file_loaded) {
+        info!("Loaded environment from {}", path.display());
+    }
+    if let Some(name) = &profile_name {
+        info!("Using profile '{}'", name);
+    }
+    let app_profiles = if matches.get_flag("app-profiles") {
+        let config_dir = original_user
+            .config_dir()
+            .context("--app-profiles needs a config directory")?;
+        Some(load_app_profiles(&config_dir, profile_name.clone())?)
     } else {
-        let debug_mode = matches.get_flag("debug-stt");
-        let stt_url = matches
-            .get_one::<String>("stt-url")
-            .map(|s| s.as_str())
-            .unwrap_or(stt_client::STT_URL);
+        None
+    };
 
-        if debug_mode {
-            debug_stt(stt_url).await?;
+    if let Some(format) = matches.get_one::<String>("list-devices") {
+        // List as the invoking user (that's whose audio session we'd record from),
+        // and without creating the virtual keyboard
+        original_user
+            .drop_privileges()
+            .context("Failed to drop root privileges")?;
+        let listing = AudioInput::describe_devices()?;
+        if format == "json" {
+            println!("{}", serde_json::to_string_pretty(&listing)?);
         } else {
-            test_stt(keyboard, stt_url).await?;
+            print!("{}", listing.to_text());
         }
+        return Ok(());
     }
 
-    Ok(())
-}
+    let device_name = "Voice Keyboard";
+    let simulate = matches.get_flag("simulate");
+    let no_type = matches.get_flag("no-type");
+    let backend: Backend = matches
+        .get_one::<String>("backend")
+        .map(|s| s.as_str())
+        .unwrap_or("uinput")
+        .parse()?;
 
-async fn test_audio() -> Result<()> {
-    info!("Testing audio input...");
+    // Step 1: Create virtual keyboard while we have root privileges
+    // (helper programs type asynchronously and need no device)
+    let hardware: Option<Box<dyn KeyboardHardware + Send>> = if simulate {
+        // Run the typing logic against a mock that prints each action
+        Some(Box::new(SimulatedKeyboardHardware::new()))
+    } else if backend == Backend::Uinput && !no_type {
+        Some(Box::new(create_hardware(device_name, &matches)?))
+    } else {
+        None
+    };
 
-    // List available devices
-    info!("Available input devices:");
-    let devices = AudioInput::list_available_devices()?;
-    for (i, device) in devices.iter().enumerate() {
-        info!("  {}: {}", i + 1, device);
+    // Step 2: Drop root privileges before initializing audio
+    original_user
+        .drop_privileges()
+        .context("Failed to drop root privileges")?;
+
+    if matches.get_flag("benchmark") {
+        let report = if simulate {
+            // The simulator's printing would be most of what gets timed
+            let hardware = SyncHardware::new(MockKeyboardHardware::new());
+            benchmark(&mut AsyncVirtualKeyboard::new(hardware), Duration::ZERO).await?
+        } else {
+            let Some(hardware) = hardware else {
+                anyhow::bail!(
+                    "--benchmark times the uinput keyboard; run it without {}",
+                    uinput_conflict(no_type)
+                );
+            };
+            let delay = matches
+                .get_one::<u64>("start-delay-secs")
+                .map_or(SELF_TEST_DELAY, |&secs| Duration::from_secs(secs));
+            let hardware = SyncHardware::new(hardware);
+            benchmark(&mut AsyncVirtualKeyboard::new(hardware), delay).await?
+        };
+        println!("{report}");
+        return Ok(());
     }
 
-    // Create audio input
-    let mut audio_input = AudioInput::new()?;
+    if matches.get_flag("self-test") {
+        let Some(mut hardware) = hardware else {
+            anyhow::bail!(
+                "--self-test checks the uinput keyboard; run it without {}",
+                uinput_conflict(no_type)
+            );
+        };
+        let delay = matches
+            .get_one::<u64>("start-delay-secs")
+            .map_or(SELF_TEST_DELAY, |&secs| Duration::from_secs(secs));
+        return self_test(&mut hardware, delay).await;
+    }
+
+    let mut stt_config = SttConfig::default();
+    if let Some(url) = matches.get_one::<String>("stt-url") {
+        stt_config.url = url.clone();
+    }
+    if let Some(scheme) = matches.get_one::<String>("auth-scheme") {
+        stt_config.auth_scheme = scheme.parse()?;
+    }
+    if let Some(headers) = matches.get_many::<(HeaderName, HeaderValue)>("header") {
+        stt_config.headers = headers.cloned().collect();
+    }
+    stt_config.subprotocol = matches.get_one::<String>("subprotocol").cloned();
+    if let Some(urls) = matches.get_many::<String>("compare-url") {
+        stt_config.compare_urls = urls.cloned().collect();
+    }
+    if let Some(path) = matches.get_one::<String>("dump-protocol") {
+        stt_config.protocol_dump = Some(ProtocolDump::open(Path::new(path))?);
+        info!("Recording STT protocol frames to {}", path);
+    }
+    if let Some(encoding) = matches.get_one::<String>("encoding") {
+        stt_config.encoding = encoding.parse()?;
+    }
+    stt_config.min_eot_confidence = matches.get_one::<f64>("min-eot-confidence").copied();
+    apply_model_options(&mut stt_config, &matches)?;
+    if let Some(&retries) = matches.get_one::<u32>("connect-retries") {
+        stt_config.retry.retries = retries;
+    }
+    if let Some(&backoff_ms) = matches.get_one::<u64>("connect-backoff-ms") {
+        stt_config.retry.initial_backoff = Duration::from_millis(backoff_ms);
+    }
+    if let Some(&secs) = matches.get_one::<u64>("connect-timeout-secs") {
+        stt_config.connect_timeout = Duration::from_secs(secs);
+    }
+    stt_config.max_reconnects = matches.get_one::<u32>("max-reconnects").copied();
+
+    let mut session_options = SessionOptions {
+        interim_mode: matches
+            .get_one::<String>("interim-mode")
+            .map(|s| s.as_str())
+            .unwrap_or("live")
+            .parse()?,
+        once: matches.get_flag("once"),
+        finalize_after: matches
+            .get_one::<u64>("finalize-after-ms")
+            .map(|&ms| Duration::from_millis(ms)),
+        turn_merge: matches
+            .get_one::<u64>("turn-merge-ms")
+            .map(|&ms| Duration::from_millis(ms)),
+        stabilize_tail: matches
+            .get_one::<u64>("stabilize-tail")
+            .map(|&k| k as usize),
+        app_profiles,
+        ..SessionOptions::default()
+    };
+    if let (InterimMode::Debounced(_), Some(&ms)) = (
+        session_options.interim_mode,
+        matches.get_one::<u64>("interim-debounce-ms"),
+    ) {
+        session_options.interim_mode = InterimMode::Debounced(Duration::from_millis(ms));
+    }
+    let commit_mode = session_options.interim_mode == InterimMode::Commit;
+    if commit_mode && session_options.turn_merge.is_some() {
+        warn!("--turn-merge-ms has no effect with --interim-mode commit");
+    }
+    if commit_mode && session_options.stabilize_tail.is_some() {
+        warn!("--stabilize-tail has no effect with --interim-mode commit");
+    }
+    if commit_mode && matches.get_flag("local-endpointing") {
+        warn!("--local-endpointing has no effect with --interim-mode commit");
+    }
+    if let Some(&secs) = matches.get_one::<u64>("start-delay-secs") {
+        session_options.start_delay = Duration::from_secs(secs);
+    }
+
+    let mut audio_options = AudioOptions::default();
+    if let Some(&factor) = matches.get_one::<f32>("gain") {
+        audio_options.gain = GainMode::Fixed(Gain::new(factor)?);
+    } else if let Some(&target) = matches.get_one::<f32>("auto-gain") {
+        audio_options.gain = GainMode::Auto(AutoGain::new(target));
+    }
+    audio_options.devices = matches
+        .get_many::<String>("device")
+        .map(|names| names.cloned().collect())
+        .unwrap_or_default();
+    if matches.get_flag("remember-device") {
+        audio_options.remembered_device = original_user
+            .config_dir()
+            .map(|dir| dir.join(REMEMBERED_DEVICE_FILE));
+    }
+    audio_options.chunk_ms = matches.get_one::<u32>("chunk-ms").copied();
+    audio_options.capture_format = matches
+        .get_one::<cpal::SampleFormat>("capture-format")
+        .copied();
+    audio_options.channels = matches.get_one::<u16>("channels").copied();
+    audio_options.sample_rate = matches.get_one::<u32>("sample-rate").copied();
+    if let Some(weights) = matches.get_one::<String>("channel-weights") {
+        audio_options.channel_weights = Some(weights.parse()?);
+    } else if let Some(&channel) = matches.get_one::<u16>("channel") {
+        audio_options.channel_weights = Some(ChannelWeights::single(channel as usize));
+    }
+    audio_options.noise_gate = matches.get_one::<f32>("noise-gate").copied();
+    audio_options.prebuffer = matches
+        .get_one::<u64>("prebuffer-ms")
+        .map(|&ms| Duration::from_millis(ms));
+    audio_options.watchdog_timeout = match matches.get_one::<u64>("audio-watchdog-secs") {
+        Some(0) => None,
+        Some(&secs) => Some(Duration::from_secs(secs)),
+        None => Some(DEFAULT_WATCHDOG_TIMEOUT),
+    };
+    audio_options.restart_stalled_audio = matches.get_flag("restart-stalled-audio");
+    audio_options.verbose_audio = matches.get_flag("verbose-audio");
+    if matches.get_flag("local-endpointing") {
+        audio_options.local_endpointing = Some(
+            matches
+                .get_one::<u64>("endpoint-silence-ms")
+                .map_or(DEFAULT_ENDPOINT_SILENCE, |&ms| Duration::from_millis(ms)),
+        );
+    }
+
+    metrics::spawn_reporter(
+        matches
+            .get_one::<u64>("stats-interval")
+            .map(|&secs| Duration::from_secs(secs)),
+    );
+    if let Some(&port) = matches.get_one::<u16>("control-port") {
+        let addr = control::spawn(port).await?;
+        info!("Control endpoint listening on http://{}", addr);
+    }
+    // Bound after dropping privileges so the user's programs can connect
+    if let Some(path) = matches.get_one::<String>("socket") {
+        session_options.socket = Some(TranscriptSocket::bind(Path::new(path))?);
+        info!("Streaming transcripts to socket {}", path);
+    }
+
+    if matches.get_flag("test-audio") {
+        test_audio(&audio_options).await?;
+    } else if matches.get_flag("debug-stt") && !matches.get_flag("test-stt") {
+        debug_stt(&stt_config, &audio_options).await?;
+    } else if let (true, Some(socket)) = (no_type, &session_options.socket) {
+        stream_to_socket(socket, &stt_config, &audio_options).await?;
+    } else if let Some(hardware) = hardware {
+        // Keystrokes run off the runtime, so a held key doesn't stall audio
+        let mut keyboard = AsyncVirtualKeyboard::new(SyncHardware::new(hardware));
+        configure_keyboard(keyboard.keyboard_mut(), &matches, None)?;
+        configure_extras(keyboard.keyboard_mut(), &matches)?;
+        test_stt(keyboard, &session_options, &stt_config, &audio_options).await?;
+    } else {
+        info!("Typing through {:?}", backend);
+        let mut keyboard = AsyncVirtualKeyboard::new(CommandKeyboardHardware::new(backend)?);
+        configure_keyboard(keyboard.keyboard_mut(), &matches, None)?;
+        configure_extras(keyboard.keyboard_mut(), &matches)?;
+        test_stt(keyboard, &session_options, &stt_config, &audio_options).await?;
+    }
+
+    Ok(())
+}
+
+/// The flag that leaves no uinput keyboard for a mode that needs one
+fn uinput_conflict(no_type: bool) -> &'static str {
+    if no_type {
+        "--no-type"
+    } else {
+        "--backend"
+    }
+}
+
+/// Create the uinput-backed keyboard; must run before dropping root privileges
+fn create_hardware(device_name: &str, matches: &ArgMatches) -> Result<RealKeyboardHardware> {
+    debug!("Creating virtual keyboard device (requires root privileges)...");
+    let uinput_retry = RetryPolicy {
+        retries: matches
+            .get_one::<u32>("uinput-retries")
+            .copied()
+            .unwrap_or(3),
+        initial_backoff: Duration::from_millis(250),
+        max_backoff: Duration::from_secs(2),
+    };
+    let layout: KeyboardLayout = matches
+        .get_one::<String>("layout")
+        .map(|s| s.as_str())
+        .unwrap_or("us")
+        .parse()?;
+    let keymap_overrides = matches
+        .get_one::<String>("keymap-file")
+        .map(|path| layout::load_keymap_file(Path::new(path)))
+        .transpose()?;
+    let mut hardware = RealKeyboardHardware::with_retry(device_name, uinput_retry)
+        .context("Failed to create keyboard hardware")?;
+    hardware.set_layout(layout);
+    hardware.set_hold_shift(matches.get_flag("hold-shift"));
+    if let Some(&ms) = matches.get_one::<u64>("char-delay-ms") {
+        hardware.set_char_delay(Duration::from_millis(ms));
+    }
+    let delay = matches.get_one::<u64>("repeat-delay-ms");
+    let period = matches.get_one::<u64>("repeat-period-ms");
+    if delay.is_some() || period.is_some() {
+        let default = KeyRepeat::default();
+        hardware.set_key_repeat(KeyRepeat {
+            delay: delay.map_or(default.delay, |&ms| Duration::from_millis(ms)),
+            period: period.map_or(default.period, |&ms| Duration::from_millis(ms)),
+        })?;
+    }
+    if let Some(overrides) = keymap_overrides {
+        info!("Loaded {} keymap overrides", overrides.len());
+        hardware.set_keymap_overrides(overrides, matches.get_flag("keymap-replace"));
+    }
+    debug!("Virtual keyboard created successfully");
+    Ok(hardware)
+}
+
+/// Apply `--quality`, then the explicit model and end-of-turn flags over it
+fn apply_model_options(stt_config: &mut SttConfig, matches: &ArgMatches) -> Result<()> {
+    if let Some(quality) = matches.get_one::<String>("quality") {
+        stt_config.apply_quality(quality.parse()?);
+    }
+    if let Some(model) = matches.get_one::<String>("stt-model") {
+        stt_config.model = model.clone();
+    }
+    if let Some(&threshold) = matches.get_one::<f64>("eot-threshold") {
+        stt_config.eot_threshold = Some(threshold);
+    }
+    if let Some(&timeout_ms) = matches.get_one::<u32>("eot-timeout-ms") {
+        stt_config.eot_timeout_ms = Some(timeout_ms);
+    }
+    Ok(())
+}
+
+/// Read the app-profiles file, and parse the command line once with each
+/// profile it names (and the startup one) in front
+fn load_app_profiles(config_dir: &Path, fallback: Option<String>) -> Result<AppProfileSet> {
+    let apps = AppProfiles::load(&config_dir.join(APP_PROFILES_FILE))?;
+    let profiles_file = config_dir.join("profiles");
+    let names = apps
+        .profiles()
+        .into_iter()
+        .map(|name| Some(name.to_string()));
+    let mut matches = HashMap::new();
+    let mut options = HashMap::new();
+    for name in names.chain([fallback.clone()]) {
+        let profile = match &name {
+            Some(name) => {
+                let profile = profile::resolve(name, Some(&profiles_file))?;
+                profile
+                    .validate(&cli())
+                    .with_context(|| format!("Invalid profile '{name}'"))?;
+                profile
+            }
+            None => profile::Profile::default(),
+        };
+        let args = profile::expand_args(env::args_os(), &profile);
+        matches.insert(name.clone(), cli().try_get_matches_from(args)?);
+        options.insert(name, profile.option_names().map(str::to_string).collect());
+    }
+    Ok(AppProfileSet {
+        apps,
+        fallback,
+        matches,
+        options,
+    })
+}
+
+/// Apply the typing options from the command line; with `only`, just the
+/// options it names
+fn configure_keyboard<H: KeyboardHardware>(
+    keyboard: &mut VirtualKeyboard<H>,
+    matches: &ArgMatches,
+    only: Option<&HashSet<String>>,
+) -> Result<()> {
+    let applies = |ids: &[&str]| only.is_none_or(|only| ids.iter().any(|&id| only.contains(id)));
+    // Auto-enter still strips a spoken 'enter', so it implies voice-enter
+    if applies(&["auto-enter", "voice-enter"]) {
+        let auto_enter_enabled = matches.get_flag("auto-enter");
+        let voice_enter_enabled = matches.get_flag("voice-enter") || auto_enter_enabled;
+        keyboard.set_voice_enter_enabled(voice_enter_enabled);
+        keyboard.set_auto_enter_enabled(auto_enter_enabled);
+    }
+    if applies(&["voice-enter-verbs"]) {
+        keyboard.set_enter_verb_enabled(matches.get_flag("voice-enter-verbs"));
+    }
+    if applies(&["voice-commands"]) {
+        keyboard.set_voice_commands_enabled(matches.get_flag("voice-commands"));
+    }
+    if applies(&["smart-punctuation"]) {
+        keyboard.set_smart_punctuation_enabled(matches.get_flag("smart-punctuation"));
+    }
+    if applies(&["continuous-line"]) {
+        keyboard.set_continuous_line_enabled(matches.get_flag("continuous-line"));
+    }
+    if applies(&["max-backspace"]) {
+        keyboard.set_max_backspace(matches.get_one::<usize>("max-backspace").copied());
+    }
+    if applies(&["uppercase"]) {
+        keyboard.set_uppercase_enabled(matches.get_flag("uppercase"));
+    }
+    if applies(&["force-lowercase"]) {
+        keyboard.set_force_lowercase(matches.get_flag("force-lowercase"));
+    }
+    if applies(&["number-mode"]) {
+        keyboard.set_number_mode_enabled(matches.get_flag("number-mode"));
+    }
+    if applies(&["spoken-punctuation"]) {
+        keyboard.set_spoken_punctuation_enabled(matches.get_flag("spoken-punctuation"));
+    }
+    // Unset options are set too, so switching profiles (--app-profiles)
+    // also undoes what the last one set
+    if applies(&["substitutions"]) {
+        let substitutions = matches
+            .get_one::<String>("substitutions")
+            .map(|path| transforms::load_substitutions(Path::new(path)))
+            .transpose()?;
+        keyboard.set_substitutions(substitutions);
+    }
+    if applies(&["blocklist", "blocklist-mode"]) {
+        let blocklist = match matches.get_one::<String>("blocklist") {
+            Some(path) => {
+                let mode = matches
+                    .get_one::<String>("blocklist-mode")
+                    .map(|s| s.as_str())
+                    .unwrap_or("mask")
+                    .parse()?;
+                Some(transforms::load_blocklist(Path::new(path), mode)?)
+            }
+            None => None,
+        };
+        keyboard.set_blocklist(blocklist);
+    }
+    if applies(&["diff-strategy"]) {
+        if let Some(strategy) = matches.get_one::<String>("diff-strategy") {
+            keyboard.set_diff_strategy(strategy.parse()?);
+        }
+    }
+    if applies(&[
+        "backspace-batch",
+        "backspace-pause-ms",
+        "max-backspace-rate",
+    ]) {
+        let default_pacing = BackspacePacing::default();
+        keyboard.set_backspace_pacing(BackspacePacing {
+            batch: matches
+                .get_one::<u16>("backspace-batch")
+                .map_or(default_pacing.batch, |&n| n as usize),
+            pause: matches
+                .get_one::<u64>("backspace-pause-ms")
+                .map_or(default_pacing.pause, |&ms| Duration::from_millis(ms)),
+            max_per_second: matches.get_one::<u32>("max-backspace-rate").copied(),
+        });
+    }
+    if applies(&["enter-key"]) {
+        if let Some(enter_key) = matches.get_one::<String>("enter-key") {
+            keyboard.set_enter_key(enter_key.parse()?);
+        }
+    }
+    if applies(&["turn-separator"]) {
+        if let Some(separator) = matches.get_one::<String>("turn-separator") {
+            keyboard.set_turn_separator(separator.parse()?);
+        }
+    }
+    if applies(&["trim-leading-space"]) {
+        keyboard.set_trim_leading_space(matches.get_flag("trim-leading-space"));
+    }
+    if applies(&["line-prefix", "line-suffix"]) {
+        let affix = |id| matches.get_one::<String>(id).cloned().unwrap_or_default();
+        keyboard.set_line_affixes(affix("line-prefix"), affix("line-suffix"));
+    }
+    if applies(&["date-format"]) {
+        keyboard.set_date_format(
+            matches
+                .get_one::<String>("date-format")
+                .map_or(DEFAULT_DATE_FORMAT, |s| s.as_str())
+                .to_string(),
+        );
+    }
+    if applies(&["time-format"]) {
+        keyboard.set_time_format(
+            matches
+                .get_one::<String>("time-format")
+                .map_or(DEFAULT_TIME_FORMAT, |s| s.as_str())
+                .to_string(),
+        );
+    }
+    if applies(&["input-mode"]) {
+        if let Some(mode) = matches.get_one::<String>("input-mode") {
+            keyboard.set_input_mode(mode.parse()?);
+        }
+    }
+    if applies(&["tab-as-spaces"]) {
+        let spaces = matches.get_one::<u8>("tab-as-spaces").copied().unwrap_or(0);
+        keyboard.set_tab_as_spaces(spaces as usize);
+    }
+    if applies(&["fuzzy-commands"]) {
+        let edits = matches
+            .get_one::<u8>("fuzzy-commands")
+            .copied()
+            .unwrap_or(0);
+        keyboard.commands_mut().set_fuzzy_edits(edits as usize);
+    }
+    Ok(())
+}
+
+/// Set up what's added once per session rather than switched per profile
+fn configure_extras<H: KeyboardHardware>(
+    keyboard: &mut VirtualKeyboard<H>,
+    matches: &ArgMatches,
+) -> Result<()> {
+    let clipboard_command = matches.get_one::<String>("clipboard-command");
+    if matches.get_flag("clipboard-mirror") || clipboard_command.is_some() {
+        let clipboard = match clipboard_command {
+            Some(command) => CommandClipboard::parse(command)?,
+            None => CommandClipboard::detect(),
+        };
+        // A backup copy isn't worth refusing to type over
+        if clipboard.is_available() {
+            info!("Copying finalized lines with {}", clipboard.program());
+            clipboard::mirror_to(keyboard, clipboard);
+        } else {
+            warn!(
+                "{} not found; not copying to the clipboard",
+                clipboard.program()
+            );
+        }
+    }
+    if matches.get_flag("emoji") {
+        let mut emoji = emoji::EmojiTable::builtin();
+        if let Some(path) = matches.get_one::<String>("emoji-file") {
+            for (name, c) in emoji::load_emoji_file(Path::new(path))? {
+                emoji.insert(&name, c);
+            }
+        }
+        keyboard.commands_mut().add_emoji_commands(&emoji);
+        keyboard.set_unicode_input_enabled(!matches.get_flag("emoji-as-names"));
+    }
+    Ok(())
+}
+
+/// Open the configured input device with the requested capture format
+fn open_audio_input(audio_options: &AudioOptions) -> Result<AudioInput> {
+    // An explicit --device wins over the remembered one
+    let remembered = match &audio_options.remembered_device {
+        Some(path) if audio_options.devices.is_empty() => {
+            RememberedDevice::restore(path, &AudioInput::available_device_names()?)
+        }
+        _ => None,
+    };
+    let device = match audio_options.devices.as_slice() {
+        [] => None,
+        [only] => Some(only.as_str()),
+        preferred => {
+            let available = AudioInput::available_device_names()?;
+            let device = audio_input::pick_device(preferred, &available).ok_or_else(|| {
+                VkError::DeviceUnavailable {
+                    message: format!("None of the input devices {:?} were found", preferred),
+                    errno: None,
+                }
+            })?;
+            info!("Using input device '{}'", device);
+            Some(device)
+        }
+    };
+    let mut audio_input = match remembered {
+        Some(remembered) => AudioInput::with_config(
+            Some(&remembered.name),
+            audio_options.channels.or(Some(remembered.channels)),
+            audio_options.sample_rate.or(Some(remembered.sample_rate)),
+        )?,
+        None => AudioInput::with_config(device, audio_options.channels, audio_options.sample_rate)?,
+    };
+    if let Some(format) = audio_options.capture_format {
+        audio_input.set_sample_format(format)?;
+    }
+    if let Some(weights) = &audio_options.channel_weights {
+        weights.check(audio_input.get_channels())?;
+    }
     debug!(
         "Using audio device with {} channels at {} Hz",
         audio_input.get_channels(),
         audio_input.get_sample_rate()
     );
+    Ok(audio_input)
+}
+
+/// Move capture to the next available `--device` preference if the one
+/// being recorded has gone (e.g. undocking took its mic along)
+fn fail_over_audio<F>(
+    audio_input: &mut AudioInput,
+    audio_options: &AudioOptions,
+    recording: &F,
+) -> Result<()>
+where
+    F: FnMut(&[f32]) + Send + Clone + 'static,
+{
+    if audio_options.devices.len() < 2 {
+        return Ok(());
+    }
+    let current = audio_input.device_name()?;
+    let available = AudioInput::available_device_names()?;
+    let Some(next) = audio_input::failover_device(&audio_options.devices, &available, &current)
+    else {
+        return Ok(());
+    };
+    info!(
+        "Audio device '{}' is gone; switching to '{}'",
+        current, next
+    );
+
+    // The processing is set up for the current channel count and rate;
+    // convert to them when the next device can't capture them itself
+    let (channels, sample_rate) = (audio_input.get_channels(), audio_input.get_sample_rate());
+    let mut next_input = AudioInput::with_config(Some(next), Some(channels), Some(sample_rate))?;
+    if (next_input.get_channels(), next_input.get_sample_rate()) != (channels, sample_rate) {
+        info!(
+            "Converting '{}' from {} ch at {} Hz to {} ch at {} Hz",
+            next,
+            next_input.get_channels(),
+            next_input.get_sample_rate(),
+            channels,
+            sample_rate
+        );
+        next_input.deliver_as(channels, sample_rate);
+    }
+    if let Some(format) = audio_options.capture_format {
+        next_input.set_sample_format(format)?;
+    }
+    audio_input.stop_recording();
+    next_input.start_recording(recording.clone())?;
+    *audio_input = next_input;
+    Ok(())
+}
+
+async fn test_audio(audio_options: &AudioOptions) -> Result<()> {
+    info!("Testing audio input...");
+
+    // List available devices
+    info!("Available input devices:");
+    let devices = AudioInput::list_available_devices()?;
+    for device in &devices {
+        info!("  {}: {}", device.index + 1, device.summary());
+    }
 
-    // Test recording for 5 seconds
+    // Create audio input
+    let mut audio_input = open_audio_input(audio_options)?;
+
+    // Test recording for 5 seconds, metering each channel on its own
     let (tx, rx) = mpsc::channel();
+    let channels = audio_input.get_channels() as usize;
 
     audio_input.start_recording(move |data| {
-        let level = data.iter().map(|&x| x.abs()).sum::<f32>() / data.len() as f32;
-        let _ = tx.send(level);
+        let _ = tx.send(audio_processing::channel_levels(data, channels));
     })?;
 
     info!("Recording for 5 seconds...");
     let start = std::time::Instant::now();
 
+    // Narrower bars when there are several, so a line holds them all
+    let bar_width = (50 / channels.max(1)).max(10);
     while start.elapsed() < Duration::from_secs(5) {
-        if let Ok(level) = rx.try_recv() {
-            let bar_length = (level * 50.0) as usize;
-            let bar: String = "#".repeat(bar_length);
-            info!("Level: {:.2} [{}]", level, bar);
+        if let Ok(levels) = rx.try_recv() {
+            let meters: Vec<String> = levels
+                .iter()
+                .map(|&level| {
+                    let bar_length = ((level * bar_width as f32) as usize).min(bar_width);
+                    format!("{:.2} [{}]", level, "#".repeat(bar_length))
+                })
+                .collect();
+            info!(
+                "Level: {} clipped: {}",
+                meters.join(" "),
+                audio_input.clip_count()
+            );
         }
         thread::sleep(Duration::from_millis(50));
     }
 
+    let clipped = audio_input.clip_count();
+    if clipped > 0 {
+        warn!(
+            "{} samples clipped; lower the microphone level, as clipping hurts recognition",
+            clipped
+        );
+    }
     info!("Audio test completed!");
     Ok(())
 }
 
-async fn test_stt(keyboard: VirtualKeyboard<RealKeyboardHardware>, stt_url: &str) -> Result<()> {
+/// Where transcripts are typed. Sessions go through the async variant, so
+/// keystrokes never block the runtime; the tests drive the sync layer directly.
+trait TypingTarget {
+    async fn apply(
+        &mut self,
+        filter: &mut InterimFilter,
+        event: &str,
+        transcript: &str,
+    ) -> Result<()>;
+    async fn finalize_stalled(&mut self, filter: &mut InterimFilter) -> Result<bool>;
+    async fn finalize_locally(&mut self, filter: &mut InterimFilter) -> Result<bool>;
+    async fn finish_held_turn(&mut self, filter: &mut InterimFilter) -> Result<bool>;
+    async fn release_all(&mut self) -> Result<()>;
+    /// Apply the typing options `only` names from `matches`, e.g. another
+    /// profile's
+    fn configure(&mut self, matches: &ArgMatches, only: &HashSet<String>) -> Result<()>;
+}
+
+#[cfg(test)]
+impl<H: KeyboardHardware> TypingTarget for VirtualKeyboard<H> {
+    async fn apply(
+        &mut self,
+        filter: &mut InterimFilter,
+        event: &str,
+        transcript: &str,
+    ) -> Result<()> {
+        filter.apply(self, event, transcript, Instant::now())
+    }
+
+    async fn finalize_stalled(&mut self, filter: &mut InterimFilter) -> Result<bool> {
+        filter.finalize_stalled(self)
+    }
+
+    async fn finalize_locally(&mut self, filter: &mut InterimFilter) -> Result<bool> {
+        filter.finalize_locally(self)
+    }
+
+    async fn finish_held_turn(&mut self, filter: &mut InterimFilter) -> Result<bool> {
+        filter.finish_held_turn(self)
+    }
+
+    async fn release_all(&mut self) -> Result<()> {
+        VirtualKeyboard::release_all(self)
+    }
+
+    fn configure(&mut self, matches: &ArgMatches, only: &HashSet<String>) -> Result<()> {
+        configure_keyboard(self, matches, Some(only))
+    }
+}
+
+impl<A: AsyncKeyboardHardware> TypingTarget for AsyncVirtualKeyboard<A> {
+    async fn apply(
+        &mut self,
+        filter: &mut InterimFilter,
+        event: &str,
+        transcript: &str,
+    ) -> Result<()> {
+        self.run(|keyboard| filter.apply(keyboard, event, transcript, Instant::now()))
+            .await
+    }
+
+    async fn finalize_stalled(&mut self, filter: &mut InterimFilter) -> Result<bool> {
+        self.run(|keyboard| filter.finalize_stalled(keyboard)).await
+    }
+
+    async fn finalize_locally(&mut self, filter: &mut InterimFilter) -> Result<bool> {
+        self.run(|keyboard| filter.finalize_locally(keyboard)).await
+    }
+
+    async fn finish_held_turn(&mut self, filter: &mut InterimFilter) -> Result<bool> {
+        self.run(|keyboard| filter.finish_held_turn(keyboard)).await
+    }
+
+    async fn release_all(&mut self) -> Result<()> {
+        AsyncVirtualKeyboard::release_all(self).await
+    }
+
+    fn configure(&mut self, matches: &ArgMatches, only: &HashSet<String>) -> Result<()> {
+        configure_keyboard(self.keyboard_mut(), matches, Some(only))
+    }
+}
+
+async fn test_stt<K: TypingTarget>(
+    mut keyboard: K,
+    session_options: &SessionOptions,
+    stt_config: &SttConfig,
+    audio_options: &AudioOptions,
+) -> Result<()> {
     info!("Testing speech-to-text functionality...");
 
-    // Wrap keyboard in a mutex to allow mutable access from the closure
-    let keyboard = std::sync::Arc::new(std::sync::Mutex::new(keyboard));
-    let keyboard_clone = keyboard.clone();
-    // Rate-limit plain Update logs to at most once per second
-    let last_update_log = std::sync::Arc::new(std::sync::Mutex::new(None::<Instant>));
-    let last_update_log_cloned = last_update_log.clone();
+    // Results are typed in order by one task, so typing can await the backend
+    let (result_tx, mut result_rx) = tokio::sync::mpsc::unbounded_channel();
+    let socket = session_options.socket.clone();
+    let on_transcription = move |result: stt_client::TranscriptionResult| {
+        if let Some(socket) = &socket {
+            socket.send(&result);
+        }
+        let _ = result_tx.send(result);
+    };
+    let stop = tokio::sync::Notify::new();
 
-    run_stt(stt_url, move |result| {
-        if !result.transcript.is_empty() {
-            if result.event == "Update" {
-                let now = Instant::now();
-                let mut last = last_update_log_cloned.lock().unwrap();
-                let should_log = match *last {
-                    Some(prev) => now.duration_since(prev) >= Duration::from_secs(1),
-                    None => true,
-                };
-                if should_log {
-                    info!("Transcription [{}]: {}", result.event, result.transcript);
-                    *last = Some(now);
+    let typing = async {
+        // Rate-limit plain Update logs to at most once per second
+        let mut last_update_log = None::<Instant>;
+        let mut interim_filter = InterimFilter::new(session_options.interim_mode);
+        interim_filter.set_finalize_after(session_options.finalize_after);
+        interim_filter.set_turn_merge(session_options.turn_merge);
+        interim_filter.set_stabilize_tail(session_options.stabilize_tail);
+        let mut limit = TurnLimit::new(session_options.once.then_some(1));
+        let endpointing = audio_options.local_endpointing.is_some();
+        let app_profiles = session_options.app_profiles.as_ref();
+        let mut focus = app_profiles.map(|set| {
+            watch_focus(FocusProfiles::new(
+                focus::detect(),
+                set.apps.clone(),
+                set.fallback.clone(),
+            ))
+        });
+        let mut active_profile = app_profiles.and_then(|set| set.fallback.clone());
+        // A profile switch waits for the turn in progress to end
+        let mut pending_profile = None;
+
+        loop {
+            if let (Some(set), false) = (app_profiles, interim_filter.in_turn()) {
+                if let Some((window, profile)) = pending_profile.take() {
+                    if let Err(e) =
+                        switch_profile(&mut keyboard, set, &mut active_profile, window, profile)
+                    {
+                        warn!("Failed to switch profiles: {:#}", e);
+                    }
                 }
-            } else {
-                // Always log non-Update events (StartOfTurn, Preflight, SpeechResumed, EndOfTurn)
-                info!("Transcription [{}]: {}", result.event, result.transcript);
             }
-        }
 
-        let mut kb = keyboard_clone.lock().unwrap();
+            // With --finalize-after-ms, a stalled stream ends the line locally
+            let stall = interim_filter
+                .finalize_deadline()
+                .map(tokio::time::Instant::from_std);
+            let result = tokio::select! {
+                result = result_rx.recv() => match result {
+                    Some(result) => result,
+                    None => break,
+                },
+                _ = tokio::time::sleep_until(stall.unwrap_or_else(tokio::time::Instant::now)),
+                    if stall.is_some() =>
+                {
+                    let outcome = keyboard.finalize_stalled(&mut interim_filter).await;
+                    let reason = "No transcript for a while";
+                    after_local_finalize(&mut keyboard, outcome, reason, stt_config).await;
+                    continue;
+                }
+                // With --local-endpointing, the microphone going quiet ends the turn
+                _ = audio_options.end_of_speech.fired(), if endpointing => {
+                    let outcome = keyboard.finalize_locally(&mut interim_filter).await;
+                    let reason = "Silence after speech";
+                    after_local_finalize(&mut keyboard, outcome, reason, stt_config).await;
+                    continue;
+                }
+                // With --app-profiles, typing options follow the focused window
+                change = async { focus.as_mut()?.recv().await }, if focus.is_some() => {
+                    match change {
+                        Some(Ok(change)) => pending_profile = Some(change),
+                        Some(Err(e)) => {
+                            warn!("Stopped following the focused window: {:#}", e);
+                            focus = None;
+                        }
+                        None => focus = None,
+                    }
+                    continue;
+                }
+            };
+
+            // The server may flush more results after we stop sending audio
+            if limit.is_reached() {
+                debug!("Ignoring [{}] after the last turn", result.event);
+                continue;
+            }
 
-        // Handle different event types
-        match result.event.as_str() {
-            "EndOfTurn" => {
-                // Finalize the transcript; treat failure as fatal
-                if let Err(e) = kb.finalize_transcript() {
-                    error!("Failed to finalize transcript: {}", e);
-                    std::process::exit(1);
+            if !result.transcript.is_empty() {
+                if result.event == "Update" {
+                    let now = Instant::now();
+                    let should_log = match last_update_log {
+                        Some(prev) => now.duration_since(prev) >= Duration::from_secs(1),
+                        None => true,
+                    };
+                    if should_log {
+                        info!("Transcription [{}]: {}", result.event, result.transcript);
+                        last_update_log = Some(now);
+                    }
+                } else {
+                    // Always log non-Update events (StartOfTurn, Preflight, SpeechResumed, EndOfTurn)
+                    info!("Transcription [{}]: {}", result.event, result.transcript);
                 }
             }
-            _ => {
-                // Handle incremental updates; treat failure as fatal
-                if let Err(e) = kb.update_transcript(&result.transcript) {
-                    error!("Failed to update transcript: {}", e);
-                    std::process::exit(1);
+
+            // Type or finalize according to the interim mode; treat failure as fatal
+            if let Err(e) = keyboard
+                .apply(&mut interim_filter, &result.event, &result.transcript)
+                .await
+            {
+                error!("Failed to apply transcript [{}]: {}", result.event, e);
+                // Best effort: the failure may have interrupted a shifted character
+                let _ = keyboard.release_all().await;
+                std::process::exit(1);
+            }
+
+            if limit.record(&result.event) {
+                info!("Turn finished, stopping (--once)");
+                stop.notify_one();
+            }
+        }
+
+        // A turn end held for --turn-merge-ms still ends its line
+        if let Err(e) = keyboard.finish_held_turn(&mut interim_filter).await {
+            error!("Failed to finalize the last turn: {}", e);
+        }
+    };
+
+    // The typing loop ends once the STT client is done and has dropped its sender
+    let session = async {
+        countdown(session_options.start_delay).await;
+        let (result, ()) = tokio::join!(
+            run_stt(stt_config, audio_options, on_transcription, &stop),
+            typing
+        );
+        result
+    };
+
+    // Stop cleanly on Ctrl+C instead of dying mid-keystroke. With --once the
+    // first Ctrl+C ends the turn instead: Finalize has the server close it
+    // now, and the session ends once its last results are typed.
+    let mut finishing = false;
+    let result = {
+        tokio::pin!(session);
+        loop {
+            tokio::select! {
+                result = &mut session => break result,
+                _ = tokio::signal::ctrl_c() => {
+                    if session_options.once && !finishing {
+                        info!("Received Ctrl+C, finishing the turn (Ctrl+C again to quit)...");
+                        stt_config.finalize.fire();
+                        stop.notify_one();
+                        finishing = true;
+                        continue;
+                    }
+                    info!("Received Ctrl+C, shutting down...");
+                    break Ok(());
                 }
             }
         }
-    }).await
+    };
+
+    if let Err(e) = keyboard.release_all().await {
+        warn!("Failed to release modifier keys: {}", e);
+    }
+
+    result
 }
 
-async fn debug_stt(stt_url: &str) -> Result<()> {
-    info!("Debugging speech-to-text functionality...");
-    info!("STT Service URL: {}", stt_url);
+/// A profile change for the focused window: the window, and its profile
+type FocusChange = (Option<WindowClass>, Option<String>);
 
-    run_stt(stt_url, |result| {
-        // Only show non-empty transcriptions
-        if !result.transcript.is_empty() {
-            info!("Transcription [{}]: {}", result.event, result.transcript);
+/// Poll the focused window on its own thread, since asking the X server
+/// blocks, and send each profile change; stops at the first error or once
+/// the receiver is gone
+fn watch_focus(
+    mut focus: FocusProfiles,
+) -> tokio::sync::mpsc::UnboundedReceiver<Result<FocusChange>> {
+    let (tx, rx) = tokio::sync::mpsc::unbounded_channel();
+    std::thread::spawn(move || loop {
+        std::thread::sleep(FOCUS_POLL_INTERVAL);
+        let change = match focus.poll() {
+            Ok(Some(change)) => Ok(change),
+            Ok(None) if tx.is_closed() => break,
+            Ok(None) => continue,
+            Err(e) => Err(e),
+        };
+        let failed = change.is_err();
+        if tx.send(change).is_err() || failed {
+            break;
         }
+    });
+    rx
+}
+
+/// Switch the typing options from the active profile to the focused window's
+fn switch_profile<K: TypingTarget>(
+    keyboard: &mut K,
+    set: &AppProfileSet,
+    active: &mut Option<String>,
+    window: Option<WindowClass>,
+    profile: Option<String>,
+) -> Result<()> {
+    let (matches, options) = set.switch(active, &profile)?;
+    keyboard.configure(matches, &options)?;
+    info!(
+        "Focus moved to {}; using profile '{}'",
+        window.map_or("no window".to_string(), |w| w.to_string()),
+        profile.as_deref().unwrap_or("none")
+    );
+    *active = profile;
+    Ok(())
+}
+
+/// Log a line the client finalized without the server and have the server
+/// end the turn too, if it supports that; failing to finalize is fatal
+async fn after_local_finalize<K: TypingTarget>(
+    keyboard: &mut K,
+    outcome: Result<bool>,
+    reason: &str,
+    stt_config: &SttConfig,
+) {
+    match outcome {
+        Ok(true) => {
+            info!("{}, finalized the line locally", reason);
+            stt_config.finalize.fire();
+        }
+        Ok(false) => {}
+        Err(e) => {
+            error!("Failed to finalize the line locally: {}", e);
+            let _ = keyboard.release_all().await;
+            std::process::exit(1);
+        }
+    }
+}
+
+/// Typed by `--self-test`: lowercase letters, digits and shifted symbols
+const SELF_TEST_TEXT: &str = "the quick brown fox 1234567890 !@#$%";
+
+/// Time to focus a window before `--self-test` types, unless `--start-delay-secs` is given
+const SELF_TEST_DELAY: Duration = Duration::from_secs(3);
+
+/// Type a known string and press Enter, so keyboard problems (permissions,
+/// layout) can be told apart from audio or STT ones
+async fn self_test<H: KeyboardHardware>(hardware: &mut H, delay: Duration) -> Result<()> {
+    info!("Self-test: will type '{}' and press Enter", SELF_TEST_TEXT);
+    countdown(delay).await;
+    hardware.type_text(SELF_TEST_TEXT)?;
+    hardware.press_enter()?;
+    info!(
+        "Self-test done; the focused window should show exactly: {}",
+        SELF_TEST_TEXT
+    );
+    Ok(())
+}
+
+/// Times `--benchmark` types the self-test string (with a space after each)
+const BENCHMARK_REPEATS: usize = 20;
+
+/// What `--benchmark` measured
+#[derive(Debug, Clone, PartialEq)]
+struct BenchmarkReport {
+    chars: usize,
+    elapsed: Duration,
+    /// write(2) calls, for backends that write to a device
+    writes: Option<u64>,
+}
+
+impl BenchmarkReport {
+    fn chars_per_sec(&self) -> f64 {
+        self.chars as f64 / self.elapsed.as_secs_f64().max(f64::EPSILON)
+    }
+}
+
+impl fmt::Display for BenchmarkReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Typed {} characters in {:.2}s ({:.0} chars/s)",
+            self.chars,
+            self.elapsed.as_secs_f64(),
+            self.chars_per_sec()
+        )?;
+        if let Some(writes) = self.writes {
+            let per_char = writes as f64 / self.chars.max(1) as f64;
+            write!(f, "; {writes} device writes ({per_char:.1} per character)")?;
+        }
+        Ok(())
+    }
+}
+
+/// Type a long sample in one go and time it, to show what the typing path
+/// costs on this machine. It goes through the keystroke queue and blocking
+/// pool a session types through, so the rate is one a session can reach.
+async fn benchmark<A: AsyncKeyboardHardware>(
+    keyboard: &mut AsyncVirtualKeyboard<A>,
+    delay: Duration,
+) -> Result<BenchmarkReport> {
+    let sample = format!("{SELF_TEST_TEXT} ").repeat(BENCHMARK_REPEATS);
+    let chars = sample.chars().count();
+    info!("Benchmark: will type {} characters", chars);
+    countdown(delay).await;
+
+    let writes_before = keyboard.hardware().device_writes();
+    let start = Instant::now();
+    keyboard.run(|keyboard| keyboard.type_raw(&sample)).await?;
+    let elapsed = start.elapsed();
+    let writes = keyboard
+        .hardware()
+        .device_writes()
+        .zip(writes_before)
+        .map(|(after, before)| after - before);
+    Ok(BenchmarkReport {
+        chars,
+        elapsed,
+        writes,
     })
+}
+
+/// Wait out the start delay, logging a countdown once per second
+async fn countdown(delay: Duration) {
+    if delay.is_zero() {
+        return;
+    }
+    info!(
+        "Focus the window to type into; starting in {} seconds...",
+        delay.as_secs()
+    );
+
+    let mut remaining = delay;
+    while !remaining.is_zero() {
+        let step = remaining.min(Duration::from_secs(1));
+        tokio::time::sleep(step).await;
+        remaining -= step;
+        if !remaining.is_zero() {
+            info!("{}...", remaining.as_secs_f32().ceil());
+        }
+    }
+}
+
+async fn debug_stt(stt_config: &SttConfig, audio_options: &AudioOptions) -> Result<()> {
+    info!("Debugging speech-to-text functionality...");
+    info!("STT Service URL: {}", stt_config.url);
+
+    run_stt(
+        stt_config,
+        audio_options,
+        |result| {
+            // Only show non-empty transcriptions
+            if !result.transcript.is_empty() {
+                info!("Transcription [{}]: {}", result.event, result.transcript);
+            }
+        },
+        &tokio::sync::Notify::new(),
+    )
     .await
 }
 
-async fn run_stt<F>(stt_url: &str, on_transcription: F) -> Result<()>
+/// Transcribe for the `--socket` clients only (`--no-type`)
+async fn stream_to_socket(
+    socket: &TranscriptSocket,
+    stt_config: &SttConfig,
+    audio_options: &AudioOptions,
+) -> Result<()> {
+    info!("Not typing; results only go to the socket");
+    let socket = socket.clone();
+    let stop = tokio::sync::Notify::new();
+    let session = run_stt(
+        stt_config,
+        audio_options,
+        move |result| {
+            if !result.transcript.is_empty() {
+                debug!("Transcription [{}]: {}", result.event, result.transcript);
+            }
+            socket.send(&result);
+        },
+        &stop,
+    );
+    // Return rather than die on Ctrl+C, so the socket file is removed
+    tokio::select! {
+        result = session => result,
+        _ = tokio::signal::ctrl_c() => {
+            info!("Received Ctrl+C, shutting down...");
+            Ok(())
+        }
+    }
+}
+
+/// How often `run_stt` checks that audio is still arriving
+const WATCHDOG_CHECK_INTERVAL: Duration = Duration::from_millis(500);
+
+/// How often `run_stt` checks that the device being recorded is still there,
+/// when `--device` names fallbacks
+const DEVICE_CHECK_INTERVAL: Duration = Duration::from_secs(2);
+
+/// How often `--app-profiles` checks which window has focus
+const FOCUS_POLL_INTERVAL: Duration = Duration::from_millis(500);
+
+/// How often `--verbose-audio` logs a rollup
+const AUDIO_FLOW_INTERVAL: Duration = Duration::from_secs(1);
+
+async fn run_stt<F>(
+    stt_config: &SttConfig,
+    audio_options: &AudioOptions,
+    on_transcription: F,
+    stop: &tokio::sync::Notify,
+) -> Result<()>
 where
-    F: Fn(stt_client::TranscriptionResult) + Send + 'static,
+    F: Fn(stt_client::TranscriptionResult) + Clone + Send + 'static,
 {
-    let mut audio_input = AudioInput::new()?;
-    debug!(
-        "Using audio device with {} channels at {} Hz",
-        audio_input.get_channels(),
-        audio_input.get_sample_rate()
+    let mut audio_input = open_audio_input(audio_options)?;
+
+    let mut audio_buffer = AudioBuffer::new(
+        audio_input.get_sample_rate(),
+        audio_options.chunk_ms.unwrap_or(DEFAULT_CHUNK_MS),
+        stt_config.encoding,
     );
+    let stt_client = SttClient::from_config(stt_config, audio_input.get_sample_rate());
 
-    let mut audio_buffer = AudioBuffer::new(audio_input.get_sample_rate(), 160);
-    let stt_client = SttClient::new(stt_url, audio_input.get_sample_rate());
+    // With comparison services, log the primary's finished turns alongside theirs
+    let comparing = !stt_config.compare_urls.is_empty();
+    let on_transcription = move |result: stt_client::TranscriptionResult| {
+        if !result.transcript.is_empty() {
+            control::CONTROL.record_transcript();
+        }
+        // With --max-reconnects the session can drop and come back
+        match result.event.as_str() {
+            stt_client::DISCONNECTED_EVENT => control::CONTROL.set_connected(false),
+            stt_client::RECONNECTED_EVENT => control::CONTROL.set_connected(true),
+            _ => {}
+        }
+        if comparing && result.event == "EndOfTurn" {
+            info!("[primary] {}", result.transcript);
+        }
+        on_transcription(result)
+    };
 
+    let stt_url = &stt_config.url;
     info!(?stt_url, "Connecting to STT service...");
-    let (audio_tx, handle) = stt_client
+    let (audio_tx, mut handle) = stt_client
         .connect_and_transcribe(on_transcription)
         .await
         .context("Failed to connect to STT service")?;
+    control::CONTROL.set_connected(true);
+
+    // Comparison connections only log; their failures never stop the session
+    let mut compare_sinks = Vec::new();
+    let mut compare_tasks = Vec::new();
+    for url in &stt_config.compare_urls {
+        // The protocol dump and Finalize requests are for the primary service only
+        let config = SttConfig {
+            url: url.clone(),
+            protocol_dump: None,
+            finalize: Default::default(),
+            ..stt_config.clone()
+        };
+        let label = url.clone();
+        let connected = SttClient::from_config(&config, audio_input.get_sample_rate())
+            .connect_and_transcribe(move |result| {
+                if result.event == "EndOfTurn" {
+                    info!("[{}] {}", label, result.transcript);
+                }
+            })
+            .await;
+        let (tx, compare_handle) = match connected {
+            Ok(connection) => connection,
+            Err(e) => {
+                warn!("Skipping comparison STT service {}: {:#}", url, e);
+                continue;
+            }
+        };
+        compare_sinks.push(tx);
+        let url = url.clone();
+        compare_tasks.push(tokio::spawn(async move {
+            match compare_handle.await {
+                Ok(Ok(())) => {}
+                Ok(Err(e)) => warn!("Comparison STT {} failed: {:#}", url, e),
+                Err(e) => warn!("Comparison STT {} task failed: {}", url, e),
+            }
+        }));
+    }
+    // The primary connection takes every chunk; the comparison ones share a
+    // broadcast so a slow one skips audio instead of holding up the rest
+    let compare_tx = (!compare_sinks.is_empty())
+        .then(|| stt_client::fan_out(compare_sinks, stt_client::FAN_OUT_CAPACITY));
 
     info!("Listening for speech... Speak into your microphone!");
     info!("Press Ctrl+C to stop.");
 
-    let audio_tx = std::sync::Arc::new(audio_tx);
     let audio_tx_clone = audio_tx.clone();
     let channels = audio_input.get_channels();
+    let channel_weights = audio_options.channel_weights.clone();
+    let mut gain = audio_options.gain.clone();
+    let mut noise_gate = audio_options.noise_gate.map(|threshold| {
+        NoiseGate::new(
+            threshold,
+            audio_input.get_sample_rate(),
+            audio_options.prebuffer.unwrap_or(DEFAULT_PREBUFFER),
+        )
+    });
 
-    // Start recording
-    audio_input.start_recording(move |data| {
+    let mut watchdog = audio_options
+        .watchdog_timeout
+        .map(|timeout| AudioWatchdog::new(timeout, Instant::now()));
+    let chunk_stamp = watchdog.as_ref().map(AudioWatchdog::stamp);
+    let mut endpointer = audio_options.local_endpointing.map(|silence| {
+        let level = audio_options.noise_gate.unwrap_or(DEFAULT_SPEECH_LEVEL);
+        Endpointer::new(level, audio_input.get_sample_rate(), silence)
+    });
+    let end_of_speech = audio_options.end_of_speech.clone();
+    let audio_flow = audio_options
+        .verbose_audio
+        .then(|| Arc::new(AudioFlow::default()));
+    let callback_flow = audio_flow.clone();
+
+    // Shared so the watchdog can restart the stream with the same processing
+    let process_audio = Arc::new(Mutex::new(move |data: &[f32]| {
         debug!("Received audio data: {} samples", data.len());
 
-        // Average stereo channels to mono
-        let mono_data: Vec<f32> = if channels == 2 {
-            let mut mono = Vec::with_capacity(data.len() / 2);
-            for chunk in data.chunks_exact(2) {
-                mono.push((chunk[0] + chunk[1]) / 2.0);
-            }
+        // Apply gain before downmixing so every channel is scaled the same
+        let mut samples = data.to_vec();
+        if control::CONTROL.is_muted() {
+            // Stream silence so the connection stays open while muted
+            samples.fill(0.0);
+        } else {
+            gain.apply(&mut samples);
+        }
+
+        // Average (or weight) the channels to mono
+        let mono_data = if channels > 1 {
+            let mono = match &channel_weights {
+                Some(weights) => weights.downmix(&samples, channels as usize),
+                None => audio_processing::downmix(&samples, channels as usize),
+            };
             debug!("Averaged samples: {}", mono.len());
             mono
         } else {
-            data.to_vec()
+            samples
+        };
+        if endpointer.as_mut().is_some_and(|e| e.process(&mono_data)) {
+            debug!("Silence after speech; ending the turn locally");
+            end_of_speech.fire();
+        }
+        let mono_data = match &mut noise_gate {
+            Some(gate) => gate.process(&mono_data),
+            None => mono_data,
         };
 
         // Create audio chunks and send them
         let chunks = audio_buffer.add_samples(&mono_data);
         for chunk in chunks {
-            debug!("Sending audio chunk: {} bytes", chunk.len());
+            let len = chunk.len();
+            debug!("Sending audio chunk: {} bytes", len);
+            if let Some(compare_tx) = &compare_tx {
+                // Fails only once every comparison connection has ended
+                let _ = compare_tx.send(chunk.clone());
+            }
             if let Err(e) = audio_tx_clone.blocking_send(chunk) {
                 error!("Failed to send audio chunk: {}", e);
+                continue;
+            }
+            if let Some(flow) = &callback_flow {
+                flow.record_chunk(len);
+            }
+            if let Some(stamp) = &chunk_stamp {
+                stamp.record(Instant::now());
             }
         }
-    })?;
+        if let Some(flow) = &callback_flow {
+            flow.set_buffered(audio_buffer.buffered());
+        }
+    }));
+    let recording = move |data: &[f32]| (process_audio.lock().unwrap())(data);
+
+    // Start recording
+    audio_input.start_recording(recording.clone())?;
+    if let Some(path) = &audio_options.remembered_device {
+        let saved = audio_input.remembered().and_then(|d| d.save(path));
+        if let Err(e) = saved {
+            warn!("Failed to remember the audio device: {:#}", e);
+        }
+    }
+
+    // Wait for the STT client to finish (or Ctrl+C), unless asked to stop first
+    let mut watchdog_tick = tokio::time::interval(WATCHDOG_CHECK_INTERVAL);
+    let mut device_tick = tokio::time::interval(DEVICE_CHECK_INTERVAL);
+    let mut flow_tick = tokio::time::interval_at(
+        tokio::time::Instant::now() + AUDIO_FLOW_INTERVAL,
+        AUDIO_FLOW_INTERVAL,
+    );
+    let mut last_flow_rollup = Instant::now();
+    let result = loop {
+        tokio::select! {
+            result = &mut handle => break result,
+            _ = stop.notified() => {
+                // Dropping the stream and the callback drops their senders; once ours
+                // is gone too the clients send CloseStream and the servers close after
+                // their last results
+                audio_input.stop_recording();
+                drop(recording);
+                drop(audio_tx);
+                let result = handle.await;
+                for task in compare_tasks {
+                    let _ = task.await;
+                }
+                break result;
+            }
+            _ = watchdog_tick.tick(), if watchdog.is_some() => {
+                let Some(silent_for) = watchdog.as_mut().and_then(|w| w.check(Instant::now()))
+                else {
+                    continue;
+                };
+                warn!(
+                    "No audio from the microphone for {:.1}s; is the device still working?",
+                    silent_for.as_secs_f32()
+                );
+                if audio_options.restart_stalled_audio {
+                    info!("Restarting the audio stream");
+                    audio_input.stop_recording();
+                    if let Err(e) = audio_input.start_recording(recording.clone()) {
+                        error!("Failed to restart the audio stream: {:#}", e);
+                    }
+                }
+            }
+            _ = device_tick.tick(), if audio_options.devices.len() > 1 => {
+                // A failed switch is tried again on the next tick
+                if let Err(e) = fail_over_audio(&mut audio_input, audio_options, &recording) {
+                    warn!("Failed to switch audio devices: {:#}", e);
+                }
+            }
+            _ = flow_tick.tick(), if audio_flow.is_some() => {
+                let now = Instant::now();
+                if let Some(flow) = &audio_flow {
+                    info!("Audio: {}", flow.take(now.duration_since(last_flow_rollup)));
+                }
+                last_flow_rollup = now;
+            }
+        }
+    };
+    control::CONTROL.set_connected(false);
+    result??;
+
+    Ok(())
+}
+
+/// Set variables from a dotenv file, keeping any already in the environment.
+/// Returns false when the file doesn't exist.
+fn load_env_file(path: &Path) -> Result<bool> {
+    let Some(vars) = env_file_vars(path, |key| env::var_os(key).is_some())? else {
+        return Ok(false);
+    };
+    for (key, value) in vars {
+        env::set_var(key, value);
+    }
+    Ok(true)
+}
+
+/// Variables a dotenv file would set, leaving out those `is_set` reports as
+/// already present. None when the file doesn't exist.
+fn env_file_vars(
+    path: &Path,
+    is_set: impl Fn(&str) -> bool,
+) -> Result<Option<Vec<(String, String)>>> {
+    let context = || format!("Failed to load {}", path.display());
+    let iter = match dotenvy::from_path_iter(path) {
+        Ok(iter) => iter,
+        Err(dotenvy::Error::Io(e)) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e).with_context(context),
+    };
+    let mut vars = Vec::new();
+    for item in iter {
+        let (key, value) = item.with_context(context)?;
+        if !is_set(&key) {
+            vars.push((key, value));
+        }
+    }
+    Ok(Some(vars))
+}
+
+/// Replace the supplementary group list with `user`'s groups and `gid`
+#[cfg(not(target_os = "macos"))]
+fn init_groups(user: &CStr, gid: Gid) -> nix::Result<()> {
+    nix::unistd::initgroups(user, gid)
+}
+
+/// nix leaves `initgroups` out on macOS as well
+#[cfg(target_os = "macos")]
+fn init_groups(user: &CStr, gid: Gid) -> nix::Result<()> {
+    let res = unsafe { libc::initgroups(user.as_ptr(), gid.as_raw() as libc::c_int) };
+    nix::errno::Errno::result(res).map(drop)
+}
 
-    // Just wait for the STT client to finish (will be interrupted by Ctrl+C)
-    handle.await??;
+/// Replace the supplementary group list with just `gid`
+#[cfg(not(target_os = "macos"))]
+fn set_only_group(gid: Gid) -> nix::Result<()> {
+    nix::unistd::setgroups(&[gid])
+}
+
+/// nix leaves `setgroups` out on macOS, but the libc call is there
+#[cfg(target_os = "macos")]
+fn set_only_group(gid: Gid) -> nix::Result<()> {
+    let groups = [gid.as_raw()];
+    let res = unsafe { libc::setgroups(1, groups.as_ptr()) };
+    nix::errno::Errno::result(res).map(drop)
+}
 
+/// Check that real and effective ids all match the target after dropping
+/// privileges, so we never carry on half-dropped (e.g. with euid still root)
+fn verify_dropped(target: (Uid, Gid), uids: (Uid, Uid), gids: (Gid, Gid)) -> Result<()> {
+    let (uid, gid) = target;
+    if uids != (uid, uid) {
+        anyhow::bail!(
+            "Privilege drop incomplete: uid={} euid={}, expected {}",
+            uids.0,
+            uids.1,
+            uid
+        );
+    }
+    if gids != (gid, gid) {
+        anyhow::bail!(
+            "Privilege drop incomplete: gid={} egid={}, expected {}",
+            gids.0,
+            gids.1,
+            gid
+        );
+    }
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use virtual_keyboard::{KeyAction, MockKeyboardHardware};
+
+    #[test]
+    fn test_explicit_model_flags_override_quality() {
+        let matches = cli().get_matches_from([
+            "voice-keyboard",
+            "--quality",
+            "accurate",
+            "--eot-threshold",
+            "0.9",
+            "--stt-model",
+            "flux-next",
+        ]);
+        let mut stt_config = SttConfig::default();
+        apply_model_options(&mut stt_config, &matches).unwrap();
+
+        assert_eq!(stt_config.model, "flux-next");
+        assert_eq!(stt_config.eot_threshold, Some(0.9));
+        // Left to the preset
+        assert_eq!(stt_config.eot_timeout_ms, Some(7000));
+    }
+
+    #[tokio::test]
+    async fn test_self_test_types_every_character() {
+        let mut hardware = MockKeyboardHardware::new();
+        self_test(&mut hardware, Duration::ZERO).await.unwrap();
+
+        let text: String = hardware.typed_chars.iter().collect();
+        assert_eq!(text, SELF_TEST_TEXT);
+        assert_eq!(hardware.enter_count, 1);
+        // The default layout has a key for each character
+        assert!(!hardware
+            .actions
+            .iter()
+            .any(|action| matches!(action, KeyAction::Text(_))));
+    }
+
+    #[tokio::test]
+    async fn test_local_endpointing_across_pauses_in_one_utterance() {
+        // Typing as the CLI would, for a server that never ends the turn: its
+        // transcript just grows while the speaker pauses between phrases
+        let matches = cli().get_matches_from(["voice-keyboard", "--local-endpointing"]);
+        let mut keyboard = VirtualKeyboard::new(MockKeyboardHardware::new());
+        configure_keyboard(&mut keyboard, &matches, None).unwrap();
+        let mut filter = InterimFilter::new(InterimMode::Live);
+        let stt_config = SttConfig::default();
+
+        // 100 ms blocks at 16 kHz; a second of quiet after each phrase
+        let silence = Duration::from_millis(800);
+        let mut endpointer = Endpointer::new(DEFAULT_SPEECH_LEVEL, 16_000, silence);
+        let (speech, pause) = (vec![0.2; 1600], vec![0.0; 1600]);
+        let mut transcript = String::new();
+        for phrase in ["one", "two", "three four"] {
+            if !transcript.is_empty() {
+                transcript.push(' ');
+            }
+            transcript.push_str(phrase);
+            for _ in 0..5 {
+                assert!(!endpointer.process(&speech));
+            }
+            keyboard
+                .apply(&mut filter, "Update", &transcript)
+                .await
+                .unwrap();
+            let ended = (0..10).filter(|_| endpointer.process(&pause)).count();
+            assert_eq!(ended, 1);
+            let outcome = TypingTarget::finalize_locally(&mut keyboard, &mut filter).await;
+            after_local_finalize(&mut keyboard, outcome, "Silence", &stt_config).await;
+        }
+
+        let text: String = keyboard.hardware().typed_chars.iter().collect();
+        assert_eq!(text, "one two three four");
+        assert_eq!(keyboard.hardware().backspace_count, 0);
+        assert_eq!(keyboard.hardware().enter_count, 0);
+    }
+
+    #[tokio::test]
+    async fn test_benchmark_reports_the_sample_it_typed() {
+        let hardware = SyncHardware::new(MockKeyboardHardware::new());
+        let mut keyboard = AsyncVirtualKeyboard::new(hardware);
+        let report = benchmark(&mut keyboard, Duration::ZERO).await.unwrap();
+
+        let expected = (SELF_TEST_TEXT.len() + 1) * BENCHMARK_REPEATS;
+        assert_eq!(report.chars, expected);
+        let mock = keyboard.hardware().get_ref().unwrap();
+        assert_eq!(mock.typed_chars.len(), expected);
+        assert!(report.chars_per_sec().is_finite() && report.chars_per_sec() > 0.0);
+        // The mock has no device to write to
+        assert_eq!(report.writes, None);
+        assert!(report
+            .to_string()
+            .starts_with(&format!("Typed {expected} characters in ")));
+
+        let report = BenchmarkReport {
+            chars: 200,
+            elapsed: Duration::from_secs(2),
+            writes: Some(800),
+        };
+        assert_eq!(
+            report.to_string(),
+            "Typed 200 characters in 2.00s (100 chars/s); 800 device writes (4.0 per character)"
+        );
+    }
+
+    #[test]
+    fn test_profile_switch_only_touches_profile_options() {
+        let args = ["voice-keyboard", "--voice-enter"];
+        let (mut matches, mut options) = (HashMap::new(), HashMap::new());
+        for name in [None, Some("chat".to_string())] {
+            let profile = name
+                .as_deref()
+                .map_or_else(profile::Profile::default, |name| {
+                    profile::Profile::builtin(name).unwrap()
+                });
+            let expanded = profile::expand_args(args.map(Into::into), &profile);
+            matches.insert(name.clone(), cli().get_matches_from(expanded));
+            options.insert(name, profile.option_names().map(str::to_string).collect());
+        }
+        let set = AppProfileSet {
+            apps: AppProfiles::default(),
+            fallback: None,
+            matches,
+            options,
+        };
+        let mut keyboard = VirtualKeyboard::new(MockKeyboardHardware::new());
+        configure_keyboard(&mut keyboard, &set.matches[&None], None).unwrap();
+        // Chosen by voice, which no profile here sets
+        keyboard.set_input_mode(commands::InputMode::Dictation);
+        let turn = |keyboard: &mut VirtualKeyboard<MockKeyboardHardware>| {
+            keyboard.update_transcript("hi").unwrap();
+            keyboard.finalize_transcript().unwrap();
+            std::mem::take(&mut keyboard.hardware_mut().enter_count)
+        };
+
+        let chat = Some("chat".to_string());
+        let (to_chat, only) = set.switch(&None, &chat).unwrap();
+        assert_eq!(
+            only,
+            HashSet::from(["auto-enter".into(), "enter-key".into()])
+        );
+        configure_keyboard(&mut keyboard, to_chat, Some(&only)).unwrap();
+        assert_eq!(turn(&mut keyboard), 1);
+        assert_eq!(keyboard.input_mode(), commands::InputMode::Dictation);
+
+        // Switching back undoes what chat set, and only that
+        let (back, only) = set.switch(&chat, &None).unwrap();
+        configure_keyboard(&mut keyboard, back, Some(&only)).unwrap();
+        assert_eq!(turn(&mut keyboard), 0);
+        assert_eq!(keyboard.input_mode(), commands::InputMode::Dictation);
+
+        assert!(set.switch(&None, &Some("unknown".to_string())).is_err());
+    }
+
+    #[test]
+    fn test_profile_options_yield_to_explicit_flags() {
+        let profile = profile::Profile::builtin("editor").unwrap();
+        profile.validate(&cli()).unwrap();
+        let args = ["voice-keyboard", "--test-stt", "--enter-key", "ctrl-enter"].map(Into::into);
+        let matches = cli().get_matches_from(profile::expand_args(args, &profile));
+
+        // Set by the profile
+        assert!(matches.get_flag("voice-enter"));
+        assert!(matches.get_flag("smart-punctuation"));
+        assert_eq!(
+            matches.get_one::<String>("turn-separator").unwrap(),
+            "space"
+        );
+        // Given on the command line as well, where it wins
+        assert_eq!(
+            matches.get_one::<String>("enter-key").unwrap(),
+            "ctrl-enter"
+        );
+        assert!(matches.get_flag("test-stt"));
+        // A switch the profile turns on can be turned off again
+        let args = ["voice-keyboard", "--no-voice-enter"].map(Into::into);
+        let matches = cli().get_matches_from(profile::expand_args(args, &profile));
+        assert!(!matches.get_flag("voice-enter"));
+        assert!(matches.get_flag("smart-punctuation"));
+        let matches = cli().get_matches_from(["voice-keyboard", "--no-uppercase", "--uppercase"]);
+        assert!(matches.get_flag("uppercase"));
+        // Repeatable options still collect every occurrence
+        let matches =
+            cli().get_matches_from(["voice-keyboard", "--compare-url", "a", "--compare-url", "b"]);
+        assert_eq!(
+            matches.get_many::<String>("compare-url").unwrap().count(),
+            2
+        );
+
+        let mut bad = profile::Profile::default();
+        bad.set("enter-key", None);
+        assert!(bad.validate(&cli()).is_err());
+        bad = profile::Profile::default();
+        bad.set("no-such-flag", None);
+        assert!(bad.validate(&cli()).is_err());
+    }
+
+    #[test]
+    fn test_verify_dropped() {
+        let user = Uid::from_raw(1000);
+        let group = Gid::from_raw(1000);
+        let root = Uid::from_raw(0);
+        let wheel = Gid::from_raw(0);
+        let target = (user, group);
+
+        assert!(verify_dropped(target, (user, user), (group, group)).is_ok());
+        // Effective ids that kept root are the dangerous half-dropped case
+        assert!(verify_dropped(target, (user, root), (group, group)).is_err());
+        assert!(verify_dropped(target, (root, user), (group, group)).is_err());
+        assert!(verify_dropped(target, (user, user), (group, wheel)).is_err());
+        assert!(verify_dropped(target, (user, user), (wheel, group)).is_err());
+    }
+
+    #[test]
+    fn test_env_file_does_not_override_environment() {
+        let dir = env::temp_dir().join(format!("voice-keyboard-env-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join(".env");
+        std::fs::write(
+            &path,
+            "VK_TEST_ENV_FROM_FILE=file\nVK_TEST_ENV_PRESET=file\n",
+        )
+        .unwrap();
+
+        // The shell already set the preset, so only the other one comes through
+        let vars = env_file_vars(&path, |key| key == "VK_TEST_ENV_PRESET").unwrap();
+        let expected = vec![("VK_TEST_ENV_FROM_FILE".into(), "file".into())];
+        assert_eq!(vars, Some(expected));
+
+        let missing = env_file_vars(&dir.join("missing.env"), |_| false).unwrap();
+        assert_eq!(missing, None);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
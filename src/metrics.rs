@@ -0,0 +1,151 @@
+use std::fmt;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+use tokio::signal::unix::{signal, SignalKind};
+use tracing::{info, warn};
+
+/// Process-wide runtime counters, cheap enough to bump on every event
+pub static METRICS: Metrics = Metrics::new();
+
+#[derive(Debug, Default)]
+pub struct Metrics {
+    turns: AtomicU64,
+    reconnects: AtomicU64,
+    audio_bytes_sent: AtomicU64,
+    chars_typed: AtomicU64,
+    backspaces: AtomicU64,
+}
+
+/// A point-in-time copy of the counters
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct MetricsSnapshot {
+    pub turns: u64,
+    pub reconnects: u64,
+    pub audio_bytes_sent: u64,
+    pub chars_typed: u64,
+    pub backspaces: u64,
+}
+
+impl fmt::Display for MetricsSnapshot {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "turns={} reconnects={} audio_bytes_sent={} chars_typed={} backspaces={}",
+            self.turns, self.reconnects, self.audio_bytes_sent, self.chars_typed, self.backspaces
+        )
+    }
+}
+
+impl Metrics {
+    pub const fn new() -> Self {
+        Self {
+            turns: AtomicU64::new(0),
+            reconnects: AtomicU64::new(0),
+            audio_bytes_sent: AtomicU64::new(0),
+            chars_typed: AtomicU64::new(0),
+            backspaces: AtomicU64::new(0),
+        }
+    }
+
+    /// Count an STT event; each "EndOfTurn" is one turn
+    pub fn record_transcription(&self, event: &str) {
+        if event == "EndOfTurn" {
+            self.turns.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    pub fn record_reconnect(&self) {
+        self.reconnects.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn add_audio_bytes(&self, bytes: usize) {
+        self.audio_bytes_sent
+            .fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    pub fn add_chars_typed(&self, chars: usize) {
+        self.chars_typed.fetch_add(chars as u64, Ordering::Relaxed);
+    }
+
+    pub fn record_backspace(&self) {
+        self.backspaces.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn snapshot(&self) -> MetricsSnapshot {
+        MetricsSnapshot {
+            turns: self.turns.load(Ordering::Relaxed),
+            reconnects: self.reconnects.load(Ordering::Relaxed),
+            audio_bytes_sent: self.audio_bytes_sent.load(Ordering::Relaxed),
+            chars_typed: self.chars_typed.load(Ordering::Relaxed),
+            backspaces: self.backspaces.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// Log a stats line on every SIGUSR2, and every `interval` if given
+pub fn spawn_reporter(interval: Option<Duration>) {
+    tokio::spawn(async move {
+        let mut usr2 = match signal(SignalKind::user_defined2()) {
+            Ok(usr2) => usr2,
+            Err(e) => {
+                warn!("Failed to install SIGUSR2 handler for stats: {}", e);
+                return;
+            }
+        };
+
+        let mut ticker = interval.map(|every| {
+            let mut ticker = tokio::time::interval(every);
+            ticker.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+            ticker
+        });
+
+        loop {
+            tokio::select! {
+                _ = usr2.recv() => {}
+                _ = async { ticker.as_mut().unwrap().tick().await }, if ticker.is_some() => {}
+            }
+            info!("Stats: {}", METRICS.snapshot());
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_counters_follow_simulated_events() {
+        let metrics = Metrics::new();
+        for event in [
+            "StartOfTurn",
+            "Update",
+            "Update",
+            "EndOfTurn",
+            "Update",
+            "EndOfTurn",
+        ] {
+            metrics.record_transcription(event);
+        }
+        metrics.record_reconnect();
+        metrics.add_audio_bytes(5120);
+        metrics.add_audio_bytes(5120);
+        metrics.add_chars_typed(11);
+        metrics.record_backspace();
+        metrics.record_backspace();
+
+        assert_eq!(
+            metrics.snapshot(),
+            MetricsSnapshot {
+                turns: 2,
+                reconnects: 1,
+                audio_bytes_sent: 10240,
+                chars_typed: 11,
+                backspaces: 2,
+            }
+        );
+        assert_eq!(
+            metrics.snapshot().to_string(),
+            "turns=2 reconnects=1 audio_bytes_sent=10240 chars_typed=11 backspaces=2"
+        );
+    }
+}
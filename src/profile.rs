@@ -0,0 +1,240 @@
+// Named bundles of command-line options for different target apps
+// (--profile chat). A profile's options are placed in front of the user's
+// own arguments, so precedence is: defaults < built-in profile < profiles
+// file < explicit flags.
+//
+// The profiles file is INI-like:
+//   [chat]
+//   auto-enter
+//   enter-key = ctrl-enter
+// Each line is a long option name (the leading `--` is optional), with
+// `= value` for options that take one. `# ` lines are comments.
+
+use anyhow::{anyhow, bail, Context, Result};
+use std::collections::HashMap;
+use std::ffi::OsString;
+use std::path::Path;
+
+/// Options by name, with a value for those that take one
+type BuiltinOptions = &'static [(&'static str, Option<&'static str>)];
+
+/// Profiles that need no profiles file
+const BUILTIN_PROFILES: &[(&str, BuiltinOptions)] = &[
+    // Every turn is a message, sent with Enter
+    (
+        "chat",
+        &[("auto-enter", None), ("enter-key", Some("enter"))],
+    ),
+    // Sentences run on; a spoken "enter" starts a new line without submitting
+    (
+        "editor",
+        &[
+            ("voice-enter", None),
+            ("smart-punctuation", None),
+            ("enter-key", Some("shift-enter")),
+            ("turn-separator", Some("space")),
+        ],
+    ),
+    // Nothing runs until "enter" is said, and no periods end up in commands
+    ("terminal", &[("voice-enter", None)]),
+];
+
+/// Options set by a profile, in the order they were given
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct Profile {
+    options: Vec<(String, Option<String>)>,
+}
+
+impl Profile {
+    pub fn builtin(name: &str) -> Option<Self> {
+        let (_, options) = BUILTIN_PROFILES.iter().find(|(n, _)| *n == name)?;
+        let mut profile = Self::default();
+        for &(option, value) in options.iter() {
+            profile.set(option, value.map(str::to_string));
+        }
+        Some(profile)
+    }
+
+    /// Set an option, replacing an earlier value for it
+    pub fn set(&mut self, option: &str, value: Option<String>) {
+        match self.options.iter_mut().find(|(o, _)| o == option) {
+            Some(entry) => entry.1 = value,
+            None => self.options.push((option.to_string(), value)),
+        }
+    }
+
+    /// Overlay `other`, whose options win
+    pub fn merge(&mut self, other: &Profile) {
+        for (option, value) in &other.options {
+            self.set(option, value.clone());
+        }
+    }
+
+    /// Check every option against the command line definition
+    pub fn validate(&self, cli: &clap::Command) -> Result<()> {
+        for (option, value) in &self.options {
+            let arg = cli
+                .get_arguments()
+                .find(|arg| arg.get_long() == Some(option.as_str()) && option != "profile")
+                .ok_or_else(|| anyhow!("Profile sets unknown option '--{}'", option))?;
+            match (arg.get_action().takes_values(), value) {
+                (true, None) => bail!("Profile option '--{}' needs a value", option),
+                (false, Some(_)) => bail!("Profile option '--{}' takes no value", option),
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+
+    /// The names of the options it sets
+    pub fn option_names(&self) -> impl Iterator<Item = &str> {
+        self.options.iter().map(|(option, _)| option.as_str())
+    }
+
+    /// The options as command-line arguments
+    pub fn to_args(&self) -> Vec<String> {
+        self.options
+            .iter()
+            .map(|(option, value)| match value {
+                Some(value) => format!("--{option}={value}"),
+                None => format!("--{option}"),
+            })
+            .collect()
+    }
+}
+
+/// Parse a profiles file into profiles by name
+pub fn parse_profiles(text: &str) -> Result<HashMap<String, Profile>> {
+    let mut profiles = HashMap::new();
+    let mut current: Option<&mut Profile> = None;
+    for (number, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line == "#" || line.starts_with("# ") {
+            continue;
+        }
+        if let Some(name) = line.strip_prefix('[').and_then(|l| l.strip_suffix(']')) {
+            let name = name.trim();
+            if name.is_empty() {
+                bail!("Empty profile name on line {}", number + 1);
+            }
+            current = Some(profiles.entry(name.to_string()).or_default());
+            continue;
+        }
+        let profile = current
+            .as_deref_mut()
+            .ok_or_else(|| anyhow!("Option before any [profile] on line {}", number + 1))?;
+        let (option, value) = match line.split_once('=') {
+            Some((option, value)) => (option, Some(value.trim().to_string())),
+            None => (line, None),
+        };
+        let option = option.trim().trim_start_matches("--");
+        if option.is_empty() {
+            bail!("Missing option name on line {}", number + 1);
+        }
+        profile.set(option, value);
+    }
+    Ok(profiles)
+}
+
+/// The named profile: the built-in one, overlaid with its section of the
+/// profiles file if there is one
+pub fn resolve(name: &str, profiles_file: Option<&Path>) -> Result<Profile> {
+    let mut from_file = None;
+    if let Some(path) = profiles_file {
+        match std::fs::read_to_string(path) {
+            Ok(text) => {
+                let mut profiles = parse_profiles(&text)
+                    .with_context(|| format!("Invalid profiles file {}", path.display()))?;
+                from_file = profiles.remove(name);
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e).with_context(|| format!("Failed to read {}", path.display())),
+        }
+    }
+
+    match (Profile::builtin(name), from_file) {
+        (Some(mut profile), Some(file)) => {
+            profile.merge(&file);
+            Ok(profile)
+        }
+        (Some(profile), None) | (None, Some(profile)) => Ok(profile),
+        (None, None) => {
+            let builtin: Vec<&str> = BUILTIN_PROFILES.iter().map(|(n, _)| *n).collect();
+            bail!(
+                "Unknown profile '{}' (built in: {}; others go in {})",
+                name,
+                builtin.join(", "),
+                profiles_file.map_or("a profiles file".into(), |p| p.display().to_string())
+            )
+        }
+    }
+}
+
+/// Command-line arguments with the profile's options ahead of the user's,
+/// so that explicit flags override them
+pub fn expand_args<I>(args: I, profile: &Profile) -> Vec<OsString>
+where
+    I: IntoIterator<Item = OsString>,
+{
+    let mut args = args.into_iter();
+    let mut expanded: Vec<OsString> = args.next().into_iter().collect();
+    expanded.extend(profile.to_args().into_iter().map(OsString::from));
+    expanded.extend(args);
+    expanded
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_profiles_file() {
+        let profiles = parse_profiles(
+            "# per-app settings\n[chat]\n--auto-enter\nenter-key = ctrl-enter\n\n[notes]\nsmart-punctuation\nenter-key=enter\nenter-key = shift-enter\n",
+        )
+        .unwrap();
+
+        assert_eq!(
+            profiles["chat"].to_args(),
+            vec!["--auto-enter", "--enter-key=ctrl-enter"]
+        );
+        // A repeated option keeps its place and takes the last value
+        assert_eq!(
+            profiles["notes"].to_args(),
+            vec!["--smart-punctuation", "--enter-key=shift-enter"]
+        );
+
+        let err = parse_profiles("auto-enter\n[chat]").unwrap_err();
+        assert!(err.to_string().contains("line 1"));
+        assert!(parse_profiles("[ ]").is_err());
+    }
+
+    #[test]
+    fn test_file_section_overlays_builtin() {
+        let path = std::env::temp_dir().join(format!("vk-profiles-{}", std::process::id()));
+        std::fs::write(
+            &path,
+            "[chat]\nenter-key = ctrl-enter\nvoice-commands\n[mine]\nuppercase\n",
+        )
+        .unwrap();
+
+        let chat = resolve("chat", Some(&path)).unwrap();
+        assert_eq!(
+            chat.to_args(),
+            vec!["--auto-enter", "--enter-key=ctrl-enter", "--voice-commands"]
+        );
+        assert_eq!(
+            resolve("mine", Some(&path)).unwrap().to_args(),
+            vec!["--uppercase"]
+        );
+        let _ = std::fs::remove_file(&path);
+
+        // A missing file leaves the built-in profiles
+        assert_eq!(
+            resolve("terminal", Some(&path)).unwrap(),
+            Profile::builtin("terminal").unwrap()
+        );
+        let err = resolve("nope", None).unwrap_err();
+        assert!(err.to_string().contains("chat, editor, terminal"));
+    }
+}
@@ -0,0 +1,70 @@
+// Raw record of the STT WebSocket traffic for --dump-protocol, kept apart
+// from the log so it captures every frame whatever the log level.
+// One line per frame: unix time, direction, then the text as sent; audio
+// frames are summarized by size.
+
+use anyhow::{Context, Result};
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio_tungstenite::tungstenite::Message;
+use tracing::warn;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    Sent,
+    Received,
+}
+
+impl Direction {
+    fn label(self) -> &'static str {
+        match self {
+            Self::Sent => "send",
+            Self::Received => "recv",
+        }
+    }
+}
+
+/// Appends frames to a file; clones share it
+#[derive(Debug, Clone)]
+pub struct ProtocolDump {
+    file: Arc<Mutex<File>>,
+}
+
+impl ProtocolDump {
+    pub fn open(path: &Path) -> Result<Self> {
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .with_context(|| format!("Failed to open protocol dump {}", path.display()))?;
+        Ok(Self {
+            file: Arc::new(Mutex::new(file)),
+        })
+    }
+
+    /// Write one frame; a failed write is logged rather than ending the session
+    pub fn record(&self, direction: Direction, message: &Message) {
+        let body = match message {
+            Message::Text(text) => text.clone(),
+            Message::Binary(data) => format!("binary ({} bytes)", data.len()),
+            Message::Close(frame) => match frame {
+                Some(frame) => format!("close {} {}", u16::from(frame.code), frame.reason),
+                None => "close".to_string(),
+            },
+            // Keepalives say nothing about the protocol
+            Message::Ping(_) | Message::Pong(_) | Message::Frame(_) => return,
+        };
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        let line = format!("{:.3} {} {}\n", now.as_secs_f64(), direction.label(), body);
+
+        let mut file = self.file.lock().unwrap_or_else(|e| e.into_inner());
+        if let Err(e) = file.write_all(line.as_bytes()) {
+            warn!("Failed to write protocol dump: {}", e);
+        }
+    }
+}
@@ -0,0 +1,61 @@
+// Retry policy shared by the STT connection and uinput device creation
+
+use std::time::Duration;
+
+/// Bounded retries with exponential backoff
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RetryPolicy {
+    /// Extra attempts after the first one fails
+    pub retries: u32,
+    /// Delay before the first retry; doubled after each failed attempt
+    pub initial_backoff: Duration,
+    /// Upper bound for the delay between attempts
+    pub max_backoff: Duration,
+}
+
+impl RetryPolicy {
+    /// Try once and give up on failure
+    pub fn none() -> Self {
+        Self {
+            retries: 0,
+            initial_backoff: Duration::ZERO,
+            max_backoff: Duration::ZERO,
+        }
+    }
+
+    /// Delay to wait after the given (zero-based) failed attempt
+    pub fn backoff_for(&self, attempt: u32) -> Duration {
+        let factor = 1u32.checked_shl(attempt).unwrap_or(u32::MAX);
+        self.initial_backoff
+            .saturating_mul(factor)
+            .min(self.max_backoff)
+    }
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            retries: 3,
+            initial_backoff: Duration::from_secs(1),
+            max_backoff: Duration::from_secs(8),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_retry_backoff_doubles_and_caps() {
+        let retry = RetryPolicy {
+            retries: 5,
+            initial_backoff: Duration::from_millis(100),
+            max_backoff: Duration::from_millis(300),
+        };
+        assert_eq!(retry.backoff_for(0), Duration::from_millis(100));
+        assert_eq!(retry.backoff_for(1), Duration::from_millis(200));
+        assert_eq!(retry.backoff_for(2), Duration::from_millis(300));
+        assert_eq!(retry.backoff_for(40), Duration::from_millis(300));
+    }
+}
@@ -1,15 +1,35 @@
 use anyhow::{anyhow, bail, Context, Result};
+use base64::Engine;
 use futures_util::{SinkExt, StreamExt};
-use http::{header::AUTHORIZATION, HeaderValue};
+use http::{
+    header::{AUTHORIZATION, SEC_WEBSOCKET_PROTOCOL},
+    HeaderName, HeaderValue,
+};
 use serde::{Deserialize, Serialize};
 use std::env;
-use tokio::sync::mpsc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::net::TcpStream;
+use tokio::sync::{broadcast, mpsc, Notify};
 use tokio_tungstenite::tungstenite::client::IntoClientRequest;
 use tokio_tungstenite::tungstenite::error::Error as WsError;
-use tokio_tungstenite::{connect_async, tungstenite::Message};
-use tracing::{debug, error, info};
+use tokio_tungstenite::tungstenite::handshake::client::Request;
+use tokio_tungstenite::{connect_async, tungstenite::Message, MaybeTlsStream, WebSocketStream};
+use tracing::{debug, error, info, warn};
+
+use crate::audio_processing::Encoding;
+use crate::error::VkError;
+use crate::metrics::METRICS;
+use crate::protocol_dump::{Direction, ProtocolDump};
+use crate::retry::RetryPolicy;
+
+type WsStream = WebSocketStream<MaybeTlsStream<TcpStream>>;
 
 pub const STT_URL: &str = "wss://api.deepgram.com/v2/listen";
+pub const DEFAULT_MODEL: &str = "flux-general-en";
+/// How long one connection attempt (TCP, TLS and the WebSocket upgrade) may take
+pub const DEFAULT_CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct WordInfo {
@@ -28,6 +48,121 @@ pub struct TranscriptionResult {
     pub end_of_turn_confidence: f64,
 }
 
+/// Passed to the transcription callback (with no transcript) when the service
+/// dropped the session and a reconnect begins; the open turn was discarded, so
+/// the next results start a fresh line
+pub const DISCONNECTED_EVENT: &str = "Disconnected";
+
+/// Passed to the transcription callback once a new session replaced a dropped one
+pub const RECONNECTED_EVENT: &str = "Reconnected";
+
+impl TranscriptionResult {
+    /// A connection event for the callback, outside any turn
+    fn connection_event(event: &str) -> Self {
+        Self {
+            event: event.to_string(),
+            turn_index: 0,
+            start: 0.0,
+            timestamp: 0.0,
+            transcript: String::new(),
+            words: Vec::new(),
+            end_of_turn_confidence: 0.0,
+        }
+    }
+}
+
+/// Tracks the turn in progress so the line is finalized even when the server
+/// doesn't end it: an EndOfTurn below the confidence floor is passed on as an
+/// Update, and a turn left open is ended when the next starts or the
+/// connection closes
+#[derive(Debug, Default)]
+struct TurnGate {
+    min_eot_confidence: Option<f64>,
+    open_turn: Option<TranscriptionResult>,
+}
+
+impl TurnGate {
+    fn new(min_eot_confidence: Option<f64>) -> Self {
+        Self {
+            min_eot_confidence,
+            open_turn: None,
+        }
+    }
+
+    /// The results to hand on for one from the server, in order
+    fn accept(&mut self, mut result: TranscriptionResult) -> Vec<TranscriptionResult> {
+        let mut results = Vec::new();
+        if self
+            .open_turn
+            .as_ref()
+            .is_some_and(|open| open.turn_index != result.turn_index)
+        {
+            results.extend(self.close());
+        }
+
+        if result.event == "EndOfTurn"
+            && self
+                .min_eot_confidence
+                .is_some_and(|min| result.end_of_turn_confidence < min)
+        {
+            debug!(
+                "End of turn {} at confidence {:.2} is below the minimum; treating it as an update",
+                result.turn_index, result.end_of_turn_confidence
+            );
+            result.event = "Update".to_string();
+        }
+        self.open_turn = (result.event != "EndOfTurn").then(|| result.clone());
+        results.push(result);
+        results
+    }
+
+    /// Forget the open turn without ending it; returns its text
+    fn discard(&mut self) -> Option<String> {
+        self.open_turn.take().map(|turn| turn.transcript)
+    }
+
+    /// End the open turn, if it has any text
+    fn close(&mut self) -> Option<TranscriptionResult> {
+        let turn = self
+            .open_turn
+            .take()
+            .filter(|turn| !turn.transcript.is_empty())?;
+        Some(TranscriptionResult {
+            event: "EndOfTurn".to_string(),
+            ..turn
+        })
+    }
+}
+
+/// Control message asking the server to end the current turn now
+const FINALIZE_MESSAGE: &str = r#"{"type":"Finalize"}"#;
+
+/// Error code a server answers a control message it doesn't know with
+pub const UNSUPPORTED_MESSAGE_CODE: &str = "UNPARSABLE_CLIENT_MESSAGE";
+
+/// Asks an open connection to send `Finalize`, so the server closes the turn
+/// without waiting for its endpointing. Clones fire the same connection.
+#[derive(Debug, Clone, Default)]
+pub struct FinalizeTrigger(Arc<Notify>);
+
+impl FinalizeTrigger {
+    pub fn fire(&self) {
+        self.0.notify_one();
+    }
+
+    async fn fired(&self) {
+        self.0.notified().await;
+    }
+}
+
+/// Whether a Finalize is waiting on the server, and whether the server has
+/// turned one down (after which none are sent)
+#[derive(Debug, Default)]
+struct FinalizeState {
+    pending: AtomicBool,
+    unsupported: AtomicBool,
+}
+
 // New server message schema with `type` discriminator
 #[derive(Debug, Clone, Deserialize)]
 #[serde(tag = "type")]
@@ -63,48 +198,226 @@ enum ServerMessage {
         #[serde(default)]
         preflight_threshold: Option<f64>,
     },
+    // A type added to the schema after this client; ignored
+    #[serde(other)]
+    Unknown,
 }
 
 fn enrich_ws_error(err: WsError) -> anyhow::Error {
-    match err {
-        WsError::Http(resp) => {
-            let (parts, body_opt) = resp.into_parts();
-            let status = parts.status;
-            let headers = parts.headers;
-            let mut header_lines = String::new();
-            for (k, v) in headers.iter() {
-                // Limit very long values
-                let val = v.to_str().unwrap_or("<binary>");
-                let shortened = if val.len() > 256 { &val[..256] } else { val };
-                header_lines.push_str(&format!("\n  {}: {}", k, shortened));
+    VkError::from(err).into()
+}
+
+/// How the API key is presented in the Authorization header
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub enum AuthScheme {
+    /// `Token <key>` (Deepgram)
+    #[default]
+    Token,
+    /// `Bearer <key>`
+    Bearer,
+    /// `Basic base64(<user:password>)`; the key holds `user:password`
+    Basic,
+    /// `<scheme> <key>` for any other scheme name
+    Custom(String),
+}
+
+impl AuthScheme {
+    /// Build the Authorization header value for the given key/credentials
+    pub fn header_value(&self, credentials: &str) -> Result<HeaderValue> {
+        let value = match self {
+            AuthScheme::Token => format!("Token {credentials}"),
+            AuthScheme::Bearer => format!("Bearer {credentials}"),
+            AuthScheme::Basic => format!(
+                "Basic {}",
+                base64::engine::general_purpose::STANDARD.encode(credentials)
+            ),
+            AuthScheme::Custom(scheme) => format!("{scheme} {credentials}"),
+        };
+        HeaderValue::from_str(&value)
+            .map_err(|_| anyhow!("Invalid Authorization header value for {:?} scheme", self))
+    }
+}
+
+impl std::str::FromStr for AuthScheme {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_ascii_lowercase().as_str() {
+            "token" => Ok(AuthScheme::Token),
+            "bearer" => Ok(AuthScheme::Bearer),
+            "basic" => Ok(AuthScheme::Basic),
+            "" => bail!("Auth scheme must not be empty"),
+            _ if s.chars().any(|c| c.is_whitespace()) => {
+                bail!("Auth scheme '{}' must not contain whitespace", s)
             }
-            let body_text = body_opt
-                .as_ref()
-                .map(|b| String::from_utf8_lossy(b).to_string())
-                .unwrap_or_else(|| "<no body>".to_string());
-            anyhow!(
-                "WebSocket HTTP handshake failed: {}\nHeaders:{}\nBody: {}",
-                status,
-                header_lines,
-                body_text
-            )
+            _ => Ok(AuthScheme::Custom(s.to_string())),
         }
-        WsError::Io(e) => anyhow!("WebSocket I/O error: {}", e),
-        WsError::Tls(e) => anyhow!("WebSocket TLS error: {}", e),
-        WsError::Protocol(e) => anyhow!("WebSocket protocol error: {}", e),
-        WsError::Capacity(e) => anyhow!("WebSocket capacity error: {}", e),
-        WsError::AlreadyClosed => anyhow!("WebSocket already closed"),
-        WsError::ConnectionClosed => anyhow!("WebSocket connection closed"),
-        WsError::Url(e) => anyhow!("WebSocket URL error: {}", e),
-        WsError::HttpFormat(e) => anyhow!("WebSocket HTTP format error: {}", e),
-        WsError::Utf8 => anyhow!("WebSocket UTF-8 error"),
-        other => anyhow!(other),
     }
 }
 
+/// Parse a `--min-eot-confidence` value between 0 and 1
+pub fn parse_confidence(raw: &str) -> Result<f64> {
+    let confidence: f64 = raw
+        .parse()
+        .map_err(|_| anyhow!("Invalid confidence '{}': expected a number", raw))?;
+    if !(0.0..=1.0).contains(&confidence) {
+        bail!("Confidence {} is outside 0 to 1", confidence);
+    }
+    Ok(confidence)
+}
+
+/// Parse a `Name: value` header, rejecting invalid names or values
+pub fn parse_header(raw: &str) -> Result<(HeaderName, HeaderValue)> {
+    let (name, value) = raw
+        .split_once(':')
+        .ok_or_else(|| anyhow!("Invalid header '{}': expected NAME:VALUE", raw))?;
+    let name = HeaderName::from_bytes(name.trim().as_bytes())
+        .with_context(|| format!("Invalid header name in '{raw}'"))?;
+    let value = HeaderValue::from_str(value.trim())
+        .with_context(|| format!("Invalid header value in '{raw}'"))?;
+    Ok((name, value))
+}
+
+/// `--quality` presets over the model and end-of-turn settings, for users who
+/// don't know the provider's model names
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Quality {
+    /// End turns early: least delay, more lines cut mid-thought
+    Fast,
+    /// The service's own defaults
+    Balanced,
+    /// Wait for surer endpoints before ending a turn
+    Accurate,
+}
+
+/// What a quality preset sends as query parameters
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct QualityParams {
+    pub model: &'static str,
+    pub eot_threshold: f64,
+    pub eot_timeout_ms: u32,
+}
+
+impl Quality {
+    /// The parameters behind each preset; update them here as the provider's
+    /// models and recommendations change
+    pub fn params(self) -> QualityParams {
+        match self {
+            Self::Fast => QualityParams {
+                model: DEFAULT_MODEL,
+                eot_threshold: 0.6,
+                eot_timeout_ms: 3000,
+            },
+            Self::Balanced => QualityParams {
+                model: DEFAULT_MODEL,
+                eot_threshold: 0.7,
+                eot_timeout_ms: 5000,
+            },
+            Self::Accurate => QualityParams {
+                model: DEFAULT_MODEL,
+                eot_threshold: 0.85,
+                eot_timeout_ms: 7000,
+            },
+        }
+    }
+}
+
+impl std::str::FromStr for Quality {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_ascii_lowercase().as_str() {
+            "fast" => Ok(Self::Fast),
+            "balanced" => Ok(Self::Balanced),
+            "accurate" => Ok(Self::Accurate),
+            other => bail!(
+                "Unknown quality '{}' (expected fast, balanced or accurate)",
+                other
+            ),
+        }
+    }
+}
+
+/// Connection settings for the STT service, independent of the audio format
+#[derive(Debug, Clone)]
+pub struct SttConfig {
+    pub url: String,
+    pub auth_scheme: AuthScheme,
+    pub retry: RetryPolicy,
+    /// Extra handshake headers, e.g. for API gateways
+    pub headers: Vec<(HeaderName, HeaderValue)>,
+    /// WebSocket subprotocol to request
+    pub subprotocol: Option<String>,
+    pub encoding: Encoding,
+    /// Extra services that get the same audio; their transcripts are only logged
+    pub compare_urls: Vec<String>,
+    /// Where to record every frame exchanged with the service
+    pub protocol_dump: Option<ProtocolDump>,
+    /// EndOfTurn events less confident than this don't finalize the line
+    pub min_eot_confidence: Option<f64>,
+    pub model: String,
+    /// End-of-turn confidence the service needs to end a turn; its default when `None`
+    pub eot_threshold: Option<f64>,
+    /// Silence after which the service ends a turn regardless; its default when `None`
+    pub eot_timeout_ms: Option<u32>,
+    /// Fired to have the service end the current turn early
+    pub finalize: FinalizeTrigger,
+    /// Limit for each connection attempt, including the handshake
+    pub connect_timeout: Duration,
+    /// Reconnect after the service ends the session, until this many
+    /// attempts in a row fail; a dropped session just ends when `None`
+    pub max_reconnects: Option<u32>,
+}
+
+impl SttConfig {
+    /// Take the model and end-of-turn settings from a preset
+    pub fn apply_quality(&mut self, quality: Quality) {
+        let params = quality.params();
+        self.model = params.model.to_string();
+        self.eot_threshold = Some(params.eot_threshold);
+        self.eot_timeout_ms = Some(params.eot_timeout_ms);
+    }
+}
+
+impl Default for SttConfig {
+    fn default() -> Self {
+        Self {
+            url: STT_URL.to_string(),
+            auth_scheme: AuthScheme::default(),
+            retry: RetryPolicy::default(),
+            headers: Vec::new(),
+            subprotocol: None,
+            encoding: Encoding::default(),
+            compare_urls: Vec::new(),
+            protocol_dump: None,
+            min_eot_confidence: None,
+            model: DEFAULT_MODEL.to_string(),
+            eot_threshold: None,
+            eot_timeout_ms: None,
+            finalize: FinalizeTrigger::default(),
+            connect_timeout: DEFAULT_CONNECT_TIMEOUT,
+            max_reconnects: None,
+        }
+    }
+}
+
+#[derive(Clone)]
 pub struct SttClient {
     url: String,
     sample_rate: u32,
+    auth_scheme: AuthScheme,
+    retry: RetryPolicy,
+    headers: Vec<(HeaderName, HeaderValue)>,
+    subprotocol: Option<String>,
+    encoding: Encoding,
+    protocol_dump: Option<ProtocolDump>,
+    min_eot_confidence: Option<f64>,
+    model: String,
+    eot_threshold: Option<f64>,
+    eot_timeout_ms: Option<u32>,
+    finalize: FinalizeTrigger,
+    connect_timeout: Duration,
+    max_reconnects: Option<u32>,
 }
 
 impl SttClient {
@@ -112,21 +425,125 @@ impl SttClient {
         Self {
             url: url.to_string(),
             sample_rate,
+            auth_scheme: AuthScheme::default(),
+            retry: RetryPolicy::none(),
+            headers: Vec::new(),
+            subprotocol: None,
+            encoding: Encoding::default(),
+            protocol_dump: None,
+            min_eot_confidence: None,
+            model: DEFAULT_MODEL.to_string(),
+            eot_threshold: None,
+            eot_timeout_ms: None,
+            finalize: FinalizeTrigger::default(),
+            connect_timeout: DEFAULT_CONNECT_TIMEOUT,
+            max_reconnects: None,
         }
     }
 
-    pub async fn connect_and_transcribe<F>(
-        &self,
-        mut on_transcription: F,
-    ) -> Result<(mpsc::Sender<Vec<u8>>, tokio::task::JoinHandle<Result<()>>)>
-    where
-        F: FnMut(TranscriptionResult) + Send + 'static,
-    {
+    pub fn from_config(config: &SttConfig, sample_rate: u32) -> Self {
+        let mut client = Self::new(&config.url, sample_rate);
+        client.set_auth_scheme(config.auth_scheme.clone());
+        client.set_retry_policy(config.retry);
+        client.set_headers(config.headers.clone());
+        client.set_subprotocol(config.subprotocol.clone());
+        client.set_encoding(config.encoding);
+        client.set_protocol_dump(config.protocol_dump.clone());
+        client.set_min_eot_confidence(config.min_eot_confidence);
+        client.set_model(&config.model);
+        client.set_eot_threshold(config.eot_threshold);
+        client.set_eot_timeout_ms(config.eot_timeout_ms);
+        client.set_finalize_trigger(config.finalize.clone());
+        client.set_connect_timeout(config.connect_timeout);
+        client.set_max_reconnects(config.max_reconnects);
+        client
+    }
+
+    /// Set the Authorization scheme used with DEEPGRAM_API_KEY
+    pub fn set_auth_scheme(&mut self, scheme: AuthScheme) {
+        self.auth_scheme = scheme;
+    }
+
+    /// Set how often the initial connection is retried before giving up
+    pub fn set_retry_policy(&mut self, retry: RetryPolicy) {
+        self.retry = retry;
+    }
+
+    /// Set how long a connection attempt may take before it counts as failed
+    pub fn set_connect_timeout(&mut self, timeout: Duration) {
+        self.connect_timeout = timeout;
+    }
+
+    /// Reconnect when the service ends the session without being asked,
+    /// giving up once `max` attempts in a row have failed (backing off per
+    /// the retry policy); `None` lets the session end instead
+    pub fn set_max_reconnects(&mut self, max: Option<u32>) {
+        self.max_reconnects = max;
+    }
+
+    /// Set extra headers sent with the WebSocket handshake
+    pub fn set_headers(&mut self, headers: Vec<(HeaderName, HeaderValue)>) {
+        self.headers = headers;
+    }
+
+    /// Set the WebSocket subprotocol requested during the handshake
+    pub fn set_subprotocol(&mut self, subprotocol: Option<String>) {
+        self.subprotocol = subprotocol;
+    }
+
+    /// Set the audio encoding announced to the service
+    pub fn set_encoding(&mut self, encoding: Encoding) {
+        self.encoding = encoding;
+    }
+
+    /// Record every frame sent and received, for debugging schema problems
+    pub fn set_protocol_dump(&mut self, dump: Option<ProtocolDump>) {
+        self.protocol_dump = dump;
+    }
+
+    /// Only finalize on EndOfTurn events at least this confident; less
+    /// certain ones are passed on as updates
+    pub fn set_min_eot_confidence(&mut self, min: Option<f64>) {
+        self.min_eot_confidence = min;
+    }
+
+    /// Set the model requested from the service
+    pub fn set_model(&mut self, model: &str) {
+        self.model = model.to_string();
+    }
+
+    /// Set the end-of-turn confidence the service needs to end a turn
+    pub fn set_eot_threshold(&mut self, threshold: Option<f64>) {
+        self.eot_threshold = threshold;
+    }
+
+    /// Set the silence after which the service ends a turn regardless
+    pub fn set_eot_timeout_ms(&mut self, timeout_ms: Option<u32>) {
+        self.eot_timeout_ms = timeout_ms;
+    }
+
+    /// Firing `trigger` has the open connection send Finalize, forcing the
+    /// server to end the current turn. A server that rejects it is left to
+    /// its own endpointing.
+    pub fn set_finalize_trigger(&mut self, trigger: FinalizeTrigger) {
+        self.finalize = trigger;
+    }
+
+    fn build_request(&self) -> Result<Request> {
         // Build WebSocket URL with query parameters
-        let ws_url = format!(
-            "{}?model=flux-general-en&sample_rate={}&encoding=linear16",
-            self.url, self.sample_rate
+        let mut ws_url = format!(
+            "{}?model={}&sample_rate={}&encoding={}",
+            self.url,
+            self.model,
+            self.sample_rate,
+            self.encoding.as_str()
         );
+        if let Some(threshold) = self.eot_threshold {
+            ws_url.push_str(&format!("&eot_threshold={threshold}"));
+        }
+        if let Some(timeout_ms) = self.eot_timeout_ms {
+            ws_url.push_str(&format!("&eot_timeout_ms={timeout_ms}"));
+        }
 
         debug!("Connecting to speech-to-text service: {}", ws_url);
 
@@ -138,26 +555,182 @@ impl SttClient {
         // Optional Authorization from environment
         if let Ok(api_key) = env::var("DEEPGRAM_API_KEY") {
             if !api_key.is_empty() {
-                let value = format!("Token {api_key}");
-                match HeaderValue::from_str(&value) {
-                    Ok(hv) => {
-                        request.headers_mut().insert(AUTHORIZATION, hv);
-                        debug!("Added Authorization header from DEEPGRAM_API_KEY");
-                    }
-                    Err(_) => {
-                        // Treat invalid header as fatal
-                        bail!(
-                            "Invalid Authorization header value constructed from DEEPGRAM_API_KEY"
-                        );
-                    }
-                }
+                // Treat invalid header as fatal
+                let hv = self.auth_scheme.header_value(&api_key).context(
+                    "Invalid Authorization header value constructed from DEEPGRAM_API_KEY",
+                )?;
+                request.headers_mut().insert(AUTHORIZATION, hv);
+                debug!(
+                    "Added {:?} Authorization header from DEEPGRAM_API_KEY",
+                    self.auth_scheme
+                );
             }
         } else {
             debug!("DEEPGRAM_API_KEY not set; connecting without Authorization header");
         }
 
-        // Establish WebSocket connection with the request
-        let (ws_stream, _resp) = connect_async(request).await.map_err(enrich_ws_error)?;
+        // Gateway extras; appended so repeated names are all sent
+        for (name, value) in &self.headers {
+            request.headers_mut().append(name.clone(), value.clone());
+            debug!("Added {} header", name);
+        }
+
+        if let Some(protocol) = &self.subprotocol {
+            let hv = HeaderValue::from_str(protocol)
+                .with_context(|| format!("Invalid WebSocket subprotocol '{protocol}'"))?;
+            request.headers_mut().insert(SEC_WEBSOCKET_PROTOCOL, hv);
+            debug!("Requesting WebSocket subprotocol '{}'", protocol);
+        }
+
+        Ok(request)
+    }
+
+    /// Open the WebSocket, retrying the handshake per the retry policy
+    async fn connect_with_retry(&self) -> Result<WsStream> {
+        let mut attempt = 0;
+        loop {
+            // Request construction errors are configuration problems; don't retry them
+            let request = self.build_request()?;
+            // A black-holed network or a server that never upgrades would otherwise hang here
+            let connect = tokio::time::timeout(self.connect_timeout, connect_async(request));
+            let connected = match connect.await {
+                Ok(result) => result.map_err(enrich_ws_error),
+                Err(_) => Err(VkError::Connection(format!(
+                    "Timed out after {:.1}s connecting to the speech-to-text service",
+                    self.connect_timeout.as_secs_f32()
+                ))
+                .into()),
+            };
+            match connected {
+                Ok((ws_stream, _resp)) => return Ok(ws_stream),
+                // Retrying won't fix rejected credentials
+                Err(e)
+                    if attempt < self.retry.retries
+                        && !matches!(VkError::find(&e), Some(VkError::AuthRejected { .. })) =>
+                {
+                    let delay = self.retry.backoff_for(attempt);
+                    attempt += 1;
+                    warn!(
+                        "Could not reach the speech-to-text service (attempt {}/{}): {}",
+                        attempt,
+                        self.retry.retries + 1,
+                        e
+                    );
+                    warn!("Retrying in {:.1}s...", delay.as_secs_f32());
+                    tokio::time::sleep(delay).await;
+                }
+                Err(e) => {
+                    return Err(e.context(format!(
+                        "Giving up after {} connection attempt(s)",
+                        attempt + 1
+                    )))
+                }
+            }
+        }
+    }
+
+    /// Connect and stream: audio sent on the returned sender goes to the
+    /// service, and the handle finishes when the session does. With
+    /// `set_max_reconnects`, a session the service ends is replaced by a new
+    /// connection behind the same sender; the callback sees
+    /// `DISCONNECTED_EVENT` when that starts and `RECONNECTED_EVENT` once it
+    /// succeeds.
+    pub async fn connect_and_transcribe<F>(
+        &self,
+        mut on_transcription: F,
+    ) -> Result<(mpsc::Sender<Vec<u8>>, tokio::task::JoinHandle<Result<()>>)>
+    where
+        F: FnMut(TranscriptionResult) + Clone + Send + 'static,
+    {
+        let Some(max_reconnects) = self.max_reconnects else {
+            return self.connect_session(on_transcription, true).await;
+        };
+        // The turn a dropped session leaves open is discarded rather than
+        // finalized: the words are on screen, but no Enter or command for them
+        let (mut sink, mut session) = self
+            .connect_session(on_transcription.clone(), false)
+            .await?;
+
+        let (audio_tx, mut audio_rx) = mpsc::channel::<Vec<u8>>(32);
+        let client = self.clone();
+        let handle = tokio::spawn(async move {
+            loop {
+                tokio::select! {
+                    audio_data = audio_rx.recv() => match audio_data {
+                        // A closed session fails the send; the other branch reconnects
+                        Some(audio_data) => {
+                            let _ = sink.send(audio_data).await;
+                        }
+                        None => {
+                            drop(sink);
+                            return session.await?;
+                        }
+                    },
+                    result = &mut session => {
+                        match result? {
+                            Ok(()) => warn!("The speech-to-text service ended the session"),
+                            Err(e) => warn!("Lost the speech-to-text session: {:#}", e),
+                        }
+                        on_transcription(TranscriptionResult::connection_event(DISCONNECTED_EVENT));
+                        (sink, session) = client
+                            .reconnect(on_transcription.clone(), max_reconnects)
+                            .await?;
+                        info!("Reconnected to the speech-to-text service");
+                        on_transcription(TranscriptionResult::connection_event(RECONNECTED_EVENT));
+                    }
+                }
+            }
+        });
+
+        Ok((audio_tx, handle))
+    }
+
+    /// Connect again after a session ended, allowing `max_reconnects` failed
+    /// attempts in a row
+    async fn reconnect<F>(
+        &self,
+        on_transcription: F,
+        max_reconnects: u32,
+    ) -> Result<(mpsc::Sender<Vec<u8>>, tokio::task::JoinHandle<Result<()>>)>
+    where
+        F: FnMut(TranscriptionResult) + Send + 'static,
+    {
+        let gave_up = || {
+            format!(
+                "Lost the speech-to-text service and {max_reconnects} reconnect(s) in a row failed"
+            )
+        };
+        if max_reconnects == 0 {
+            return Err(VkError::Connection(gave_up()).into());
+        }
+        // Once per lost session, however many attempts getting it back takes
+        METRICS.record_reconnect();
+        let client = SttClient {
+            retry: RetryPolicy {
+                retries: max_reconnects - 1,
+                ..self.retry
+            },
+            ..self.clone()
+        };
+        client
+            .connect_session(on_transcription, false)
+            .await
+            .with_context(gave_up)
+    }
+
+    /// Open one connection and stream over it until the session ends
+    /// A turn still open when the server closes is finalized if we were
+    /// closing the stream or `finish_open_turn` is set, and discarded otherwise.
+    async fn connect_session<F>(
+        &self,
+        mut on_transcription: F,
+        finish_open_turn: bool,
+    ) -> Result<(mpsc::Sender<Vec<u8>>, tokio::task::JoinHandle<Result<()>>)>
+    where
+        F: FnMut(TranscriptionResult) + Send + 'static,
+    {
+        // Establish WebSocket connection
+        let ws_stream = self.connect_with_retry().await?;
 
         debug!("Connected to speech-to-text service");
 
@@ -165,29 +738,61 @@ impl SttClient {
 
         // Create channel for sending audio data
         let (audio_tx, mut audio_rx) = mpsc::channel::<Vec<u8>>(32);
+        let send_dump = self.protocol_dump.clone();
+        let receive_dump = self.protocol_dump.clone();
+        let min_eot_confidence = self.min_eot_confidence;
+        let finalize = self.finalize.clone();
+        let send_finalize_state = Arc::new(FinalizeState::default());
+        let receive_finalize_state = send_finalize_state.clone();
+        // Set once we send CloseStream, so the server closing after it is expected
+        let closing = Arc::new(AtomicBool::new(false));
+        let send_closing = closing.clone();
+        let dump = |dump: &Option<ProtocolDump>, direction, message: &Message| {
+            if let Some(dump) = dump {
+                dump.record(direction, message);
+            }
+        };
 
         // Spawn task to handle WebSocket communication
         let handle = tokio::spawn(async move {
             // Task to send audio data (fatal on send error)
             let send_task = tokio::spawn(async move {
-                while let Some(audio_data) = audio_rx.recv().await {
-                    if let Err(e) = ws_sender
-                        .send(Message::Binary(audio_data))
-                        .await
-                        .map_err(enrich_ws_error)
-                    {
+                loop {
+                    let audio_data = tokio::select! {
+                        biased;
+                        _ = finalize.fired() => {
+                            if send_finalize_state.unsupported.load(Ordering::Relaxed) {
+                                debug!("Not sending Finalize; the server rejected it before");
+                                continue;
+                            }
+                            let message = Message::Text(FINALIZE_MESSAGE.to_string());
+                            debug!("Sending Finalize control message");
+                            dump(&send_dump, Direction::Sent, &message);
+                            send_finalize_state.pending.store(true, Ordering::Relaxed);
+                            ws_sender.send(message).await.map_err(enrich_ws_error)?;
+                            continue;
+                        }
+                        audio_data = audio_rx.recv() => match audio_data {
+                            Some(audio_data) => audio_data,
+                            None => break,
+                        },
+                    };
+                    let len = audio_data.len();
+                    let message = Message::Binary(audio_data);
+                    dump(&send_dump, Direction::Sent, &message);
+                    if let Err(e) = ws_sender.send(message).await.map_err(enrich_ws_error) {
                         error!("Failed to send audio data: {}", e);
                         return Err(e);
                     }
+                    METRICS.add_audio_bytes(len);
                 }
 
                 // Audio channel closed: inform server no more audio is coming
-                let close_msg = String::from("{\"type\":\"CloseStream\"}");
+                send_closing.store(true, Ordering::Relaxed);
+                let close_msg = Message::Text(String::from("{\"type\":\"CloseStream\"}"));
                 debug!("Sending CloseStream control message");
-                ws_sender
-                    .send(Message::Text(close_msg))
-                    .await
-                    .map_err(enrich_ws_error)?;
+                dump(&send_dump, Direction::Sent, &close_msg);
+                ws_sender.send(close_msg).await.map_err(enrich_ws_error)?;
 
                 // Do not close the socket from client; server will close after sending responses
                 Ok::<(), anyhow::Error>(())
@@ -195,7 +800,12 @@ impl SttClient {
 
             // Task to receive messages (fatal on parse/socket error per policy)
             let receive_task = tokio::spawn(async move {
+                let mut turns = TurnGate::new(min_eot_confidence);
+                let mut lost = None;
                 while let Some(msg) = ws_receiver.next().await {
+                    if let Ok(message) = &msg {
+                        dump(&receive_dump, Direction::Received, message);
+                    }
                     match msg {
                         Ok(Message::Text(text)) => {
                             debug!("Received text message: {}", text);
@@ -205,7 +815,10 @@ impl SttClient {
                                 Ok(m) => m,
                                 Err(e) => {
                                     error!("Failed to parse message JSON: {} in {}", e, text);
-                                    return Err(anyhow!("invalid server JSON: {e}"));
+                                    return Err(VkError::Protocol(format!(
+                                        "invalid server JSON: {e}"
+                                    ))
+                                    .into());
                                 }
                             };
 
@@ -231,15 +844,35 @@ impl SttClient {
                                     description,
                                     websocket_close_code,
                                 } => {
+                                    // Not every server knows Finalize; carry on without it
+                                    if code == UNSUPPORTED_MESSAGE_CODE
+                                        && receive_finalize_state
+                                            .pending
+                                            .swap(false, Ordering::Relaxed)
+                                    {
+                                        warn!(
+                                            "Server rejected Finalize ({}: {}); leaving turn ends to its endpointing",
+                                            code, description
+                                        );
+                                        receive_finalize_state
+                                            .unsupported
+                                            .store(true, Ordering::Relaxed);
+                                        continue;
+                                    }
                                     error!(
                                         "Server error [{}]: {} (close_code={:?}, seq={:?})",
                                         code, description, websocket_close_code, sequence_id
                                     );
-                                    return Err(anyhow!(
-                                        "server error: {} - {}",
-                                        code,
-                                        description
-                                    ));
+                                    return Err(VkError::Server { code, description }.into());
+                                }
+                                ServerMessage::Unknown => {
+                                    let kind = serde_json::from_str::<serde_json::Value>(&text)
+                                        .ok()
+                                        .and_then(|value| {
+                                            value["type"].as_str().map(str::to_string)
+                                        })
+                                        .unwrap_or_default();
+                                    warn!("Ignoring server message of unknown type '{}'", kind);
                                 }
                                 ServerMessage::TurnInfo {
                                     request_id: _,
@@ -262,12 +895,23 @@ impl SttClient {
                                         words,
                                         end_of_turn_confidence,
                                     };
-                                    on_transcription(result);
+                                    METRICS.record_transcription(&result.event);
+                                    if result.event == "EndOfTurn" {
+                                        receive_finalize_state
+                                            .pending
+                                            .store(false, Ordering::Relaxed);
+                                    }
+                                    for result in turns.accept(result) {
+                                        on_transcription(result);
+                                    }
                                 }
                             }
                         }
                         Ok(Message::Binary(_data)) => {
-                            return Err(anyhow!("received binary data--this isn't expected"))
+                            return Err(VkError::Protocol(
+                                "received binary data--this isn't expected".to_string(),
+                            )
+                            .into())
                         }
                         Ok(Message::Close(_)) => {
                             debug!("WebSocket closed by server");
@@ -276,34 +920,100 @@ impl SttClient {
                         Err(e) => {
                             let e2 = enrich_ws_error(e);
                             error!("WebSocket error: {}", e2);
-                            return Err(e2);
+                            lost = Some(e2);
+                            break;
                         }
                         _ => {}
                     }
                 }
-                Ok::<(), anyhow::Error>(())
+
+                // Closing (or dropping) mid-turn would leave the line (and
+                // any trailing command word) unfinished, so end the turn ourselves,
+                // unless a reconnect will start the line over
+                if finish_open_turn || closing.load(Ordering::Relaxed) {
+                    if let Some(turn) = turns.close() {
+                        info!("Server closed mid-turn, finalizing: {}", turn.transcript);
+                        on_transcription(turn);
+                    }
+                } else if let Some(text) = turns.discard() {
+                    info!("Session lost mid-turn, dropping: {}", text);
+                }
+                // After our CloseStream, the server hanging up uncleanly loses nothing
+                match lost {
+                    Some(e) if !closing.load(Ordering::Relaxed) => Err(e),
+                    _ => Ok(()),
+                }
             });
 
-            // Wait for both tasks to finish
-            let (_sr, _rr) = tokio::try_join!(send_task, receive_task)?;
+            // The session is over once the server closes. Normally that follows
+            // our CloseStream; if the server went first, stop streaming to it.
+            let received = receive_task.await?;
+            if send_task.is_finished() {
+                let _sr = send_task.await?;
+            } else {
+                debug!("Server closed while audio was still streaming");
+                send_task.abort();
+            }
 
-            Ok(())
+            // A server error or a dropped socket ends the session with it
+            received
         });
 
         Ok((audio_tx, handle))
     }
 }
 
+/// Chunks a slow connection may fall behind before it starts skipping audio
+pub const FAN_OUT_CAPACITY: usize = 64;
+
+/// Copy every audio chunk sent on the returned sender to each of `sinks`
+/// A sink that falls more than `capacity` chunks behind skips the oldest ones
+/// rather than holding up the others. Dropping every sender closes all sinks.
+pub fn fan_out(sinks: Vec<mpsc::Sender<Vec<u8>>>, capacity: usize) -> broadcast::Sender<Vec<u8>> {
+    let (tx, _) = broadcast::channel(capacity);
+    for (index, sink) in sinks.into_iter().enumerate() {
+        let mut rx = tx.subscribe();
+        tokio::spawn(async move {
+            loop {
+                match rx.recv().await {
+                    Ok(chunk) => {
+                        if sink.send(chunk).await.is_err() {
+                            debug!("STT connection {} stopped taking audio", index);
+                            break;
+                        }
+                    }
+                    Err(broadcast::error::RecvError::Lagged(skipped)) => {
+                        warn!(
+                            "STT connection {} fell behind; skipped {} audio chunks",
+                            index, skipped
+                        );
+                    }
+                    Err(broadcast::error::RecvError::Closed) => break,
+                }
+            }
+        });
+    }
+    tx
+}
+
+/// Default audio sent per WebSocket message.
+/// Smaller chunks reach the server sooner (lower latency) but cost more
+/// messages and per-frame overhead; larger chunks batch better but add delay.
+pub const DEFAULT_CHUNK_MS: u32 = 160;
+
 pub struct AudioBuffer {
     buffer: Vec<u8>,
     chunk_size: usize,
+    encoding: Encoding,
 }
 
 impl AudioBuffer {
-    pub fn new(sample_rate: u32, chunk_duration_ms: u32) -> Self {
-        // Calculate chunk size for 16-bit PCM audio
-        // chunk_size = sample_rate * (chunk_duration_ms / 1000) * 2 bytes per sample
-        let chunk_size = (sample_rate * chunk_duration_ms / 1000 * 2) as usize;
+    pub fn new(sample_rate: u32, chunk_duration_ms: u32, encoding: Encoding) -> Self {
+        // Calculate chunk size for the encoded audio
+        // chunk_size = sample_rate * (chunk_duration_ms / 1000) * bytes per sample
+        // Whole samples only (an even byte count for 16-bit PCM); never less than one sample
+        let samples = (sample_rate as u64 * chunk_duration_ms as u64 / 1000).max(1);
+        let chunk_size = samples as usize * encoding.bytes_per_sample();
 
         debug!(
             "AudioBuffer: sample_rate={}, chunk_duration_ms={}, calculated chunk_size={} bytes",
@@ -313,20 +1023,16 @@ impl AudioBuffer {
         Self {
             buffer: Vec::new(),
             chunk_size,
+            encoding,
         }
     }
 
     pub fn add_samples(&mut self, samples: &[f32]) -> Vec<Vec<u8>> {
-        // Convert f32 samples to 16-bit PCM
-        let pcm_data: Vec<u8> = samples
-            .iter()
-            .flat_map(|&sample| {
-                let pcm_sample = (sample.clamp(-1.0, 1.0) * i16::MAX as f32) as i16;
-                pcm_sample.to_le_bytes()
-            })
-            .collect();
-
-        self.buffer.extend_from_slice(&pcm_data);
+        // Convert f32 samples to 16-bit PCM, then to the wire encoding
+        for &sample in samples {
+            let pcm_sample = (sample.clamp(-1.0, 1.0) * i16::MAX as f32) as i16;
+            self.encoding.encode(pcm_sample, &mut self.buffer);
+        }
 
         // Extract complete chunks
         let mut chunks = Vec::new();
@@ -348,6 +1054,11 @@ impl AudioBuffer {
         chunks
     }
 
+    /// Encoded bytes waiting for a full chunk
+    pub fn buffered(&self) -> usize {
+        self.buffer.len()
+    }
+
     #[allow(dead_code)]
     pub fn flush(&mut self) -> Option<Vec<u8>> {
         if !self.buffer.is_empty() {
@@ -363,15 +1074,808 @@ impl AudioBuffer {
 mod tests {
     use super::*;
     use std::sync::{
-        atomic::{AtomicBool, Ordering},
+        atomic::{AtomicBool, AtomicUsize, Ordering},
         Arc,
     };
-    use std::time::Duration;
+
+    /// Held by the tests that reconnect, so each sees only its own share of
+    /// the process-wide reconnect count
+    static RECONNECTING: tokio::sync::Mutex<()> = tokio::sync::Mutex::const_new(());
 
     fn init_tracing() {
         let _ = tracing_subscriber::fmt::try_init();
     }
 
+    #[test]
+    fn test_auth_scheme_header_values() {
+        assert_eq!(
+            AuthScheme::Token.header_value("abc").unwrap(),
+            HeaderValue::from_static("Token abc")
+        );
+        assert_eq!(
+            AuthScheme::Bearer.header_value("abc").unwrap(),
+            HeaderValue::from_static("Bearer abc")
+        );
+        // "user:pass" base64-encodes to "dXNlcjpwYXNz"
+        assert_eq!(
+            AuthScheme::Basic.header_value("user:pass").unwrap(),
+            HeaderValue::from_static("Basic dXNlcjpwYXNz")
+        );
+        assert_eq!(
+            AuthScheme::Custom("ApiKey".to_string())
+                .header_value("abc")
+                .unwrap(),
+            HeaderValue::from_static("ApiKey abc")
+        );
+        assert!(AuthScheme::Token.header_value("bad\nkey").is_err());
+    }
+
+    /// Local WebSocket server that answers the first `reject` handshakes with a 503
+    async fn spawn_flaky_server(reject: usize) -> String {
+        use tokio::io::AsyncWriteExt;
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let mut seen = 0;
+            while let Ok((mut stream, _)) = listener.accept().await {
+                seen += 1;
+                if seen <= reject {
+                    let _ = stream
+                        .write_all(b"HTTP/1.1 503 Service Unavailable\r\nContent-Length: 0\r\n\r\n")
+                        .await;
+                    continue;
+                }
+                tokio::spawn(async move {
+                    if let Ok(mut ws) = tokio_tungstenite::accept_async(stream).await {
+                        while let Some(Ok(_)) = ws.next().await {}
+                    }
+                });
+            }
+        });
+        format!("ws://{addr}/listen")
+    }
+
+    /// Local server that takes the n-th connection if `accept[n]` says so and
+    /// answers the rest with a 503. An accepted session sends an open turn
+    /// and drops the connection on the first audio frame.
+    async fn spawn_dropping_server(accept: Vec<bool>) -> (String, Arc<AtomicUsize>) {
+        use crate::fake_stt::turn_info;
+        use tokio::io::AsyncWriteExt;
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let connections = Arc::new(AtomicUsize::new(0));
+        let seen = connections.clone();
+        tokio::spawn(async move {
+            while let Ok((mut stream, _)) = listener.accept().await {
+                let n = seen.fetch_add(1, Ordering::SeqCst);
+                if !accept.get(n).copied().unwrap_or(false) {
+                    let _ = stream
+                        .write_all(b"HTTP/1.1 503 Service Unavailable\r\nContent-Length: 0\r\n\r\n")
+                        .await;
+                    continue;
+                }
+                tokio::spawn(async move {
+                    let Ok(mut ws) = tokio_tungstenite::accept_async(stream).await else {
+                        return;
+                    };
+                    if let Some(Ok(_)) = ws.next().await {
+                        let text = format!("session {n}");
+                        for event in ["StartOfTurn", "Update"] {
+                            let _ = ws.send(Message::Text(turn_info(event, 0, &text))).await;
+                        }
+                    }
+                    // Gone without a close handshake, as when the server dies
+                    drop(ws);
+                });
+            }
+        });
+        (format!("ws://{addr}/listen"), connections)
+    }
+
+    fn fast_retry(retries: u32) -> RetryPolicy {
+        RetryPolicy {
+            retries,
+            initial_backoff: Duration::from_millis(5),
+            max_backoff: Duration::from_millis(20),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_connect_retries_until_server_accepts() {
+        let url = spawn_flaky_server(2).await;
+        let mut client = SttClient::new(&url, 16_000);
+        client.set_retry_policy(fast_retry(2));
+
+        let result = client.connect_and_transcribe(|_| {}).await;
+        assert!(
+            result.is_ok(),
+            "should connect on the third attempt: {:?}",
+            result.err()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_connect_gives_up_after_retries() {
+        let url = spawn_flaky_server(3).await;
+        let mut client = SttClient::new(&url, 16_000);
+        client.set_retry_policy(fast_retry(2));
+
+        let err = client.connect_and_transcribe(|_| {}).await.err().unwrap();
+        assert!(format!("{err:#}").contains("Giving up after 3 connection attempt(s)"));
+    }
+
+    #[tokio::test]
+    async fn test_reconnects_until_too_many_fail_in_a_row() {
+        use std::sync::Mutex;
+
+        // Two failures in a row are allowed: after the first drop one fails
+        // and the next succeeds, which starts the count over; after the second
+        // drop both fail and the client gives up
+        let script = vec![true, false, true, false, false];
+        let (url, connections) = spawn_dropping_server(script).await;
+        let _reconnecting = RECONNECTING.lock().await;
+        let reconnects_before = METRICS.snapshot().reconnects;
+        let mut client = SttClient::new(&url, 16_000);
+        client.set_retry_policy(fast_retry(0));
+        client.set_max_reconnects(Some(2));
+
+        let results = Arc::new(Mutex::new(Vec::new()));
+        let results_clone = results.clone();
+        let (audio_tx, handle) = client
+            .connect_and_transcribe(move |result| results_clone.lock().unwrap().push(result))
+            .await
+            .unwrap();
+        let stream = async {
+            // The same sender feeds each new connection
+            while !handle.is_finished() {
+                let _ = audio_tx.send(vec![0; 320]).await;
+                tokio::time::sleep(Duration::from_millis(5)).await;
+            }
+        };
+        tokio::time::timeout(Duration::from_secs(5), stream)
+            .await
+            .expect("client did not give up");
+
+        let err = handle.await.unwrap().unwrap_err();
+        let message = format!("{err:#}");
+        assert!(
+            message.contains("2 reconnect(s) in a row failed"),
+            "{message}"
+        );
+        assert!(matches!(VkError::find(&err), Some(VkError::Connection(_))));
+        assert_eq!(connections.load(Ordering::SeqCst), 5);
+        // One per dropped session, not one per attempt
+        assert_eq!(METRICS.snapshot().reconnects - reconnects_before, 2);
+
+        // Each dropped session's open turn was discarded, not finalized, and
+        // the callback heard about every drop and the reconnect in between
+        let results = results.lock().unwrap();
+        assert!(results.iter().all(|r| r.event != "EndOfTurn"));
+        let connection: Vec<&str> = results
+            .iter()
+            .map(|r| r.event.as_str())
+            .filter(|event| [DISCONNECTED_EVENT, RECONNECTED_EVENT].contains(event))
+            .collect();
+        assert_eq!(
+            connection,
+            vec![DISCONNECTED_EVENT, RECONNECTED_EVENT, DISCONNECTED_EVENT]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_reconnect_mid_line_leaves_the_line_as_typed() {
+        use crate::interim::{InterimFilter, InterimMode};
+        use crate::virtual_keyboard::{MockKeyboardHardware, VirtualKeyboard};
+        use std::sync::Mutex;
+
+        // Each session types half a line and drops; the third connection fails
+        let (url, _) = spawn_dropping_server(vec![true, true]).await;
+        let _reconnecting = RECONNECTING.lock().await;
+        let mut client = SttClient::new(&url, 16_000);
+        client.set_retry_policy(fast_retry(0));
+        client.set_max_reconnects(Some(1));
+
+        let results = Arc::new(Mutex::new(Vec::new()));
+        let results_clone = results.clone();
+        let (audio_tx, handle) = client
+            .connect_and_transcribe(move |result| results_clone.lock().unwrap().push(result))
+            .await
+            .unwrap();
+        let stream = async {
+            while !handle.is_finished() {
+                let _ = audio_tx.send(vec![0; 320]).await;
+                tokio::time::sleep(Duration::from_millis(5)).await;
+            }
+        };
+        tokio::time::timeout(Duration::from_secs(5), stream)
+            .await
+            .expect("client did not give up");
+        assert!(handle.await.unwrap().is_err());
+
+        // As the CLI types by default: every finalized turn would press Enter
+        let mut kb = VirtualKeyboard::new(MockKeyboardHardware::new());
+        kb.set_voice_enter_enabled(false);
+        let mut filter = InterimFilter::new(InterimMode::Live);
+        for result in results.lock().unwrap().iter() {
+            filter
+                .apply(
+                    &mut kb,
+                    &result.event,
+                    &result.transcript,
+                    std::time::Instant::now(),
+                )
+                .unwrap();
+        }
+
+        // The second session's text wasn't diffed against the first's
+        let text: String = kb.hardware().typed_chars.iter().collect();
+        assert_eq!(text, "session 0session 1");
+        assert_eq!(kb.hardware().backspace_count, 0);
+        assert_eq!(kb.hardware().enter_count, 0);
+        assert_eq!(kb.get_current_text(), "");
+    }
+
+    #[tokio::test]
+    async fn test_connect_times_out_when_the_upgrade_never_completes() {
+        // Accepts the TCP connection, then never answers the handshake
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let mut held = Vec::new();
+            while let Ok((stream, _)) = listener.accept().await {
+                held.push(stream);
+            }
+        });
+
+        let mut client = SttClient::new(&format!("ws://{addr}/listen"), 16_000);
+        client.set_connect_timeout(Duration::from_millis(100));
+        client.set_retry_policy(fast_retry(1));
+
+        let connect = client.connect_and_transcribe(|_| {});
+        let err = tokio::time::timeout(Duration::from_secs(5), connect)
+            .await
+            .expect("the connect timeout should fire")
+            .err()
+            .unwrap();
+        assert!(matches!(VkError::find(&err), Some(VkError::Connection(_))));
+        let message = format!("{err:#}");
+        assert!(message.contains("Giving up after 2 connection attempt(s)"));
+        assert!(message.contains("Timed out after 0.1s"), "{message}");
+    }
+
+    #[tokio::test]
+    async fn test_audio_pipeline_against_fake_server() {
+        use crate::fake_stt::{turn_info, FakeSttServer};
+        use std::sync::Mutex;
+
+        let server = FakeSttServer::start(vec![
+            turn_info("StartOfTurn", 0, "hello"),
+            turn_info("EndOfTurn", 0, "hello world"),
+        ])
+        .await;
+
+        let results = Arc::new(Mutex::new(Vec::new()));
+        let results_clone = results.clone();
+        let client = SttClient::new(&server.url, 16_000);
+        let (audio_tx, handle) = client
+            .connect_and_transcribe(move |result| results_clone.lock().unwrap().push(result))
+            .await
+            .unwrap();
+
+        // 10 ms chunks at 16 kHz are 160 samples (320 bytes); 400 samples make
+        // two full chunks with 80 samples left buffered
+        let mut buffer = AudioBuffer::new(16_000, 10, Encoding::Linear16);
+        let levels = [-1.0, -0.5, 0.0, 0.5, 1.0];
+        let samples: Vec<f32> = (0..400).map(|i| levels[i % levels.len()]).collect();
+        let chunks = buffer.add_samples(&samples);
+        assert_eq!(chunks.len(), 2);
+        for chunk in chunks {
+            audio_tx.send(chunk).await.unwrap();
+        }
+        drop(audio_tx);
+
+        tokio::time::timeout(Duration::from_secs(5), handle)
+            .await
+            .expect("client did not finish")
+            .unwrap()
+            .unwrap();
+
+        let pcm = [-32767i16, -16383, 0, 16383, 32767];
+        let expected: Vec<u8> = (0..320)
+            .flat_map(|i| pcm[i % pcm.len()].to_le_bytes())
+            .collect();
+        let received = server.received();
+        assert!(received.uri.contains("sample_rate=16000&encoding=linear16"));
+        assert_eq!(received.audio.len(), 2);
+        assert_eq!(received.audio_bytes(), expected);
+        assert_eq!(received.control, vec![r#"{"type":"CloseStream"}"#]);
+
+        let results = results.lock().unwrap();
+        let events: Vec<(&str, &str)> = results
+            .iter()
+            .map(|r| (r.event.as_str(), r.transcript.as_str()))
+            .collect();
+        assert_eq!(
+            events,
+            vec![("StartOfTurn", "hello"), ("EndOfTurn", "hello world")]
+        );
+    }
+
+    fn turn(
+        event: &str,
+        turn_index: u32,
+        transcript: &str,
+        confidence: f64,
+    ) -> TranscriptionResult {
+        TranscriptionResult {
+            event: event.to_string(),
+            turn_index,
+            start: 0.0,
+            timestamp: 1.0,
+            transcript: transcript.to_string(),
+            words: Vec::new(),
+            end_of_turn_confidence: confidence,
+        }
+    }
+
+    fn events(results: &[TranscriptionResult]) -> Vec<(&str, u32, &str)> {
+        results
+            .iter()
+            .map(|r| (r.event.as_str(), r.turn_index, r.transcript.as_str()))
+            .collect()
+    }
+
+    #[test]
+    fn test_uncertain_end_of_turn_does_not_finalize() {
+        let mut gate = TurnGate::new(Some(0.8));
+        let mut results = Vec::new();
+        for result in [
+            turn("StartOfTurn", 0, "hello", 0.1),
+            turn("EndOfTurn", 0, "hello", 0.5),
+            // The turn resumes, then ends with confidence
+            turn("Update", 0, "hello there", 0.3),
+            turn("EndOfTurn", 0, "hello there", 0.8),
+            turn("StartOfTurn", 1, "next", 0.1),
+            turn("EndOfTurn", 1, "next", 0.95),
+        ] {
+            results.extend(gate.accept(result));
+        }
+        assert_eq!(
+            events(&results),
+            vec![
+                ("StartOfTurn", 0, "hello"),
+                ("Update", 0, "hello"),
+                ("Update", 0, "hello there"),
+                ("EndOfTurn", 0, "hello there"),
+                ("StartOfTurn", 1, "next"),
+                ("EndOfTurn", 1, "next"),
+            ]
+        );
+        assert!(gate.close().is_none());
+    }
+
+    #[test]
+    fn test_demoted_end_of_turn_finalizes_before_the_next_turn() {
+        let mut gate = TurnGate::new(Some(0.8));
+        let mut results = gate.accept(turn("EndOfTurn", 0, "maybe done", 0.4));
+        results.extend(gate.accept(turn("StartOfTurn", 1, "new", 0.1)));
+        results.extend(gate.accept(turn("EndOfTurn", 1, "new words", 0.2)));
+        // The last one is still open when the connection closes
+        results.extend(gate.close());
+        assert_eq!(
+            events(&results),
+            vec![
+                ("Update", 0, "maybe done"),
+                ("EndOfTurn", 0, "maybe done"),
+                ("StartOfTurn", 1, "new"),
+                ("Update", 1, "new words"),
+                ("EndOfTurn", 1, "new words"),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_end_of_turn_ungated_by_default() {
+        let mut gate = TurnGate::new(None);
+        let results = gate.accept(turn("EndOfTurn", 0, "hello", 0.0));
+        assert_eq!(events(&results), vec![("EndOfTurn", 0, "hello")]);
+
+        assert_eq!(parse_confidence("0.75").unwrap(), 0.75);
+        assert!(parse_confidence("1.5").is_err());
+        assert!(parse_confidence("high").is_err());
+    }
+
+    #[tokio::test]
+    async fn test_finalize_is_sent_when_requested() {
+        use crate::fake_stt::{turn_info, FakeSttServer};
+
+        let server = FakeSttServer::start(vec![turn_info("EndOfTurn", 0, "hello")]).await;
+        let finalize = FinalizeTrigger::default();
+        let mut client = SttClient::new(&server.url, 16_000);
+        client.set_finalize_trigger(finalize.clone());
+        let (audio_tx, handle) = client.connect_and_transcribe(|_| {}).await.unwrap();
+        audio_tx.send(vec![0; 320]).await.unwrap();
+        finalize.fire();
+        // Give the send task a chance to act on the request before the stream ends
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        drop(audio_tx);
+
+        tokio::time::timeout(Duration::from_secs(5), handle)
+            .await
+            .expect("client did not finish")
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            server.received().control,
+            vec![r#"{"type":"Finalize"}"#, r#"{"type":"CloseStream"}"#]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_rejected_finalize_is_not_fatal() {
+        use crate::fake_stt::{turn_info, FakeSttServer};
+        use std::sync::Mutex;
+
+        let server =
+            FakeSttServer::start_rejecting_finalize(vec![turn_info("EndOfTurn", 0, "hello")]).await;
+        let results = Arc::new(Mutex::new(Vec::new()));
+        let results_clone = results.clone();
+        let finalize = FinalizeTrigger::default();
+        let mut client = SttClient::new(&server.url, 16_000);
+        client.set_finalize_trigger(finalize.clone());
+        let (audio_tx, handle) = client
+            .connect_and_transcribe(move |result| results_clone.lock().unwrap().push(result))
+            .await
+            .unwrap();
+        finalize.fire();
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        audio_tx.send(vec![0; 320]).await.unwrap();
+        // Once rejected, it isn't sent again
+        finalize.fire();
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        drop(audio_tx);
+
+        tokio::time::timeout(Duration::from_secs(5), handle)
+            .await
+            .expect("client did not finish")
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            server.received().control,
+            vec![r#"{"type":"Finalize"}"#, r#"{"type":"CloseStream"}"#]
+        );
+        assert_eq!(results.lock().unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_other_errors_after_finalize_are_fatal() {
+        use crate::fake_stt::{turn_info, FakeSttServer};
+
+        let server =
+            FakeSttServer::start_failing_after_finalize(vec![turn_info("Update", 0, "hello")])
+                .await;
+        let finalize = FinalizeTrigger::default();
+        let mut client = SttClient::new(&server.url, 16_000);
+        client.set_finalize_trigger(finalize.clone());
+        let (audio_tx, handle) = client.connect_and_transcribe(|_| {}).await.unwrap();
+        audio_tx.send(vec![0; 320]).await.unwrap();
+        finalize.fire();
+
+        let result = tokio::time::timeout(Duration::from_secs(5), handle)
+            .await
+            .expect("client did not finish")
+            .unwrap();
+        let err = result.unwrap_err();
+        assert!(matches!(
+            VkError::find(&err),
+            Some(VkError::Server { code, .. }) if code == "INTERNAL_SERVER_ERROR"
+        ));
+        drop(audio_tx);
+    }
+
+    #[tokio::test]
+    async fn test_server_close_finalizes_the_open_turn() {
+        use crate::fake_stt::{turn_info, FakeSttServer};
+        use crate::interim::{InterimFilter, InterimMode};
+        use crate::virtual_keyboard::{MockKeyboardHardware, VirtualKeyboard};
+        use std::sync::Mutex;
+
+        let server = FakeSttServer::start_closing(vec![
+            turn_info("StartOfTurn", 0, "hello"),
+            turn_info("Update", 0, "hello enter"),
+        ])
+        .await;
+
+        let results = Arc::new(Mutex::new(Vec::new()));
+        let results_clone = results.clone();
+        let client = SttClient::new(&server.url, 16_000);
+        let (audio_tx, handle) = client
+            .connect_and_transcribe(move |result| results_clone.lock().unwrap().push(result))
+            .await
+            .unwrap();
+        audio_tx.send(vec![0; 320]).await.unwrap();
+
+        // The server closes on its own, while we are still streaming
+        tokio::time::timeout(Duration::from_secs(5), handle)
+            .await
+            .expect("client did not finish")
+            .unwrap()
+            .unwrap();
+        assert!(server.received().control.is_empty());
+
+        let results = results.lock().unwrap();
+        let events: Vec<(&str, &str)> = results
+            .iter()
+            .map(|r| (r.event.as_str(), r.transcript.as_str()))
+            .collect();
+        assert_eq!(
+            events,
+            vec![
+                ("StartOfTurn", "hello"),
+                ("Update", "hello enter"),
+                ("EndOfTurn", "hello enter")
+            ]
+        );
+
+        // Typed, the synthesized end of turn evaluates the trailing "enter"
+        let mut kb = VirtualKeyboard::new(MockKeyboardHardware::new());
+        let mut filter = InterimFilter::new(InterimMode::Live);
+        for result in results.iter() {
+            filter
+                .apply(
+                    &mut kb,
+                    &result.event,
+                    &result.transcript,
+                    std::time::Instant::now(),
+                )
+                .unwrap();
+        }
+        let text: String = kb.hardware().typed_chars.iter().collect();
+        assert_eq!(text, "hello");
+        assert!(kb.hardware().enter_pressed);
+    }
+
+    #[tokio::test]
+    async fn test_unknown_message_types_are_ignored() {
+        use crate::fake_stt::{turn_info, FakeSttServer};
+        use std::sync::Mutex;
+
+        let server = FakeSttServer::start(vec![
+            r#"{"type":"SpeakerChange","speaker":2}"#.to_string(),
+            turn_info("EndOfTurn", 0, "still here"),
+        ])
+        .await;
+
+        let results = Arc::new(Mutex::new(Vec::new()));
+        let results_clone = results.clone();
+        let client = SttClient::new(&server.url, 16_000);
+        let (audio_tx, handle) = client
+            .connect_and_transcribe(move |result| {
+                results_clone.lock().unwrap().push(result.transcript)
+            })
+            .await
+            .unwrap();
+        audio_tx.send(vec![0; 320]).await.unwrap();
+        drop(audio_tx);
+        tokio::time::timeout(Duration::from_secs(5), handle)
+            .await
+            .expect("client did not finish")
+            .unwrap()
+            .unwrap();
+
+        assert_eq!(*results.lock().unwrap(), vec!["still here"]);
+    }
+
+    #[tokio::test]
+    async fn test_protocol_dump_records_frames_both_ways() {
+        use crate::fake_stt::{turn_info, FakeSttServer};
+
+        let path = std::env::temp_dir().join(format!("vk-protocol-{}", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let server = FakeSttServer::start(vec![turn_info("EndOfTurn", 0, "hello")]).await;
+
+        let mut client = SttClient::new(&server.url, 16_000);
+        client.set_protocol_dump(Some(ProtocolDump::open(&path).unwrap()));
+        let (audio_tx, handle) = client.connect_and_transcribe(|_| {}).await.unwrap();
+        audio_tx.send(vec![0; 320]).await.unwrap();
+        drop(audio_tx);
+        tokio::time::timeout(Duration::from_secs(5), handle)
+            .await
+            .expect("client did not finish")
+            .unwrap()
+            .unwrap();
+
+        let dump = std::fs::read_to_string(&path).unwrap();
+        let _ = std::fs::remove_file(&path);
+        let frames: Vec<(&str, &str)> = dump
+            .lines()
+            .map(|line| {
+                let (timestamp, rest) = line.split_once(' ').unwrap();
+                assert!(timestamp.parse::<f64>().unwrap() > 1.6e9);
+                rest.split_once(' ').unwrap()
+            })
+            .collect();
+        assert!(frames.contains(&("send", "binary (320 bytes)")));
+        assert!(frames.contains(&("send", r#"{"type":"CloseStream"}"#)));
+        assert!(frames.contains(&("recv", turn_info("EndOfTurn", 0, "hello").as_str())));
+        // Audio goes out before the stream is closed
+        let position = |frame| frames.iter().position(|f| *f == frame).unwrap();
+        assert!(
+            position(("send", "binary (320 bytes)"))
+                < position(("send", r#"{"type":"CloseStream"}"#))
+        );
+    }
+
+    #[tokio::test]
+    async fn test_fan_out_sends_identical_audio_to_every_server() {
+        use crate::fake_stt::{turn_info, FakeSttServer};
+
+        let primary = FakeSttServer::start(vec![turn_info("EndOfTurn", 0, "hello")]).await;
+        let compare = FakeSttServer::start(vec![turn_info("EndOfTurn", 0, "yellow")]).await;
+
+        let mut senders = Vec::new();
+        let mut handles = Vec::new();
+        let transcripts = Arc::new(std::sync::Mutex::new(Vec::new()));
+        for server in [&primary, &compare] {
+            let transcripts = transcripts.clone();
+            let (tx, handle) = SttClient::new(&server.url, 16_000)
+                .connect_and_transcribe(move |result| {
+                    transcripts.lock().unwrap().push(result.transcript)
+                })
+                .await
+                .unwrap();
+            senders.push(tx);
+            handles.push(handle);
+        }
+
+        let audio_tx = fan_out(senders, FAN_OUT_CAPACITY);
+        let chunks: Vec<Vec<u8>> = (0..5u8).map(|i| vec![i; 320]).collect();
+        for chunk in &chunks {
+            audio_tx.send(chunk.clone()).unwrap();
+        }
+        drop(audio_tx);
+
+        for handle in handles {
+            tokio::time::timeout(Duration::from_secs(5), handle)
+                .await
+                .expect("client did not finish")
+                .unwrap()
+                .unwrap();
+        }
+
+        assert_eq!(primary.received().audio, chunks);
+        assert_eq!(compare.received().audio, chunks);
+        assert_eq!(
+            compare.received().control,
+            vec![r#"{"type":"CloseStream"}"#]
+        );
+        let mut transcripts = transcripts.lock().unwrap().clone();
+        transcripts.sort();
+        assert_eq!(transcripts, vec!["hello", "yellow"]);
+    }
+
+    #[test]
+    fn test_chunk_size_matches_duration() {
+        let cases = [
+            (16_000, 160, 5120),
+            (16_000, 40, 1280),
+            (16_000, 10, 320),
+            (48_000, 20, 1920),
+            (44_100, 15, 1322), // 661.5 samples rounds down to whole samples
+            (8_000, 0, 2),
+        ];
+        for (sample_rate, chunk_ms, expected) in cases {
+            let size = AudioBuffer::new(sample_rate, chunk_ms, Encoding::Linear16).chunk_size;
+            assert_eq!(size, expected, "{sample_rate} Hz, {chunk_ms} ms");
+            assert_eq!(size % 2, 0);
+        }
+    }
+
+    #[test]
+    fn test_mulaw_buffer_and_query() {
+        let mut buffer = AudioBuffer::new(8_000, 10, Encoding::Mulaw);
+        assert_eq!(buffer.chunk_size, 80);
+        let chunks = buffer.add_samples(&[0.0; 80]);
+        assert_eq!(chunks, vec![vec![0xFF; 80]]);
+
+        let config = SttConfig {
+            url: "ws://127.0.0.1:1/listen".to_string(),
+            encoding: Encoding::Mulaw,
+            ..SttConfig::default()
+        };
+        let request = SttClient::from_config(&config, 8_000)
+            .build_request()
+            .unwrap();
+        assert!(request
+            .uri()
+            .to_string()
+            .ends_with("sample_rate=8000&encoding=mulaw"));
+    }
+
+    fn query(config: &SttConfig) -> String {
+        let request = SttClient::from_config(config, 16_000)
+            .build_request()
+            .unwrap();
+        request.uri().query().unwrap().to_string()
+    }
+
+    #[test]
+    fn test_quality_presets_resolve_to_query_params() {
+        let cases = [
+            (
+                "fast",
+                "model=flux-general-en&sample_rate=16000&encoding=linear16&eot_threshold=0.6&eot_timeout_ms=3000",
+            ),
+            (
+                "balanced",
+                "model=flux-general-en&sample_rate=16000&encoding=linear16&eot_threshold=0.7&eot_timeout_ms=5000",
+            ),
+            (
+                "Accurate",
+                "model=flux-general-en&sample_rate=16000&encoding=linear16&eot_threshold=0.85&eot_timeout_ms=7000",
+            ),
+        ];
+        for (name, expected) in cases {
+            let mut config = SttConfig::default();
+            config.apply_quality(name.parse().unwrap());
+            assert_eq!(query(&config), expected, "quality {name}");
+        }
+        assert!("best".parse::<Quality>().is_err());
+
+        // Without a preset only the model is sent
+        assert_eq!(
+            query(&SttConfig::default()),
+            "model=flux-general-en&sample_rate=16000&encoding=linear16"
+        );
+    }
+
+    #[test]
+    fn test_configured_headers_on_request() {
+        let config = SttConfig {
+            url: "ws://127.0.0.1:1/listen".to_string(),
+            headers: vec![
+                parse_header("X-Api-Version: 2").unwrap(),
+                parse_header("x-tenant:acme").unwrap(),
+            ],
+            subprotocol: Some("flux.v1".to_string()),
+            ..SttConfig::default()
+        };
+        let request = SttClient::from_config(&config, 16000)
+            .build_request()
+            .unwrap();
+
+        let headers = request.headers();
+        assert_eq!(headers.get("x-api-version").unwrap(), "2");
+        assert_eq!(headers.get("X-Tenant").unwrap(), "acme");
+        assert_eq!(headers.get(SEC_WEBSOCKET_PROTOCOL).unwrap(), "flux.v1");
+    }
+
+    #[test]
+    fn test_parse_header_rejects_malformed_input() {
+        assert!(parse_header("no-colon").is_err());
+        assert!(parse_header("bad name: value").is_err());
+        assert!(parse_header(": value").is_err());
+        assert!(parse_header("X-Ok: line\nbreak").is_err());
+
+        let (name, value) = parse_header("X-Empty:").unwrap();
+        assert_eq!(name, "x-empty");
+        assert_eq!(value, "");
+    }
+
+    #[test]
+    fn test_auth_scheme_parse() {
+        assert_eq!("token".parse::<AuthScheme>().unwrap(), AuthScheme::Token);
+        assert_eq!("Bearer".parse::<AuthScheme>().unwrap(), AuthScheme::Bearer);
+        assert_eq!("basic".parse::<AuthScheme>().unwrap(), AuthScheme::Basic);
+        assert_eq!(
+            "ApiKey".parse::<AuthScheme>().unwrap(),
+            AuthScheme::Custom("ApiKey".to_string())
+        );
+        assert!("two words".parse::<AuthScheme>().is_err());
+    }
+
     #[tokio::test]
     async fn test_connect_and_receive_turninfo_with_silence() {
         init_tracing();
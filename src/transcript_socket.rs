@@ -0,0 +1,228 @@
+// Optional Unix socket that streams every transcription result as a JSON
+// line, for programs that want the text rather than keystrokes (`--socket`).
+// Clients may connect and disconnect at any time; each gets the results from
+// when it connected on.
+
+use anyhow::{bail, Context, Result};
+use std::os::unix::fs::{FileTypeExt, MetadataExt};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use tokio::io::AsyncWriteExt;
+use tokio::net::{UnixListener, UnixStream};
+use tokio::sync::broadcast;
+use tracing::{debug, error, warn};
+
+use crate::stt_client::TranscriptionResult;
+
+/// Results a slow client may fall behind by before it skips some
+const CLIENT_BACKLOG: usize = 256;
+
+/// Handle for publishing results to every connected client
+#[derive(Debug, Clone)]
+pub struct TranscriptSocket {
+    lines: broadcast::Sender<Arc<str>>,
+    _file: Arc<SocketFile>,
+}
+
+/// The socket's path, removed once the last handle is dropped so no stale
+/// socket is left behind on exit
+#[derive(Debug)]
+struct SocketFile {
+    path: PathBuf,
+    /// Device and inode, so a socket another run bound since isn't removed
+    id: (u64, u64),
+}
+
+impl Drop for SocketFile {
+    fn drop(&mut self) {
+        let ours = std::fs::symlink_metadata(&self.path)
+            .is_ok_and(|meta| (meta.dev(), meta.ino()) == self.id);
+        if !ours {
+            return;
+        }
+        if let Err(e) = std::fs::remove_file(&self.path) {
+            warn!("Failed to remove socket {}: {}", self.path.display(), e);
+        }
+    }
+}
+
+impl TranscriptSocket {
+    /// Listen on `path`, replacing a socket left over from an earlier run, and
+    /// remove it again when dropped; must be called inside the runtime
+    pub fn bind(path: &Path) -> Result<Self> {
+        match std::fs::symlink_metadata(path) {
+            Ok(meta) if meta.file_type().is_socket() => std::fs::remove_file(path)
+                .with_context(|| format!("Failed to remove old socket {}", path.display()))?,
+            Ok(_) => bail!("{} exists and is not a socket", path.display()),
+            Err(_) => {}
+        }
+        let listener = UnixListener::bind(path)
+            .with_context(|| format!("Failed to bind socket {}", path.display()))?;
+        let meta = std::fs::symlink_metadata(path)
+            .with_context(|| format!("Failed to stat socket {}", path.display()))?;
+        let file = SocketFile {
+            path: path.to_path_buf(),
+            id: (meta.dev(), meta.ino()),
+        };
+
+        let (lines, _) = broadcast::channel(CLIENT_BACKLOG);
+        tokio::spawn(serve(listener, lines.clone()));
+        Ok(Self {
+            lines,
+            _file: Arc::new(file),
+        })
+    }
+
+    /// Send a result to the clients connected right now
+    pub fn send(&self, result: &TranscriptionResult) {
+        let line = match serde_json::to_string(result) {
+            Ok(json) => json + "\n",
+            Err(e) => {
+                error!("Failed to encode transcript for the socket: {}", e);
+                return;
+            }
+        };
+        // No clients is fine; the result just isn't kept
+        let _ = self.lines.send(line.into());
+    }
+}
+
+/// Accept clients until the process exits
+async fn serve(listener: UnixListener, lines: broadcast::Sender<Arc<str>>) {
+    loop {
+        match listener.accept().await {
+            Ok((stream, _)) => {
+                debug!("Transcript socket client connected");
+                tokio::spawn(feed_client(stream, lines.subscribe()));
+            }
+            Err(e) => warn!("Failed to accept a transcript socket client: {}", e),
+        }
+    }
+}
+
+/// Write each result to one client until it goes away
+async fn feed_client(mut stream: UnixStream, mut lines: broadcast::Receiver<Arc<str>>) {
+    loop {
+        match lines.recv().await {
+            Ok(line) => {
+                if let Err(e) = stream.write_all(line.as_bytes()).await {
+                    debug!("Transcript socket client disconnected: {}", e);
+                    return;
+                }
+            }
+            Err(broadcast::error::RecvError::Lagged(skipped)) => {
+                warn!(
+                    "Transcript socket client fell behind; skipped {} results",
+                    skipped
+                );
+            }
+            Err(broadcast::error::RecvError::Closed) => return,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+    use tokio::io::{AsyncBufReadExt, BufReader, Lines};
+
+    fn result(event: &str, transcript: &str) -> TranscriptionResult {
+        TranscriptionResult {
+            event: event.to_string(),
+            turn_index: 0,
+            start: 0.0,
+            timestamp: 0.0,
+            transcript: transcript.to_string(),
+            words: Vec::new(),
+            end_of_turn_confidence: 0.9,
+        }
+    }
+
+    async fn connect(
+        path: &Path,
+        socket: &TranscriptSocket,
+        clients: usize,
+    ) -> Lines<BufReader<UnixStream>> {
+        let stream = UnixStream::connect(path).await.unwrap();
+        // Results sent before the server has taken the client aren't delivered
+        while socket.lines.receiver_count() < clients {
+            tokio::time::sleep(Duration::from_millis(5)).await;
+        }
+        BufReader::new(stream).lines()
+    }
+
+    async fn next_result(lines: &mut Lines<BufReader<UnixStream>>) -> serde_json::Value {
+        let line = tokio::time::timeout(Duration::from_secs(5), lines.next_line())
+            .await
+            .expect("a line in time")
+            .unwrap()
+            .expect("the socket to stay open");
+        serde_json::from_str(&line).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_clients_read_json_lines_and_may_come_and_go() {
+        let path = std::env::temp_dir().join(format!("vk-socket-{}", std::process::id()));
+        // A leftover socket from an earlier run is replaced
+        drop(std::os::unix::net::UnixListener::bind(&path).unwrap());
+        let socket = TranscriptSocket::bind(&path).unwrap();
+
+        // Nobody listening yet
+        socket.send(&result("Update", "lost"));
+
+        let mut first = connect(&path, &socket, 1).await;
+        socket.send(&result("Update", "hello"));
+        let line = next_result(&mut first).await;
+        assert_eq!(line["event"], "Update");
+        assert_eq!(line["transcript"], "hello");
+
+        let mut second = connect(&path, &socket, 2).await;
+        drop(first);
+        socket.send(&result("EndOfTurn", "hello world"));
+        socket.send(&result("StartOfTurn", "again"));
+        assert_eq!(next_result(&mut second).await["transcript"], "hello world");
+        assert_eq!(next_result(&mut second).await["event"], "StartOfTurn");
+
+        // The disconnected client's task ends without taking the others down
+        while socket.lines.receiver_count() > 1 {
+            socket.send(&result("Update", "again and"));
+            tokio::time::sleep(Duration::from_millis(5)).await;
+        }
+        let mut third = connect(&path, &socket, 2).await;
+        socket.send(&result("EndOfTurn", "again and again"));
+        assert_eq!(
+            next_result(&mut third).await["transcript"],
+            "again and again"
+        );
+
+        // Gone once the last handle is
+        let clone = socket.clone();
+        drop(socket);
+        assert!(path.exists());
+        drop(clone);
+        assert!(!path.exists());
+    }
+
+    #[tokio::test]
+    async fn test_leaves_a_socket_bound_since_in_place() {
+        let path = std::env::temp_dir().join(format!("vk-rebound-{}", std::process::id()));
+        let socket = TranscriptSocket::bind(&path).unwrap();
+        // Another run took over the path after this one bound it
+        let other = TranscriptSocket::bind(&path).unwrap();
+        drop(socket);
+        assert!(path.exists());
+        drop(other);
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn test_refuses_to_replace_a_regular_file() {
+        let path = std::env::temp_dir().join(format!("vk-not-socket-{}", std::process::id()));
+        std::fs::write(&path, "keep me").unwrap();
+        let err = TranscriptSocket::bind(&path).unwrap_err();
+        assert!(format!("{err}").ends_with("is not a socket"));
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "keep me");
+        let _ = std::fs::remove_file(&path);
+    }
+}
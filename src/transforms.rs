@@ -0,0 +1,527 @@
+// Text transforms applied to a transcript before it is diffed against the screen
+
+use anyhow::{bail, Context, Result};
+use regex::{Captures, Regex};
+use std::collections::HashMap;
+use std::path::Path;
+use std::str::FromStr;
+use std::sync::LazyLock;
+
+const UNITS: [&str; 10] = [
+    "zero", "one", "two", "three", "four", "five", "six", "seven", "eight", "nine",
+];
+const TEENS: [&str; 10] = [
+    "ten",
+    "eleven",
+    "twelve",
+    "thirteen",
+    "fourteen",
+    "fifteen",
+    "sixteen",
+    "seventeen",
+    "eighteen",
+    "nineteen",
+];
+const TENS: [&str; 8] = [
+    "twenty", "thirty", "forty", "fifty", "sixty", "seventy", "eighty", "ninety",
+];
+
+/// A run of number words separated by spaces or hyphens
+static NUMBER_RUN: LazyLock<Regex> = LazyLock::new(|| {
+    let words: Vec<&str> = UNITS.iter().chain(&TEENS).chain(&TENS).copied().collect();
+    let word = format!("(?:{})", words.join("|"));
+    Regex::new(&format!(r"(?i)\b{word}(?:[\s-]+{word})*\b")).unwrap()
+});
+
+fn unit_value(word: &str) -> Option<u32> {
+    UNITS.iter().position(|&w| w == word).map(|i| i as u32)
+}
+
+fn word_value(word: &str) -> Option<u32> {
+    unit_value(word)
+        .or_else(|| TEENS.iter().position(|&w| w == word).map(|i| 10 + i as u32))
+        .or_else(|| {
+            TENS.iter()
+                .position(|&w| w == word)
+                .map(|i| 20 + 10 * i as u32)
+        })
+}
+
+/// Replace spoken number words with digits, e.g. "five five five one two"
+/// becomes "55512" and "twenty three" becomes "23"
+/// Consecutive number words are joined without spaces, as when dictating phone
+/// numbers or codes. Only exact number words match, so "for" or "to" stay as-is.
+pub fn numbers_to_digits(text: &str) -> String {
+    NUMBER_RUN
+        .replace_all(text, |caps: &Captures| {
+            let words: Vec<String> = caps[0]
+                .split(|c: char| c.is_whitespace() || c == '-')
+                .filter(|w| !w.is_empty())
+                .map(|w| w.to_lowercase())
+                .collect();
+
+            let mut digits = String::new();
+            let mut i = 0;
+            while i < words.len() {
+                let value = word_value(&words[i]).unwrap_or_default();
+                // "twenty three" is 23, not 203
+                let next_unit = words.get(i + 1).and_then(|w| unit_value(w));
+                match next_unit {
+                    Some(unit) if value >= 20 && unit > 0 => {
+                        digits.push_str(&(value + unit).to_string());
+                        i += 2;
+                    }
+                    _ => {
+                        digits.push_str(&value.to_string());
+                        i += 1;
+                    }
+                }
+            }
+            digits
+        })
+        .into_owned()
+}
+
+/// A spoken punctuation word, along with any punctuation the STT service
+/// already attached to it ("Period." is one mark, not two) and the spaces after
+static SPOKEN_MARK: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(
+        r"(?i)\b(comma|period|full\s+stop|question\s+mark|exclamation\s+(?:mark|point)|semi\s*colon|colon)\b[,.;:!?]*\s*",
+    )
+    .unwrap()
+});
+
+/// Marks that attach to the word before them and never take a space before
+const ATTACHED_MARKS: &[char] = &[',', '.', ';', ':', '!', '?', ')', ']', '}'];
+
+fn spoken_mark(word: &str) -> char {
+    let word = word.to_lowercase();
+    match word.split_whitespace().next().unwrap_or_default() {
+        "comma" => ',',
+        "question" => '?',
+        "exclamation" => '!',
+        "colon" => ':',
+        w if w.starts_with("semi") => ';',
+        _ => '.',
+    }
+}
+
+/// Replace spoken punctuation words with marks, e.g. "hello comma world"
+/// becomes "hello, world"
+/// The space before each mark is removed and exactly one space follows it,
+/// unless it ends the text or another mark comes right after.
+pub fn spoken_punctuation(text: &str) -> String {
+    let mut out = String::with_capacity(text.len());
+    let mut last = 0;
+    let mut space_pending = false;
+    let push_text = |out: &mut String, segment: &str, space_pending: bool| {
+        if space_pending && !segment.is_empty() && !segment.starts_with(ATTACHED_MARKS) {
+            out.push(' ');
+        }
+        out.push_str(segment);
+    };
+
+    for caps in SPOKEN_MARK.captures_iter(text) {
+        let whole = caps.get(0).unwrap();
+        push_text(&mut out, &text[last..whole.start()], space_pending);
+        out.truncate(out.trim_end().len());
+        out.push(spoken_mark(&caps[1]));
+        last = whole.end();
+        space_pending = true;
+    }
+    push_text(&mut out, &text[last..], space_pending);
+    out
+}
+
+/// Drop control characters (NUL, escapes, stray CRs...) that malformed STT
+/// output may contain; newlines and tabs are kept for `flatten_line_breaks`
+pub fn strip_control_chars(text: &str) -> String {
+    text.chars()
+        .filter(|&c| !c.is_control() || c == '\n' || c == '\t')
+        .collect()
+}
+
+/// Turn each run of whitespace holding a newline or tab into one space.
+/// Typed, they would press Enter or Tab mid-transcript, which may send a
+/// message or move focus, and can't be backspaced like text.
+pub fn flatten_line_breaks(text: &str) -> String {
+    let mut out = String::with_capacity(text.len());
+    let mut run = String::new();
+    for c in text.chars() {
+        if c.is_whitespace() {
+            run.push(c);
+            continue;
+        }
+        push_whitespace_run(&mut out, &run);
+        run.clear();
+        out.push(c);
+    }
+    push_whitespace_run(&mut out, &run);
+    out
+}
+
+fn push_whitespace_run(out: &mut String, run: &str) {
+    if run.contains(['\n', '\t']) {
+        out.push(' ');
+    } else {
+        out.push_str(run);
+    }
+}
+
+/// What happens to a blocklisted word
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum BlockMode {
+    /// Replace it with `***`
+    #[default]
+    Mask,
+    /// Remove it along with the space before it
+    Drop,
+}
+
+impl FromStr for BlockMode {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_ascii_lowercase().as_str() {
+            "mask" => Ok(Self::Mask),
+            "drop" => Ok(Self::Drop),
+            other => bail!("Unknown blocklist mode '{}' (expected mask or drop)", other),
+        }
+    }
+}
+
+/// Words (or phrases) censored before typing, matched whole-word and
+/// case-insensitively
+#[derive(Debug, Clone)]
+pub struct Blocklist {
+    pattern: Regex,
+    mode: BlockMode,
+}
+
+impl Blocklist {
+    pub fn new<S: AsRef<str>>(words: &[S], mode: BlockMode) -> Result<Self> {
+        let alternatives: Vec<String> = words
+            .iter()
+            .map(|word| word.as_ref().trim())
+            .filter(|word| !word.is_empty())
+            .map(whole_phrase)
+            .collect();
+        if alternatives.is_empty() {
+            bail!("The blocklist is empty");
+        }
+        // Dropping also takes the whitespace before the word
+        let prefix = if mode == BlockMode::Drop { r"\s*" } else { "" };
+        let pattern = format!(r"(?i){prefix}(?:{})", alternatives.join("|"));
+        Ok(Self {
+            pattern: Regex::new(&pattern).context("Invalid blocklist")?,
+            mode,
+        })
+    }
+
+    pub fn apply(&self, text: &str) -> String {
+        match self.mode {
+            BlockMode::Mask => self.pattern.replace_all(text, "***").into_owned(),
+            BlockMode::Drop => {
+                let dropped = self.pattern.replace_all(text, "");
+                // A dropped first word would otherwise leave the line starting with a space
+                if text.starts_with(char::is_whitespace) {
+                    dropped.into_owned()
+                } else {
+                    dropped.trim_start().to_string()
+                }
+            }
+        }
+    }
+}
+
+/// Read a blocklist file: one word or phrase per line, `#` starts a comment line
+pub fn load_blocklist(path: &Path, mode: BlockMode) -> Result<Blocklist> {
+    let text = std::fs::read_to_string(path)
+        .with_context(|| format!("Failed to read blocklist {}", path.display()))?;
+    let words: Vec<&str> = text
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .collect();
+    Blocklist::new(&words, mode).with_context(|| format!("In {}", path.display()))
+}
+
+/// User vocabulary fixes, e.g. "deep gram" -> "Deepgram": whole phrases
+/// replaced case-insensitively, whatever whitespace the STT put between
+/// their words. Where phrases overlap, the longest one wins.
+#[derive(Debug, Clone)]
+pub struct Substitutions {
+    pattern: Regex,
+    /// Replacement by phrase, lowercased with single spaces between words
+    replacements: HashMap<String, String>,
+}
+
+impl Substitutions {
+    pub fn new<S: AsRef<str>>(pairs: &[(S, S)]) -> Result<Self> {
+        let mut replacements = HashMap::new();
+        for (phrase, replacement) in pairs {
+            let key = phrase_key(phrase.as_ref());
+            if key.is_empty() {
+                bail!("Empty phrase for substitution '{}'", replacement.as_ref());
+            }
+            replacements.insert(key, replacement.as_ref().to_string());
+        }
+        if replacements.is_empty() {
+            bail!("No substitutions");
+        }
+
+        // The regex takes the first alternative that matches, so longer phrases go first
+        let mut phrases: Vec<&String> = replacements.keys().collect();
+        phrases.sort_by(|a, b| b.len().cmp(&a.len()).then(a.cmp(b)));
+        let alternatives: Vec<String> = phrases.iter().map(|phrase| whole_phrase(phrase)).collect();
+        let pattern = format!("(?i)(?:{})", alternatives.join("|"));
+        Ok(Self {
+            pattern: Regex::new(&pattern).context("Invalid substitutions")?,
+            replacements,
+        })
+    }
+
+    pub fn apply(&self, text: &str) -> String {
+        self.pattern
+            .replace_all(text, |caps: &Captures| {
+                // Case folding can match text that lowercases to another key
+                match self.replacements.get(&phrase_key(&caps[0])) {
+                    Some(replacement) => replacement.clone(),
+                    None => caps[0].to_string(),
+                }
+            })
+            .into_owned()
+    }
+}
+
+/// A pattern matching `phrase` as whole words, with any whitespace between
+/// its words
+fn whole_phrase(phrase: &str) -> String {
+    let words: Vec<String> = phrase.split_whitespace().map(regex::escape).collect();
+    // Only word characters have a word boundary ("c++" ends in a symbol)
+    let boundary = |word_char: bool| if word_char { r"\b" } else { "" };
+    format!(
+        "{}{}{}",
+        boundary(phrase.starts_with(is_word_char)),
+        words.join(r"\s+"),
+        boundary(phrase.ends_with(is_word_char))
+    )
+}
+
+fn phrase_key(phrase: &str) -> String {
+    phrase
+        .split_whitespace()
+        .collect::<Vec<_>>()
+        .join(" ")
+        .to_lowercase()
+}
+
+fn is_word_char(c: char) -> bool {
+    c.is_alphanumeric() || c == '_'
+}
+
+/// Read a substitutions file: one `phrase -> replacement` per line, `#`
+/// starts a comment line
+pub fn load_substitutions(path: &Path) -> Result<Substitutions> {
+    let text = std::fs::read_to_string(path)
+        .with_context(|| format!("Failed to read substitutions {}", path.display()))?;
+    let mut pairs = Vec::new();
+    for (number, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let Some((phrase, replacement)) = line.split_once("->") else {
+            bail!(
+                "{}:{}: expected 'phrase -> replacement'",
+                path.display(),
+                number + 1
+            );
+        };
+        pairs.push((phrase.trim(), replacement.trim()));
+    }
+    Substitutions::new(&pairs).with_context(|| format!("In {}", path.display()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_number_words_become_digits() {
+        let cases = vec![
+            ("five five five one two three four", "5551234"),
+            ("call me at Five five five", "call me at 555"),
+            ("room twelve", "room 12"),
+            ("twenty three", "23"),
+            ("twenty-three", "23"),
+            ("twenty", "20"),
+            ("ninety nine bottles", "99 bottles"),
+            ("one twenty five", "125"),
+            ("zero, one.", "0, 1."),
+        ];
+        for (input, expected) in cases {
+            assert_eq!(numbers_to_digits(input), expected, "input: '{input}'");
+        }
+    }
+
+    #[test]
+    fn test_non_number_words_untouched() {
+        let cases = vec![
+            "this is for you",
+            "go to the store",
+            "someone ate",
+            "often tense",
+            "twentyish",
+            "",
+        ];
+        for input in cases {
+            assert_eq!(numbers_to_digits(input), input);
+        }
+    }
+
+    #[test]
+    fn test_spoken_punctuation_spacing() {
+        let cases = vec![
+            ("hello comma world", "hello, world"),
+            ("hello comma  world", "hello, world"),
+            ("hello , world", "hello , world"),
+            ("hello period", "hello."),
+            (
+                "Hello period. How are you question mark",
+                "Hello. How are you?",
+            ),
+            ("wait exclamation point stop", "wait! stop"),
+            ("wait exclamation mark", "wait!"),
+            ("note colon buy milk semicolon eggs", "note: buy milk; eggs"),
+            ("a semi colon b", "a; b"),
+            ("one full stop two", "one. two"),
+            ("really question mark exclamation mark", "really?!"),
+            ("so comma period", "so,."),
+            ("comma first", ", first"),
+            ("Comma, okay Period.", ", okay."),
+            ("say it comma) then", "say it,) then"),
+            ("it is comma \"quoted\"", "it is, \"quoted\""),
+        ];
+        for (input, expected) in cases {
+            assert_eq!(spoken_punctuation(input), expected, "input: '{input}'");
+        }
+    }
+
+    #[test]
+    fn test_spoken_punctuation_leaves_other_words() {
+        let cases = vec![
+            "periodic commas and colonoscopy",
+            "pi is 3.14, roughly",
+            "example.com",
+            "",
+        ];
+        for input in cases {
+            assert_eq!(spoken_punctuation(input), input);
+        }
+    }
+
+    #[test]
+    fn test_control_chars_stripped() {
+        assert_eq!(strip_control_chars("he\0llo\u{1b}[31m"), "hello[31m");
+        assert_eq!(strip_control_chars("a\r\nb\tc\u{7f}"), "a\nb\tc");
+        assert_eq!(strip_control_chars("plain text"), "plain text");
+    }
+
+    #[test]
+    fn test_line_breaks_flattened_to_spaces() {
+        assert_eq!(flatten_line_breaks("one\ntwo\tthree"), "one two three");
+        assert_eq!(flatten_line_breaks("one \n\n two\n"), "one two ");
+        assert_eq!(flatten_line_breaks("\tindented"), " indented");
+        // Spaces alone are left as they are
+        assert_eq!(flatten_line_breaks("a  b "), "a  b ");
+    }
+
+    #[test]
+    fn test_blocklist_mask() {
+        let blocklist = Blocklist::new(&["darn", "heck no"], BlockMode::Mask).unwrap();
+        assert_eq!(
+            blocklist.apply("Darn, that's DARN good"),
+            "***, that's *** good"
+        );
+        assert_eq!(blocklist.apply("oh heck  no"), "oh ***");
+        // Whole words only
+        assert_eq!(blocklist.apply("darned darnit"), "darned darnit");
+
+        // Entries that start or end with a symbol still match
+        let blocklist = Blocklist::new(&["c++", "#hashtag", "a.b."], BlockMode::Mask).unwrap();
+        assert_eq!(
+            blocklist.apply("C++ and #hashtag, a.b. done"),
+            "*** and ***, *** done"
+        );
+        assert_eq!(blocklist.apply("abc++ #hashtags"), "abc++ #hashtags");
+    }
+
+    #[test]
+    fn test_blocklist_drop_keeps_spacing() {
+        let blocklist = Blocklist::new(&["darn", "heck"], BlockMode::Drop).unwrap();
+        let cases = [
+            ("well darn it", "well it"),
+            ("darn it", "it"),
+            ("it broke darn", "it broke"),
+            ("oh darn, again", "oh, again"),
+            ("darn heck darn", ""),
+            ("heck. Fine", ". Fine"),
+            ("nothing here", "nothing here"),
+        ];
+        for (input, expected) in cases {
+            assert_eq!(blocklist.apply(input), expected, "input: '{input}'");
+        }
+        assert!(Blocklist::new(&["", "  "], BlockMode::Drop).is_err());
+        assert_eq!("DROP".parse::<BlockMode>().unwrap(), BlockMode::Drop);
+    }
+
+    #[test]
+    fn test_substitutions_replace_whole_phrases() {
+        let substitutions = Substitutions::new(&[
+            ("the problem", "theorem"),
+            ("deep gram", "Deepgram"),
+            ("deep gram api", "Deepgram API"),
+            ("gram", "g"),
+            ("see plus plus", "C++"),
+            ("c++", "C++"),
+        ])
+        .unwrap();
+        let cases = [
+            ("prove the problem", "prove theorem"),
+            ("Deep  Gram rocks", "Deepgram rocks"),
+            // The longest phrase wins where they overlap
+            ("call the deep gram API now", "call the Deepgram API now"),
+            ("one gram, deep gram.", "one g, Deepgram."),
+            ("in see plus plus or c++!", "in C++ or C++!"),
+            // Whole words only, and spacing around the phrase is kept
+            ("the problems  deepgram", "the problems  deepgram"),
+            ("  deep gram ", "  Deepgram "),
+        ];
+        for (input, expected) in cases {
+            assert_eq!(substitutions.apply(input), expected, "input: '{input}'");
+        }
+        assert!(Substitutions::new(&[(" ", "x")]).is_err());
+        assert!(Substitutions::new::<&str>(&[]).is_err());
+    }
+
+    #[test]
+    fn test_load_substitutions_file() {
+        let path = std::env::temp_dir().join(format!("vk-substitutions-{}", std::process::id()));
+        std::fs::write(
+            &path,
+            "# vocabulary\ndeep gram -> Deepgram\n\nk eight s->k8s\n",
+        )
+        .unwrap();
+        let substitutions = load_substitutions(&path).unwrap();
+        assert_eq!(
+            substitutions.apply("deep gram on k eight s"),
+            "Deepgram on k8s"
+        );
+
+        std::fs::write(&path, "deep gram = Deepgram\n").unwrap();
+        let err = load_substitutions(&path).unwrap_err();
+        assert!(format!("{err}").ends_with(":1: expected 'phrase -> replacement'"));
+        let _ = std::fs::remove_file(&path);
+    }
+}
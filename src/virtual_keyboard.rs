@@ -1,109 +1,536 @@
 #![allow(dead_code)]
 
-use anyhow::{Context, Result};
+#[cfg(target_os = "linux")]
+use anyhow::Context;
+use anyhow::Result;
+use chrono::format::{Item, StrftimeItems};
+use chrono::NaiveDateTime;
+use nix::errno::Errno;
+#[cfg(target_os = "linux")]
 use nix::fcntl::{open, OFlag};
+#[cfg(target_os = "linux")]
 use nix::sys::stat::Mode;
+#[cfg(target_os = "linux")]
 use nix::unistd::close;
-use regex::Regex;
+use std::collections::{HashMap, VecDeque};
 use std::io::Write;
+#[cfg(target_os = "linux")]
 use std::os::unix::io::FromRawFd;
-use tracing::{debug, error, info, warn};
+use std::str::FromStr;
+use std::time::Duration;
+#[cfg(target_os = "linux")]
+use tracing::error;
+use tracing::{debug, info, warn};
 
+use crate::commands::{CommandAction, CommandMatch, CommandTable, InputMode, ENTER_COMMAND};
+use crate::diff::{self, KeyOp};
+use crate::error::VkError;
 use crate::input_event::*;
+use crate::layout::{KeyboardLayout, Keymap};
+#[cfg(target_os = "linux")]
+use crate::metrics::METRICS;
+use crate::retry::RetryPolicy;
+use crate::transforms::{self, Blocklist, Substitutions};
 
 // Define ioctl macros for uinput
 // The nix ioctl_write_int! macro requires the ioctl type and number
+#[cfg(target_os = "linux")]
 nix::ioctl_write_int!(ui_set_evbit, b'U', 100);
+#[cfg(target_os = "linux")]
 nix::ioctl_write_int!(ui_set_keybit, b'U', 101);
+#[cfg(target_os = "linux")]
 nix::ioctl_none!(ui_dev_create, b'U', 1);
+#[cfg(target_os = "linux")]
 nix::ioctl_none!(ui_dev_destroy, b'U', 2);
 
+/// How long a write may keep hitting EAGAIN before we give up on the device
+#[cfg(target_os = "linux")]
+const WRITE_STALL_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(1);
+
+/// Write all of `bytes` to a non-blocking fd, returning how many write calls
+/// that took. Short writes continue with the remaining bytes and EAGAIN/EINTR
+/// are retried after a brief yield, so an event (or its SYN) is never
+/// half-delivered.
+#[cfg(target_os = "linux")]
+fn write_all_nonblocking(fd: i32, bytes: &[u8]) -> Result<u64> {
+    let mut written = 0;
+    let mut calls = 0;
+    let mut stalled_since = None;
+
+    while written < bytes.len() {
+        let remaining = &bytes[written..];
+        calls += 1;
+        let result = unsafe {
+            libc::write(
+                fd,
+                remaining.as_ptr() as *const libc::c_void,
+                remaining.len(),
+            )
+        };
+
+        if result > 0 {
+            written += result as usize;
+            stalled_since = None;
+            continue;
+        }
+
+        if result == 0 {
+            return Err(anyhow::anyhow!(
+                "Partial write: expected {} bytes, wrote {}",
+                bytes.len(),
+                written
+            ));
+        }
+
+        let err = std::io::Error::last_os_error();
+        match err.raw_os_error() {
+            Some(libc::EAGAIN) | Some(libc::EINTR) => {
+                let since = *stalled_since.get_or_insert_with(std::time::Instant::now);
+                if since.elapsed() > WRITE_STALL_TIMEOUT {
+                    return Err(anyhow::anyhow!(
+                        "Device not accepting writes: wrote {} of {} bytes",
+                        written,
+                        bytes.len()
+                    ));
+                }
+                std::thread::yield_now();
+            }
+            _ => return Err(err).context("Failed to write input event"),
+        }
+    }
+
+    Ok(calls)
+}
+
+/// Whether a device creation error is worth retrying
+/// Busy devices and a driver still coming up are transient; a missing
+/// /dev/uinput (the module isn't loaded), permission errors and anything
+/// unrecognised are fatal.
+fn is_transient_error(err: &anyhow::Error) -> bool {
+    err.chain().any(|cause| {
+        let errno = if let Some(errno) = cause.downcast_ref::<Errno>() {
+            Some(*errno)
+        } else {
+            cause
+                .downcast_ref::<std::io::Error>()
+                .and_then(|e| e.raw_os_error())
+                .map(Errno::from_i32)
+        };
+        matches!(
+            errno,
+            Some(Errno::EBUSY | Errno::EAGAIN | Errno::EINTR | Errno::ENODEV)
+        )
+    })
+}
+
+/// What to tell the user when /dev/uinput can't be opened
+#[cfg(target_os = "linux")]
+fn uinput_open_error_message(errno: Errno) -> String {
+    match errno {
+        Errno::ENOENT | Errno::ENODEV => "Failed to open /dev/uinput: the uinput module isn't \
+            loaded (run `sudo modprobe uinput`, and add it to /etc/modules-load.d to keep it)"
+            .to_string(),
+        Errno::EACCES | Errno::EPERM => "Failed to open /dev/uinput: permission denied (run as \
+            root with `sudo -E`, or add a udev rule giving your user access to uinput)"
+            .to_string(),
+        other => format!("Failed to open /dev/uinput ({})", other.desc()),
+    }
+}
+
+/// The error for a failed /dev/uinput open
+/// The errno is kept as its source, so retries still see the cause.
+#[cfg(target_os = "linux")]
+fn uinput_open_error(errno: Errno) -> VkError {
+    let message = uinput_open_error_message(errno);
+    match errno {
+        Errno::EACCES | Errno::EPERM => VkError::DevicePermission { message, errno },
+        _ => VkError::DeviceUnavailable {
+            message,
+            errno: Some(errno),
+        },
+    }
+}
+
+/// Run `attempt` until it succeeds, a non-transient error occurs, or the
+/// policy's retries are used up, sleeping with backoff in between
+fn retry_transient<T, F>(retry: RetryPolicy, mut attempt: F) -> Result<T>
+where
+    F: FnMut() -> Result<T>,
+{
+    let mut failures = 0;
+    loop {
+        match attempt() {
+            Ok(value) => return Ok(value),
+            Err(e) if failures < retry.retries && is_transient_error(&e) => {
+                let delay = retry.backoff_for(failures);
+                warn!(
+                    "Virtual keyboard creation failed ({:#}); retrying in {:?} (attempt {}/{})",
+                    e,
+                    delay,
+                    failures + 1,
+                    retry.retries
+                );
+                std::thread::sleep(delay);
+                failures += 1;
+            }
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+/// Key events as (keycode, pressed)
+type KeyEvents = Vec<(u16, bool)>;
+
+/// Key events that type `c` with `keymap`, wrapped in shift and/or AltGr as
+/// needed
+fn char_key_events(keymap: &Keymap, c: char) -> Option<KeyEvents> {
+    keymap.keystroke(c).map(|stroke| stroke.events())
+}
+
+/// Key events for each character of `text`, `None` where no key produces it.
+/// With `keymap.hold_shift`, shift is pressed once before a run of shifted
+/// characters and released after it, rather than around each one.
+fn text_key_events(keymap: &Keymap, text: &str) -> Vec<(char, Option<KeyEvents>)> {
+    if !keymap.hold_shift {
+        return text
+            .chars()
+            .map(|c| (c, char_key_events(keymap, c)))
+            .collect();
+    }
+
+    let strokes: Vec<_> = text.chars().map(|c| (c, keymap.keystroke(c))).collect();
+    let shifted = |i: Option<usize>| {
+        i.and_then(|i| strokes.get(i))
+            .is_some_and(|(_, stroke)| stroke.is_some_and(|s| s.shift))
+    };
+    strokes
+        .iter()
+        .enumerate()
+        .map(|(i, &(c, stroke))| {
+            let events = stroke.map(|stroke| {
+                let mut events = stroke.events();
+                // Shift is the outermost modifier, so it's first and last
+                if stroke.shift && shifted(Some(i + 1)) {
+                    events.pop();
+                }
+                if stroke.shift && shifted(i.checked_sub(1)) {
+                    events.remove(0);
+                }
+                events
+            });
+            (c, events)
+        })
+        .collect()
+}
+
 /// Hardware abstraction trait for keyboard operations
 pub trait KeyboardHardware {
     fn type_text(&mut self, text: &str) -> Result<()>;
     fn press_backspace(&mut self) -> Result<()>;
     fn press_enter(&mut self) -> Result<()>;
     fn press_key(&mut self, keycode: u16) -> Result<()>;
+    /// Press the keys in order, then release them in reverse (e.g. ctrl+shift+t)
+    fn press_combo(&mut self, keycodes: &[u16]) -> Result<()>;
+    /// Send a key-down event and leave the key held
+    fn press_down(&mut self, keycode: u16) -> Result<()>;
+    /// Send a key-up event, whether or not the key is down
+    fn release_key(&mut self, keycode: u16) -> Result<()>;
+    /// Press a key, keep it down for `duration`, then release it
+    fn hold_key(&mut self, keycode: u16, duration: Duration) -> Result<()>;
+    /// Enter a character no key produces, via the Ctrl+Shift+U code point
+    /// input method (GTK and IBus): the hex digits, then space to commit
+    fn type_unicode(&mut self, c: char) -> Result<()> {
+        self.press_combo(&[KEY_LEFTCTRL, KEY_LEFTSHIFT, KEY_U])?;
+        self.type_text(&format!("{:x}", c as u32))?;
+        self.press_key(KEY_SPACE)
+    }
+    /// Wait between keystrokes (queued rather than slept by async backends)
+    fn pause(&mut self, duration: Duration) {
+        std::thread::sleep(duration);
+    }
+    /// Modifiers this backend has pressed and not yet released; backends
+    /// that don't track them report none
+    fn modifier_state(&self) -> ModifierState {
+        ModifierState::default()
+    }
+    /// write(2) calls made to the device so far, for backends that have one
+    fn device_writes(&self) -> Option<u64> {
+        None
+    }
+}
+
+/// Lets the output backend be picked at runtime
+impl<H: KeyboardHardware + ?Sized> KeyboardHardware for Box<H> {
+    fn type_text(&mut self, text: &str) -> Result<()> {
+        (**self).type_text(text)
+    }
+
+    fn press_backspace(&mut self) -> Result<()> {
+        (**self).press_backspace()
+    }
+
+    fn press_enter(&mut self) -> Result<()> {
+        (**self).press_enter()
+    }
+
+    fn press_key(&mut self, keycode: u16) -> Result<()> {
+        (**self).press_key(keycode)
+    }
+
+    fn press_combo(&mut self, keycodes: &[u16]) -> Result<()> {
+        (**self).press_combo(keycodes)
+    }
+
+    fn press_down(&mut self, keycode: u16) -> Result<()> {
+        (**self).press_down(keycode)
+    }
+
+    fn release_key(&mut self, keycode: u16) -> Result<()> {
+        (**self).release_key(keycode)
+    }
+
+    fn hold_key(&mut self, keycode: u16, duration: Duration) -> Result<()> {
+        (**self).hold_key(keycode, duration)
+    }
+
+    fn type_unicode(&mut self, c: char) -> Result<()> {
+        (**self).type_unicode(c)
+    }
+
+    fn pause(&mut self, duration: Duration) {
+        (**self).pause(duration)
+    }
+
+    fn modifier_state(&self) -> ModifierState {
+        (**self).modifier_state()
+    }
+
+    fn device_writes(&self) -> Option<u64> {
+        (**self).device_writes()
+    }
+}
+
+/// Which of `MODIFIER_KEYS` have been pressed and not released since, as
+/// far as the events sent say
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct ModifierState {
+    held: [bool; MODIFIER_KEYS.len()],
+}
+
+impl ModifierState {
+    /// Follow a key event; keys other than modifiers are ignored
+    pub fn record(&mut self, keycode: u16, pressed: bool) {
+        if let Some(i) = MODIFIER_KEYS.iter().position(|&key| key == keycode) {
+            self.held[i] = pressed;
+        }
+    }
+
+    pub fn is_held(&self, keycode: u16) -> bool {
+        MODIFIER_KEYS
+            .iter()
+            .zip(self.held)
+            .any(|(&key, held)| held && key == keycode)
+    }
+
+    /// Keycodes of the held modifiers, in `MODIFIER_KEYS` order
+    pub fn held(&self) -> Vec<u16> {
+        MODIFIER_KEYS
+            .iter()
+            .zip(self.held)
+            .filter_map(|(&key, held)| held.then_some(key))
+            .collect()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        !self.held.contains(&true)
+    }
+}
+
+/// Event types the virtual device advertises. EV_REP has the kernel
+/// auto-repeat held keys like a physical keyboard's.
+const DEVICE_EVENT_TYPES: [u16; 2] = [EV_KEY, EV_REP];
+
+/// Auto-repeat timing for held keys
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct KeyRepeat {
+    /// Before the first repeat
+    pub delay: Duration,
+    /// Between repeats
+    pub period: Duration,
 }
 
+impl Default for KeyRepeat {
+    /// The kernel's defaults for devices that don't set their own
+    fn default() -> Self {
+        Self {
+            delay: Duration::from_millis(250),
+            period: Duration::from_millis(33),
+        }
+    }
+}
+
+impl KeyRepeat {
+    /// Writing these to the device sets its repeat parameters
+    fn events(&self) -> [InputEvent; 2] {
+        let ms = |d: Duration| d.as_millis().min(i32::MAX as u128) as i32;
+        [
+            InputEvent::new(EV_REP, REP_DELAY, ms(self.delay)),
+            InputEvent::new(EV_REP, REP_PERIOD, ms(self.period)),
+        ]
+    }
+}
+
+/// Pause after each character uinput types, unless `--char-delay-ms` says otherwise
+#[cfg(target_os = "linux")]
+const DEFAULT_CHAR_DELAY: Duration = Duration::from_millis(10);
+
 /// Real hardware implementation using Linux uinput
+#[cfg(target_os = "linux")]
 pub struct RealKeyboardHardware {
     fd: i32,
     name: String,
+    keymap: Keymap,
+    modifiers: ModifierState,
+    /// write(2) calls made for events, for `--benchmark`
+    writes: u64,
+    /// Pause after each character
+    char_delay: Duration,
 }
 
+#[cfg(target_os = "linux")]
 impl RealKeyboardHardware {
     pub fn new(device_name: &str) -> Result<Self> {
+        Self::with_retry(device_name, RetryPolicy::none())
+    }
+
+    /// Create the device, retrying transient failures (e.g. udev not settled
+    /// yet right after boot) per `retry`. Permission errors fail immediately.
+    pub fn with_retry(device_name: &str, retry: RetryPolicy) -> Result<Self> {
         info!("Creating virtual keyboard device: {}", device_name);
 
+        let fd = retry_transient(retry, || Self::create_device(device_name))?;
+
+        info!("Virtual keyboard '{}' created successfully", device_name);
+
+        Ok(Self {
+            fd,
+            name: device_name.to_string(),
+            keymap: Keymap::default(),
+            modifiers: ModifierState::default(),
+            writes: 0,
+            char_delay: DEFAULT_CHAR_DELAY,
+        })
+    }
+
+    /// Map characters to keys for `layout`, which must match the desktop's layout
+    pub fn set_layout(&mut self, layout: KeyboardLayout) {
+        self.keymap.layout = layout;
+    }
+
+    /// Characters to map differently from the layout (e.g. from `--keymap-file`)
+    pub fn set_keymap_overrides(
+        &mut self,
+        overrides: HashMap<char, (u16, bool)>,
+        replace_layout: bool,
+    ) {
+        self.keymap.set_overrides(overrides, replace_layout);
+    }
+
+    /// Hold shift across consecutive shifted characters (e.g. "ABC")
+    pub fn set_hold_shift(&mut self, enabled: bool) {
+        self.keymap.hold_shift = enabled;
+    }
+
+    /// Pause this long after each character; zero types as fast as the
+    /// device takes events
+    pub fn set_char_delay(&mut self, delay: Duration) {
+        self.char_delay = delay;
+    }
+
+    /// Change how fast held keys repeat (the kernel picks 250/33 ms otherwise)
+    pub fn set_key_repeat(&mut self, repeat: KeyRepeat) -> Result<()> {
+        debug!("Setting key repeat to {:?}", repeat);
+        for event in repeat.events() {
+            self.send_event(event).context("Failed to set key repeat")?;
+        }
+        self.send_event(InputEvent::syn_event())
+    }
+
+    /// One attempt at opening /dev/uinput and creating the device.
+    /// The fd is closed again if any step fails.
+    fn create_device(device_name: &str) -> Result<i32> {
         // Open uinput device
         let fd = open(
             "/dev/uinput",
             OFlag::O_WRONLY | OFlag::O_NONBLOCK,
             Mode::empty(),
         )
-        .context("Failed to open /dev/uinput")?;
+        .map_err(uinput_open_error)?;
 
         debug!("Opened uinput device with fd: {}", fd);
 
-        // Enable key events
-        unsafe {
-            ui_set_evbit(fd, EV_KEY as u64).context("Failed to enable key events")?;
-        }
+        let setup = || -> Result<()> {
+            // Enable key events, and kernel auto-repeat for held keys
+            for event_type in DEVICE_EVENT_TYPES {
+                unsafe {
+                    ui_set_evbit(fd, event_type as u64)
+                        .context(format!("Failed to enable event type {event_type:#x}"))?;
+                }
+            }
 
-        // Enable all required key codes
-        let keycodes = get_all_keycodes();
-        debug!("Enabling {} key codes", keycodes.len());
+            // Enable all required key codes
+            let keycodes = get_all_keycodes();
+            debug!("Enabling {} key codes", keycodes.len());
 
-        for keycode in keycodes {
-            unsafe {
-                ui_set_keybit(fd, keycode as u64)
-                    .context(format!("Failed to enable key code {keycode}"))?;
-            }
-        }
-
-        // Set up device using the legacy uinput_user_dev interface
-        let mut uidev = crate::input_event::UInputUserDev::default();
-        // Set device name
-        let name_bytes = device_name.as_bytes();
-        let copy_len = std::cmp::min(name_bytes.len(), uidev.name.len() - 1);
-        uidev.name[..copy_len].copy_from_slice(&name_bytes[..copy_len]);
-        // Set device id
-        uidev.id.bustype = 0x03; // USB
-        uidev.id.vendor = 0x1234;
-        uidev.id.product = 0x5678;
-        uidev.id.version = 1;
-        uidev.ff_effects_max = 0;
-
-        // Write device info
-        let mut file = unsafe { std::fs::File::from_raw_fd(fd) };
-        let uidev_bytes = unsafe {
-            std::slice::from_raw_parts(
-                &uidev as *const _ as *const u8,
-                std::mem::size_of::<crate::input_event::UInputUserDev>(),
-            )
-        };
+            for keycode in keycodes {
+                unsafe {
+                    ui_set_keybit(fd, keycode as u64)
+                        .context(format!("Failed to enable key code {keycode}"))?;
+                }
+            }
 
-        file.write_all(uidev_bytes)
-            .context("Failed to write device info")?;
+            // Set up device using the legacy uinput_user_dev interface
+            let mut uidev = crate::input_event::UInputUserDev::default();
+            // Set device name
+            let name_bytes = device_name.as_bytes();
+            let copy_len = std::cmp::min(name_bytes.len(), uidev.name.len() - 1);
+            uidev.name[..copy_len].copy_from_slice(&name_bytes[..copy_len]);
+            // Set device id
+            uidev.id.bustype = 0x03; // USB
+            uidev.id.vendor = 0x1234;
+            uidev.id.product = 0x5678;
+            uidev.id.version = 1;
+            uidev.ff_effects_max = 0;
 
-        // Prevent the file from being closed when it goes out of scope
-        std::mem::forget(file);
+            // Write device info; ManuallyDrop keeps the file from closing our fd
+            let mut file = std::mem::ManuallyDrop::new(unsafe { std::fs::File::from_raw_fd(fd) });
+            let uidev_bytes = unsafe {
+                std::slice::from_raw_parts(
+                    &uidev as *const _ as *const u8,
+                    std::mem::size_of::<crate::input_event::UInputUserDev>(),
+                )
+            };
 
-        // Create the device
-        unsafe {
-            ui_dev_create(fd).context("Failed to create uinput device")?;
-        }
+            file.write_all(uidev_bytes)
+                .context("Failed to write device info")?;
 
-        info!("Virtual keyboard '{}' created successfully", device_name);
+            // Create the device
+            unsafe {
+                ui_dev_create(fd).context("Failed to create uinput device")?;
+            }
 
-        Ok(Self {
-            fd,
-            name: device_name.to_string(),
-        })
+            Ok(())
+        };
+
+        if let Err(e) = setup() {
+            let _ = close(fd);
+            return Err(e);
+        }
+
+        Ok(fd)
     }
 
-    fn send_event(&self, event: InputEvent) -> Result<()> {
+    fn send_event(&mut self, event: InputEvent) -> Result<()> {
         let event_bytes = unsafe {
             std::slice::from_raw_parts(
                 &event as *const _ as *const u8,
@@ -111,26 +538,11 @@ impl RealKeyboardHardware {
             )
         };
 
-        let bytes_written = unsafe {
-            libc::write(
-                self.fd,
-                event_bytes.as_ptr() as *const libc::c_void,
-                event_bytes.len(),
-            )
-        };
-
-        if bytes_written != event_bytes.len() as isize {
-            return Err(anyhow::anyhow!(
-                "Partial write: expected {} bytes, wrote {}",
-                event_bytes.len(),
-                bytes_written
-            ));
-        }
-
+        self.writes += write_all_nonblocking(self.fd, event_bytes)?;
         Ok(())
     }
 
-    fn send_key(&self, keycode: u16, pressed: bool) -> Result<()> {
+    fn send_key(&mut self, keycode: u16, pressed: bool) -> Result<()> {
         debug!("Sending key: {} (pressed: {})", keycode, pressed);
 
         // Send key event
@@ -141,33 +553,30 @@ impl RealKeyboardHardware {
         let syn_event = InputEvent::syn_event();
         self.send_event(syn_event)?;
 
+        // Only once the kernel has the whole event, so a failed write
+        // doesn't leave the tracked state ahead of the device
+        self.modifiers.record(keycode, pressed);
+
         Ok(())
     }
 }
 
+#[cfg(target_os = "linux")]
 impl KeyboardHardware for RealKeyboardHardware {
     fn type_text(&mut self, text: &str) -> Result<()> {
         debug!("Typing text: '{}'", text);
 
-        for c in text.chars() {
-            if let Some((keycode, needs_shift)) = char_to_keycode(c) {
-                if needs_shift {
-                    // Press shift
-                    self.send_key(KEY_LEFTSHIFT, true)?;
-
-                    // Press the key
-                    self.send_key(keycode, true)?;
-                    self.send_key(keycode, false)?;
-
-                    // Release shift
-                    self.send_key(KEY_LEFTSHIFT, false)?;
-                } else {
-                    // Just press the key
-                    self.press_key(keycode)?;
+        for (c, events) in text_key_events(&self.keymap, text) {
+            if let Some(events) = events {
+                for (keycode, pressed) in events {
+                    self.send_key(keycode, pressed)?;
                 }
+                METRICS.add_chars_typed(1);
 
                 // Small delay between characters for more natural typing
-                std::thread::sleep(std::time::Duration::from_millis(10));
+                if !self.char_delay.is_zero() {
+                    std::thread::sleep(self.char_delay);
+                }
             } else {
                 warn!("Unsupported character: '{}'", c);
             }
@@ -177,7 +586,9 @@ impl KeyboardHardware for RealKeyboardHardware {
     }
 
     fn press_backspace(&mut self) -> Result<()> {
-        self.press_key(KEY_BACKSPACE)
+        self.press_key(KEY_BACKSPACE)?;
+        METRICS.record_backspace();
+        Ok(())
     }
 
     fn press_enter(&mut self) -> Result<()> {
@@ -189,12 +600,53 @@ impl KeyboardHardware for RealKeyboardHardware {
         self.send_key(keycode, false)?;
         Ok(())
     }
+
+    fn press_combo(&mut self, keycodes: &[u16]) -> Result<()> {
+        for &keycode in keycodes {
+            self.send_key(keycode, true)?;
+        }
+        for &keycode in keycodes.iter().rev() {
+            self.send_key(keycode, false)?;
+        }
+        Ok(())
+    }
+
+    fn press_down(&mut self, keycode: u16) -> Result<()> {
+        self.send_key(keycode, true)
+    }
+
+    fn release_key(&mut self, keycode: u16) -> Result<()> {
+        self.send_key(keycode, false)
+    }
+
+    fn hold_key(&mut self, keycode: u16, duration: Duration) -> Result<()> {
+        // send_key follows each event with a SYN, so the press lands before we wait
+        self.send_key(keycode, true)?;
+        std::thread::sleep(duration);
+        self.send_key(keycode, false)
+    }
+
+    fn modifier_state(&self) -> ModifierState {
+        self.modifiers
+    }
+
+    fn device_writes(&self) -> Option<u64> {
+        Some(self.writes)
+    }
 }
 
+#[cfg(target_os = "linux")]
 impl Drop for RealKeyboardHardware {
     fn drop(&mut self) {
         info!("Destroying virtual keyboard '{}'", self.name);
 
+        // Don't leave a modifier logically held if we stopped mid-combo
+        for keycode in MODIFIER_KEYS {
+            if let Err(e) = self.send_key(keycode, false) {
+                debug!("Failed to release key {} on shutdown: {}", keycode, e);
+            }
+        }
+
         // Destroy the device
         unsafe {
             if let Err(e) = ui_dev_destroy(self.fd) {
@@ -213,667 +665,3932 @@ impl Drop for RealKeyboardHardware {
 
 // Safety: RealKeyboardHardware only contains a file descriptor and a string,
 // both of which are safe to send between threads
+#[cfg(target_os = "linux")]
 unsafe impl Send for RealKeyboardHardware {}
+#[cfg(target_os = "linux")]
 unsafe impl Sync for RealKeyboardHardware {}
 
-/// Business logic layer that handles transcript processing and enter command detection
-pub struct VirtualKeyboard<H: KeyboardHardware> {
-    hardware: H,
-    current_text: String,
-    interpret_enter_word: bool,
-    uppercase_enabled: bool,
+/// Stand-in where there's no uinput, so the rest of the crate (and the
+/// mock-based tests) still builds. It can never be constructed.
+#[cfg(not(target_os = "linux"))]
+pub struct RealKeyboardHardware {
+    never: std::convert::Infallible,
 }
 
-impl<H: KeyboardHardware> VirtualKeyboard<H> {
-    pub fn new(hardware: H) -> Self {
-        Self {
-            hardware,
-            current_text: String::new(),
-            interpret_enter_word: true,
-            uppercase_enabled: false,
-        }
+#[cfg(not(target_os = "linux"))]
+impl RealKeyboardHardware {
+    pub fn new(device_name: &str) -> Result<Self> {
+        Self::with_retry(device_name, RetryPolicy::none())
     }
 
-    /// Enable or disable interpreting the word 'enter' at the end of a turn
-    pub fn set_voice_enter_enabled(&mut self, enabled: bool) {
-        self.interpret_enter_word = enabled;
+    pub fn with_retry(_device_name: &str, _retry: RetryPolicy) -> Result<Self> {
+        Err(VkError::DeviceUnavailable {
+            message: format!(
+                "The virtual keyboard needs Linux uinput, which isn't available on {} \
+                 (try --simulate)",
+                std::env::consts::OS
+            ),
+            errno: None,
+        }
+        .into())
     }
 
-    /// Enable or disable uppercase conversion of all typed text
-    pub fn set_uppercase_enabled(&mut self, enabled: bool) {
-        self.uppercase_enabled = enabled;
+    pub fn set_layout(&mut self, _layout: KeyboardLayout) {
+        match self.never {}
     }
 
-    /// Update the transcript incrementally, handling smart backspacing
-    /// 1. Type new characters if the new transcript extends the current one
-    /// 2. Only backspace the characters that actually changed, then type the new ending
-    pub fn update_transcript(&mut self, new_transcript: &str) -> Result<()> {
-        // Conditionally convert the new transcript to uppercase
-        let processed_transcript = if self.uppercase_enabled {
-            new_transcript.to_uppercase()
-        } else {
-            new_transcript.to_string()
-        };
-        
-        debug!(
-            "Updating transcript from '{}' to '{}' (original: '{}', uppercase: {})",
-            self.current_text, processed_transcript, new_transcript, self.uppercase_enabled
-        );
+    pub fn set_keymap_overrides(
+        &mut self,
+        _overrides: HashMap<char, (u16, bool)>,
+        _replace_layout: bool,
+    ) {
+        match self.never {}
+    }
 
-        // If the new transcript is empty, clear everything
-        if processed_transcript.is_empty() {
-            self.clear_current_text()?;
-            return Ok(());
-        }
+    pub fn set_hold_shift(&mut self, _enabled: bool) {
+        match self.never {}
+    }
 
-        // Check if the new transcript is just an extension of the current one
-        if processed_transcript.starts_with(&self.current_text) {
-            // Just type the new characters
-            let new_chars = &processed_transcript[self.current_text.len()..];
-            if !new_chars.is_empty() {
-                debug!("Typing new characters: '{}'", new_chars);
-                self.hardware.type_text(new_chars)?;
-                self.current_text = processed_transcript.to_string();
-            }
-        } else {
-            // Find the common prefix between current and new transcript
-            let common_prefix_len = self
-                .current_text
-                .chars()
-                .zip(processed_transcript.chars())
-                .take_while(|(a, b)| a == b)
-                .count();
+    pub fn set_char_delay(&mut self, _delay: Duration) {
+        match self.never {}
+    }
 
-            let current_chars: Vec<char> = self.current_text.chars().collect();
-            let chars_to_backspace = current_chars.len() - common_prefix_len;
+    pub fn set_key_repeat(&mut self, _repeat: KeyRepeat) -> Result<()> {
+        match self.never {}
+    }
+}
 
-            debug!(
-                "Common prefix length: {}, need to backspace {} characters",
-                common_prefix_len, chars_to_backspace
-            );
+#[cfg(not(target_os = "linux"))]
+impl KeyboardHardware for RealKeyboardHardware {
+    fn type_text(&mut self, _text: &str) -> Result<()> {
+        match self.never {}
+    }
 
-            // Only backspace the characters that differ
-            if chars_to_backspace > 0 {
-                debug!("Backspacing {} characters", chars_to_backspace);
-                for _ in 0..chars_to_backspace {
-                    self.hardware.press_backspace()?;
-                }
-            }
+    fn press_backspace(&mut self) -> Result<()> {
+        match self.never {}
+    }
 
-            // Type the new ending (everything after the common prefix)
-            let new_chars: Vec<char> = processed_transcript.chars().collect();
-            if common_prefix_len < new_chars.len() {
-                let new_ending: String = new_chars[common_prefix_len..].iter().collect();
-                debug!("Typing new ending: '{}'", new_ending);
-                self.hardware.type_text(&new_ending)?;
-            }
+    fn press_enter(&mut self) -> Result<()> {
+        match self.never {}
+    }
 
-            self.current_text = processed_transcript.to_string();
-        }
+    fn press_key(&mut self, _keycode: u16) -> Result<()> {
+        match self.never {}
+    }
 
-        Ok(())
+    fn press_combo(&mut self, _keycodes: &[u16]) -> Result<()> {
+        match self.never {}
     }
 
-    /// Finalize the current transcript
-    /// If the transcript ends with "enter" (with optional punctuation/whitespace),
-    /// backspace that portion and press the ENTER key
-    /// Otherwise, just finalize without pressing enter
-    pub fn finalize_transcript(&mut self) -> Result<()> {
-        debug!("Finalizing transcript: '{}'", self.current_text);
-        
-        if self.interpret_enter_word {
-            // Regex to match "enter" (case-insensitive) at the end, optionally followed by 
-            // punctuation and/or whitespace: (?i)\s*\benter\b[[:punct:]\s]*$
-            // (?i) = case insensitive
-            // \s* = optional leading whitespace  
-            // \benter\b = the word "enter" with word boundaries
-            // [[:punct:]\s]* = optional trailing punctuation or whitespace
-            // $ = end of string
-            let enter_regex = Regex::new(r"(?i)\s*\benter\b[[:punct:]\s]*$").unwrap();
-            
-            // Find the match and extract the information we need before mutating self
-            let match_info = enter_regex.find(&self.current_text).map(|m| {
-                (m.start(), m.as_str().chars().count(), m.as_str().to_string())
-            });
-            
-            if let Some((start_pos, chars_to_backspace, matched_str)) = match_info {
-                debug!("Found 'enter' command at end of transcript: '{}'", matched_str);
-                debug!("Backspacing {} characters for 'enter' command", chars_to_backspace);
-                
-                // Backspace the matched portion
-                for _ in 0..chars_to_backspace {
-                    self.hardware.press_backspace()?;
-                    // Small delay between backspaces for reliability
-                    std::thread::sleep(std::time::Duration::from_millis(5));
-                }
-                
-                // Update our internal tracking to remove the backspaced characters
-                self.current_text = self.current_text[..start_pos].to_string();
-                
-                // Press the actual ENTER key
-                debug!("Pressing ENTER key");
-                self.hardware.press_enter()?;
-            }
-        } else {
-            // Voice-enter disabled: always press ENTER at end-of-turn
-            debug!("Voice-enter disabled; pressing ENTER at end-of-turn");
-            self.hardware.press_enter()?;
-        }
-        
-        // Clear the current text tracking
-        self.current_text.clear();
-        
-        Ok(())
+    fn press_down(&mut self, _keycode: u16) -> Result<()> {
+        match self.never {}
     }
 
-    /// Clear the current text by backspacing
-    fn clear_current_text(&mut self) -> Result<()> {
-        if !self.current_text.is_empty() {
-            self.backspace_current_text()?;
-        }
-        Ok(())
+    fn release_key(&mut self, _keycode: u16) -> Result<()> {
+        match self.never {}
     }
 
-    /// Backspace all characters in the current text
-    fn backspace_current_text(&mut self) -> Result<()> {
-        let char_count = self.current_text.chars().count();
-        debug!("Backspacing {} characters", char_count);
+    fn hold_key(&mut self, _keycode: u16, _duration: Duration) -> Result<()> {
+        match self.never {}
+    }
+}
 
-        for _ in 0..char_count {
-            self.hardware.press_backspace()?;
-            // Small delay between backspaces for reliability
-            std::thread::sleep(std::time::Duration::from_millis(5));
-        }
+/// Which key combination the "enter" action sends
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum EnterKey {
+    #[default]
+    Enter,
+    /// Newline rather than send in most chat apps
+    ShiftEnter,
+    /// Submit in apps where plain Enter inserts a newline
+    CtrlEnter,
+}
 
-        self.current_text.clear();
-        Ok(())
-    }
+impl FromStr for EnterKey {
+    type Err = anyhow::Error;
 
-    /// Get the current text that has been typed
-    pub fn get_current_text(&self) -> &str {
-        &self.current_text
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_ascii_lowercase().as_str() {
+            "enter" => Ok(Self::Enter),
+            "shift-enter" => Ok(Self::ShiftEnter),
+            "ctrl-enter" => Ok(Self::CtrlEnter),
+            other => anyhow::bail!(
+                "Unknown enter key '{}' (expected enter, shift-enter or ctrl-enter)",
+                other
+            ),
+        }
     }
 }
 
-/// Mock hardware implementation for testing
-pub struct MockKeyboardHardware {
-    pub typed_chars: Vec<char>,
-    pub backspace_count: usize,
-    pub enter_pressed: bool,
+/// What is typed between consecutive turns that didn't end with Enter
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum TurnSeparator {
+    /// Turns run together
+    #[default]
+    None,
+    Space,
+    Newline,
 }
 
-impl MockKeyboardHardware {
-    pub fn new() -> Self {
-        Self {
-            typed_chars: Vec::new(),
-            backspace_count: 0,
-            enter_pressed: false,
+impl FromStr for TurnSeparator {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_ascii_lowercase().as_str() {
+            "none" => Ok(Self::None),
+            "space" => Ok(Self::Space),
+            "newline" => Ok(Self::Newline),
+            other => anyhow::bail!(
+                "Unknown turn separator '{}' (expected none, space or newline)",
+                other
+            ),
         }
     }
 }
 
-impl KeyboardHardware for MockKeyboardHardware {
-    fn type_text(&mut self, text: &str) -> Result<()> {
-        for c in text.chars() {
-            self.typed_chars.push(c);
+/// How far back a transcript correction is retyped from
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum DiffStrategy {
+    /// From the first differing character (fewest keystrokes)
+    #[default]
+    Char,
+    /// From the start of the word containing the first difference, so a
+    /// changed word is always retyped whole rather than spliced mid-word
+    Word,
+    /// Fewest keystrokes: edit in place with Left/End when that beats
+    /// retyping the tail. Only for apps where those keys move the text cursor.
+    Minimal,
+}
+
+impl FromStr for DiffStrategy {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_ascii_lowercase().as_str() {
+            "char" => Ok(Self::Char),
+            "word" => Ok(Self::Word),
+            "minimal" => Ok(Self::Minimal),
+            other => anyhow::bail!(
+                "Unknown diff strategy '{}' (expected char, word or minimal)",
+                other
+            ),
         }
-        Ok(())
     }
+}
 
-    fn press_backspace(&mut self) -> Result<()> {
-        self.backspace_count += 1;
-        if !self.typed_chars.is_empty() {
-            self.typed_chars.pop();
+/// How runs of backspaces are spaced out, for targets (remote desktops, web
+/// editors) that drop key events sent too quickly
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BackspacePacing {
+    /// Backspaces sent back to back before each pause
+    pub batch: usize,
+    /// Pause after each batch
+    pub pause: Duration,
+    /// Cap on the average backspaces per second; pauses are lengthened to keep under it
+    pub max_per_second: Option<u32>,
+}
+
+impl Default for BackspacePacing {
+    fn default() -> Self {
+        Self {
+            batch: 1,
+            pause: Duration::ZERO,
+            max_per_second: None,
         }
-        Ok(())
     }
+}
 
-    fn press_enter(&mut self) -> Result<()> {
-        self.enter_pressed = true;
-        Ok(())
+impl BackspacePacing {
+    /// How long to wait after a batch of `count` backspaces
+    fn pause_after(&self, count: usize) -> Duration {
+        let capped = self.max_per_second.map_or(Duration::ZERO, |rate| {
+            Duration::from_secs(count as u64) / rate.max(1)
+        });
+        self.pause.max(capped)
     }
+}
 
-    fn press_key(&mut self, _keycode: u16) -> Result<()> {
-        // Mock implementation - could log the keycode if needed
-        Ok(())
+/// Move a split point back to the start of the word it falls in
+fn word_start(chars: &[char], mut index: usize) -> usize {
+    while index > 0 && !chars[index - 1].is_whitespace() {
+        index -= 1;
     }
+    index
 }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+/// Observer invoked with the tracked text after it changes
+pub type TextChangeHook = Box<dyn Fn(&str) + Send>;
 
-    #[test]
-    fn test_incremental_typing_extension() {
-        let mut kb = VirtualKeyboard::new(MockKeyboardHardware::new());
+/// Source of the local time for "insert date" and "insert time"
+pub type Clock = Box<dyn Fn() -> NaiveDateTime + Send>;
 
-        // Start with "hello"
-        kb.update_transcript("hello").unwrap();
-        assert_eq!(kb.current_text, "hello");
-        assert_eq!(kb.hardware.typed_chars, ['h', 'e', 'l', 'l', 'o']);
-        assert_eq!(kb.hardware.backspace_count, 0);
+/// Commands "undo that" can go back through
+const UNDO_HISTORY: usize = 10;
 
-        // Extend to "hello world"
-        kb.update_transcript("hello world").unwrap();
-        assert_eq!(kb.current_text, "hello world");
-        assert_eq!(
-            kb.hardware.typed_chars,
-            ['h', 'e', 'l', 'l', 'o', ' ', 'w', 'o', 'r', 'l', 'd']
-        );
-        assert_eq!(kb.hardware.backspace_count, 0);
-    }
+/// A finalized command as "undo that" sees it
+#[derive(Debug, Clone)]
+struct UndoEntry {
+    command: String,
+    /// Text the command removed, re-typed on undo; `None` if it can't be undone
+    deleted: Option<String>,
+}
 
-    #[test]
-    fn test_incremental_typing_change() {
-        let mut kb = VirtualKeyboard::new(MockKeyboardHardware::new());
+/// What a command did, as reported by `dispatch_command`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CommandOutcome {
+    /// The command typed this text (e.g. a date, an emoji or tab-as-spaces)
+    TypedText(String),
+    /// The command sent these keys, held together
+    PressedKey(Vec<u16>),
+    /// The command ended the turn without typing or sending keys of its own,
+    /// e.g. pressing Enter, deleting text or switching mode
+    Finalized,
+    /// No command matches the phrase; nothing was done
+    NoMatch,
+}
 
-        // Start with "hello"
-        kb.update_transcript("hello").unwrap();
-        assert_eq!(kb.current_text, "hello");
-        assert_eq!(kb.hardware.typed_chars, ['h', 'e', 'l', 'l', 'o']);
+pub const DEFAULT_DATE_FORMAT: &str = "%Y-%m-%d";
+pub const DEFAULT_TIME_FORMAT: &str = "%H:%M";
 
-        // Change to "hi there" - should only backspace "ello" and type "i there"
-        kb.update_transcript("hi there").unwrap();
-        assert_eq!(kb.current_text, "hi there");
-        // Should have backspaced 4 characters ("ello") and typed 7 new ones ("i there")
-        assert_eq!(kb.hardware.backspace_count, 4);
-        assert_eq!(
-            kb.hardware.typed_chars,
-            ['h', 'i', ' ', 't', 'h', 'e', 'r', 'e']
-        );
+/// Check a strftime format such as `%d/%m/%Y` for `--date-format` and `--time-format`
+pub fn parse_time_format(s: &str) -> Result<String> {
+    if StrftimeItems::new(s).any(|item| item == Item::Error) {
+        anyhow::bail!("Invalid date/time format '{}' (see chrono's strftime)", s);
     }
+    Ok(s.to_string())
+}
 
-    #[test]
-    fn test_finalize_transcript() {
-        let mut kb = VirtualKeyboard::new(MockKeyboardHardware::new());
+/// Business logic layer that handles transcript processing and enter command detection
+pub struct VirtualKeyboard<H: KeyboardHardware> {
+    hardware: H,
+    current_text: String,
+    interpret_enter_word: bool,
+    commands: CommandTable,
+    /// Whether command phrases are acted on or typed
+    input_mode: InputMode,
+    /// Spaces the "tab" command types instead of pressing Tab; 0 for the key
+    tab_spaces: usize,
+    auto_enter: bool,
+    smart_punctuation: bool,
+    continuous_line: bool,
+    number_mode: bool,
+    spoken_punctuation: bool,
+    substitutions: Option<Substitutions>,
+    blocklist: Option<Blocklist>,
+    /// Text finalized on the current line, kept across turns in continuous-line mode
+    line_base: String,
+    /// What the previous turn left on screen, for "correct that" (best-effort:
+    /// we can't see the cursor, so it is forgotten after Enter or a key command)
+    last_finalized: String,
+    /// The last line a turn finalized, without the line suffix, and whether
+    /// Enter followed it, for "repeat that"
+    last_line: Option<(String, bool)>,
+    /// Recent commands for "undo that", oldest first; forgotten once text is
+    /// typed after them or the cursor moves away
+    undo_history: VecDeque<UndoEntry>,
+    /// Whether a space separator was typed before this turn's text
+    turn_separated: bool,
+    /// Whether an update arrived since the last finalize, so a repeated
+    /// EndOfTurn for the same turn is ignored rather than acted on twice
+    turn_open: bool,
+    uppercase_enabled: bool,
+    force_lowercase: bool,
+    max_backspace: Option<usize>,
+    diff_strategy: DiffStrategy,
+    backspace_pacing: BackspacePacing,
+    enter_key: EnterKey,
+    turn_separator: TurnSeparator,
+    /// Whether the previous turn's text ends the line, so the next turn
+    /// starts with the separator
+    separator_pending: bool,
+    /// Whether that text ends in whitespace, so a space separator would double it
+    pending_after_space: bool,
+    /// Whether the last turn was finalized by the client rather than the
+    /// server, so the speaker may be mid-utterance: more text continues after
+    /// a space, and the turn's Enter waits for the server to end the turn
+    ended_locally: bool,
+    /// Whether the current turn starts at the beginning of a line, as far as we know
+    line_start: bool,
+    trim_leading_space: bool,
+    /// Typed before the first text of each line and after its last, before
+    /// any Enter
+    line_prefix: String,
+    line_suffix: String,
+    /// Whether the desktop accepts Ctrl+Shift+U code point input
+    unicode_input: bool,
+    on_text_change: Option<TextChangeHook>,
+    on_finalize: Option<TextChangeHook>,
+    date_format: String,
+    time_format: String,
+    clock: Clock,
+}
 
-        // Type some text
-        kb.update_transcript("hello").unwrap();
-        assert_eq!(kb.current_text, "hello");
-        assert!(!kb.hardware.enter_pressed);
+impl<H: KeyboardHardware> VirtualKeyboard<H> {
+    pub fn new(hardware: H) -> Self {
+        Self {
+            hardware,
+            current_text: String::new(),
+            interpret_enter_word: true,
+            commands: CommandTable::with_enter(false),
+            input_mode: InputMode::default(),
+            tab_spaces: 0,
+            auto_enter: false,
+            smart_punctuation: false,
+            continuous_line: false,
+            number_mode: false,
+            spoken_punctuation: false,
+            substitutions: None,
+            blocklist: None,
+            line_base: String::new(),
+            last_finalized: String::new(),
+            last_line: None,
+            undo_history: VecDeque::new(),
+            turn_separated: false,
+            turn_open: false,
+            uppercase_enabled: false,
+            force_lowercase: false,
+            max_backspace: None,
+            diff_strategy: DiffStrategy::default(),
+            backspace_pacing: BackspacePacing::default(),
+            enter_key: EnterKey::default(),
+            turn_separator: TurnSeparator::default(),
+            separator_pending: false,
+            pending_after_space: false,
+            ended_locally: false,
+            line_start: true,
+            trim_leading_space: false,
+            line_prefix: String::new(),
+            line_suffix: String::new(),
+            unicode_input: true,
+            on_text_change: None,
+            on_finalize: None,
+            date_format: DEFAULT_DATE_FORMAT.to_string(),
+            time_format: DEFAULT_TIME_FORMAT.to_string(),
+            clock: Box::new(|| chrono::Local::now().naive_local()),
+        }
+    }
 
-        // Finalize (should NOT press enter since text doesn't end with "enter")
-        kb.finalize_transcript().unwrap();
-        assert_eq!(kb.current_text, "");
-        assert!(!kb.hardware.enter_pressed); // Should remain false
-        assert_eq!(kb.hardware.typed_chars, ['h', 'e', 'l', 'l', 'o']);
+    /// Enable or disable interpreting the word 'enter' at the end of a turn
+    pub fn set_voice_enter_enabled(&mut self, enabled: bool) {
+        self.interpret_enter_word = enabled;
+        self.commands.set_enabled(ENTER_COMMAND, enabled);
     }
 
-    #[test]
-    fn test_empty_transcript() {
-        let mut kb = VirtualKeyboard::new(MockKeyboardHardware::new());
+    /// Enable or disable absorbing a verb ("press", "hit", "send") directly
+    /// before the trailing 'enter', so "press enter" leaves no stray text
+    pub fn set_enter_verb_enabled(&mut self, enabled: bool) {
+        self.commands.upsert(CommandTable::enter_command(enabled));
+    }
 
-        // Start with some text
-        kb.update_transcript("hello").unwrap();
-        assert_eq!(kb.current_text, "hello");
+    /// Enable or disable the optional editing commands ("tab", "escape",
+    /// "scratch that", "clear line") on top of "enter"
+    pub fn set_voice_commands_enabled(&mut self, enabled: bool) {
+        if enabled {
+            self.commands.add_builtin_commands();
+        } else {
+            self.commands
+                .retain(|command| command.name == ENTER_COMMAND);
+        }
+    }
 
-        // Clear with empty transcript
-        kb.update_transcript("").unwrap();
-        assert_eq!(kb.current_text, "");
-        assert_eq!(kb.hardware.backspace_count, 5);
+    /// Start in `mode`; anything but blended also adds the phrases that
+    /// switch modes, so dictation mode can be left by voice
+    pub fn set_input_mode(&mut self, mode: InputMode) {
+        if mode != InputMode::Blended {
+            self.commands.add_mode_commands();
+        }
+        self.input_mode = mode;
     }
 
-    #[test]
-    fn test_smart_backspacing_partial_change() {
-        let mut kb = VirtualKeyboard::new(MockKeyboardHardware::new());
+    pub fn input_mode(&self) -> InputMode {
+        self.input_mode
+    }
 
-        // Start with "hello world"
-        kb.update_transcript("hello world").unwrap();
-        assert_eq!(kb.current_text, "hello world");
-        assert_eq!(
-            kb.hardware.typed_chars,
-            ['h', 'e', 'l', 'l', 'o', ' ', 'w', 'o', 'r', 'l', 'd']
-        );
+    /// The trailing-phrase commands checked when a turn is finalized
+    pub fn commands_mut(&mut self) -> &mut CommandTable {
+        &mut self.commands
+    }
 
-        // Change to "hello there" - should only backspace "world" and type "there"
-        kb.update_transcript("hello there").unwrap();
-        assert_eq!(kb.current_text, "hello there");
-        // Should have backspaced 5 characters ("world") and typed 5 new ones ("there")
-        assert_eq!(kb.hardware.backspace_count, 5);
-        assert_eq!(
-            kb.hardware.typed_chars,
-            ['h', 'e', 'l', 'l', 'o', ' ', 't', 'h', 'e', 'r', 'e']
-        );
+    /// Have the "tab" command type `spaces` spaces (e.g. to indent code
+    /// where Tab would trigger completion); 0 presses the Tab key
+    pub fn set_tab_as_spaces(&mut self, spaces: usize) {
+        self.tab_spaces = spaces;
     }
 
-    #[test]
-    fn test_smart_backspacing_no_common_prefix() {
-        let mut kb = VirtualKeyboard::new(MockKeyboardHardware::new());
+    /// Enable or disable pressing ENTER after every finalized turn that isn't
+    /// a command. A spoken 'enter' is still stripped, and ENTER is pressed only once.
+    pub fn set_auto_enter_enabled(&mut self, enabled: bool) {
+        self.auto_enter = enabled;
+    }
 
-        // Start with "hello"
-        kb.update_transcript("hello").unwrap();
-        assert_eq!(kb.current_text, "hello");
+    /// Enable or disable adding a trailing period to unpunctuated lines on finalize
+    pub fn set_smart_punctuation_enabled(&mut self, enabled: bool) {
+        self.smart_punctuation = enabled;
+    }
 
-        // Change to "goodbye" - no common prefix, should backspace all and retype
-        kb.update_transcript("goodbye").unwrap();
-        assert_eq!(kb.current_text, "goodbye");
-        // Should have backspaced 5 characters and typed 7 new ones
-        assert_eq!(kb.hardware.backspace_count, 5);
-        assert_eq!(kb.hardware.typed_chars, ['g', 'o', 'o', 'd', 'b', 'y', 'e']);
+    /// Enable or disable keeping finalized text on the line when no ENTER is pressed.
+    /// The next turn then diffs against it instead of starting fresh.
+    pub fn set_continuous_line_enabled(&mut self, enabled: bool) {
+        self.continuous_line = enabled;
+        if !enabled {
+            self.line_base.clear();
+        }
     }
 
-    #[test]
-    fn test_smart_backspacing_shortening() {
-        let mut kb = VirtualKeyboard::new(MockKeyboardHardware::new());
+    /// Enable or disable converting spoken number words to digits
+    /// ("five five five" -> "555")
+    pub fn set_number_mode_enabled(&mut self, enabled: bool) {
+        self.number_mode = enabled;
+    }
 
-        // Start with "hello world"
-        kb.update_transcript("hello world").unwrap();
-        assert_eq!(kb.current_text, "hello world");
+    /// Enable or disable typing spoken punctuation words ("comma", "question mark", ...) as marks
+    pub fn set_spoken_punctuation_enabled(&mut self, enabled: bool) {
+        self.spoken_punctuation = enabled;
+    }
 
-        // Change to "hello" - should only backspace " world"
-        kb.update_transcript("hello").unwrap();
-        assert_eq!(kb.current_text, "hello");
-        // Should have backspaced 6 characters (" world") and typed 0 new ones
-        assert_eq!(kb.hardware.backspace_count, 6);
-        assert_eq!(kb.hardware.typed_chars, ['h', 'e', 'l', 'l', 'o']);
+    /// Set the strftime format typed by "insert date"
+    pub fn set_date_format(&mut self, format: String) {
+        self.date_format = format;
     }
 
-    #[test]
-    fn test_finalize_with_enter_command() {
-        let mut kb = VirtualKeyboard::new(MockKeyboardHardware::new());
+    /// Set the strftime format typed by "insert time"
+    pub fn set_time_format(&mut self, format: String) {
+        self.time_format = format;
+    }
 
-        // Type text ending with "enter"
-        kb.update_transcript("Write a unit test enter").unwrap();
-        assert_eq!(kb.current_text, "Write a unit test enter");
-        assert!(!kb.hardware.enter_pressed);
+    /// Replace the local clock, e.g. with a fixed time in tests
+    pub fn set_clock(&mut self, clock: Clock) {
+        self.clock = clock;
+    }
 
-        // Finalize - should backspace "enter" and press ENTER key
-        kb.finalize_transcript().unwrap();
-        assert_eq!(kb.current_text, "");
-        assert!(kb.hardware.enter_pressed);
-        // Should have backspaced 6 characters (" enter")
-        assert_eq!(kb.hardware.backspace_count, 6);
+    /// Replace the user's vocabulary phrases before anything else rewrites them
+    pub fn set_substitutions(&mut self, substitutions: Option<Substitutions>) {
+        self.substitutions = substitutions;
     }
 
-    #[test]
-    fn test_finalize_with_enter_only() {
-        let mut kb = VirtualKeyboard::new(MockKeyboardHardware::new());
+    /// Censor or drop blocklisted words before they are typed
+    pub fn set_blocklist(&mut self, blocklist: Option<Blocklist>) {
+        self.blocklist = blocklist;
+    }
 
-        // Type only "enter"
-        kb.update_transcript("enter").unwrap();
-        assert_eq!(kb.current_text, "enter");
-        assert!(!kb.hardware.enter_pressed);
+    /// Enable or disable uppercase conversion of all typed text
+    pub fn set_uppercase_enabled(&mut self, enabled: bool) {
+        self.uppercase_enabled = enabled;
+    }
 
-        // Finalize - should backspace all and press ENTER key
-        kb.finalize_transcript().unwrap();
-        assert_eq!(kb.current_text, "");
-        assert!(kb.hardware.enter_pressed);
-        // Should have backspaced 5 characters ("enter")
-        assert_eq!(kb.hardware.backspace_count, 5);
+    /// Type everything in lowercase, whatever casing the model picks; only
+    /// applies without uppercase mode
+    pub fn set_force_lowercase(&mut self, enabled: bool) {
+        self.force_lowercase = enabled;
     }
 
-    #[test]
-    fn test_finalize_with_enter_case_insensitive() {
-        let mut kb = VirtualKeyboard::new(MockKeyboardHardware::new());
+    /// Limit how many characters a single transcript update may backspace.
+    /// Corrections beyond the cap are clamped so a runaway transcript can't
+    /// eat text that was on screen before it. `None` means unlimited.
+    pub fn set_max_backspace(&mut self, max: Option<usize>) {
+        self.max_backspace = max;
+    }
 
-        // Type text ending with "ENTER" (uppercase)
-        kb.update_transcript("hello ENTER").unwrap();
-        assert_eq!(kb.current_text, "hello ENTER");
-        assert!(!kb.hardware.enter_pressed);
+    /// Choose whether corrections are retyped from the first differing
+    /// character (default), from the start of that word, or edited in place
+    pub fn set_diff_strategy(&mut self, strategy: DiffStrategy) {
+        self.diff_strategy = strategy;
+    }
 
-        // Finalize - should still recognize and handle it
-        kb.finalize_transcript().unwrap();
-        assert_eq!(kb.current_text, "");
+    /// Space out backspaces for targets that can't keep up with them
+    pub fn set_backspace_pacing(&mut self, pacing: BackspacePacing) {
+        self.backspace_pacing = pacing;
+    }
+
+    /// Choose the key combination sent for "enter" (spoken or automatic)
+    pub fn set_enter_key(&mut self, enter_key: EnterKey) {
+        self.enter_key = enter_key;
+    }
+
+    /// Choose what is typed before a turn that follows one without Enter.
+    /// Continuous-line mode joins turns with a space of its own.
+    pub fn set_turn_separator(&mut self, separator: TurnSeparator) {
+        self.turn_separator = separator;
+    }
+
+    /// Drop a single leading space from transcripts typed at the start of a
+    /// line; mid-line, it still separates the turn from the text before it
+    pub fn set_trim_leading_space(&mut self, enabled: bool) {
+        self.trim_leading_space = enabled;
+    }
+
+    /// Wrap each line in `prefix` and `suffix`, e.g. "- " for a bullet list.
+    /// In continuous-line mode a line ends only at Enter or a key command.
+    pub fn set_line_affixes(&mut self, prefix: String, suffix: String) {
+        self.line_prefix = prefix;
+        self.line_suffix = suffix;
+    }
+
+    /// Enable or disable entering emoji with Ctrl+Shift+U; when disabled an
+    /// emoji command types the emoji's name instead
+    pub fn set_unicode_input_enabled(&mut self, enabled: bool) {
+        self.unicode_input = enabled;
+    }
+
+    /// Register a hook called with the tracked text whenever it changes,
+    /// e.g. to render the live dictation buffer in an overlay
+    pub fn set_on_text_change<F>(&mut self, hook: F)
+    where
+        F: Fn(&str) + Send + 'static,
+    {
+        self.on_text_change = Some(Box::new(hook));
+    }
+
+    /// Register a hook called with each finalized line, once its command
+    /// phrase is gone (e.g. to copy it to the clipboard)
+    pub fn set_on_finalize<F>(&mut self, hook: F)
+    where
+        F: Fn(&str) + Send + 'static,
+    {
+        self.on_finalize = Some(Box::new(hook));
+    }
+
+    fn notify_text_change(&self) {
+        if let Some(hook) = &self.on_text_change {
+            hook(&self.current_text);
+        }
+    }
+
+    /// Clamp a backspace count to the configured cap, warning when it applies
+    fn clamp_backspaces(&self, requested: usize) -> usize {
+        match self.max_backspace {
+            Some(max) if requested > max => {
+                warn!(
+                    "Update would backspace {} characters; clamping to {}",
+                    requested, max
+                );
+                max
+            }
+            _ => requested,
+        }
+    }
+
+    /// Update the transcript incrementally, handling smart backspacing
+    /// 1. Type new characters if the new transcript extends the current one
+    /// 2. Only backspace the characters that actually changed, then type the new ending
+    pub fn update_transcript(&mut self, new_transcript: &str) -> Result<()> {
+        self.turn_open = true;
+        let target = self.turn_target(new_transcript);
+        if target == self.current_text {
+            // STT often repeats a transcript verbatim; nothing to diff
+            return Ok(());
+        }
+
+        debug!(
+            "Updating transcript from '{}' to '{}' (original: '{}', uppercase: {})",
+            self.current_text, target, new_transcript, self.uppercase_enabled
+        );
+        self.type_turn_separator(&target)?;
+        self.sync_text(&target)
+    }
+
+    /// Separate a new turn's first text, `next`, from the previous turn's
+    /// The separator is typed outside the tracked text, so it is never diffed.
+    /// No space is typed next to whitespace either turn already has.
+    fn type_turn_separator(&mut self, next: &str) -> Result<()> {
+        if !std::mem::take(&mut self.separator_pending) {
+            return Ok(());
+        }
+        let spaced = self.pending_after_space || next.starts_with(char::is_whitespace);
+        if spaced && (self.ended_locally || self.turn_separator == TurnSeparator::Space) {
+            debug!("Turns already separated by whitespace");
+            return Ok(());
+        }
+        if self.ended_locally {
+            debug!("Continuing a locally finalized utterance after a space");
+            self.turn_separated = true;
+            return self.hardware.type_text(" ");
+        }
+        debug!("Typing turn separator: {:?}", self.turn_separator);
+        match self.turn_separator {
+            TurnSeparator::None => Ok(()),
+            TurnSeparator::Space => {
+                self.turn_separated = true;
+                self.hardware.type_text(" ")
+            }
+            TurnSeparator::Newline => {
+                // Re-typed text would land on the new line
+                self.undo_history.clear();
+                self.hardware.press_enter()
+            }
+        }
+    }
+
+    /// Apply the enabled text transforms to a raw transcript
+    fn process_transcript(&self, transcript: &str) -> String {
+        // Garbage control characters would otherwise churn the diff
+        let processed = transforms::strip_control_chars(transcript);
+
+        let processed = match &self.substitutions {
+            Some(substitutions) => substitutions.apply(&processed),
+            None => processed,
+        };
+
+        let processed = match &self.blocklist {
+            Some(blocklist) => blocklist.apply(&processed),
+            None => processed,
+        };
+
+        // Conditionally convert number words to digits
+        let processed = if self.number_mode {
+            transforms::numbers_to_digits(&processed)
+        } else {
+            processed
+        };
+
+        let processed = if self.spoken_punctuation {
+            transforms::spoken_punctuation(&processed)
+        } else {
+            processed
+        };
+
+        // Conditionally force the case; before the diff, so the model
+        // recasing a word mid-turn types nothing
+        let processed = if self.uppercase_enabled {
+            processed.to_uppercase()
+        } else if self.force_lowercase {
+            processed.to_lowercase()
+        } else {
+            processed
+        };
+
+        // Enter and Tab only come from commands, so tracking stays one
+        // character per typed key
+        transforms::flatten_line_breaks(&processed)
+    }
+
+    /// Process a transcript and place it on the line, giving the text the
+    /// turn should leave on screen
+    fn turn_target(&self, transcript: &str) -> String {
+        let processed = self.process_transcript(transcript);
+        let at_line_start = self.line_start && self.line_base.is_empty();
+        let processed = match processed.strip_prefix(' ') {
+            // It would indent the fresh line
+            Some(rest) if self.trim_leading_space && at_line_start => rest.to_string(),
+            _ => processed,
+        };
+        // The prefix goes in front of the line's first text only
+        if self.line_base.is_empty() && !processed.is_empty() {
+            return format!("{}{}", self.line_prefix, processed);
+        }
+        self.continue_line(processed)
+    }
+
+    /// Whether `text` is just the prefix, e.g. once a turn's only words were
+    /// a command phrase (which takes the space before it along)
+    fn only_prefix(&self, text: &str) -> bool {
+        !self.line_prefix.is_empty()
+            && self.line_base.is_empty()
+            && text.trim_end() == self.line_prefix.trim_end()
+    }
+
+    /// Place a transcript on the line retained from earlier turns
+    /// A transcript that already repeats the retained text is used as-is;
+    /// a fresh one is appended after it with a separating space.
+    fn continue_line(&self, transcript: String) -> String {
+        let base = &self.line_base;
+        if base.is_empty() || transcript.starts_with(base.as_str()) {
+            transcript
+        } else if transcript.is_empty() || base.ends_with(char::is_whitespace) {
+            format!("{base}{transcript}")
+        } else {
+            format!("{base} {transcript}")
+        }
+    }
+
+    /// Type `text` as-is, bypassing the transcript diff, and append it to the
+    /// tracked text. For scripted input such as snippets; unlike
+    /// `update_transcript` it never backspaces.
+    pub fn type_raw(&mut self, text: &str) -> Result<()> {
+        if text.is_empty() {
+            return Ok(());
+        }
+        debug!("Typing raw text: '{}'", text);
+        self.hardware.type_text(text)?;
+        self.current_text.push_str(text);
+        self.notify_text_change();
+        Ok(())
+    }
+
+    /// Bring the typed text in line with `target` using the minimal
+    /// backspace-then-type diff against `current_text`
+    fn sync_text(&mut self, target: &str) -> Result<()> {
+        let before = self.current_text.clone();
+        let result = self.apply_diff(target);
+        if self.current_text != before {
+            self.notify_text_change();
+        }
+        result
+    }
+
+    fn apply_diff(&mut self, processed_transcript: &str) -> Result<()> {
+        // If the new transcript is empty, clear everything (up to the backspace cap)
+        if processed_transcript.is_empty() {
+            let char_count = self.current_text.chars().count();
+            let allowed = self.clamp_backspaces(char_count);
+            if allowed < char_count {
+                self.backspace_chars(allowed)?;
+            } else {
+                self.clear_current_text()?;
+            }
+            return Ok(());
+        }
+
+        // Check if the new transcript is just an extension of the current one
+        if processed_transcript.starts_with(&self.current_text) {
+            // Just type the new characters
+            let new_chars = &processed_transcript[self.current_text.len()..];
+            if !new_chars.is_empty() {
+                debug!("Typing new characters: '{}'", new_chars);
+                self.hardware.type_text(new_chars)?;
+                self.current_text = processed_transcript.to_string();
+            }
+        } else if self.diff_strategy == DiffStrategy::Minimal
+            && self.apply_in_place(processed_transcript)?
+        {
+            self.current_text = processed_transcript.to_string();
+        } else {
+            // Find the common prefix between current and new transcript
+            let common_prefix_len = self
+                .current_text
+                .chars()
+                .zip(processed_transcript.chars())
+                .take_while(|(a, b)| a == b)
+                .count();
+
+            let current_chars: Vec<char> = self.current_text.chars().collect();
+            let common_prefix_len = match self.diff_strategy {
+                DiffStrategy::Word => word_start(&current_chars, common_prefix_len),
+                _ => common_prefix_len,
+            };
+            let needed_backspaces = current_chars.len() - common_prefix_len;
+            let chars_to_backspace = self.clamp_backspaces(needed_backspaces);
+
+            debug!(
+                "Common prefix length: {}, need to backspace {} characters",
+                common_prefix_len, chars_to_backspace
+            );
+
+            // Only backspace the characters that differ
+            if chars_to_backspace > 0 {
+                debug!("Backspacing {} characters", chars_to_backspace);
+                self.press_backspaces(chars_to_backspace)?;
+            }
+
+            // Type the new ending (everything after the common prefix)
+            let new_chars: Vec<char> = processed_transcript.chars().collect();
+            let new_ending: String = new_chars[common_prefix_len.min(new_chars.len())..]
+                .iter()
+                .collect();
+            if !new_ending.is_empty() {
+                debug!("Typing new ending: '{}'", new_ending);
+                self.hardware.type_text(&new_ending)?;
+            }
+
+            if chars_to_backspace < needed_backspaces {
+                // Clamped: whatever we could not delete stays on screen ahead of the new ending
+                let kept: String = current_chars[..current_chars.len() - chars_to_backspace]
+                    .iter()
+                    .collect();
+                self.current_text = kept + &new_ending;
+            } else {
+                self.current_text = processed_transcript.to_string();
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Apply the change with cursor movement if that takes fewer keystrokes
+    /// than retyping the tail (and stays within the backspace cap).
+    /// Returns false, having sent nothing, when retyping should be used instead.
+    fn apply_in_place(&mut self, target: &str) -> Result<bool> {
+        let old: Vec<char> = self.current_text.chars().collect();
+        let new: Vec<char> = target.chars().collect();
+        let plan = diff::cursor_plan(&old, &new);
+        let backspaces: usize = plan
+            .iter()
+            .map(|op| match op {
+                KeyOp::Backspace(n) => *n,
+                _ => 0,
+            })
+            .sum();
+        let retype_cost = diff::keystrokes(&diff::retype_plan(&old, &new));
+        if diff::keystrokes(&plan) >= retype_cost || self.clamp_backspaces(backspaces) < backspaces
+        {
+            return Ok(false);
+        }
+
+        debug!("Editing in place: {:?}", plan);
+        for op in plan {
+            match op {
+                KeyOp::Left(n) => self.repeat_key(KEY_LEFT, n)?,
+                KeyOp::Right(n) => self.repeat_key(KEY_RIGHT, n)?,
+                KeyOp::End => self.hardware.press_key(KEY_END)?,
+                KeyOp::Backspace(n) => self.press_backspaces(n)?,
+                KeyOp::Type(text) => self.hardware.type_text(&text)?,
+            }
+        }
+        Ok(true)
+    }
+
+    fn repeat_key(&mut self, keycode: u16, count: usize) -> Result<()> {
+        for _ in 0..count {
+            self.hardware.press_key(keycode)?;
+        }
+        Ok(())
+    }
+
+    /// Finalize the current transcript
+    /// If the transcript ends with a phrase from the command table (by default just
+    /// "enter", with optional punctuation/whitespace), backspace that portion and
+    /// run its action. Otherwise, just finalize without pressing enter (unless
+    /// auto-enter is on)
+    pub fn finalize_transcript(&mut self) -> Result<()> {
+        self.finalize(false)
+    }
+
+    /// Finalize the current transcript without the server having ended the
+    /// turn (e.g. the stream stalled), while the speaker may still be talking.
+    /// Commands act as usual, but the Enter that every turn's end calls for
+    /// (without voice-enter, or with auto-enter) waits for `finish_local_turn`
+    /// or the turn's next finalize, and more text continues after a space.
+    pub fn finalize_locally(&mut self) -> Result<()> {
+        self.finalize(true)
+    }
+
+    /// The server ended a turn that `finalize_locally` already committed in
+    /// full: press the Enter held back for the turn's end, if one is due
+    pub fn finish_local_turn(&mut self) -> Result<()> {
+        if !std::mem::take(&mut self.ended_locally)
+            || (self.interpret_enter_word && !self.auto_enter)
+        {
+            return Ok(());
+        }
+        self.press_enter_key()?;
+        self.separator_pending = false;
+        self.line_start = true;
+        self.undo_history.clear();
+        self.last_finalized.clear();
+        if let Some((_, enter)) = &mut self.last_line {
+            *enter = true;
+        }
+        self.line_base.clear();
+        if !self.current_text.is_empty() {
+            self.current_text.clear();
+            self.notify_text_change();
+        }
+        Ok(())
+    }
+
+    fn finalize(&mut self, local: bool) -> Result<()> {
+        if !self.turn_open {
+            debug!("Turn already finalized; ignoring repeated end-of-turn");
+            return Ok(());
+        }
+        self.turn_open = false;
+        debug!("Finalizing transcript: '{}'", self.current_text);
+
+        let command = self
+            .commands
+            .find_in_mode(&self.current_text, self.input_mode);
+        if let Some(command) = &command {
+            debug!(
+                "Found '{}' command at end of transcript: '{}'",
+                command.name,
+                &self.current_text[command.start..]
+            );
+
+            // Backspace the spoken phrase itself
+            self.backspace_chars(command.char_len)?;
+            self.notify_text_change();
+        }
+        self.finish_turn(command, local)?;
+        Ok(())
+    }
+
+    /// Run the command `phrase` names as if it had ended the current turn,
+    /// without it going through a transcript (e.g. from a local keyword
+    /// spotter). The whole phrase must be the command; text around one
+    /// isn't typed, and gets `NoMatch`.
+    pub fn dispatch_command(&mut self, phrase: &str) -> Result<CommandOutcome> {
+        let command = self
+            .commands
+            .find_in_mode(phrase, self.input_mode)
+            .filter(|command| phrase[..command.start].trim().is_empty());
+        let Some(command) = command else {
+            debug!("No command matches '{}'", phrase);
+            return Ok(CommandOutcome::NoMatch);
+        };
+        debug!("Dispatching '{}' command", command.name);
+        self.turn_open = false;
+        self.finish_turn(Some(command), false)
+    }
+
+    /// Type a turn's final transcript in one go, leaving out a trailing
+    /// command phrase, then act on the command and finalize the turn.
+    /// Nothing is typed for the turn before this, so the text never reflows.
+    pub fn commit_transcript(&mut self, transcript: &str) -> Result<()> {
+        let target = self.turn_target(transcript);
+        let command = self.commands.find_in_mode(&target, self.input_mode);
+        let text = match &command {
+            Some(command) => {
+                debug!(
+                    "Found '{}' command at end of transcript: '{}'",
+                    command.name,
+                    &target[command.start..]
+                );
+                &target[..command.start]
+            }
+            None => target.as_str(),
+        };
+        let text = if self.only_prefix(text) { "" } else { text };
+        debug!("Committing transcript: '{}'", text);
+        if !text.is_empty() {
+            self.type_turn_separator(text)?;
+        }
+        self.sync_text(text)?;
+        self.turn_open = false;
+        self.finish_turn(command, false)?;
+        Ok(())
+    }
+
+    /// Act on the turn's command (whose phrase is already off screen), press
+    /// Enter if called for, and reset tracking for the next turn. A `local`
+    /// finalize leaves the turn's own Enter to the server's end of turn.
+    fn finish_turn(
+        &mut self,
+        command: Option<CommandMatch>,
+        local: bool,
+    ) -> Result<CommandOutcome> {
+        // Voice-enter disabled: press ENTER at the end of every dictated turn;
+        // a command turn only presses it when the command is "enter"
+        let command_turn = command.is_some();
+        let mut press_enter = !self.interpret_enter_word && !command_turn && !local;
+        // Keys to press once the text edits are done
+        let mut key_action = None;
+        // Spaces typed for "tab" indent rather than end a sentence, and
+        // re-typed text was punctuated (or not) before
+        let mut skip_punctuation = false;
+        // What "undo that" should know about this turn's command
+        let mut undo_entry = None;
+        let mut outcome = CommandOutcome::Finalized;
+
+        // Earlier commands can't be undone in place once text follows them
+        if self.current_text != self.line_base {
+            self.undo_history.clear();
+        }
+
+        if let Some(command) = command {
+            let before = self.current_text.clone();
+            let reversible = matches!(
+                command.action,
+                CommandAction::DeleteLastWord | CommandAction::ClearLine
+            );
+            if command.action != CommandAction::UndoLast {
+                undo_entry = Some(UndoEntry {
+                    command: command.name.clone(),
+                    deleted: None,
+                });
+            }
+            match command.action {
+                CommandAction::PressKey(KEY_ENTER) => press_enter = true,
+                CommandAction::PressKey(KEY_TAB) if self.tab_spaces > 0 => {
+                    let target = format!("{}{}", self.current_text, " ".repeat(self.tab_spaces));
+                    self.sync_text(&target)?;
+                    skip_punctuation = true;
+                }
+                CommandAction::PressKey(_)
+                | CommandAction::PressCombo(_)
+                | CommandAction::HoldKey(..) => key_action = Some(command.action),
+                CommandAction::DeleteLastWord => self.delete_last_word()?,
+                CommandAction::ClearLine => self.sync_text("")?,
+                CommandAction::DeleteLine => self.delete_line()?,
+                CommandAction::CorrectLast => self.correct_last()?,
+                CommandAction::RepeatLast => {
+                    press_enter |= self.repeat_last()?;
+                    skip_punctuation = true;
+                }
+                CommandAction::EndSentence => self.end_sentence()?,
+                CommandAction::InsertDate => {
+                    let format = self.date_format.clone();
+                    self.insert_timestamp(&format)?;
+                }
+                CommandAction::InsertTime => {
+                    let format = self.time_format.clone();
+                    self.insert_timestamp(&format)?;
+                }
+                CommandAction::Literal(text) => {
+                    let target = format!("{}{}", self.current_text, text);
+                    self.sync_text(&target)?;
+                }
+                CommandAction::Emoji { emoji, name } => self.type_emoji(emoji, &name)?,
+                CommandAction::SetMode(mode) => {
+                    debug!("Switching to {:?} mode", mode);
+                    self.input_mode = mode;
+                }
+                CommandAction::UndoLast => {
+                    self.undo_last()?;
+                    skip_punctuation = true;
+                }
+            }
+            if let (true, Some(entry)) = (reversible, &mut undo_entry) {
+                let deleted = before.strip_prefix(self.current_text.as_str());
+                entry.deleted = deleted.map(String::from);
+            }
+            if let Some(typed) = self.current_text.strip_prefix(before.as_str()) {
+                if !typed.is_empty() {
+                    outcome = CommandOutcome::TypedText(typed.to_string());
+                }
+            }
+        }
+
+        if self.auto_enter && !press_enter && !command_turn && !local {
+            debug!("Auto-enter enabled; pressing ENTER at end-of-turn");
+            press_enter = true;
+        }
+
+        // A line that ends in a key command (e.g. tab to the next field) isn't a sentence
+        if self.smart_punctuation && key_action.is_none() && !skip_punctuation {
+            self.add_terminal_punctuation()?;
+        }
+
+        // Kept before the suffix, which a repeat that ends the line adds again
+        let line = self.turn_line().to_string();
+
+        // A prefix with nothing after it is taken back; a finished line gets
+        // the suffix after its cleaned-up text
+        if self.only_prefix(&self.current_text) {
+            self.sync_text("")?;
+        } else if (press_enter || key_action.is_some() || !self.continuous_line)
+            && !self.current_text.is_empty()
+            && !self.line_suffix.is_empty()
+        {
+            let target = format!("{}{}", self.current_text, self.line_suffix);
+            self.sync_text(&target)?;
+        }
+
+        let pressed_key = key_action.is_some();
+        match key_action {
+            Some(CommandAction::PressKey(keycode)) => {
+                debug!("Pressing key {} for voice command", keycode);
+                self.hardware.press_key(keycode)?;
+                outcome = CommandOutcome::PressedKey(vec![keycode]);
+            }
+            Some(CommandAction::PressCombo(keys)) => {
+                debug!("Pressing key combo {:?} for voice command", keys);
+                self.hardware.press_combo(&keys)?;
+                outcome = CommandOutcome::PressedKey(keys);
+            }
+            Some(CommandAction::HoldKey(keycode, duration)) => {
+                debug!("Holding key {} for {:?}", keycode, duration);
+                self.hardware.hold_key(keycode, duration)?;
+                outcome = CommandOutcome::PressedKey(vec![keycode]);
+            }
+            _ => {}
+        }
+
+        if press_enter {
+            self.press_enter_key()?;
+        }
+
+        // Nothing earlier can be re-typed in place once the cursor moved
+        if press_enter || pressed_key {
+            self.undo_history.clear();
+            if let Some(entry) = &mut undo_entry {
+                entry.deleted = None;
+            }
+        }
+        if let Some(entry) = undo_entry {
+            if self.undo_history.len() == UNDO_HISTORY {
+                self.undo_history.pop_front();
+            }
+            self.undo_history.push_back(entry);
+        }
+        self.turn_separated = false;
+
+        if let Some(hook) = &self.on_finalize {
+            let line = self.turn_line();
+            if !line.is_empty() {
+                hook(line);
+            }
+        }
+        if !line.is_empty() && !self.only_prefix(&line) {
+            self.last_line = Some((line, press_enter));
+        }
+
+        // Enter or a key command may have moved the cursor away from this turn's text
+        self.last_finalized = if press_enter || pressed_key {
+            String::new()
+        } else {
+            match self.current_text.strip_prefix(self.line_base.as_str()) {
+                Some(added) if self.continuous_line => added.to_string(),
+                _ => self.current_text.clone(),
+            }
+        };
+
+        self.ended_locally = local && !press_enter && !pressed_key && !self.current_text.is_empty();
+
+        // The next turn needs separating unless it starts elsewhere or this one typed nothing
+        if press_enter || pressed_key || self.continuous_line {
+            self.separator_pending = false;
+        } else if !self.current_text.is_empty() {
+            self.separator_pending = true;
+            self.pending_after_space = self.current_text.ends_with(char::is_whitespace);
+        }
+        self.line_start = press_enter
+            || (self.separator_pending && self.turn_separator == TurnSeparator::Newline)
+            || (self.line_start && self.current_text.is_empty());
+
+        if self.continuous_line && !press_enter {
+            // Keep tracking the line so the next turn continues it
+            debug!("Continuous line: keeping '{}'", self.current_text);
+            self.line_base = self.current_text.clone();
+            return Ok(outcome);
+        }
+
+        // Clear the current text tracking
+        self.line_base.clear();
+        if !self.current_text.is_empty() {
+            self.current_text.clear();
+            self.notify_text_change();
+        }
+
+        Ok(outcome)
+    }
+
+    /// This turn's part of the line: all of it, or what the turn added to a
+    /// continued line
+    fn turn_line(&self) -> &str {
+        match self.current_text.strip_prefix(self.line_base.as_str()) {
+            Some(added) if self.continuous_line => added.trim_start(),
+            _ => self.current_text.as_str(),
+        }
+    }
+
+    /// Press the configured Enter key (or combo)
+    fn press_enter_key(&mut self) -> Result<()> {
+        debug!("Pressing {:?}", self.enter_key);
+        match self.enter_key {
+            EnterKey::Enter => self.hardware.press_enter(),
+            EnterKey::ShiftEnter => self.hardware.press_combo(&[KEY_LEFTSHIFT, KEY_ENTER]),
+            EnterKey::CtrlEnter => self.hardware.press_combo(&[KEY_LEFTCTRL, KEY_ENTER]),
+        }
+    }
+
+    /// Type the clock's time in `format` where the command phrase was,
+    /// after a space when it follows other text
+    fn insert_timestamp(&mut self, format: &str) -> Result<()> {
+        let stamp = (self.clock)().format(format).to_string();
+        debug!("Inserting timestamp '{}'", stamp);
+        if self.current_text.ends_with(|c: char| !c.is_whitespace()) {
+            self.type_raw(" ")?;
+        }
+        self.type_raw(&stamp)
+    }
+
+    /// Insert an emoji where its spoken name was
+    fn type_emoji(&mut self, emoji: char, name: &str) -> Result<()> {
+        // The matched phrase took the space before it along
+        if !self.current_text.is_empty() && !self.current_text.ends_with(char::is_whitespace) {
+            self.type_raw(" ")?;
+        }
+        if !self.unicode_input {
+            return self.type_raw(name);
+        }
+        debug!("Entering emoji U+{:04X}", emoji as u32);
+        self.hardware.type_unicode(emoji)?;
+        self.current_text.push(emoji);
+        self.notify_text_change();
+        Ok(())
+    }
+
+    /// Revert the most recent command by re-typing what it deleted
+    /// A command that can't be reverted is only logged, and ends the history
+    /// since what came before it can no longer be restored in place.
+    fn undo_last(&mut self) -> Result<()> {
+        let Some(entry) = self.undo_history.pop_back() else {
+            info!("Nothing to undo");
+            return Ok(());
+        };
+        let Some(deleted) = entry.deleted else {
+            info!("Can't undo '{}'", entry.command);
+            self.undo_history.clear();
+            return Ok(());
+        };
+        // The separator already stands in for the deleted text's leading space
+        let deleted = if self.turn_separated && self.current_text.is_empty() {
+            deleted.trim_start()
+        } else {
+            deleted.as_str()
+        };
+        debug!("Undoing '{}': re-typing '{}'", entry.command, deleted);
+        let target = format!("{}{}", self.current_text, deleted);
+        self.sync_text(&target)
+    }
+
+    /// Remove this turn's text and what the previous turn typed, so it can be re-dictated
+    fn correct_last(&mut self) -> Result<()> {
+        let previous = std::mem::take(&mut self.last_finalized);
+        if previous.is_empty() {
+            debug!("Nothing finalized to correct");
+            return Ok(());
+        }
+        debug!("Correcting previous turn: '{}'", previous);
+
+        if self.continuous_line {
+            // The previous turn is the tail of the retained line
+            let base = self
+                .line_base
+                .strip_suffix(previous.as_str())
+                .unwrap_or_default()
+                .to_string();
+            self.line_base = base.clone();
+            return self.sync_text(&base);
+        }
+
+        // The previous turn is no longer tracked, so backspace past this turn's
+        // text and the separator typed ahead of it
+        self.sync_text("")?;
+        let separator = usize::from(self.turn_separated && self.current_text.is_empty());
+        let count = self.clamp_backspaces(previous.chars().count() + separator);
+        self.backspace_chars(count)
+    }
+
+    /// Type the last finalized line again after whatever is on screen;
+    /// returns whether Enter followed it
+    fn repeat_last(&mut self) -> Result<bool> {
+        let Some((line, enter)) = self.last_line.clone() else {
+            info!("Nothing finalized to repeat");
+            return Ok(false);
+        };
+        debug!("Repeating '{}'", line);
+        if self.current_text.is_empty() {
+            self.type_turn_separator(&line)?;
+        } else if self.current_text.ends_with(|c: char| !c.is_whitespace()) {
+            self.type_raw(" ")?;
+        }
+        self.type_raw(&line)?;
+        Ok(enter)
+    }
+
+    /// Select to the start of the line and delete it, whatever is on it.
+    /// What we tracked of the line is gone along with it.
+    fn delete_line(&mut self) -> Result<()> {
+        debug!("Deleting the line with Shift+Home, Backspace");
+        self.hardware.press_combo(&[KEY_LEFTSHIFT, KEY_HOME])?;
+        self.hardware.press_backspace()?;
+        self.line_base.clear();
+        self.last_finalized.clear();
+        self.separator_pending = false;
+        self.ended_locally = false;
+        self.line_start = true;
+        if !self.current_text.is_empty() {
+            self.current_text.clear();
+            self.notify_text_change();
+        }
+        Ok(())
+    }
+
+    /// Remove the last word (and the whitespace before it) from the typed text
+    fn delete_last_word(&mut self) -> Result<()> {
+        let trimmed = self.current_text.trim_end();
+        let kept = match trimmed.rfind(char::is_whitespace) {
+            Some(index) => trimmed[..index].to_string(),
+            None => String::new(),
+        };
+        debug!("Deleting last word: '{}' -> '{}'", self.current_text, kept);
+        self.sync_text(&kept)
+    }
+
+    /// End a sentence-like line with a period if it has no terminal punctuation.
+    /// Goes through the diff so trailing whitespace is replaced, not typed after.
+    fn add_terminal_punctuation(&mut self) -> Result<()> {
+        let trimmed = self.current_text.trim_end();
+        if trimmed.chars().last().is_some_and(|c| c.is_alphanumeric()) {
+            let punctuated = format!("{trimmed}.");
+            debug!(
+                "Smart punctuation: '{}' -> '{}'",
+                self.current_text, punctuated
+            );
+            self.sync_text(&punctuated)?;
+        }
+        Ok(())
+    }
+
+    /// End the text with one period, replacing a trailing comma or colon;
+    /// text that already ends a sentence is left alone
+    fn end_sentence(&mut self) -> Result<()> {
+        let trimmed = self
+            .current_text
+            .trim_end()
+            .trim_end_matches([',', ';', ':'])
+            .trim_end();
+        let target = if trimmed.ends_with(['.', '!', '?']) {
+            trimmed.to_string()
+        } else {
+            format!("{trimmed}.")
+        };
+        debug!("Ending sentence: '{}' -> '{}'", self.current_text, target);
+        self.sync_text(&target)
+    }
+
+    /// Clear the current text by backspacing
+    fn clear_current_text(&mut self) -> Result<()> {
+        if !self.current_text.is_empty() {
+            self.backspace_current_text()?;
+        }
+        Ok(())
+    }
+
+    /// Backspace the last `count` characters of the current text
+    fn backspace_chars(&mut self, count: usize) -> Result<()> {
+        debug!("Backspacing {} characters", count);
+
+        self.press_backspaces(count)?;
+        for _ in 0..count {
+            self.current_text.pop();
+        }
+
+        Ok(())
+    }
+
+    /// Backspace all characters in the current text
+    fn backspace_current_text(&mut self) -> Result<()> {
+        let char_count = self.current_text.chars().count();
+        debug!("Backspacing {} characters", char_count);
+
+        self.press_backspaces(char_count)?;
+        self.current_text.clear();
+        Ok(())
+    }
+
+    /// Press Backspace `count` times in batches, pausing after each so slow
+    /// targets don't drop any
+    fn press_backspaces(&mut self, count: usize) -> Result<()> {
+        let pacing = self.backspace_pacing;
+        let mut remaining = count;
+        while remaining > 0 {
+            let batch = remaining.min(pacing.batch.max(1));
+            for _ in 0..batch {
+                self.hardware.press_backspace()?;
+            }
+            let pause = pacing.pause_after(batch);
+            if !pause.is_zero() {
+                self.hardware.pause(pause);
+            }
+            remaining -= batch;
+        }
+        Ok(())
+    }
+
+    /// Forget the in-progress text without backspacing it, leaving what is on
+    /// screen as typed. For when the STT session restarts (e.g. after a
+    /// reconnect) and its next transcript won't continue the old one.
+    pub fn reset_tracking(&mut self) {
+        debug!(
+            "Resetting tracking; keeping '{}' on screen",
+            self.current_text
+        );
+        self.line_base.clear();
+        self.last_finalized.clear();
+        self.turn_open = false;
+        self.ended_locally = false;
+        if !self.current_text.is_empty() {
+            self.separator_pending = true;
+            self.pending_after_space = self.current_text.ends_with(char::is_whitespace);
+            self.line_start = false;
+            self.current_text.clear();
+            self.notify_text_change();
+        }
+    }
+
+    /// Get the current text that has been typed
+    pub fn get_current_text(&self) -> &str {
+        &self.current_text
+    }
+
+    /// Modifiers the hardware has pressed and not released, e.g. to check
+    /// for a stuck shift or ctrl
+    pub fn modifier_state(&self) -> ModifierState {
+        self.hardware.modifier_state()
+    }
+
+    /// Send key-up events for every modifier so no shift/ctrl/alt stays held,
+    /// e.g. after an error interrupted typing mid-combo
+    pub fn release_all(&mut self) -> Result<()> {
+        let held = self.hardware.modifier_state();
+        if !held.is_empty() {
+            warn!("Releasing modifier keys left held: {:?}", held.held());
+        }
+        debug!("Releasing all modifier keys");
+        for keycode in MODIFIER_KEYS {
+            self.hardware.release_key(keycode)?;
+        }
+        Ok(())
+    }
+
+    /// Press or release modifiers until the held ones match `state`, e.g. to
+    /// leave one held as an interrupted combo would
+    #[cfg(test)]
+    pub fn set_modifier_state(&mut self, state: ModifierState) -> Result<()> {
+        let current = self.hardware.modifier_state();
+        for keycode in MODIFIER_KEYS {
+            match (current.is_held(keycode), state.is_held(keycode)) {
+                (false, true) => self.hardware.press_down(keycode)?,
+                (true, false) => self.hardware.release_key(keycode)?,
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+
+    /// The underlying keyboard hardware
+    pub fn hardware(&self) -> &H {
+        &self.hardware
+    }
+
+    pub fn hardware_mut(&mut self) -> &mut H {
+        &mut self.hardware
+    }
+}
+
+/// A single event recorded by the mock keyboard, in order
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum KeyAction {
+    Press(u16),
+    Release(u16),
+    /// Text with no key mapping, which real hardware would skip
+    Text(String),
+    /// Time a key was meant to stay down (the mock doesn't actually sleep)
+    Wait(Duration),
+}
+
+/// Mock hardware implementation for testing
+pub struct MockKeyboardHardware {
+    pub typed_chars: Vec<char>,
+    /// Characters to the right of the cursor (moved by Left/Right/End)
+    pub cursor_offset: usize,
+    pub backspace_count: usize,
+    pub enter_pressed: bool,
+    pub enter_count: usize,
+    pub pressed_keys: Vec<u16>,
+    pub combos: Vec<Vec<u16>>,
+    pub released_keys: Vec<u16>,
+    /// Every key press/release in the order the real device would see it
+    pub actions: Vec<KeyAction>,
+    pub keymap: Keymap,
+    /// Time spent in pauses so far (the mock doesn't actually sleep)
+    pub elapsed: Duration,
+    /// `elapsed` at each backspace
+    pub backspace_times: Vec<Duration>,
+    /// Modifiers pressed in `actions` and not released since
+    pub modifiers: ModifierState,
+}
+
+impl MockKeyboardHardware {
+    pub fn new() -> Self {
+        Self {
+            typed_chars: Vec::new(),
+            cursor_offset: 0,
+            backspace_count: 0,
+            enter_pressed: false,
+            enter_count: 0,
+            pressed_keys: Vec::new(),
+            combos: Vec::new(),
+            released_keys: Vec::new(),
+            actions: Vec::new(),
+            keymap: Keymap::default(),
+            elapsed: Duration::ZERO,
+            backspace_times: Vec::new(),
+            modifiers: ModifierState::default(),
+        }
+    }
+
+    fn record(&mut self, action: KeyAction) {
+        match action {
+            KeyAction::Press(keycode) => self.modifiers.record(keycode, true),
+            KeyAction::Release(keycode) => self.modifiers.record(keycode, false),
+            _ => {}
+        }
+        self.actions.push(action);
+    }
+
+    fn tap(&mut self, keycode: u16) {
+        self.record(KeyAction::Press(keycode));
+        self.record(KeyAction::Release(keycode));
+    }
+}
+
+impl KeyboardHardware for MockKeyboardHardware {
+    fn type_text(&mut self, text: &str) -> Result<()> {
+        for (c, events) in text_key_events(&self.keymap, text) {
+            let at = self.typed_chars.len() - self.cursor_offset;
+            self.typed_chars.insert(at, c);
+            let Some(events) = events else {
+                self.record(KeyAction::Text(c.to_string()));
+                continue;
+            };
+            for (keycode, pressed) in events {
+                self.record(if pressed {
+                    KeyAction::Press(keycode)
+                } else {
+                    KeyAction::Release(keycode)
+                });
+            }
+        }
+        Ok(())
+    }
+
+    fn press_backspace(&mut self) -> Result<()> {
+        self.backspace_count += 1;
+        self.backspace_times.push(self.elapsed);
+        let at = self.typed_chars.len() - self.cursor_offset;
+        if at > 0 {
+            self.typed_chars.remove(at - 1);
+        }
+        self.tap(KEY_BACKSPACE);
+        Ok(())
+    }
+
+    fn press_enter(&mut self) -> Result<()> {
+        self.enter_pressed = true;
+        self.enter_count += 1;
+        self.tap(KEY_ENTER);
+        Ok(())
+    }
+
+    fn press_key(&mut self, keycode: u16) -> Result<()> {
+        self.pressed_keys.push(keycode);
+        match keycode {
+            KEY_LEFT => self.cursor_offset = (self.cursor_offset + 1).min(self.typed_chars.len()),
+            KEY_RIGHT => self.cursor_offset = self.cursor_offset.saturating_sub(1),
+            KEY_END => self.cursor_offset = 0,
+            // A space key is text the field keeps, like a typed one
+            KEY_SPACE => {
+                let at = self.typed_chars.len() - self.cursor_offset;
+                self.typed_chars.insert(at, ' ');
+            }
+            _ => {}
+        }
+        self.tap(keycode);
+        Ok(())
+    }
+
+    fn press_combo(&mut self, keycodes: &[u16]) -> Result<()> {
+        self.combos.push(keycodes.to_vec());
+        for &keycode in keycodes {
+            self.record(KeyAction::Press(keycode));
+        }
+        for &keycode in keycodes.iter().rev() {
+            self.record(KeyAction::Release(keycode));
+        }
+        Ok(())
+    }
+
+    fn press_down(&mut self, keycode: u16) -> Result<()> {
+        self.record(KeyAction::Press(keycode));
+        Ok(())
+    }
+
+    fn release_key(&mut self, keycode: u16) -> Result<()> {
+        self.released_keys.push(keycode);
+        self.record(KeyAction::Release(keycode));
+        Ok(())
+    }
+
+    fn hold_key(&mut self, keycode: u16, duration: Duration) -> Result<()> {
+        self.record(KeyAction::Press(keycode));
+        self.record(KeyAction::Wait(duration));
+        self.record(KeyAction::Release(keycode));
+        Ok(())
+    }
+
+    fn type_unicode(&mut self, c: char) -> Result<()> {
+        // Record the keys, but show the character the input method would produce
+        self.press_combo(&[KEY_LEFTCTRL, KEY_LEFTSHIFT, KEY_U])?;
+        self.record(KeyAction::Text(format!("{:x}", c as u32)));
+        self.tap(KEY_SPACE);
+        let at = self.typed_chars.len() - self.cursor_offset;
+        self.typed_chars.insert(at, c);
+        Ok(())
+    }
+
+    fn pause(&mut self, duration: Duration) {
+        self.elapsed += duration;
+    }
+
+    fn modifier_state(&self) -> ModifierState {
+        self.modifiers
+    }
+}
+
+/// Runs the keyboard state machine against the mock and reports each action
+/// with the resulting simulated line, so `--simulate` shows what would be typed
+/// without touching the focused window
+pub struct SimulatedKeyboardHardware<W: Write> {
+    mock: MockKeyboardHardware,
+    out: W,
+}
+
+impl SimulatedKeyboardHardware<std::io::Stdout> {
+    pub fn new() -> Self {
+        Self::with_output(std::io::stdout())
+    }
+}
+
+impl<W: Write> SimulatedKeyboardHardware<W> {
+    pub fn with_output(out: W) -> Self {
+        Self {
+            mock: MockKeyboardHardware::new(),
+            out,
+        }
+    }
+
+    fn report(&mut self, action: &str) -> Result<()> {
+        let line: String = self.mock.typed_chars.iter().collect();
+        writeln!(self.out, "[simulate] {:<20} -> {:?}", action, line)?;
+        Ok(())
+    }
+}
+
+impl<W: Write> KeyboardHardware for SimulatedKeyboardHardware<W> {
+    fn type_text(&mut self, text: &str) -> Result<()> {
+        self.mock.type_text(text)?;
+        self.report(&format!("type {:?}", text))
+    }
+
+    fn press_backspace(&mut self) -> Result<()> {
+        self.mock.press_backspace()?;
+        self.report("backspace")
+    }
+
+    fn press_enter(&mut self) -> Result<()> {
+        self.mock.press_enter()?;
+        // The line is sent; start a fresh one
+        self.report("enter")?;
+        self.mock.typed_chars.clear();
+        self.mock.cursor_offset = 0;
+        Ok(())
+    }
+
+    fn press_key(&mut self, keycode: u16) -> Result<()> {
+        self.mock.press_key(keycode)?;
+        self.report(&format!("key {}", keycode))
+    }
+
+    fn press_combo(&mut self, keycodes: &[u16]) -> Result<()> {
+        self.mock.press_combo(keycodes)?;
+        self.report(&format!("combo {:?}", keycodes))
+    }
+
+    fn press_down(&mut self, keycode: u16) -> Result<()> {
+        self.mock.press_down(keycode)?;
+        self.report(&format!("down {}", keycode))
+    }
+
+    fn release_key(&mut self, keycode: u16) -> Result<()> {
+        // Only sent as a safety net on shutdown; not worth reporting
+        self.mock.release_key(keycode)
+    }
+
+    fn hold_key(&mut self, keycode: u16, duration: Duration) -> Result<()> {
+        self.mock.hold_key(keycode, duration)?;
+        self.report(&format!("hold {} for {:?}", keycode, duration))
+    }
+
+    fn type_unicode(&mut self, c: char) -> Result<()> {
+        self.mock.type_unicode(c)?;
+        self.report(&format!("unicode U+{:04X}", c as u32))
+    }
+
+    fn modifier_state(&self) -> ModifierState {
+        self.mock.modifier_state()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::Context;
+
+    #[test]
+    fn test_incremental_typing_extension() {
+        let mut kb = VirtualKeyboard::new(MockKeyboardHardware::new());
+
+        // Start with "hello"
+        kb.update_transcript("hello").unwrap();
+        assert_eq!(kb.current_text, "hello");
+        assert_eq!(kb.hardware.typed_chars, ['h', 'e', 'l', 'l', 'o']);
+        assert_eq!(kb.hardware.backspace_count, 0);
+
+        // Extend to "hello world"
+        kb.update_transcript("hello world").unwrap();
+        assert_eq!(kb.current_text, "hello world");
+        assert_eq!(
+            kb.hardware.typed_chars,
+            ['h', 'e', 'l', 'l', 'o', ' ', 'w', 'o', 'r', 'l', 'd']
+        );
+        assert_eq!(kb.hardware.backspace_count, 0);
+    }
+
+    #[test]
+    fn test_incremental_typing_change() {
+        let mut kb = VirtualKeyboard::new(MockKeyboardHardware::new());
+
+        // Start with "hello"
+        kb.update_transcript("hello").unwrap();
+        assert_eq!(kb.current_text, "hello");
+        assert_eq!(kb.hardware.typed_chars, ['h', 'e', 'l', 'l', 'o']);
+
+        // Change to "hi there" - should only backspace "ello" and type "i there"
+        kb.update_transcript("hi there").unwrap();
+        assert_eq!(kb.current_text, "hi there");
+        // Should have backspaced 4 characters ("ello") and typed 7 new ones ("i there")
+        assert_eq!(kb.hardware.backspace_count, 4);
+        assert_eq!(
+            kb.hardware.typed_chars,
+            ['h', 'i', ' ', 't', 'h', 'e', 'r', 'e']
+        );
+    }
+
+    #[test]
+    fn test_word_diff_retypes_whole_changed_word() {
+        let before = "we met at the station yesterday";
+        let after = "we met at the stadium yesterday";
+
+        let mut char_kb = VirtualKeyboard::new(MockKeyboardHardware::new());
+        char_kb.update_transcript(before).unwrap();
+        char_kb.update_transcript(after).unwrap();
+
+        let mut word_kb = VirtualKeyboard::new(MockKeyboardHardware::new());
+        word_kb.set_diff_strategy(DiffStrategy::Word);
+        word_kb.update_transcript(before).unwrap();
+        word_kb.update_transcript(after).unwrap();
+
+        // Char-level keeps "sta"; word-level goes back to the start of "station"
+        assert_eq!(char_kb.hardware.backspace_count, "tion yesterday".len());
+        assert_eq!(word_kb.hardware.backspace_count, "station yesterday".len());
+        assert_eq!(char_kb.current_text, after);
+        assert_eq!(word_kb.current_text, after);
+        let typed: String = word_kb.hardware.typed_chars.iter().collect();
+        assert_eq!(typed, after);
+
+        // A difference right after a space is already on a word boundary
+        let mut word_kb = VirtualKeyboard::new(MockKeyboardHardware::new());
+        word_kb.set_diff_strategy(DiffStrategy::Word);
+        word_kb.update_transcript("hello world").unwrap();
+        word_kb.update_transcript("hello there").unwrap();
+        assert_eq!(word_kb.hardware.backspace_count, 5);
+
+        assert_eq!("WORD".parse::<DiffStrategy>().unwrap(), DiffStrategy::Word);
+        assert!("line".parse::<DiffStrategy>().is_err());
+    }
+
+    #[test]
+    fn test_minimal_diff_edits_in_place() {
+        let before = "the quick brown fox jumps over the lazy dog";
+        let after = "a quick brown fox jumps over the lazy dog";
+
+        let mut kb = VirtualKeyboard::new(MockKeyboardHardware::new());
+        kb.set_diff_strategy(DiffStrategy::Minimal);
+        kb.update_transcript(before).unwrap();
+        kb.hardware.pressed_keys.clear();
+        kb.update_transcript(after).unwrap();
+
+        assert_eq!(kb.current_text, after);
+        assert_eq!(kb.hardware.typed_chars.iter().collect::<String>(), after);
+        assert_eq!(kb.hardware.cursor_offset, 0);
+        assert_eq!(kb.hardware.backspace_count, 3);
+        let lefts = kb.hardware.pressed_keys.iter().filter(|&&k| k == KEY_LEFT);
+        assert_eq!(lefts.count(), 40);
+        assert_eq!(kb.hardware.pressed_keys.last(), Some(&KEY_END));
+
+        // Growing the line still just types the new characters
+        kb.update_transcript(&format!("{after} again")).unwrap();
+        assert_eq!(kb.hardware.backspace_count, 3);
+
+        // A change at the end isn't worth moving the cursor for
+        let mut kb = VirtualKeyboard::new(MockKeyboardHardware::new());
+        kb.set_diff_strategy(DiffStrategy::Minimal);
+        kb.update_transcript("hello world").unwrap();
+        kb.update_transcript("hello word").unwrap();
+        assert!(kb.hardware.pressed_keys.is_empty());
+        assert_eq!(
+            kb.hardware.typed_chars.iter().collect::<String>(),
+            "hello word"
+        );
+    }
+
+    #[test]
+    fn test_emoji_command_enters_code_point() {
+        let mut kb = VirtualKeyboard::new(MockKeyboardHardware::new());
+        kb.commands_mut()
+            .add_emoji_commands(&crate::emoji::EmojiTable::builtin());
+        kb.update_transcript("nice work party emoji").unwrap();
+        kb.finalize_transcript().unwrap();
+
+        assert_eq!(
+            kb.hardware.typed_chars.iter().collect::<String>(),
+            "nice work 🎉"
+        );
+        assert_eq!(
+            kb.hardware.combos,
+            vec![vec![KEY_LEFTCTRL, KEY_LEFTSHIFT, KEY_U]]
+        );
+        assert!(kb
+            .hardware
+            .actions
+            .contains(&KeyAction::Text("1f389".to_string())));
+        assert_eq!(kb.last_finalized, "nice work 🎉");
+
+        // Without the input method the name is typed instead
+        let mut kb = VirtualKeyboard::new(MockKeyboardHardware::new());
+        kb.commands_mut()
+            .add_emoji_commands(&crate::emoji::EmojiTable::builtin());
+        kb.set_unicode_input_enabled(false);
+        kb.update_transcript("love it heart emoji").unwrap();
+        kb.finalize_transcript().unwrap();
+        assert_eq!(
+            kb.hardware.typed_chars.iter().collect::<String>(),
+            "love it heart"
+        );
+        assert!(kb.hardware.combos.is_empty());
+    }
+
+    #[test]
+    fn test_finalize_transcript() {
+        let mut kb = VirtualKeyboard::new(MockKeyboardHardware::new());
+
+        // Type some text
+        kb.update_transcript("hello").unwrap();
+        assert_eq!(kb.current_text, "hello");
+        assert!(!kb.hardware.enter_pressed);
+
+        // Finalize (should NOT press enter since text doesn't end with "enter")
+        kb.finalize_transcript().unwrap();
+        assert_eq!(kb.current_text, "");
+        assert!(!kb.hardware.enter_pressed); // Should remain false
+        assert_eq!(kb.hardware.typed_chars, ['h', 'e', 'l', 'l', 'o']);
+    }
+
+    #[test]
+    fn test_empty_transcript() {
+        let mut kb = VirtualKeyboard::new(MockKeyboardHardware::new());
+
+        // Start with some text
+        kb.update_transcript("hello").unwrap();
+        assert_eq!(kb.current_text, "hello");
+
+        // Clear with empty transcript
+        kb.update_transcript("").unwrap();
+        assert_eq!(kb.current_text, "");
+        assert_eq!(kb.hardware.backspace_count, 5);
+    }
+
+    #[test]
+    fn test_smart_backspacing_partial_change() {
+        let mut kb = VirtualKeyboard::new(MockKeyboardHardware::new());
+
+        // Start with "hello world"
+        kb.update_transcript("hello world").unwrap();
+        assert_eq!(kb.current_text, "hello world");
+        assert_eq!(
+            kb.hardware.typed_chars,
+            ['h', 'e', 'l', 'l', 'o', ' ', 'w', 'o', 'r', 'l', 'd']
+        );
+
+        // Change to "hello there" - should only backspace "world" and type "there"
+        kb.update_transcript("hello there").unwrap();
+        assert_eq!(kb.current_text, "hello there");
+        // Should have backspaced 5 characters ("world") and typed 5 new ones ("there")
+        assert_eq!(kb.hardware.backspace_count, 5);
+        assert_eq!(
+            kb.hardware.typed_chars,
+            ['h', 'e', 'l', 'l', 'o', ' ', 't', 'h', 'e', 'r', 'e']
+        );
+    }
+
+    #[test]
+    fn test_smart_backspacing_no_common_prefix() {
+        let mut kb = VirtualKeyboard::new(MockKeyboardHardware::new());
+
+        // Start with "hello"
+        kb.update_transcript("hello").unwrap();
+        assert_eq!(kb.current_text, "hello");
+
+        // Change to "goodbye" - no common prefix, should backspace all and retype
+        kb.update_transcript("goodbye").unwrap();
+        assert_eq!(kb.current_text, "goodbye");
+        // Should have backspaced 5 characters and typed 7 new ones
+        assert_eq!(kb.hardware.backspace_count, 5);
+        assert_eq!(kb.hardware.typed_chars, ['g', 'o', 'o', 'd', 'b', 'y', 'e']);
+    }
+
+    #[test]
+    fn test_smart_backspacing_shortening() {
+        let mut kb = VirtualKeyboard::new(MockKeyboardHardware::new());
+
+        // Start with "hello world"
+        kb.update_transcript("hello world").unwrap();
+        assert_eq!(kb.current_text, "hello world");
+
+        // Change to "hello" - should only backspace " world"
+        kb.update_transcript("hello").unwrap();
+        assert_eq!(kb.current_text, "hello");
+        // Should have backspaced 6 characters (" world") and typed 0 new ones
+        assert_eq!(kb.hardware.backspace_count, 6);
+        assert_eq!(kb.hardware.typed_chars, ['h', 'e', 'l', 'l', 'o']);
+    }
+
+    #[test]
+    fn test_finalize_with_enter_command() {
+        let mut kb = VirtualKeyboard::new(MockKeyboardHardware::new());
+
+        // Type text ending with "enter"
+        kb.update_transcript("Write a unit test enter").unwrap();
+        assert_eq!(kb.current_text, "Write a unit test enter");
+        assert!(!kb.hardware.enter_pressed);
+
+        // Finalize - should backspace "enter" and press ENTER key
+        kb.finalize_transcript().unwrap();
+        assert_eq!(kb.current_text, "");
+        assert!(kb.hardware.enter_pressed);
+        // Should have backspaced 6 characters (" enter")
+        assert_eq!(kb.hardware.backspace_count, 6);
+    }
+
+    #[test]
+    fn test_finalize_with_enter_only() {
+        let mut kb = VirtualKeyboard::new(MockKeyboardHardware::new());
+
+        // Type only "enter"
+        kb.update_transcript("enter").unwrap();
+        assert_eq!(kb.current_text, "enter");
+        assert!(!kb.hardware.enter_pressed);
+
+        // Finalize - should backspace all and press ENTER key
+        kb.finalize_transcript().unwrap();
+        assert_eq!(kb.current_text, "");
+        assert!(kb.hardware.enter_pressed);
+        // Should have backspaced 5 characters ("enter")
+        assert_eq!(kb.hardware.backspace_count, 5);
+    }
+
+    #[test]
+    fn test_finalize_with_enter_case_insensitive() {
+        let mut kb = VirtualKeyboard::new(MockKeyboardHardware::new());
+
+        // Type text ending with "ENTER" (uppercase)
+        kb.update_transcript("hello ENTER").unwrap();
+        assert_eq!(kb.current_text, "hello ENTER");
+        assert!(!kb.hardware.enter_pressed);
+
+        // Finalize - should still recognize and handle it
+        kb.finalize_transcript().unwrap();
+        assert_eq!(kb.current_text, "");
+        assert!(kb.hardware.enter_pressed);
+        // Should have backspaced 6 characters (" ENTER")
+        assert_eq!(kb.hardware.backspace_count, 6);
+    }
+
+    #[test]
+    fn test_finalize_without_enter_command() {
+        let mut kb = VirtualKeyboard::new(MockKeyboardHardware::new());
+
+        // Type text not ending with "enter"
+        kb.update_transcript("hello world").unwrap();
+        assert_eq!(kb.current_text, "hello world");
+        assert!(!kb.hardware.enter_pressed);
+
+        // Finalize - should NOT press ENTER key since text doesn't end with "enter"
+        kb.finalize_transcript().unwrap();
+        assert_eq!(kb.current_text, "");
+        assert!(!kb.hardware.enter_pressed); // Should remain false
+        // Should not have backspaced anything for the enter command
+        assert_eq!(kb.hardware.backspace_count, 0);
+        assert_eq!(kb.hardware.typed_chars, ['h', 'e', 'l', 'l', 'o', ' ', 'w', 'o', 'r', 'l', 'd']);
+    }
+
+    #[test]
+    fn test_finalize_with_enter_in_middle() {
+        let mut kb = VirtualKeyboard::new(MockKeyboardHardware::new());
+
+        // Type text with "enter" in the middle, not at the end
+        kb.update_transcript("enter the room").unwrap();
+        assert_eq!(kb.current_text, "enter the room");
+        assert!(!kb.hardware.enter_pressed);
+
+        // Finalize - should NOT press ENTER key since "enter" is not the last word
+        kb.finalize_transcript().unwrap();
+        assert_eq!(kb.current_text, "");
+        assert!(!kb.hardware.enter_pressed); // Should remain false
+        // Should not have backspaced anything for the enter command
+        assert_eq!(kb.hardware.backspace_count, 0);
+        assert_eq!(kb.hardware.typed_chars, ['e', 'n', 't', 'e', 'r', ' ', 't', 'h', 'e', ' ', 'r', 'o', 'o', 'm']);
+    }
+
+    #[test]
+    fn test_enter_capitalization_patterns() {
+        // Test various capitalization patterns
+        let test_cases = vec![
+            ("hello enter", true), // lowercase
+            ("hello ENTER", true), // uppercase
+            ("hello Enter", true), // title case
+            ("hello EnTeR", true), // mixed case
+            ("hello eNtEr", true), // mixed case
+        ];
+
+        for (input, should_trigger) in test_cases {
+            let mut kb = VirtualKeyboard::new(MockKeyboardHardware::new());
+            kb.update_transcript(input).unwrap();
+            kb.finalize_transcript().unwrap();
+
+            if should_trigger {
+                assert!(
+                    kb.hardware.enter_pressed,
+                    "Should trigger ENTER for: '{}'",
+                    input
+                );
+            } else {
+                assert!(
+                    !kb.hardware.enter_pressed,
+                    "Should NOT trigger ENTER for: '{}'",
+                    input
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_enter_with_punctuation() {
+        // Test punctuation patterns - these SHOULD work with the regex approach
+        let test_cases = vec![
+            ("hello enter.", true),   // period attached
+            ("hello enter!", true),   // exclamation attached
+            ("hello enter?", true),   // question mark attached
+            ("hello enter,", true),   // comma attached
+            ("hello enter;", true),   // semicolon attached
+            ("hello enter:", true),   // colon attached
+            ("hello enter...", true), // multiple periods
+            ("hello enter!!", true),  // multiple exclamations
+        ];
+
+        for (input, should_trigger) in test_cases {
+            let mut kb = VirtualKeyboard::new(MockKeyboardHardware::new());
+            kb.update_transcript(input).unwrap();
+            kb.finalize_transcript().unwrap();
+
+            if should_trigger {
+                assert!(
+                    kb.hardware.enter_pressed,
+                    "Should trigger ENTER for: '{}'",
+                    input
+                );
+            } else {
+                assert!(
+                    !kb.hardware.enter_pressed,
+                    "Should NOT trigger ENTER for: '{}'",
+                    input
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_enter_with_whitespace_and_punctuation() {
+        // Test whitespace and punctuation combinations
+        let test_cases = vec![
+            ("hello enter ", true),   // trailing space
+            ("hello enter  ", true),  // multiple trailing spaces
+            ("hello enter\t", true),  // trailing tab
+            ("hello enter\n", true),  // trailing newline
+            ("hello enter .", true),  // space then period
+            ("hello enter !", true),  // space then exclamation
+            ("hello enter ?", true),  // space then question mark
+            ("hello enter . ", true), // period with spaces
+            ("hello enter ! ", true), // exclamation with spaces
+        ];
+
+        for (input, should_trigger) in test_cases {
+            let mut kb = VirtualKeyboard::new(MockKeyboardHardware::new());
+            kb.update_transcript(input).unwrap();
+            kb.finalize_transcript().unwrap();
+
+            if should_trigger {
+                assert!(
+                    kb.hardware.enter_pressed,
+                    "Should trigger ENTER for: '{}'",
+                    input
+                );
+            } else {
+                assert!(
+                    !kb.hardware.enter_pressed,
+                    "Should NOT trigger ENTER for: '{}'",
+                    input
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_enter_with_leading_whitespace() {
+        // Test cases with whitespace before "enter"
+        let test_cases = vec![
+            ("hello  enter", true), // double space before enter
+            ("hello\tenter", true), // tab before enter
+            ("hello\nenter", true), // newline before enter (unlikely but possible)
+        ];
+
+        for (input, should_trigger) in test_cases {
+            let mut kb = VirtualKeyboard::new(MockKeyboardHardware::new());
+            kb.update_transcript(input).unwrap();
+            kb.finalize_transcript().unwrap();
+
+            if should_trigger {
+                assert!(
+                    kb.hardware.enter_pressed,
+                    "Should trigger ENTER for: '{}'",
+                    input
+                );
+            } else {
+                assert!(
+                    !kb.hardware.enter_pressed,
+                    "Should NOT trigger ENTER for: '{}'",
+                    input
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_enter_negative_cases() {
+        // Test cases that should NOT trigger enter
+        let test_cases = vec![
+            ("hello world", false),      // no enter
+            ("enter the room", false),   // enter not at end
+            ("hello entered", false),    // "entered" is not "enter"
+            ("hello entering", false),   // "entering" is not "enter"
+            ("hello center", false),     // "center" contains "enter" but is not "enter"
+            ("hello enterprise", false), // "enterprise" contains "enter" but is not "enter"
+            ("hello enter now", false),  // "enter" not at the very end
+        ];
+
+        for (input, should_trigger) in test_cases {
+            let mut kb = VirtualKeyboard::new(MockKeyboardHardware::new());
+            kb.update_transcript(input).unwrap();
+            kb.finalize_transcript().unwrap();
+
+            if should_trigger {
+                assert!(
+                    kb.hardware.enter_pressed,
+                    "Should trigger ENTER for: '{}'",
+                    input
+                );
+            } else {
+                assert!(
+                    !kb.hardware.enter_pressed,
+                    "Should NOT trigger ENTER for: '{}'",
+                    input
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_uppercase_mode_basic() {
+        let mut kb = VirtualKeyboard::new(MockKeyboardHardware::new());
+        kb.set_uppercase_enabled(true);
+
+        // Type some text
+        kb.update_transcript("hello world").unwrap();
+        assert_eq!(kb.current_text, "HELLO WORLD");
+        assert_eq!(kb.hardware.typed_chars, ['H', 'E', 'L', 'L', 'O', ' ', 'W', 'O', 'R', 'L', 'D']);
+    }
+
+    #[test]
+    fn test_uppercase_mode_incremental() {
+        let mut kb = VirtualKeyboard::new(MockKeyboardHardware::new());
+        kb.set_uppercase_enabled(true);
+
+        // Start with "hello"
+        kb.update_transcript("hello").unwrap();
+        assert_eq!(kb.current_text, "HELLO");
+
+        // Extend to "hello world"
+        kb.update_transcript("hello world").unwrap();
+        assert_eq!(kb.current_text, "HELLO WORLD");
+        assert_eq!(kb.hardware.typed_chars, ['H', 'E', 'L', 'L', 'O', ' ', 'W', 'O', 'R', 'L', 'D']);
+    }
+
+    #[test]
+    fn test_uppercase_mode_with_enter_command() {
+        let mut kb = VirtualKeyboard::new(MockKeyboardHardware::new());
+        kb.set_uppercase_enabled(true);
+
+        // Type text ending with "enter"
+        kb.update_transcript("hello enter").unwrap();
+        assert_eq!(kb.current_text, "HELLO ENTER");
+        assert!(!kb.hardware.enter_pressed);
+
+        // Finalize - should backspace "ENTER" and press ENTER key
+        kb.finalize_transcript().unwrap();
+        assert_eq!(kb.current_text, "");
+        assert!(kb.hardware.enter_pressed);
+        // Should have backspaced 6 characters (" ENTER")
+        assert_eq!(kb.hardware.backspace_count, 6);
+    }
+
+    #[test]
+    fn test_force_lowercase_ignores_recasing() {
+        let mut kb = VirtualKeyboard::new(MockKeyboardHardware::new());
+        kb.set_force_lowercase(true);
+
+        kb.update_transcript("Hello").unwrap();
+        kb.update_transcript("Hello World").unwrap();
+        assert_eq!(kb.current_text, "hello world");
+
+        // The model changing its mind about casing alone is no edit at all
+        kb.update_transcript("hello World").unwrap();
+        kb.update_transcript("HELLO WORLD").unwrap();
+        assert_eq!(kb.hardware.backspace_count, 0);
+
+        // A real revision only retypes what changed, in lowercase
+        kb.update_transcript("Hello Word").unwrap();
+        assert_eq!(kb.current_text, "hello word");
+        assert_eq!(
+            kb.hardware.typed_chars.iter().collect::<String>(),
+            "hello word"
+        );
+        assert_eq!(kb.hardware.backspace_count, 2);
+    }
+
+    #[test]
+    fn test_normal_mode_basic() {
+        let mut kb = VirtualKeyboard::new(MockKeyboardHardware::new());
+        // uppercase_enabled is false by default
+
+        // Type some text
+        kb.update_transcript("Hello World").unwrap();
+        assert_eq!(kb.current_text, "Hello World");
+        assert_eq!(kb.hardware.typed_chars, ['H', 'e', 'l', 'l', 'o', ' ', 'W', 'o', 'r', 'l', 'd']);
+    }
+
+    #[test]
+    fn test_enter_verb_absorbed_when_enabled() {
+        let test_cases = vec![
+            ("hello press enter", "press enter", 12), // " press enter"
+            ("hello hit enter", "hit enter", 10),     // " hit enter"
+            ("hello send enter.", "send enter", 12),  // " send enter."
+        ];
+
+        for (input, phrase, expected_backspaces) in test_cases {
+            let mut kb = VirtualKeyboard::new(MockKeyboardHardware::new());
+            kb.set_enter_verb_enabled(true);
+            kb.update_transcript(input).unwrap();
+            kb.finalize_transcript().unwrap();
+
+            assert!(
+                kb.hardware.enter_pressed,
+                "Should trigger ENTER for: '{}'",
+                input
+            );
+            assert_eq!(
+                kb.hardware.backspace_count, expected_backspaces,
+                "Should backspace the whole '{}' phrase for: '{}'",
+                phrase, input
+            );
+            assert_eq!(kb.hardware.typed_chars, ['h', 'e', 'l', 'l', 'o']);
+        }
+    }
+
+    #[test]
+    fn test_enter_verb_not_adjacent() {
+        let mut kb = VirtualKeyboard::new(MockKeyboardHardware::new());
+        kb.set_enter_verb_enabled(true);
+
+        // "press" is not directly before "enter", so only " enter" is stripped
+        kb.update_transcript("press the button enter").unwrap();
+        kb.finalize_transcript().unwrap();
+        assert!(kb.hardware.enter_pressed);
+        assert_eq!(kb.hardware.backspace_count, 6);
+        assert_eq!(
+            kb.hardware.typed_chars.iter().collect::<String>(),
+            "press the button"
+        );
+    }
+
+    #[test]
+    fn test_enter_verb_disabled_by_default() {
+        let mut kb = VirtualKeyboard::new(MockKeyboardHardware::new());
+
+        // Without verb absorption, "press " stays typed
+        kb.update_transcript("hello press enter").unwrap();
+        kb.finalize_transcript().unwrap();
+        assert!(kb.hardware.enter_pressed);
+        assert_eq!(kb.hardware.backspace_count, 6);
+        assert_eq!(
+            kb.hardware.typed_chars.iter().collect::<String>(),
+            "hello press"
+        );
+    }
+
+    #[test]
+    fn test_space_command_presses_space() {
+        let mut kb = VirtualKeyboard::new(MockKeyboardHardware::new());
+        kb.set_voice_commands_enabled(true);
+        kb.set_smart_punctuation_enabled(true);
+
+        kb.update_transcript("foo space").unwrap();
+        kb.finalize_transcript().unwrap();
+
+        // " space" is backspaced and a real space pressed in its place
+        assert_eq!(kb.hardware.backspace_count, 6);
+        assert_eq!(kb.hardware.pressed_keys, vec![KEY_SPACE]);
+        assert_eq!(kb.hardware.typed_chars.iter().collect::<String>(), "foo ");
+
+        kb.update_transcript("two spaces").unwrap();
+        kb.finalize_transcript().unwrap();
+        assert_eq!(kb.hardware.pressed_keys, vec![KEY_SPACE]);
+        assert_eq!(
+            kb.hardware.typed_chars.iter().collect::<String>(),
+            "foo two spaces."
+        );
+    }
+
+    #[test]
+    fn test_blocklist_applies_before_diff() {
+        let mut kb = VirtualKeyboard::new(MockKeyboardHardware::new());
+        kb.set_blocklist(Some(
+            Blocklist::new(&["darn"], transforms::BlockMode::Drop).unwrap(),
+        ));
+
+        kb.update_transcript("well dar").unwrap();
+        kb.update_transcript("well darn").unwrap();
+        assert_eq!(kb.current_text, "well");
+        kb.update_transcript("well darn it").unwrap();
+        assert_eq!(kb.current_text, "well it");
+        assert_eq!(
+            kb.hardware.typed_chars.iter().collect::<String>(),
+            "well it"
+        );
+    }
+
+    #[test]
+    fn test_commit_types_final_text_once() {
+        let mut kb = VirtualKeyboard::new(MockKeyboardHardware::new());
+        kb.set_voice_enter_enabled(true);
+        kb.set_voice_commands_enabled(true);
+
+        // The command phrase is never typed, so nothing is backspaced
+        kb.commit_transcript("search for cats enter").unwrap();
+        assert_eq!(
+            kb.hardware.typed_chars.iter().collect::<String>(),
+            "search for cats"
+        );
+        assert_eq!(kb.hardware.backspace_count, 0);
+        assert!(kb.hardware.enter_pressed);
+        assert_eq!(kb.current_text, "");
+
+        kb.commit_transcript("next field tab").unwrap();
+        assert_eq!(kb.hardware.pressed_keys, vec![KEY_TAB]);
+        assert_eq!(kb.hardware.backspace_count, 0);
+    }
+
+    #[test]
+    fn test_enter_key_modes() {
+        let cases = [
+            (EnterKey::Enter, vec![]),
+            (EnterKey::ShiftEnter, vec![vec![KEY_LEFTSHIFT, KEY_ENTER]]),
+            (EnterKey::CtrlEnter, vec![vec![KEY_LEFTCTRL, KEY_ENTER]]),
+        ];
+        for (enter_key, combos) in cases {
+            let mut kb = VirtualKeyboard::new(MockKeyboardHardware::new());
+            kb.set_voice_enter_enabled(true);
+            kb.set_enter_key(enter_key);
+
+            kb.update_transcript("send this enter").unwrap();
+            kb.finalize_transcript().unwrap();
+
+            assert_eq!(kb.hardware.enter_pressed, enter_key == EnterKey::Enter);
+            assert_eq!(kb.hardware.combos, combos, "{enter_key:?}");
+            assert_eq!(kb.current_text, "");
+        }
+        assert_eq!(
+            "Shift-Enter".parse::<EnterKey>().unwrap(),
+            EnterKey::ShiftEnter
+        );
+        assert!("alt-enter".parse::<EnterKey>().is_err());
+    }
+
+    #[test]
+    fn test_turn_separator_between_turns() {
+        for (separator, expected, enters) in [
+            (TurnSeparator::None, "onetwothree", 0),
+            (TurnSeparator::Space, "one two three", 0),
+            (TurnSeparator::Newline, "onetwothree", 2),
+        ] {
+            let mut kb = VirtualKeyboard::new(MockKeyboardHardware::new());
+            kb.set_voice_enter_enabled(true);
+            kb.set_turn_separator(separator);
+
+            for turn in ["one", "two", "three"] {
+                kb.update_transcript(&turn[..1]).unwrap();
+                kb.update_transcript(turn).unwrap();
+                kb.finalize_transcript().unwrap();
+            }
+
+            let text: String = kb.hardware.typed_chars.iter().collect();
+            assert_eq!(text, expected, "{separator:?}");
+            assert_eq!(kb.hardware.enter_count, enters, "{separator:?}");
+        }
+    }
+
+    #[test]
+    fn test_turn_separator_skipped_after_enter() {
+        let mut kb = VirtualKeyboard::new(MockKeyboardHardware::new());
+        kb.set_voice_enter_enabled(true);
+        kb.set_turn_separator(TurnSeparator::Space);
+
+        kb.update_transcript("first enter").unwrap();
+        kb.finalize_transcript().unwrap();
+        // A turn with nothing to type leaves the separator for the next one
+        kb.update_transcript("second").unwrap();
+        kb.finalize_transcript().unwrap();
+        kb.update_transcript("").unwrap();
+        kb.finalize_transcript().unwrap();
+        kb.commit_transcript("third").unwrap();
+
+        let text: String = kb.hardware.typed_chars.iter().collect();
+        assert_eq!(text, "firstsecond third");
+        assert_eq!(
+            "Newline".parse::<TurnSeparator>().unwrap(),
+            TurnSeparator::Newline
+        );
+        assert!("tab".parse::<TurnSeparator>().is_err());
+    }
+
+    #[test]
+    fn test_control_chars_dropped_before_diff() {
+        let mut kb = VirtualKeyboard::new(MockKeyboardHardware::new());
+
+        kb.update_transcript("hello\0 wor\u{1b}ld").unwrap();
+        assert_eq!(kb.current_text, "hello world");
+
+        // The same words with different garbage are not a change
+        kb.update_transcript("hel\u{7}lo world\r").unwrap();
+        assert_eq!(kb.hardware.backspace_count, 0);
+
+        kb.update_transcript("hello\u{0} world\tagain").unwrap();
+        assert_eq!(kb.hardware.backspace_count, 0);
+        assert_eq!(
+            kb.hardware.typed_chars.iter().collect::<String>(),
+            "hello world again"
+        );
+    }
+
+    #[test]
+    fn test_embedded_line_breaks_typed_as_spaces() {
+        let mut kb = VirtualKeyboard::new(MockKeyboardHardware::new());
+        kb.set_voice_enter_enabled(true);
+        kb.update_transcript("first line\nsecond").unwrap();
+        assert_eq!(kb.current_text, "first line second");
+        kb.update_transcript("first line\n\tsecond line").unwrap();
+        assert_eq!(kb.current_text, "first line second line");
+        assert_eq!(kb.hardware.backspace_count, 0);
+        for key in [KEY_ENTER, KEY_TAB] {
+            assert!(!kb.hardware.actions.contains(&KeyAction::Press(key)));
+        }
+
+        // A trailing command still works, and only it presses Enter
+        let transcript = "first line\nsecond line\nenter";
+        kb.update_transcript(transcript).unwrap();
+        kb.finalize_transcript().unwrap();
+        assert_eq!(
+            kb.hardware.typed_chars.iter().collect::<String>(),
+            "first line second line"
+        );
+        assert_eq!(kb.hardware.enter_count, 1);
+        assert_eq!(kb.current_text, "");
+    }
+
+    #[test]
+    fn test_leading_space_trimmed_at_line_start_only() {
+        let mut kb = VirtualKeyboard::new(MockKeyboardHardware::new());
+        kb.set_trim_leading_space(true);
+
+        // At the start of a line the space goes, in every update of the turn
+        kb.update_transcript(" hello").unwrap();
+        kb.update_transcript(" hello world").unwrap();
+        assert_eq!(kb.current_text, "hello world");
+        assert_eq!(kb.hardware.backspace_count, 0);
+        kb.finalize_transcript().unwrap();
+
+        // Mid-line it separates this turn from the last
+        kb.update_transcript(" again").unwrap();
+        kb.update_transcript(" again enter").unwrap();
+        kb.finalize_transcript().unwrap();
+        assert_eq!(kb.hardware.enter_count, 1);
+
+        // After Enter the line is fresh again
+        kb.update_transcript(" next").unwrap();
+        kb.finalize_transcript().unwrap();
+        let text: String = kb.hardware.typed_chars.iter().collect();
+        assert_eq!(text, "hello world againnext");
+
+        // Only a single space is trimmed
+        let mut kb = VirtualKeyboard::new(MockKeyboardHardware::new());
+        kb.set_trim_leading_space(true);
+        kb.update_transcript("  indented").unwrap();
+        assert_eq!(kb.current_text, " indented");
+
+        // Disabled, the space is typed
+        let mut kb = VirtualKeyboard::new(MockKeyboardHardware::new());
+        kb.update_transcript(" hello").unwrap();
+        assert_eq!(kb.current_text, " hello");
+    }
+
+    #[test]
+    fn test_leading_space_trimmed_after_newline_separator() {
+        let mut kb = VirtualKeyboard::new(MockKeyboardHardware::new());
+        kb.set_trim_leading_space(true);
+        kb.set_turn_separator(TurnSeparator::Newline);
+        kb.update_transcript(" one").unwrap();
+        kb.finalize_transcript().unwrap();
+        kb.update_transcript(" two").unwrap();
+        kb.update_transcript(" two three").unwrap();
+        kb.finalize_transcript().unwrap();
+
+        // The separator started a new line, so neither turn is indented
+        let text: String = kb.hardware.typed_chars.iter().collect();
+        assert_eq!(text, "onetwo three");
+        assert_eq!(kb.hardware.enter_count, 1);
+
+        // A space separator keeps the turns on one line; the STT's own space
+        // separates them, so no second one is typed
+        let mut kb = VirtualKeyboard::new(MockKeyboardHardware::new());
+        kb.set_trim_leading_space(true);
+        kb.set_turn_separator(TurnSeparator::Space);
+        kb.update_transcript("one").unwrap();
+        kb.finalize_transcript().unwrap();
+        kb.update_transcript(" two").unwrap();
+        kb.finalize_transcript().unwrap();
+        let text: String = kb.hardware.typed_chars.iter().collect();
+        assert_eq!(text, "one two");
+
+        // Nor after a turn that ended in whitespace
+        let mut kb = VirtualKeyboard::new(MockKeyboardHardware::new());
+        kb.set_turn_separator(TurnSeparator::Space);
+        kb.update_transcript("one ").unwrap();
+        kb.finalize_transcript().unwrap();
+        kb.update_transcript("two").unwrap();
+        kb.finalize_transcript().unwrap();
+        let text: String = kb.hardware.typed_chars.iter().collect();
+        assert_eq!(text, "one two");
+    }
+
+    #[test]
+    fn test_backspaces_unpaced_by_default() {
+        let mut kb = VirtualKeyboard::new(MockKeyboardHardware::new());
+        kb.update_transcript("hello").unwrap();
+        kb.update_transcript("help").unwrap();
+        kb.update_transcript("").unwrap();
+
+        // Corrections and clears alike go out back to back
+        assert_eq!(kb.hardware.backspace_times, vec![Duration::ZERO; 6]);
+        assert_eq!(kb.hardware.elapsed, Duration::ZERO);
+
+        // A pause only when asked for
+        kb.set_backspace_pacing(BackspacePacing {
+            pause: Duration::from_millis(5),
+            ..BackspacePacing::default()
+        });
+        kb.update_transcript("hi").unwrap();
+        kb.update_transcript("").unwrap();
+        assert_eq!(kb.hardware.elapsed, Duration::from_millis(10));
+    }
+
+    #[test]
+    fn test_backspace_pacing_honors_batch_and_rate() {
+        let mut kb = VirtualKeyboard::new(MockKeyboardHardware::new());
+        kb.set_backspace_pacing(BackspacePacing {
+            batch: 3,
+            pause: Duration::from_millis(20),
+            max_per_second: Some(50),
+        });
+        kb.update_transcript("abcdefgh").unwrap();
+        kb.update_transcript("a").unwrap();
+
+        // Batches of 3, 3 and 1: a batch of 3 needs 60 ms to stay under 50/s,
+        // while the last one gets the plain 20 ms pause
+        let ms = Duration::from_millis;
+        let times = &kb.hardware.backspace_times;
+        assert_eq!(
+            *times,
+            vec![ms(0), ms(0), ms(0), ms(60), ms(60), ms(60), ms(120)]
+        );
+        assert_eq!(kb.hardware.elapsed, ms(140));
+        assert_eq!(kb.hardware.typed_chars, vec!['a']);
+        assert!(times.len() as f64 / kb.hardware.elapsed.as_secs_f64() <= 50.0);
+    }
+
+    #[test]
+    fn test_max_backspace_clamps_correction() {
+        let mut kb = VirtualKeyboard::new(MockKeyboardHardware::new());
+        kb.set_max_backspace(Some(3));
+
+        kb.update_transcript("hello world").unwrap();
+
+        // Would need 5 backspaces ("world"); only 3 are allowed
+        kb.update_transcript("hello there").unwrap();
+        assert_eq!(kb.hardware.backspace_count, 3);
+        assert_eq!(kb.current_text, "hello wothere");
+        assert_eq!(
+            kb.hardware.typed_chars.iter().collect::<String>(),
+            "hello wothere"
+        );
+    }
+
+    #[test]
+    fn test_max_backspace_within_cap_unchanged() {
+        let mut kb = VirtualKeyboard::new(MockKeyboardHardware::new());
+        kb.set_max_backspace(Some(5));
+
+        kb.update_transcript("hello world").unwrap();
+        kb.update_transcript("hello there").unwrap();
+        assert_eq!(kb.hardware.backspace_count, 5);
+        assert_eq!(kb.current_text, "hello there");
+    }
+
+    #[test]
+    fn test_max_backspace_clamps_empty_transcript() {
+        let mut kb = VirtualKeyboard::new(MockKeyboardHardware::new());
+        kb.set_max_backspace(Some(2));
+
+        kb.update_transcript("hello").unwrap();
+        kb.update_transcript("").unwrap();
+        assert_eq!(kb.hardware.backspace_count, 2);
+        assert_eq!(kb.current_text, "hel");
+        assert_eq!(kb.hardware.typed_chars, ['h', 'e', 'l']);
+    }
+
+    #[test]
+    fn test_auto_enter_presses_enter_without_command() {
+        let mut kb = VirtualKeyboard::new(MockKeyboardHardware::new());
+        kb.set_auto_enter_enabled(true);
+
+        kb.update_transcript("hello world").unwrap();
+        kb.finalize_transcript().unwrap();
+        assert_eq!(kb.current_text, "");
+        assert_eq!(kb.hardware.enter_count, 1);
+        assert_eq!(kb.hardware.backspace_count, 0);
+        assert_eq!(
+            kb.hardware.typed_chars.iter().collect::<String>(),
+            "hello world"
+        );
+    }
+
+    #[test]
+    fn test_auto_enter_strips_enter_word_once() {
+        let mut kb = VirtualKeyboard::new(MockKeyboardHardware::new());
+        kb.set_auto_enter_enabled(true);
+
+        kb.update_transcript("hello enter").unwrap();
+        kb.finalize_transcript().unwrap();
+        // " enter" is stripped and ENTER pressed exactly once
+        assert_eq!(kb.hardware.enter_count, 1);
+        assert_eq!(kb.hardware.backspace_count, 6);
+        assert_eq!(kb.hardware.typed_chars, ['h', 'e', 'l', 'l', 'o']);
+    }
+
+    #[test]
+    fn test_smart_punctuation_adds_period() {
+        let mut kb = VirtualKeyboard::new(MockKeyboardHardware::new());
+        kb.set_smart_punctuation_enabled(true);
+
+        kb.update_transcript("hello world").unwrap();
+        kb.finalize_transcript().unwrap();
+        assert_eq!(
+            kb.hardware.typed_chars.iter().collect::<String>(),
+            "hello world."
+        );
+        assert_eq!(kb.hardware.backspace_count, 0);
+        assert_eq!(kb.current_text, "");
+    }
+
+    #[test]
+    fn test_smart_punctuation_keeps_existing_punctuation() {
+        for input in ["hello world.", "hello world?", "hello world!"] {
+            let mut kb = VirtualKeyboard::new(MockKeyboardHardware::new());
+            kb.set_smart_punctuation_enabled(true);
+
+            kb.update_transcript(input).unwrap();
+            kb.finalize_transcript().unwrap();
+            assert_eq!(kb.hardware.typed_chars.iter().collect::<String>(), input);
+        }
+    }
+
+    #[test]
+    fn test_smart_punctuation_replaces_trailing_space_and_enter() {
+        let mut kb = VirtualKeyboard::new(MockKeyboardHardware::new());
+        kb.set_smart_punctuation_enabled(true);
+
+        // The period goes after the text, not after the stripped command or space
+        kb.update_transcript("hello world enter").unwrap();
+        kb.finalize_transcript().unwrap();
+        assert_eq!(
+            kb.hardware.typed_chars.iter().collect::<String>(),
+            "hello world."
+        );
+        assert!(kb.hardware.enter_pressed);
+
+        let mut kb = VirtualKeyboard::new(MockKeyboardHardware::new());
+        kb.set_smart_punctuation_enabled(true);
+        kb.update_transcript("hello world ").unwrap();
+        kb.finalize_transcript().unwrap();
+        assert_eq!(
+            kb.hardware.typed_chars.iter().collect::<String>(),
+            "hello world."
+        );
+        assert_eq!(kb.hardware.backspace_count, 1);
+    }
+
+    #[test]
+    fn test_text_change_hook_fires_on_each_change() {
+        use std::sync::{Arc, Mutex};
+
+        let seen = Arc::new(Mutex::new(Vec::<String>::new()));
+        let seen_clone = seen.clone();
+
+        let mut kb = VirtualKeyboard::new(MockKeyboardHardware::new());
+        kb.set_on_text_change(move |text| {
+            seen_clone.lock().unwrap().push(text.to_string());
+        });
+
+        kb.update_transcript("hello").unwrap();
+        kb.update_transcript("hello").unwrap(); // unchanged, no notification
+        kb.update_transcript("hello world").unwrap();
+        kb.update_transcript("hello there enter").unwrap();
+        kb.finalize_transcript().unwrap();
+
+        assert_eq!(
+            *seen.lock().unwrap(),
+            vec![
+                "hello",
+                "hello world",
+                "hello there enter",
+                "hello there",
+                ""
+            ]
+        );
+    }
+
+    #[test]
+    fn test_undo_restores_what_a_command_deleted() {
+        let say = |kb: &mut VirtualKeyboard<MockKeyboardHardware>, transcript: &str| {
+            kb.update_transcript(transcript).unwrap();
+            kb.finalize_transcript().unwrap();
+        };
+        let screen = |kb: &VirtualKeyboard<MockKeyboardHardware>| {
+            kb.hardware.typed_chars.iter().collect::<String>()
+        };
+
+        let mut kb = VirtualKeyboard::new(MockKeyboardHardware::new());
+        kb.set_voice_commands_enabled(true);
+        say(&mut kb, "hello big world scratch that");
+        assert_eq!(screen(&kb), "hello big");
+        say(&mut kb, "undo that");
+        assert_eq!(screen(&kb), "hello big world");
+        // Only once; the undo itself isn't undone
+        say(&mut kb, "undo that");
+        assert_eq!(screen(&kb), "hello big world");
+
+        say(&mut kb, " two three clear line");
+        say(&mut kb, "undo that");
+        assert_eq!(screen(&kb), "hello big world two three");
+
+        // A space between turns takes the place of the word's own space
+        let mut kb = VirtualKeyboard::new(MockKeyboardHardware::new());
+        kb.set_voice_commands_enabled(true);
+        kb.set_turn_separator(TurnSeparator::Space);
+        say(&mut kb, "hello world scratch that");
+        say(&mut kb, "undo that");
+        assert_eq!(screen(&kb), "hello world");
+
+        // Text typed after the command would put the word in the wrong place
+        let mut kb = VirtualKeyboard::new(MockKeyboardHardware::new());
+        kb.set_voice_commands_enabled(true);
+        say(&mut kb, "hello world scratch that");
+        say(&mut kb, " there");
+        say(&mut kb, "undo that");
+        assert_eq!(screen(&kb), "hello there");
+
+        // Enter can't be taken back
+        let mut kb = VirtualKeyboard::new(MockKeyboardHardware::new());
+        kb.set_voice_commands_enabled(true);
+        say(&mut kb, "send it enter");
+        say(&mut kb, "undo that");
+        assert_eq!(screen(&kb), "send it");
+        assert_eq!(kb.hardware.enter_count, 1);
+        assert!(kb.undo_history.is_empty());
+    }
+
+    #[test]
+    fn test_undo_without_voice_enter() {
+        // The CLI's default: dictated turns end in Enter, command turns don't
+        let mut kb = VirtualKeyboard::new(MockKeyboardHardware::new());
+        kb.set_voice_enter_enabled(false);
+        kb.set_voice_commands_enabled(true);
+        let mut say = |transcript: &str| {
+            kb.update_transcript(transcript).unwrap();
+            kb.finalize_transcript().unwrap();
+        };
+        say("hello big world scratch that");
+        say("undo that");
+        say("the whole line clear line");
+        say("undo that");
+        assert_eq!(
+            kb.hardware.typed_chars.iter().collect::<String>(),
+            "hello big worldthe whole line"
+        );
+        assert_eq!(kb.hardware.enter_count, 0);
+    }
+
+    #[test]
+    fn test_voice_commands_table_driven() {
+        // (transcript, text left on screen, keys pressed, enter pressed)
+        let cases = vec![
+            ("name tab", "name", vec![KEY_TAB], false),
+            ("oops escape.", "oops", vec![KEY_ESC], false),
+            ("hello big world scratch that", "hello big", vec![], false),
+            ("scratch that", "", vec![], false),
+            ("hello world clear line", "", vec![], false),
+            ("send it enter", "send it", vec![], true),
+            ("tabs and escapes", "tabs and escapes", vec![], false),
+        ];
+
+        for (transcript, expected_text, expected_keys, expected_enter) in cases {
+            let mut kb = VirtualKeyboard::new(MockKeyboardHardware::new());
+            kb.set_voice_commands_enabled(true);
+            kb.update_transcript(transcript).unwrap();
+            kb.finalize_transcript().unwrap();
+
+            assert_eq!(
+                kb.hardware.typed_chars.iter().collect::<String>(),
+                expected_text,
+                "wrong text for '{transcript}'"
+            );
+            assert_eq!(
+                kb.hardware.pressed_keys, expected_keys,
+                "wrong keys for '{transcript}'"
+            );
+            assert_eq!(
+                kb.hardware.enter_pressed, expected_enter,
+                "wrong enter state for '{transcript}'"
+            );
+            assert_eq!(kb.current_text, "");
+        }
+    }
+
+    #[test]
+    fn test_period_command_ends_sentence_once() {
+        let cases = [
+            ("hello period", "hello."),
+            ("hello. period", "hello."),
+            ("Hello full stop.", "Hello."),
+            ("really? Period", "really?"),
+            ("wait, period", "wait."),
+            ("and so... period", "and so..."),
+        ];
+        for (transcript, expected) in cases {
+            let mut kb = VirtualKeyboard::new(MockKeyboardHardware::new());
+            kb.set_voice_commands_enabled(true);
+            kb.set_voice_enter_enabled(true);
+            kb.update_transcript(transcript).unwrap();
+            kb.finalize_transcript().unwrap();
+
+            let text: String = kb.hardware.typed_chars.iter().collect();
+            assert_eq!(text, expected, "for '{transcript}'");
+            assert!(!kb.hardware.enter_pressed, "for '{transcript}'");
+            assert!(kb.hardware.pressed_keys.is_empty());
+        }
+    }
+
+    #[test]
+    fn test_dispatch_command_reports_what_each_phrase_did() {
+        let mut kb = VirtualKeyboard::new(MockKeyboardHardware::new());
+        kb.set_voice_commands_enabled(true);
+        kb.set_clock(Box::new(|| {
+            chrono::NaiveDate::from_ymd_opt(2024, 3, 9)
+                .unwrap()
+                .and_hms_opt(7, 5, 30)
+                .unwrap()
+        }));
+        let screen = |kb: &VirtualKeyboard<MockKeyboardHardware>| {
+            kb.hardware.typed_chars.iter().collect::<String>()
+        };
+
+        // A command ends the turn that's being typed
+        kb.update_transcript("hello big world").unwrap();
+        assert_eq!(
+            kb.dispatch_command("scratch that").unwrap(),
+            CommandOutcome::Finalized
+        );
+        assert_eq!(screen(&kb), "hello big");
+        assert_eq!(
+            kb.dispatch_command("Undo that.").unwrap(),
+            CommandOutcome::TypedText(" world".to_string())
+        );
+        assert_eq!(screen(&kb), "hello big world");
+
+        assert_eq!(
+            kb.dispatch_command("tab").unwrap(),
+            CommandOutcome::PressedKey(vec![KEY_TAB])
+        );
+        assert_eq!(
+            kb.dispatch_command("insert date").unwrap(),
+            CommandOutcome::TypedText("2024-03-09".to_string())
+        );
+        assert_eq!(screen(&kb), "hello big world2024-03-09");
+
+        // Only a whole phrase counts, and nothing is typed for the rest
+        for phrase in ["hello enter", "nonsense", ""] {
+            assert_eq!(
+                kb.dispatch_command(phrase).unwrap(),
+                CommandOutcome::NoMatch,
+                "for '{phrase}'"
+            );
+        }
+        assert_eq!(screen(&kb), "hello big world2024-03-09");
+        assert!(!kb.hardware.enter_pressed);
+
+        assert_eq!(
+            kb.dispatch_command("enter").unwrap(),
+            CommandOutcome::Finalized
+        );
+        assert_eq!(kb.hardware.enter_count, 1);
+        assert_eq!(kb.hardware.pressed_keys, vec![KEY_TAB]);
+    }
+
+    #[test]
+    fn test_insert_date_and_time_use_the_clock() {
+        let fixed = || {
+            chrono::NaiveDate::from_ymd_opt(2024, 3, 9)
+                .unwrap()
+                .and_hms_opt(7, 5, 30)
+                .unwrap()
+        };
+        let cases = [
+            ("notes insert date", None, "notes 2024-03-09"),
+            ("Insert time.", None, "07:05"),
+            (
+                "logged at insert time",
+                Some("%H:%M:%S"),
+                "logged at 07:05:30",
+            ),
+            ("due insert date", Some("%d/%m/%Y"), "due 09/03/2024"),
+        ];
+        for (transcript, format, expected) in cases {
+            let mut kb = VirtualKeyboard::new(MockKeyboardHardware::new());
+            kb.set_voice_commands_enabled(true);
+            kb.set_voice_enter_enabled(true);
+            kb.set_clock(Box::new(fixed));
+            if let Some(format) = format {
+                kb.set_date_format(format.to_string());
+                kb.set_time_format(format.to_string());
+            }
+            kb.update_transcript(transcript).unwrap();
+            kb.finalize_transcript().unwrap();
+
+            let text: String = kb.hardware.typed_chars.iter().collect();
+            assert_eq!(text, expected, "for '{transcript}'");
+            assert!(!kb.hardware.enter_pressed);
+        }
+    }
+
+    #[test]
+    fn test_time_format_is_validated() {
+        assert_eq!(
+            parse_time_format("%Y-%m-%d %H:%M").unwrap(),
+            "%Y-%m-%d %H:%M"
+        );
+        assert!(parse_time_format("%Q").is_err());
+    }
+
+    #[test]
+    fn test_delete_line_selects_to_line_start() {
+        let mut kb = VirtualKeyboard::new(MockKeyboardHardware::new());
+        kb.set_voice_commands_enabled(true);
+        kb.set_voice_enter_enabled(true);
+        kb.set_continuous_line_enabled(true);
+        kb.update_transcript("keep this").unwrap();
+        kb.finalize_transcript().unwrap();
+
+        kb.update_transcript("keep this and more delete line")
+            .unwrap();
+        let before = kb.hardware.actions.len();
+        kb.finalize_transcript().unwrap();
+
+        // The phrase is backspaced first, then the line is selected and deleted
+        let phrase_backspaces = " delete line".len();
+        let actions = &kb.hardware.actions[before + 2 * phrase_backspaces..];
+        assert_eq!(
+            actions,
+            [
+                KeyAction::Press(KEY_LEFTSHIFT),
+                KeyAction::Press(KEY_HOME),
+                KeyAction::Release(KEY_HOME),
+                KeyAction::Release(KEY_LEFTSHIFT),
+                KeyAction::Press(KEY_BACKSPACE),
+                KeyAction::Release(KEY_BACKSPACE),
+            ]
+        );
+        assert_eq!(kb.current_text, "");
+        assert!(kb.line_base.is_empty());
+        assert!(kb.last_finalized.is_empty());
+        assert!(!kb.hardware.enter_pressed);
+
+        // The next turn starts a fresh line instead of continuing the old one
+        kb.update_transcript("new").unwrap();
+        assert_eq!(kb.current_text, "new");
+    }
+
+    #[test]
+    fn test_voice_commands_off_by_default() {
+        let mut kb = VirtualKeyboard::new(MockKeyboardHardware::new());
+        kb.update_transcript("name tab").unwrap();
+        kb.finalize_transcript().unwrap();
+
+        assert_eq!(
+            kb.hardware.typed_chars.iter().collect::<String>(),
+            "name tab"
+        );
+        assert!(kb.hardware.pressed_keys.is_empty());
+
+        // Turning them off again keeps the enter entry
+        kb.set_voice_commands_enabled(true);
+        kb.set_voice_commands_enabled(false);
+        assert_eq!(kb.commands_mut().commands().len(), 1);
+    }
+
+    #[test]
+    fn test_dictation_mode_types_command_words() {
+        let mut kb = VirtualKeyboard::new(MockKeyboardHardware::new());
+        kb.set_input_mode(InputMode::Dictation);
+
+        kb.update_transcript("Then press enter").unwrap();
+        kb.finalize_transcript().unwrap();
+        assert_eq!(
+            kb.hardware.typed_chars.iter().collect::<String>(),
+            "Then press enter"
+        );
+        assert_eq!(kb.hardware.enter_count, 0);
+        assert!(kb.hardware.pressed_keys.is_empty());
+
+        // The mode switch itself is still recognized, and isn't typed
+        kb.update_transcript(" Command mode.").unwrap();
+        kb.finalize_transcript().unwrap();
+        assert_eq!(kb.input_mode(), InputMode::Command);
+        assert_eq!(
+            kb.hardware.typed_chars.iter().collect::<String>(),
+            "Then press enter"
+        );
+
+        kb.commit_transcript("enter").unwrap();
+        assert_eq!(kb.hardware.enter_count, 1);
+        assert_eq!(
+            kb.hardware.typed_chars.iter().collect::<String>(),
+            "Then press enter"
+        );
+    }
+
+    #[test]
+    fn test_custom_combo_and_literal_commands() {
+        use crate::commands::Command;
+
+        let mut kb = VirtualKeyboard::new(MockKeyboardHardware::new());
+        let table = kb.commands_mut();
+        table.upsert(
+            Command::from_phrase(
+                "reopen tab",
+                "reopen tab",
+                CommandAction::PressCombo(vec![KEY_LEFTCTRL, KEY_LEFTSHIFT, KEY_T]),
+            )
+            .unwrap(),
+        );
+        table.upsert(
+            Command::from_phrase("smiley", "smiley", CommandAction::Literal(" :)".into())).unwrap(),
+        );
+
+        kb.update_transcript("reopen tab").unwrap();
+        kb.finalize_transcript().unwrap();
+        assert_eq!(
+            kb.hardware.combos,
+            vec![vec![KEY_LEFTCTRL, KEY_LEFTSHIFT, KEY_T]]
+        );
+
+        kb.update_transcript("nice smiley").unwrap();
+        kb.finalize_transcript().unwrap();
+        assert_eq!(
+            kb.hardware.typed_chars.iter().collect::<String>(),
+            "nice :)"
+        );
+    }
+
+    #[test]
+    fn test_tab_as_spaces() {
+        let mut kb = VirtualKeyboard::new(MockKeyboardHardware::new());
+        kb.set_voice_commands_enabled(true);
+        kb.set_smart_punctuation_enabled(true);
+        kb.set_tab_as_spaces(4);
+        kb.update_transcript("if ready tab").unwrap();
+        kb.finalize_transcript().unwrap();
+
+        assert_eq!(
+            kb.hardware.typed_chars.iter().collect::<String>(),
+            "if ready    "
+        );
+        assert!(kb.hardware.pressed_keys.is_empty());
+        // The spaces are part of the turn's text, so "correct that" can take them back
+        assert_eq!(kb.last_finalized, "if ready    ");
+
+        let mut kb = VirtualKeyboard::new(MockKeyboardHardware::new());
+        kb.set_voice_commands_enabled(true);
+        kb.set_tab_as_spaces(0);
+        kb.update_transcript("name tab").unwrap();
+        kb.finalize_transcript().unwrap();
+        assert_eq!(kb.hardware.typed_chars.iter().collect::<String>(), "name");
+        assert_eq!(kb.hardware.pressed_keys, vec![KEY_TAB]);
+    }
+
+    #[test]
+    fn test_smart_punctuation_skipped_before_key_command() {
+        let mut kb = VirtualKeyboard::new(MockKeyboardHardware::new());
+        kb.set_voice_commands_enabled(true);
+        kb.set_smart_punctuation_enabled(true);
+        kb.update_transcript("Jane tab").unwrap();
+        kb.finalize_transcript().unwrap();
+
+        assert_eq!(kb.hardware.typed_chars.iter().collect::<String>(), "Jane");
+        assert_eq!(kb.hardware.pressed_keys, vec![KEY_TAB]);
+    }
+
+    #[test]
+    fn test_type_raw_appends_without_diffing() {
+        let mut kb = VirtualKeyboard::new(MockKeyboardHardware::new());
+        kb.type_raw("hello").unwrap();
+        assert_eq!(kb.hardware.typed_chars, ['h', 'e', 'l', 'l', 'o']);
+        assert_eq!(kb.current_text, "hello");
+
+        // Raw text is appended verbatim, even when it isn't an extension
+        kb.type_raw(", WORLD").unwrap();
+        assert_eq!(kb.current_text, "hello, WORLD");
+        assert_eq!(kb.hardware.backspace_count, 0);
+    }
+
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn test_write_all_nonblocking_completes_through_short_writes() {
+        use std::io::Read;
+
+        let (read_fd, write_fd) = nix::unistd::pipe().unwrap();
+        let flags = unsafe { libc::fcntl(write_fd, libc::F_GETFL) };
+        unsafe { libc::fcntl(write_fd, libc::F_SETFL, flags | libc::O_NONBLOCK) };
+
+        // Far more than the pipe buffer holds, so the writer sees both short
+        // writes and EAGAIN while the reader drains in small increments
+        let payload: Vec<u8> = (0..256 * 1024).map(|i| (i % 251) as u8).collect();
+        let expected = payload.clone();
+
+        let reader = std::thread::spawn(move || {
+            let mut file = unsafe { std::fs::File::from_raw_fd(read_fd) };
+            let mut received = Vec::new();
+            let mut chunk = [0u8; 7];
+            loop {
+                let n = file.read(&mut chunk).unwrap();
+                if n == 0 {
+                    break;
+                }
+                received.extend_from_slice(&chunk[..n]);
+            }
+            received
+        });
+
+        write_all_nonblocking(write_fd, &payload).unwrap();
+        close(write_fd).unwrap();
+
+        assert_eq!(reader.join().unwrap(), expected);
+    }
+
+    #[test]
+    fn test_finalize_clears_tracking_by_default() {
+        let mut kb = VirtualKeyboard::new(MockKeyboardHardware::new());
+        kb.update_transcript("hello").unwrap();
+        kb.finalize_transcript().unwrap();
+        assert_eq!(kb.current_text, "");
+
+        // The next turn starts fresh, so a repeated prefix is typed again
+        kb.update_transcript("hello world").unwrap();
+        kb.finalize_transcript().unwrap();
+        assert_eq!(
+            kb.hardware.typed_chars.iter().collect::<String>(),
+            "hellohello world"
+        );
+    }
+
+    #[test]
+    fn test_continuous_line_retains_text_across_turns() {
+        let mut kb = VirtualKeyboard::new(MockKeyboardHardware::new());
+        kb.set_continuous_line_enabled(true);
+        kb.update_transcript("hello").unwrap();
+        kb.finalize_transcript().unwrap();
+        assert_eq!(kb.current_text, "hello");
+
+        // A transcript repeating the line only types what's new
+        kb.update_transcript("hello world").unwrap();
+        kb.finalize_transcript().unwrap();
+        assert_eq!(
+            kb.hardware.typed_chars.iter().collect::<String>(),
+            "hello world"
+        );
+        assert_eq!(kb.hardware.backspace_count, 0);
+
+        // A fresh transcript continues the line after a space
+        kb.update_transcript("again").unwrap();
+        assert_eq!(kb.current_text, "hello world again");
+    }
+
+    #[test]
+    fn test_continuous_line_resets_after_enter() {
+        let mut kb = VirtualKeyboard::new(MockKeyboardHardware::new());
+        kb.set_continuous_line_enabled(true);
+        kb.update_transcript("hello").unwrap();
+        kb.finalize_transcript().unwrap();
+        kb.update_transcript("world enter").unwrap();
+        kb.finalize_transcript().unwrap();
         assert!(kb.hardware.enter_pressed);
-        // Should have backspaced 6 characters (" ENTER")
-        assert_eq!(kb.hardware.backspace_count, 6);
+        assert_eq!(kb.current_text, "");
+
+        kb.update_transcript("next").unwrap();
+        assert_eq!(
+            kb.hardware.typed_chars.iter().collect::<String>(),
+            "hello worldnext"
+        );
     }
 
     #[test]
-    fn test_finalize_without_enter_command() {
+    fn test_line_prefix_and_suffix_wrap_each_line() {
         let mut kb = VirtualKeyboard::new(MockKeyboardHardware::new());
+        kb.set_voice_enter_enabled(true);
+        kb.set_line_affixes("\"".to_string(), "\"".to_string());
 
-        // Type text not ending with "enter"
+        // The prefix comes with the first update and is never re-typed
+        kb.update_transcript("hello").unwrap();
+        assert_eq!(kb.current_text, "\"hello");
         kb.update_transcript("hello world").unwrap();
-        assert_eq!(kb.current_text, "hello world");
-        assert!(!kb.hardware.enter_pressed);
+        kb.finalize_transcript().unwrap();
+        let screen = |kb: &VirtualKeyboard<MockKeyboardHardware>| -> String {
+            kb.hardware.typed_chars.iter().collect()
+        };
+        assert_eq!(screen(&kb), "\"hello world\"");
+        assert_eq!(kb.hardware.backspace_count, 0);
+
+        // The suffix follows the text left once the command phrase is gone,
+        // and Enter comes last
+        kb.update_transcript("next enter").unwrap();
+        kb.finalize_transcript().unwrap();
+        assert_eq!(screen(&kb), "\"hello world\"\"next\"");
+        assert_eq!(kb.hardware.backspace_count, " enter".len());
+        assert!(kb
+            .hardware
+            .actions
+            .ends_with(&[KeyAction::Press(KEY_ENTER), KeyAction::Release(KEY_ENTER)]));
+
+        // A turn that was only a command leaves no empty pair behind
+        kb.update_transcript("enter").unwrap();
+        kb.finalize_transcript().unwrap();
+        assert_eq!(screen(&kb), "\"hello world\"\"next\"");
+        assert_eq!(kb.hardware.enter_count, 2);
+    }
+
+    #[test]
+    fn test_line_affixes_in_commit_and_continuous_line_modes() {
+        let mut kb = VirtualKeyboard::new(MockKeyboardHardware::new());
+        kb.set_voice_enter_enabled(true);
+        kb.set_line_affixes("- ".to_string(), ";".to_string());
+        kb.commit_transcript("first item enter").unwrap();
+        kb.commit_transcript("enter").unwrap();
+        assert_eq!(
+            kb.hardware.typed_chars.iter().collect::<String>(),
+            "- first item;"
+        );
+        assert_eq!(kb.hardware.backspace_count, 0);
+        assert_eq!(kb.hardware.enter_count, 2);
+
+        // A continued line is wrapped once, not per turn
+        let mut kb = VirtualKeyboard::new(MockKeyboardHardware::new());
+        kb.set_voice_enter_enabled(true);
+        kb.set_continuous_line_enabled(true);
+        kb.set_line_affixes("- ".to_string(), ";".to_string());
+        kb.update_transcript("one").unwrap();
+        kb.finalize_transcript().unwrap();
+        kb.update_transcript("two enter").unwrap();
+        kb.finalize_transcript().unwrap();
+        assert_eq!(
+            kb.hardware.typed_chars.iter().collect::<String>(),
+            "- one two;"
+        );
+        assert!(kb.hardware.enter_pressed);
+    }
+
+    #[test]
+    fn test_repeated_transcript_is_a_no_op() {
+        let mut kb = VirtualKeyboard::new(MockKeyboardHardware::new());
+        kb.update_transcript("hello world").unwrap();
+        let typed = kb.hardware.actions.len();
+        kb.update_transcript("hello world").unwrap();
+        kb.update_transcript("hello world").unwrap();
+        assert_eq!(kb.hardware.actions.len(), typed);
+        assert_eq!(kb.hardware.typed_chars.len(), 11);
+        assert_eq!(kb.hardware.backspace_count, 0);
+    }
+
+    #[test]
+    fn test_reset_tracking_keeps_text_on_screen() {
+        let mut kb = VirtualKeyboard::new(MockKeyboardHardware::new());
+        kb.update_transcript("half a sentence").unwrap();
+        kb.reset_tracking();
+        assert_eq!(kb.current_text, "");
+
+        // The new session's transcript shares no prefix, yet nothing is deleted
+        kb.update_transcript("and the rest").unwrap();
+        assert_eq!(kb.hardware.backspace_count, 0);
+        assert_eq!(
+            kb.hardware.typed_chars.iter().collect::<String>(),
+            "half a sentenceand the rest"
+        );
+
+        // Nothing from before the reset can be "corrected" away either
+        kb.set_voice_commands_enabled(true);
+        kb.finalize_transcript().unwrap();
+        kb.reset_tracking();
+        kb.update_transcript("correct that").unwrap();
+        kb.finalize_transcript().unwrap();
+        assert_eq!(kb.hardware.backspace_count, "correct that".len());
+    }
+
+    #[test]
+    fn test_double_finalize_presses_enter_once() {
+        let mut kb = VirtualKeyboard::new(MockKeyboardHardware::new());
+        kb.set_voice_enter_enabled(true);
+        kb.update_transcript("send it enter").unwrap();
+        kb.finalize_transcript().unwrap();
+        kb.finalize_transcript().unwrap();
+        assert_eq!(kb.hardware.enter_count, 1);
+
+        // Without voice-enter every turn ends in Enter, but still only once
+        kb.set_voice_enter_enabled(false);
+        kb.update_transcript("next").unwrap();
+        kb.finalize_transcript().unwrap();
+        kb.finalize_transcript().unwrap();
+        assert_eq!(kb.hardware.enter_count, 2);
+    }
+
+    #[test]
+    fn test_command_turns_skip_the_turn_enter_without_voice_enter() {
+        // The CLI's default: no --voice-enter, so dictated turns end in Enter
+        let mut kb = VirtualKeyboard::new(MockKeyboardHardware::new());
+        kb.set_voice_enter_enabled(false);
+        kb.set_voice_commands_enabled(true);
+
+        for phrase in ["name tab", "scratch that", "delete line", "done period"] {
+            kb.update_transcript(phrase).unwrap();
+            kb.finalize_transcript().unwrap();
+        }
+        assert_eq!(kb.hardware.enter_count, 0);
+        assert_eq!(kb.hardware.pressed_keys, vec![KEY_TAB]);
+
+        kb.update_transcript("hello").unwrap();
+        kb.finalize_transcript().unwrap();
+        assert_eq!(kb.hardware.enter_count, 1);
+
+        // Auto-enter leaves command turns alone too
+        kb.set_auto_enter_enabled(true);
+        kb.update_transcript("escape").unwrap();
+        kb.finalize_transcript().unwrap();
+        assert_eq!(kb.hardware.enter_count, 1);
+    }
+
+    #[test]
+    fn test_correct_that_removes_previous_turn() {
+        let mut kb = VirtualKeyboard::new(MockKeyboardHardware::new());
+        kb.set_voice_commands_enabled(true);
+        kb.update_transcript("hello world").unwrap();
+        kb.finalize_transcript().unwrap();
+        kb.update_transcript("correct that").unwrap();
+        kb.finalize_transcript().unwrap();
+        assert!(kb.hardware.typed_chars.is_empty());
+
+        // The re-dictated turn types where the old one was
+        kb.update_transcript("hello word").unwrap();
+        kb.finalize_transcript().unwrap();
+        assert_eq!(
+            kb.hardware.typed_chars.iter().collect::<String>(),
+            "hello word"
+        );
+
+        // Only one turn is remembered
+        kb.update_transcript("correct that").unwrap();
+        kb.finalize_transcript().unwrap();
+        kb.update_transcript("correct that").unwrap();
+        kb.finalize_transcript().unwrap();
+        assert!(kb.hardware.typed_chars.is_empty());
+        assert_eq!(kb.hardware.backspace_count, 12 + 11 + 12 + 10 + 12);
+    }
+
+    #[test]
+    fn test_correct_that_after_a_separated_turn() {
+        let mut kb = VirtualKeyboard::new(MockKeyboardHardware::new());
+        kb.set_voice_commands_enabled(true);
+        kb.set_turn_separator(TurnSeparator::Space);
+        kb.update_transcript("first").unwrap();
+        kb.finalize_transcript().unwrap();
+        kb.update_transcript("second").unwrap();
+        kb.finalize_transcript().unwrap();
+        kb.update_transcript("correct that").unwrap();
+        kb.finalize_transcript().unwrap();
+        // The separator typed for the command goes too; the one before "second" stays
+        assert_eq!(kb.hardware.typed_chars.iter().collect::<String>(), "first ");
+
+        kb.update_transcript("other").unwrap();
+        kb.finalize_transcript().unwrap();
+        assert_eq!(
+            kb.hardware.typed_chars.iter().collect::<String>(),
+            "first other"
+        );
+    }
+
+    #[test]
+    fn test_correct_that_in_continuous_line_keeps_earlier_turns() {
+        let mut kb = VirtualKeyboard::new(MockKeyboardHardware::new());
+        kb.set_voice_commands_enabled(true);
+        kb.set_continuous_line_enabled(true);
+        kb.update_transcript("first").unwrap();
+        kb.finalize_transcript().unwrap();
+        kb.update_transcript("second").unwrap();
+        kb.finalize_transcript().unwrap();
+        kb.update_transcript("correct that").unwrap();
+        kb.finalize_transcript().unwrap();
+        assert_eq!(kb.current_text, "first");
+
+        kb.update_transcript("other").unwrap();
+        assert_eq!(
+            kb.hardware.typed_chars.iter().collect::<String>(),
+            "first other"
+        );
+    }
+
+    #[test]
+    fn test_correct_that_with_nothing_finalized() {
+        let mut kb = VirtualKeyboard::new(MockKeyboardHardware::new());
+        kb.set_voice_commands_enabled(true);
+        kb.update_transcript("oops correct that").unwrap();
+        kb.finalize_transcript().unwrap();
+        // Only the phrase itself is removed
+        assert_eq!(kb.hardware.typed_chars.iter().collect::<String>(), "oops");
+
+        // Nor is anything corrected across an Enter
+        kb.update_transcript("sent enter").unwrap();
+        kb.finalize_transcript().unwrap();
+        kb.update_transcript("correct that").unwrap();
+        kb.finalize_transcript().unwrap();
+        assert_eq!(
+            kb.hardware.typed_chars.iter().collect::<String>(),
+            "oopssent"
+        );
+    }
+
+    #[test]
+    fn test_repeat_that_types_the_last_line_again() {
+        let mut kb = VirtualKeyboard::new(MockKeyboardHardware::new());
+        kb.set_voice_commands_enabled(true);
+        kb.set_turn_separator(TurnSeparator::Space);
+        kb.update_transcript("hello").unwrap();
+        kb.finalize_transcript().unwrap();
+        kb.update_transcript("repeat that").unwrap();
+        kb.finalize_transcript().unwrap();
+        assert_eq!(
+            kb.hardware.typed_chars.iter().collect::<String>(),
+            "hello hello"
+        );
+        assert_eq!(kb.hardware.enter_count, 0);
 
-        // Finalize - should NOT press ENTER key since text doesn't end with "enter"
+        // A line that was sent with Enter is sent again, suffix and all
+        let mut kb = VirtualKeyboard::new(MockKeyboardHardware::new());
+        kb.set_voice_commands_enabled(true);
+        kb.set_line_affixes(String::new(), ";".to_string());
+        kb.update_transcript("next row enter").unwrap();
         kb.finalize_transcript().unwrap();
-        assert_eq!(kb.current_text, "");
-        assert!(!kb.hardware.enter_pressed); // Should remain false
-        // Should not have backspaced anything for the enter command
-        assert_eq!(kb.hardware.backspace_count, 0);
-        assert_eq!(kb.hardware.typed_chars, ['h', 'e', 'l', 'l', 'o', ' ', 'w', 'o', 'r', 'l', 'd']);
+        kb.update_transcript("repeat that").unwrap();
+        kb.finalize_transcript().unwrap();
+        assert_eq!(
+            kb.hardware.typed_chars.iter().collect::<String>(),
+            "next row;next row;"
+        );
+        assert_eq!(kb.hardware.enter_count, 2);
     }
 
     #[test]
-    fn test_finalize_with_enter_in_middle() {
+    fn test_repeat_that_with_nothing_finalized() {
         let mut kb = VirtualKeyboard::new(MockKeyboardHardware::new());
-
-        // Type text with "enter" in the middle, not at the end
-        kb.update_transcript("enter the room").unwrap();
-        assert_eq!(kb.current_text, "enter the room");
-        assert!(!kb.hardware.enter_pressed);
-
-        // Finalize - should NOT press ENTER key since "enter" is not the last word
+        kb.set_voice_commands_enabled(true);
+        kb.update_transcript("repeat that").unwrap();
         kb.finalize_transcript().unwrap();
-        assert_eq!(kb.current_text, "");
-        assert!(!kb.hardware.enter_pressed); // Should remain false
-        // Should not have backspaced anything for the enter command
-        assert_eq!(kb.hardware.backspace_count, 0);
-        assert_eq!(kb.hardware.typed_chars, ['e', 'n', 't', 'e', 'r', ' ', 't', 'h', 'e', ' ', 'r', 'o', 'o', 'm']);
+        assert!(kb.hardware.typed_chars.is_empty());
+        assert_eq!(kb.hardware.enter_count, 0);
     }
 
     #[test]
-    fn test_enter_capitalization_patterns() {
-        // Test various capitalization patterns
-        let test_cases = vec![
-            ("hello enter", true), // lowercase
-            ("hello ENTER", true), // uppercase
-            ("hello Enter", true), // title case
-            ("hello EnTeR", true), // mixed case
-            ("hello eNtEr", true), // mixed case
+    fn test_spoken_punctuation_types_spaced_marks() {
+        let cases = [
+            (
+                vec![
+                    "hello comma",
+                    "hello comma world",
+                    "hello comma world period",
+                ],
+                "hello, world.",
+            ),
+            (
+                vec![
+                    "is it",
+                    "is it done question mark",
+                    "is it done question mark yes",
+                ],
+                "is it done? yes",
+            ),
+            (
+                vec!["list colon", "list colon eggs semicolon milk"],
+                "list: eggs; milk",
+            ),
         ];
-
-        for (input, should_trigger) in test_cases {
+        for (updates, expected) in cases {
             let mut kb = VirtualKeyboard::new(MockKeyboardHardware::new());
-            kb.update_transcript(input).unwrap();
+            kb.set_spoken_punctuation_enabled(true);
+            for update in &updates {
+                kb.update_transcript(update).unwrap();
+            }
             kb.finalize_transcript().unwrap();
 
-            if should_trigger {
-                assert!(
-                    kb.hardware.enter_pressed,
-                    "Should trigger ENTER for: '{}'",
-                    input
-                );
-            } else {
-                assert!(
-                    !kb.hardware.enter_pressed,
-                    "Should NOT trigger ENTER for: '{}'",
-                    input
-                );
-            }
+            assert_eq!(kb.current_text, "");
+            let text: String = kb.hardware.typed_chars.iter().collect();
+            assert_eq!(text, expected, "for {updates:?}");
         }
     }
 
     #[test]
-    fn test_enter_with_punctuation() {
-        // Test punctuation patterns - these SHOULD work with the regex approach
-        let test_cases = vec![
-            ("hello enter.", true),   // period attached
-            ("hello enter!", true),   // exclamation attached
-            ("hello enter?", true),   // question mark attached
-            ("hello enter,", true),   // comma attached
-            ("hello enter;", true),   // semicolon attached
-            ("hello enter:", true),   // colon attached
-            ("hello enter...", true), // multiple periods
-            ("hello enter!!", true),  // multiple exclamations
-        ];
+    fn test_number_mode_types_digits() {
+        let mut kb = VirtualKeyboard::new(MockKeyboardHardware::new());
+        kb.set_number_mode_enabled(true);
+        kb.update_transcript("call five").unwrap();
+        kb.update_transcript("call five five five").unwrap();
+        kb.update_transcript("call five five five for me").unwrap();
+        assert_eq!(kb.current_text, "call 555 for me");
+        assert_eq!(kb.hardware.backspace_count, 0);
 
-        for (input, should_trigger) in test_cases {
-            let mut kb = VirtualKeyboard::new(MockKeyboardHardware::new());
-            kb.update_transcript(input).unwrap();
-            kb.finalize_transcript().unwrap();
+        let mut kb = VirtualKeyboard::new(MockKeyboardHardware::new());
+        kb.update_transcript("call five").unwrap();
+        assert_eq!(kb.current_text, "call five");
+    }
 
-            if should_trigger {
-                assert!(
-                    kb.hardware.enter_pressed,
-                    "Should trigger ENTER for: '{}'",
-                    input
-                );
-            } else {
-                assert!(
-                    !kb.hardware.enter_pressed,
-                    "Should NOT trigger ENTER for: '{}'",
-                    input
-                );
-            }
-        }
+    #[test]
+    fn test_release_all_releases_every_modifier() {
+        let mut kb = VirtualKeyboard::new(MockKeyboardHardware::new());
+        kb.release_all().unwrap();
+        assert_eq!(
+            kb.hardware.released_keys,
+            vec![
+                KEY_LEFTSHIFT,
+                KEY_RIGHTSHIFT,
+                KEY_LEFTCTRL,
+                KEY_RIGHTCTRL,
+                KEY_LEFTALT,
+                KEY_RIGHTALT
+            ]
+        );
     }
 
     #[test]
-    fn test_enter_with_whitespace_and_punctuation() {
-        // Test whitespace and punctuation combinations
-        let test_cases = vec![
-            ("hello enter ", true),   // trailing space
-            ("hello enter  ", true),  // multiple trailing spaces
-            ("hello enter\t", true),  // trailing tab
-            ("hello enter\n", true),  // trailing newline
-            ("hello enter .", true),  // space then period
-            ("hello enter !", true),  // space then exclamation
-            ("hello enter ?", true),  // space then question mark
-            ("hello enter . ", true), // period with spaces
-            ("hello enter ! ", true), // exclamation with spaces
-        ];
+    fn test_modifier_state_follows_combos_and_held_shift() {
+        let mut hardware = MockKeyboardHardware::new();
+        hardware.keymap.hold_shift = true;
+        let mut kb = VirtualKeyboard::new(hardware);
+        assert!(kb.modifier_state().is_empty());
 
-        for (input, should_trigger) in test_cases {
-            let mut kb = VirtualKeyboard::new(MockKeyboardHardware::new());
-            kb.update_transcript(input).unwrap();
-            kb.finalize_transcript().unwrap();
+        kb.hardware_mut()
+            .press_combo(&[KEY_LEFTCTRL, KEY_LEFTSHIFT, KEY_T])
+            .unwrap();
+        kb.type_raw("ABc D").unwrap();
+        kb.hardware_mut().type_unicode('é').unwrap();
+        assert!(kb.modifier_state().is_empty());
+        assert!(kb
+            .hardware
+            .actions
+            .contains(&KeyAction::Press(KEY_LEFTSHIFT)));
+    }
 
-            if should_trigger {
-                assert!(
-                    kb.hardware.enter_pressed,
-                    "Should trigger ENTER for: '{}'",
-                    input
-                );
-            } else {
-                assert!(
-                    !kb.hardware.enter_pressed,
-                    "Should NOT trigger ENTER for: '{}'",
-                    input
-                );
-            }
-        }
+    #[test]
+    fn test_release_all_clears_held_modifiers() {
+        let mut kb = VirtualKeyboard::new(MockKeyboardHardware::new());
+        // As if typing stopped partway through a combo
+        let mut state = ModifierState::default();
+        state.record(KEY_LEFTCTRL, true);
+        state.record(KEY_RIGHTALT, true);
+        state.record(KEY_A, true);
+        kb.set_modifier_state(state).unwrap();
+        assert_eq!(
+            kb.hardware.actions,
+            vec![
+                KeyAction::Press(KEY_LEFTCTRL),
+                KeyAction::Press(KEY_RIGHTALT)
+            ]
+        );
+        assert_eq!(kb.modifier_state().held(), vec![KEY_LEFTCTRL, KEY_RIGHTALT]);
+        assert!(kb.modifier_state().is_held(KEY_RIGHTALT));
+        assert!(!kb.modifier_state().is_held(KEY_LEFTSHIFT));
+
+        kb.release_all().unwrap();
+        assert!(kb.modifier_state().is_empty());
+
+        // Setting a state only touches the modifiers that differ
+        state.record(KEY_RIGHTALT, false);
+        kb.set_modifier_state(state).unwrap();
+        kb.set_modifier_state(ModifierState::default()).unwrap();
+        assert_eq!(
+            kb.hardware.actions[kb.hardware.actions.len() - 2..],
+            [
+                KeyAction::Press(KEY_LEFTCTRL),
+                KeyAction::Release(KEY_LEFTCTRL)
+            ]
+        );
+        assert!(kb.modifier_state().is_empty());
     }
 
     #[test]
-    fn test_enter_with_leading_whitespace() {
-        // Test cases with whitespace before "enter"
-        let test_cases = vec![
-            ("hello  enter", true), // double space before enter
-            ("hello\tenter", true), // tab before enter
-            ("hello\nenter", true), // newline before enter (unlikely but possible)
-        ];
+    fn test_mock_records_shifted_character_sequence() {
+        let mut kb = VirtualKeyboard::new(MockKeyboardHardware::new());
+        kb.type_raw("Ab").unwrap();
+        assert_eq!(
+            kb.hardware.actions,
+            vec![
+                KeyAction::Press(KEY_LEFTSHIFT),
+                KeyAction::Press(KEY_A),
+                KeyAction::Release(KEY_A),
+                KeyAction::Release(KEY_LEFTSHIFT),
+                KeyAction::Press(KEY_B),
+                KeyAction::Release(KEY_B),
+            ]
+        );
+    }
 
-        for (input, should_trigger) in test_cases {
-            let mut kb = VirtualKeyboard::new(MockKeyboardHardware::new());
-            kb.update_transcript(input).unwrap();
-            kb.finalize_transcript().unwrap();
+    #[test]
+    fn test_hold_shift_presses_shift_once_per_run() {
+        let mut hardware = MockKeyboardHardware::new();
+        hardware.keymap.hold_shift = true;
+        let mut kb = VirtualKeyboard::new(hardware);
+        kb.type_raw("ABC").unwrap();
+        assert_eq!(
+            kb.hardware.actions,
+            vec![
+                KeyAction::Press(KEY_LEFTSHIFT),
+                KeyAction::Press(KEY_A),
+                KeyAction::Release(KEY_A),
+                KeyAction::Press(KEY_B),
+                KeyAction::Release(KEY_B),
+                KeyAction::Press(KEY_C),
+                KeyAction::Release(KEY_C),
+                KeyAction::Release(KEY_LEFTSHIFT),
+            ]
+        );
+        assert_eq!(kb.hardware.typed_chars.iter().collect::<String>(), "ABC");
+    }
 
-            if should_trigger {
-                assert!(
-                    kb.hardware.enter_pressed,
-                    "Should trigger ENTER for: '{}'",
-                    input
-                );
-            } else {
-                assert!(
-                    !kb.hardware.enter_pressed,
-                    "Should NOT trigger ENTER for: '{}'",
-                    input
-                );
-            }
-        }
+    #[test]
+    fn test_hold_shift_releases_before_unshifted_and_unmapped_characters() {
+        let mut hardware = MockKeyboardHardware::new();
+        hardware.keymap.hold_shift = true;
+        let mut kb = VirtualKeyboard::new(hardware);
+        kb.type_raw("A!bCé").unwrap();
+        assert_eq!(
+            kb.hardware.actions,
+            vec![
+                KeyAction::Press(KEY_LEFTSHIFT),
+                KeyAction::Press(KEY_A),
+                KeyAction::Release(KEY_A),
+                KeyAction::Press(KEY_1),
+                KeyAction::Release(KEY_1),
+                KeyAction::Release(KEY_LEFTSHIFT),
+                KeyAction::Press(KEY_B),
+                KeyAction::Release(KEY_B),
+                KeyAction::Press(KEY_LEFTSHIFT),
+                KeyAction::Press(KEY_C),
+                KeyAction::Release(KEY_C),
+                KeyAction::Release(KEY_LEFTSHIFT),
+                KeyAction::Text("é".to_string()),
+            ]
+        );
     }
 
     #[test]
-    fn test_enter_negative_cases() {
-        // Test cases that should NOT trigger enter
-        let test_cases = vec![
-            ("hello world", false),      // no enter
-            ("enter the room", false),   // enter not at end
-            ("hello entered", false),    // "entered" is not "enter"
-            ("hello entering", false),   // "entering" is not "enter"
-            ("hello center", false),     // "center" contains "enter" but is not "enter"
-            ("hello enterprise", false), // "enterprise" contains "enter" but is not "enter"
-            ("hello enter now", false),  // "enter" not at the very end
-        ];
+    fn test_simulated_hardware_reports_each_action() {
+        let mut kb = VirtualKeyboard::new(SimulatedKeyboardHardware::with_output(Vec::new()));
+        kb.set_voice_enter_enabled(false);
+        kb.update_transcript("hi").unwrap();
+        kb.update_transcript("ha").unwrap();
+        kb.finalize_transcript().unwrap();
 
-        for (input, should_trigger) in test_cases {
-            let mut kb = VirtualKeyboard::new(MockKeyboardHardware::new());
-            kb.update_transcript(input).unwrap();
-            kb.finalize_transcript().unwrap();
+        let output = String::from_utf8(kb.hardware.out.clone()).unwrap();
+        let lines: Vec<&str> = output.lines().collect();
+        assert_eq!(
+            lines,
+            vec![
+                r#"[simulate] type "hi"            -> "hi""#,
+                r#"[simulate] backspace            -> "h""#,
+                r#"[simulate] type "a"             -> "ha""#,
+                r#"[simulate] enter                -> "ha""#,
+            ]
+        );
+    }
 
-            if should_trigger {
-                assert!(
-                    kb.hardware.enter_pressed,
-                    "Should trigger ENTER for: '{}'",
-                    input
-                );
-            } else {
-                assert!(
-                    !kb.hardware.enter_pressed,
-                    "Should NOT trigger ENTER for: '{}'",
-                    input
-                );
-            }
+    #[test]
+    fn test_altgr_layout_holds_right_alt() {
+        let mut hardware = MockKeyboardHardware::new();
+        hardware.keymap.layout = KeyboardLayout::De;
+        let mut kb = VirtualKeyboard::new(hardware);
+        kb.type_raw("a@z").unwrap();
+        assert_eq!(
+            kb.hardware.actions,
+            vec![
+                KeyAction::Press(KEY_A),
+                KeyAction::Release(KEY_A),
+                KeyAction::Press(KEY_RIGHTALT),
+                KeyAction::Press(KEY_Q),
+                KeyAction::Release(KEY_Q),
+                KeyAction::Release(KEY_RIGHTALT),
+                KeyAction::Press(KEY_Y),
+                KeyAction::Release(KEY_Y),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_mock_records_combos_and_unmapped_text() {
+        let mut hardware = MockKeyboardHardware::new();
+        hardware.press_combo(&[KEY_LEFTCTRL, KEY_C]).unwrap();
+        hardware.type_text("é").unwrap();
+        assert_eq!(
+            hardware.actions,
+            vec![
+                KeyAction::Press(KEY_LEFTCTRL),
+                KeyAction::Press(KEY_C),
+                KeyAction::Release(KEY_C),
+                KeyAction::Release(KEY_LEFTCTRL),
+                KeyAction::Text("é".to_string()),
+            ]
+        );
+    }
+
+    fn instant_retry(retries: u32) -> RetryPolicy {
+        RetryPolicy {
+            retries,
+            initial_backoff: std::time::Duration::ZERO,
+            max_backoff: std::time::Duration::ZERO,
         }
     }
 
     #[test]
-    fn test_uppercase_mode_basic() {
-        let mut kb = VirtualKeyboard::new(MockKeyboardHardware::new());
-        kb.set_uppercase_enabled(true);
+    fn test_device_creation_retries_transient_failures() {
+        let mut calls = 0;
+        let fd = retry_transient(instant_retry(3), || {
+            calls += 1;
+            if calls <= 2 {
+                Err(anyhow::Error::from(Errno::EBUSY)).context("Failed to create uinput device")
+            } else {
+                Ok(42)
+            }
+        })
+        .unwrap();
+        assert_eq!(fd, 42);
+        assert_eq!(calls, 3);
+    }
 
-        // Type some text
-        kb.update_transcript("hello world").unwrap();
-        assert_eq!(kb.current_text, "HELLO WORLD");
-        assert_eq!(kb.hardware.typed_chars, ['H', 'E', 'L', 'L', 'O', ' ', 'W', 'O', 'R', 'L', 'D']);
+    #[test]
+    fn test_device_creation_gives_up_after_retries() {
+        let mut calls = 0;
+        let result: Result<i32> = retry_transient(instant_retry(2), || {
+            calls += 1;
+            Err(anyhow::Error::from(Errno::EAGAIN))
+        });
+        assert!(result.is_err());
+        assert_eq!(calls, 3);
     }
 
     #[test]
-    fn test_uppercase_mode_incremental() {
-        let mut kb = VirtualKeyboard::new(MockKeyboardHardware::new());
-        kb.set_uppercase_enabled(true);
+    fn test_device_creation_permission_error_is_fatal() {
+        let mut calls = 0;
+        let result: Result<i32> = retry_transient(instant_retry(5), || {
+            calls += 1;
+            Err(anyhow::Error::from(Errno::EACCES)).context("Failed to open /dev/uinput")
+        });
+        assert!(result.is_err());
+        assert_eq!(calls, 1);
 
-        // Start with "hello"
-        kb.update_transcript("hello").unwrap();
-        assert_eq!(kb.current_text, "HELLO");
+        let io_busy = anyhow::Error::from(std::io::Error::from_raw_os_error(libc::EBUSY));
+        assert!(is_transient_error(&io_busy));
+    }
 
-        // Extend to "hello world"
-        kb.update_transcript("hello world").unwrap();
-        assert_eq!(kb.current_text, "HELLO WORLD");
-        assert_eq!(kb.hardware.typed_chars, ['H', 'E', 'L', 'L', 'O', ' ', 'W', 'O', 'R', 'L', 'D']);
+    #[test]
+    fn test_missing_uinput_is_fatal() {
+        // Retrying can't load the module; the error says to run modprobe
+        let mut calls = 0;
+        let result: Result<i32> = retry_transient(instant_retry(5), || {
+            calls += 1;
+            Err(anyhow::Error::from(Errno::ENOENT)).context("Failed to open /dev/uinput")
+        });
+        assert!(result.is_err());
+        assert_eq!(calls, 1);
     }
 
     #[test]
-    fn test_uppercase_mode_with_enter_command() {
-        let mut kb = VirtualKeyboard::new(MockKeyboardHardware::new());
-        kb.set_uppercase_enabled(true);
+    #[cfg(target_os = "linux")]
+    fn test_uinput_open_error_messages() {
+        assert!(uinput_open_error_message(Errno::ENOENT).contains("modprobe uinput"));
+        assert!(uinput_open_error_message(Errno::ENODEV).contains("modprobe uinput"));
+        assert!(uinput_open_error_message(Errno::EACCES).contains("udev rule"));
+        assert!(uinput_open_error_message(Errno::EPERM).contains("run as root"));
+        assert_eq!(
+            uinput_open_error_message(Errno::EBUSY),
+            "Failed to open /dev/uinput (Device or resource busy)"
+        );
 
-        // Type text ending with "enter"
-        kb.update_transcript("hello enter").unwrap();
-        assert_eq!(kb.current_text, "HELLO ENTER");
-        assert!(!kb.hardware.enter_pressed);
+        // The errno stays behind the message, where retries still recognise it
+        let err = anyhow::Error::from(uinput_open_error(Errno::ENODEV));
+        assert!(is_transient_error(&err));
+        let missing = anyhow::Error::from(uinput_open_error(Errno::ENOENT));
+        assert!(!is_transient_error(&missing));
+        assert!(matches!(
+            VkError::find(&err),
+            Some(VkError::DeviceUnavailable { .. })
+        ));
+        assert!(matches!(
+            uinput_open_error(Errno::EACCES),
+            VkError::DevicePermission { .. }
+        ));
+        assert!(err
+            .to_string()
+            .starts_with("Failed to open /dev/uinput: the uinput module"));
+    }
 
-        // Finalize - should backspace "ENTER" and press ENTER key
-        kb.finalize_transcript().unwrap();
-        assert_eq!(kb.current_text, "");
-        assert!(kb.hardware.enter_pressed);
-        // Should have backspaced 6 characters (" ENTER")
-        assert_eq!(kb.hardware.backspace_count, 6);
+    #[test]
+    fn test_device_auto_repeats_held_keys() {
+        assert!(DEVICE_EVENT_TYPES.contains(&EV_KEY));
+        assert!(DEVICE_EVENT_TYPES.contains(&EV_REP));
+
+        let repeat = KeyRepeat {
+            delay: Duration::from_millis(400),
+            period: Duration::from_millis(50),
+        };
+        let events: Vec<(u16, u16, i32)> = repeat
+            .events()
+            .iter()
+            .map(|e| (e.type_, e.code, e.value))
+            .collect();
+        assert_eq!(
+            events,
+            vec![(EV_REP, REP_DELAY, 400), (EV_REP, REP_PERIOD, 50)]
+        );
     }
 
     #[test]
-    fn test_normal_mode_basic() {
+    fn test_scroll_command_holds_key() {
         let mut kb = VirtualKeyboard::new(MockKeyboardHardware::new());
-        // uppercase_enabled is false by default
+        kb.set_voice_commands_enabled(true);
+        kb.update_transcript("scroll down for three seconds")
+            .unwrap();
+        kb.finalize_transcript().unwrap();
 
-        // Type some text
-        kb.update_transcript("Hello World").unwrap();
-        assert_eq!(kb.current_text, "Hello World");
-        assert_eq!(kb.hardware.typed_chars, ['H', 'e', 'l', 'l', 'o', ' ', 'W', 'o', 'r', 'l', 'd']);
+        // The phrase is backspaced, then the arrow key is held
+        assert!(kb.hardware.actions.ends_with(&[
+            KeyAction::Release(KEY_BACKSPACE),
+            KeyAction::Press(KEY_DOWN),
+            KeyAction::Wait(Duration::from_secs(3)),
+            KeyAction::Release(KEY_DOWN),
+        ]));
+        assert_eq!(kb.hardware.typed_chars.iter().collect::<String>(), "");
     }
 }